@@ -0,0 +1,30 @@
+//! Audio shared by the benchmarks.
+
+pub const SAMPLE_RATE: u32 = 44100;
+pub const CHANNELS: u32 = 2;
+
+/// A few seconds of a 16-bit chord with a little noise, so frames use LPC
+/// and residual coding the way music does.
+pub fn fixture_samples(seconds: u32) -> Vec<i32> {
+    let frames = (SAMPLE_RATE * seconds) as usize;
+    let mut seed = 0x2545_f491_u32;
+    let mut noise = move || {
+        seed ^= seed << 13;
+        seed ^= seed >> 17;
+        seed ^= seed << 5;
+        (seed % 512) as f64 - 256.0
+    };
+    let mut samples = Vec::with_capacity(frames * CHANNELS as usize);
+    for i in 0..frames {
+        let t = i as f64 / SAMPLE_RATE as f64;
+        let chord: f64 = [220.0, 277.18, 329.63]
+            .iter()
+            .map(|f| (2.0 * std::f64::consts::PI * f * t).sin())
+            .sum();
+        for channel in 0..CHANNELS {
+            let value = chord * 6000.0 * (1.0 - 0.2 * channel as f64) + noise();
+            samples.push(value as i32);
+        }
+    }
+    samples
+}
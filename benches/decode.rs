@@ -0,0 +1,138 @@
+//! Decode throughput, allocations per block and seek latency.
+//!
+//! Fixtures are encoded in the setup from generated audio, so results are
+//! comparable across machines and runs without checked-in files. Run with
+//! `cargo bench --bench decode`; the allocation counts are printed before
+//! the timings.
+
+mod common;
+
+use common::{fixture_samples, CHANNELS, SAMPLE_RATE};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use soundkit::audio_packet::Encoder;
+use soundkit_flac::{decode_range, FlacDecoder, FlacEncoder};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fs::File;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+const BLOCK_SIZE: u32 = 4096;
+
+/// Counts heap allocations so the decode path's per-block cost can be shown.
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+fn encode(samples: &[i32], level: u32) -> Vec<u8> {
+    let mut encoder = FlacEncoder::new(SAMPLE_RATE, 16, CHANNELS, BLOCK_SIZE, level);
+    encoder.init().unwrap();
+    let mut output = vec![0u8; samples.len() * 4 + (1 << 16)];
+    let mut len = encoder.encode_interleaved(samples, &mut output).unwrap();
+    len += encoder.finish(&mut output[len..]).unwrap();
+    output.truncate(len);
+    output
+}
+
+/// Encodes through a seekable sink so STREAMINFO carries the length, with or
+/// without a seek table.
+fn encode_seekable(samples: &[i32], with_seek_table: bool) -> Vec<u8> {
+    let frames = samples.len() as u64 / CHANNELS as u64;
+    let path = std::env::temp_dir().join(format!(
+        "soundkit-flac-bench-{}-{}.flac",
+        std::process::id(),
+        with_seek_table
+    ));
+    let mut encoder = FlacEncoder::new(SAMPLE_RATE, 16, CHANNELS, BLOCK_SIZE, 5);
+    if with_seek_table {
+        encoder.add_seek_points(SAMPLE_RATE, frames).unwrap();
+    }
+    encoder
+        .set_seekable_sink(File::create(&path).unwrap())
+        .unwrap();
+    encoder.init().unwrap();
+    let mut output = Vec::new();
+    encoder.encode_interleaved(samples, &mut output).unwrap();
+    encoder.finish(&mut output).unwrap();
+    drop(encoder.take_sink().unwrap());
+    let encoded = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    encoded
+}
+
+fn decode_all(encoded: &[u8]) -> Vec<i32> {
+    let mut decoder = FlacDecoder::new();
+    decoder.init().unwrap();
+    decoder.feed(encoded);
+    decoder.decode_remaining().unwrap()
+}
+
+fn bench_decode(c: &mut Criterion) {
+    let samples = fixture_samples(10);
+    let blocks = (samples.len() / CHANNELS as usize).div_ceil(BLOCK_SIZE as usize);
+
+    let mut group = c.benchmark_group("decode");
+    group.throughput(Throughput::Elements(samples.len() as u64));
+    for level in [0, 5, 8] {
+        let encoded = encode(&samples, level);
+        assert_eq!(decode_all(&encoded), samples);
+
+        let before = ALLOCATIONS.load(Ordering::Relaxed);
+        black_box(decode_all(&encoded));
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed) - before;
+        println!(
+            "level {}: {} bytes, {} allocations for {} blocks ({:.2} per block)",
+            level,
+            encoded.len(),
+            allocations,
+            blocks,
+            allocations as f64 / blocks as f64
+        );
+
+        group.bench_with_input(BenchmarkId::new("level", level), &encoded, |b, encoded| {
+            b.iter(|| decode_all(black_box(encoded)))
+        });
+    }
+    group.finish();
+}
+
+fn bench_seek(c: &mut Criterion) {
+    let samples = fixture_samples(60);
+    let start = Duration::from_secs(37);
+    let end = start + Duration::from_millis(100);
+
+    let mut group = c.benchmark_group("seek");
+    for with_seek_table in [true, false] {
+        let encoded = encode_seekable(&samples, with_seek_table);
+        let name = if with_seek_table {
+            "seek table"
+        } else {
+            "binary search"
+        };
+        group.bench_with_input(BenchmarkId::from_parameter(name), &encoded, |b, encoded| {
+            b.iter(|| decode_range(black_box(encoded), start, end).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_decode, bench_seek);
+criterion_main!(benches);
@@ -0,0 +1,55 @@
+//! Encode throughput with and without the STREAMINFO MD5.
+//!
+//! libFLAC hashes every input sample to fill in the MD5, which is pure
+//! overhead for real-time streams nobody will verify. Both runs use level 5
+//! and `TrustLevel::Fast`, so libFLAC's verify decoder doesn't hide the
+//! difference. Run with `cargo bench --bench encode`.
+
+mod common;
+
+use common::{fixture_samples, CHANNELS, SAMPLE_RATE};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use soundkit::audio_packet::Encoder;
+use soundkit_flac::{EncoderConfig, FlacEncoder, TrustLevel};
+
+fn encode(samples: &[i32], config: &EncoderConfig, output: &mut [u8]) -> usize {
+    let mut encoder = FlacEncoder::from_config(config);
+    encoder.init().unwrap();
+    let len = encoder.encode_interleaved(samples, output).unwrap();
+    len + encoder.finish(&mut output[len..]).unwrap()
+}
+
+fn bench_md5(c: &mut Criterion) {
+    let samples = fixture_samples(10);
+    let mut output = vec![0u8; samples.len() * 4 + (1 << 16)];
+
+    let mut group = c.benchmark_group("encode_md5");
+    group.throughput(Throughput::Elements(samples.len() as u64));
+    // turning the MD5 off needs the md5-toggle feature
+    let settings: &[bool] = match cfg!(feature = "md5-toggle") {
+        true => &[true, false],
+        false => &[true],
+    };
+    for &write_md5 in settings {
+        let config = EncoderConfig::new(SAMPLE_RATE, CHANNELS, 16)
+            .compression_level(5)
+            .trust_level(TrustLevel::Fast)
+            .write_md5(write_md5);
+        // the streamed header is never back-patched, so check the final one
+        let mut encoder = FlacEncoder::from_config(&config);
+        encoder.init().unwrap();
+        encoder.encode_interleaved(&samples, &mut output).unwrap();
+        encoder.finish(&mut output).unwrap();
+        let md5 = encoder.final_stream_info().unwrap().md5;
+        assert_eq!(md5 == [0; 16], !write_md5);
+
+        let name = if write_md5 { "md5 on" } else { "md5 off" };
+        group.bench_with_input(BenchmarkId::from_parameter(name), &config, |b, config| {
+            b.iter(|| encode(black_box(&samples), config, &mut output))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_md5);
+criterion_main!(benches);
@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// decode_robust must return an error for bad input, never panic.
+fuzz_target!(|data: &[u8]| {
+    let _ = soundkit_flac::decode_robust(data);
+});
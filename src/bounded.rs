@@ -0,0 +1,84 @@
+use crate::{EncoderConfig, FlacEncoder, FlacError};
+use std::io::{ErrorKind, Read, Write};
+
+/// Encodes raw PCM from `reader` to `writer` in blocks of `block_samples`
+/// samples per channel, returning the number of bytes written.
+///
+/// The input is interleaved little-endian signed PCM at `config`'s bit
+/// depth, in whole bytes per sample: 2 bytes for 16-bit, 3 for 24-bit
+/// (packed), 4 for 32-bit and so on. The input and sample buffers are
+/// allocated once and reused, and each block's output is written out
+/// before the next is read, so memory use depends on `block_samples` and
+/// not on the length of the input; files far larger than memory can be
+/// transcoded this way.
+///
+/// `writer` only needs to be written forwards, so libFLAC can't go back
+/// and complete the header: STREAMINFO has no MD5 and, unless
+/// [`total_samples`](EncoderConfig::total_samples) is set, no total. Use a
+/// [`seekable sink`](FlacEncoder::set_seekable_sink) with the other
+/// encode methods where the header matters.
+/// [`accumulate_output`](EncoderConfig::accumulate_output) is ignored, as
+/// it would keep the whole stream in memory. Input ending partway through a
+/// sample frame fails with [`FlacError::InvalidInput`].
+pub fn encode_streaming_bounded<R: Read, W: Write>(
+    mut reader: R,
+    mut writer: W,
+    config: &EncoderConfig,
+    block_samples: usize,
+) -> Result<u64, FlacError> {
+    if block_samples == 0 {
+        return Err(FlacError::InvalidConfig(
+            "block_samples must be at least 1".to_string(),
+        ));
+    }
+    let bytes_per_sample = config.bits_per_sample.div_ceil(8) as usize;
+    let frame_len = bytes_per_sample * config.channels as usize;
+    let mut input = vec![0u8; block_samples * frame_len];
+    let mut samples = Vec::with_capacity(block_samples * config.channels as usize);
+
+    let mut encoder = FlacEncoder::from_config(&config.clone().accumulate_output(false));
+    encoder.restart()?;
+    let mut written = encoder.write_encoded(&mut writer)? as u64;
+    loop {
+        let len = read_full(&mut reader, &mut input)?;
+        if len % frame_len != 0 {
+            return Err(FlacError::InvalidInput(format!(
+                "Input ends {} bytes into a {}-byte sample frame",
+                len % frame_len,
+                frame_len
+            )));
+        }
+        samples.clear();
+        samples.extend(input[..len].chunks_exact(bytes_per_sample).map(|bytes| {
+            let value = bytes
+                .iter()
+                .rev()
+                .fold(0u32, |value, &byte| (value << 8) | byte as u32);
+            // sign-extend from the top byte read
+            let shift = 32 - 8 * bytes_per_sample as u32;
+            ((value << shift) as i32) >> shift
+        }));
+        encoder.encode_buffered(&samples)?;
+        written += encoder.write_encoded(&mut writer)? as u64;
+        if len < input.len() {
+            break;
+        }
+    }
+    encoder.finish_stream()?;
+    written += encoder.write_encoded(&mut writer)? as u64;
+    Ok(written)
+}
+
+/// Reads until `buffer` is full or the input ends, returning the length read.
+fn read_full<R: Read>(reader: &mut R, buffer: &mut [u8]) -> Result<usize, FlacError> {
+    let mut len = 0;
+    while len < buffer.len() {
+        match reader.read(&mut buffer[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(len)
+}
@@ -0,0 +1,458 @@
+//! In-place editing of the metadata of an encoded stream through libFLAC's
+//! metadata chain, which grows or shrinks PADDING so the audio after it
+//! needn't be rewritten.
+
+use crate::metadata::{c_string, tag_entry, vorbis_comment_from_ffi};
+use crate::{status_string, FlacError};
+use libflac_sys as ffi;
+use std::ffi::c_void;
+use std::io::{Cursor, Read, Write};
+use std::path::Path;
+use std::slice;
+
+/// The metadata blocks of a native FLAC stream, read into libFLAC's metadata
+/// chain for editing.
+///
+/// A chain is read from a file with [`open`](Self::open) or from a stream
+/// in memory with [`from_bytes`](Self::from_bytes), edited, and written back
+/// with [`save`](Self::save). Edits only touch the metadata: when the blocks
+/// still fit in the space they took, taking up or giving back PADDING as
+/// needed, only that space is rewritten, which is what keeps tag edits on
+/// large files cheap. Otherwise the whole stream is rewritten, audio
+/// included; [`needs_rewrite`](Self::needs_rewrite) tells which it will be.
+///
+/// This is the general editing API; [`FlacEncoder`](crate::FlacEncoder)
+/// setters cover metadata for streams still being encoded. Ogg FLAC is not
+/// supported.
+pub struct MetadataChain {
+    chain: *mut ffi::FLAC__Metadata_Chain,
+    /// The stream for chains read from memory, boxed so libFLAC's handle to
+    /// it stays valid. `None` for files, which libFLAC writes itself.
+    memory: Option<Box<Cursor<Vec<u8>>>>,
+}
+
+impl MetadataChain {
+    /// Reads the metadata of the FLAC file at `path`. [`save`](Self::save)
+    /// writes the file in place.
+    pub fn open(path: &Path) -> Result<Self, FlacError> {
+        let c_path = path
+            .to_str()
+            .and_then(|path| std::ffi::CString::new(path).ok())
+            .ok_or_else(|| {
+                FlacError::InvalidInput(format!("Unusable file path {}", path.display()))
+            })?;
+        let chain = Self::new(None)?;
+        if unsafe { ffi::FLAC__metadata_chain_read(chain.chain, c_path.as_ptr()) } == 0 {
+            return Err(chain.error("read", FlacError::InvalidMetadata));
+        }
+        Ok(chain)
+    }
+
+    /// Reads the metadata of a complete native FLAC stream held in `data`.
+    /// The edited stream is kept in the chain; [`stream`](Self::stream)
+    /// returns it after [`save`](Self::save).
+    pub fn from_bytes(data: &[u8]) -> Result<Self, FlacError> {
+        let mut chain = Self::new(Some(Box::new(Cursor::new(data.to_vec()))))?;
+        chain.read_memory()?;
+        Ok(chain)
+    }
+
+    fn new(memory: Option<Box<Cursor<Vec<u8>>>>) -> Result<Self, FlacError> {
+        let chain = unsafe { ffi::FLAC__metadata_chain_new() };
+        if chain.is_null() {
+            return Err(FlacError::OutOfMemory);
+        }
+        Ok(MetadataChain { chain, memory })
+    }
+
+    fn read_memory(&mut self) -> Result<(), FlacError> {
+        let handle = self.memory_handle();
+        let ok =
+            unsafe { ffi::FLAC__metadata_chain_read_with_callbacks(self.chain, handle, CALLBACKS) };
+        match ok {
+            0 => Err(self.error("read", FlacError::InvalidMetadata)),
+            _ => Ok(()),
+        }
+    }
+
+    /// The in-memory stream as a libFLAC I/O handle, rewound.
+    fn memory_handle(&mut self) -> ffi::FLAC__IOHandle {
+        match self.memory.as_mut() {
+            Some(memory) => {
+                memory.set_position(0);
+                &mut **memory as *mut Cursor<Vec<u8>> as ffi::FLAC__IOHandle
+            }
+            None => std::ptr::null_mut(),
+        }
+    }
+
+    fn error(&self, action: &str, kind: fn(String) -> FlacError) -> FlacError {
+        let status = unsafe { ffi::FLAC__metadata_chain_status(self.chain) };
+        kind(format!(
+            "Failed to {} metadata: {}",
+            action,
+            status_string(unsafe { &ffi::FLAC__Metadata_ChainStatusString }, status)
+        ))
+    }
+
+    /// The blocks in stream order, as `(block type, length in bytes)`: the
+    /// type is the number from the format specification (0 STREAMINFO,
+    /// 1 PADDING, 4 VORBIS_COMMENT and so on) and the length excludes the
+    /// 4-byte block header.
+    pub fn blocks(&self) -> Vec<(u32, u32)> {
+        let mut blocks = Vec::new();
+        if let Some(iterator) = BlockIterator::new(self.chain) {
+            loop {
+                let block = iterator.block();
+                unsafe { blocks.push(((*block).type_, (*block).length)) };
+                if !iterator.next() {
+                    break;
+                }
+            }
+        }
+        blocks
+    }
+
+    /// Total bytes of PADDING, block headers not included.
+    pub fn padding(&self) -> u64 {
+        self.blocks()
+            .iter()
+            .filter(|(block_type, _)| *block_type == ffi::FLAC__METADATA_TYPE_PADDING)
+            .map(|&(_, length)| length as u64)
+            .sum()
+    }
+
+    /// The tags as `NAME=value` pairs in stream order; empty if there is no
+    /// VORBIS_COMMENT block.
+    pub fn get_tags(&self) -> Vec<(String, String)> {
+        match self.find(ffi::FLAC__METADATA_TYPE_VORBIS_COMMENT) {
+            Some(block) => {
+                unsafe { vorbis_comment_from_ffi(&(*block).data.vorbis_comment) }.comments
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Sets tag `name` to `value`. The first entry with that name (compared
+    /// case-insensitively, as Vorbis comments are) is replaced in place and
+    /// any others removed; without one the tag is added at the end. A
+    /// VORBIS_COMMENT block is added after STREAMINFO if the stream has none.
+    pub fn set_tag(&mut self, name: &str, value: &str) -> Result<(), FlacError> {
+        let entry = tag_entry(name, value)?;
+        let block = match self.find(ffi::FLAC__METADATA_TYPE_VORBIS_COMMENT) {
+            Some(block) => Ok(block),
+            None => self.insert_vorbis_comment(),
+        };
+        unsafe {
+            // ownership of the entry's buffer passes to the block
+            let replaced = block.map(|block| {
+                ffi::FLAC__metadata_object_vorbiscomment_replace_comment(block, entry, 1, 0) != 0
+            });
+            if replaced != Ok(true) {
+                libc::free(entry.entry as *mut libc::c_void);
+            }
+            match replaced {
+                Ok(true) => Ok(()),
+                Ok(false) => Err(FlacError::InvalidMetadata(format!(
+                    "Failed to set tag {:?}",
+                    name
+                ))),
+                Err(e) => Err(e),
+            }
+        }
+    }
+
+    /// Removes every entry of tag `name` (compared case-insensitively),
+    /// returning how many there were.
+    pub fn remove_tag(&mut self, name: &str) -> Result<usize, FlacError> {
+        let c_name = c_string("Tag name", name)?;
+        let Some(block) = self.find(ffi::FLAC__METADATA_TYPE_VORBIS_COMMENT) else {
+            return Ok(0);
+        };
+        let removed = unsafe {
+            ffi::FLAC__metadata_object_vorbiscomment_remove_entries_matching(block, c_name.as_ptr())
+        };
+        usize::try_from(removed).map_err(|_| FlacError::OutOfMemory)
+    }
+
+    /// Whether [`save`](Self::save) has to rewrite the whole stream, audio
+    /// included, because the edited blocks no longer fit in the space the
+    /// old ones and their PADDING took.
+    pub fn needs_rewrite(&self) -> bool {
+        unsafe { ffi::FLAC__metadata_chain_check_if_tempfile_needed(self.chain, 1) != 0 }
+    }
+
+    /// Writes the edited metadata back to where it was read from, using
+    /// PADDING to avoid rewriting the audio where it can.
+    ///
+    /// A file is rewritten by libFLAC, through a temporary file next to it
+    /// when the audio has to move. An in-memory stream is edited in place or
+    /// rebuilt, and [`stream`](Self::stream) returns the result.
+    pub fn save(&mut self) -> Result<(), FlacError> {
+        if self.memory.is_none() {
+            return match unsafe { ffi::FLAC__metadata_chain_write(self.chain, 1, 0) } {
+                0 => Err(self.error("write", |message| std::io::Error::other(message).into())),
+                _ => Ok(()),
+            };
+        }
+
+        let handle = self.memory_handle();
+        let ok = unsafe {
+            if self.needs_rewrite() {
+                let capacity = self.memory.as_ref().map_or(0, |m| m.get_ref().len());
+                let mut rebuilt = Box::new(Cursor::new(Vec::with_capacity(capacity)));
+                let temp = &mut *rebuilt as *mut Cursor<Vec<u8>> as ffi::FLAC__IOHandle;
+                let ok = ffi::FLAC__metadata_chain_write_with_callbacks_and_tempfile(
+                    self.chain, 1, handle, CALLBACKS, temp, CALLBACKS,
+                );
+                if ok != 0 {
+                    self.memory = Some(rebuilt);
+                }
+                ok
+            } else {
+                ffi::FLAC__metadata_chain_write_with_callbacks(self.chain, 1, handle, CALLBACKS)
+            }
+        };
+        if ok == 0 {
+            return Err(self.error("write", |message| std::io::Error::other(message).into()));
+        }
+        // read the result back so the chain's offsets match the new layout
+        self.read_memory()
+    }
+
+    /// The stream a chain read with [`from_bytes`](Self::from_bytes) holds,
+    /// with the edits as of the last [`save`](Self::save). `None` for files.
+    pub fn stream(&self) -> Option<&[u8]> {
+        self.memory
+            .as_ref()
+            .map(|memory| memory.get_ref().as_slice())
+    }
+
+    fn find(&self, block_type: ffi::FLAC__MetadataType) -> Option<*mut ffi::FLAC__StreamMetadata> {
+        let iterator = BlockIterator::new(self.chain)?;
+        loop {
+            let block = iterator.block();
+            if unsafe { (*block).type_ } == block_type {
+                return Some(block);
+            }
+            if !iterator.next() {
+                return None;
+            }
+        }
+    }
+
+    fn insert_vorbis_comment(&mut self) -> Result<*mut ffi::FLAC__StreamMetadata, FlacError> {
+        let iterator = BlockIterator::new(self.chain).ok_or(FlacError::MissingStreamInfo)?;
+        unsafe {
+            let block = ffi::FLAC__metadata_object_new(ffi::FLAC__METADATA_TYPE_VORBIS_COMMENT);
+            if block.is_null() {
+                return Err(FlacError::OutOfMemory);
+            }
+            // the iterator starts at STREAMINFO, which has to stay first
+            if ffi::FLAC__metadata_iterator_insert_block_after(iterator.0, block) == 0 {
+                ffi::FLAC__metadata_object_delete(block);
+                return Err(FlacError::OutOfMemory);
+            }
+            Ok(block)
+        }
+    }
+}
+
+impl Drop for MetadataChain {
+    fn drop(&mut self) {
+        unsafe { ffi::FLAC__metadata_chain_delete(self.chain) };
+    }
+}
+
+/// A libFLAC iterator over a chain's blocks, deleted on drop.
+struct BlockIterator(*mut ffi::FLAC__Metadata_Iterator);
+
+impl BlockIterator {
+    /// An iterator at the first block; `None` if it can't be allocated.
+    fn new(chain: *mut ffi::FLAC__Metadata_Chain) -> Option<Self> {
+        let iterator = unsafe { ffi::FLAC__metadata_iterator_new() };
+        if iterator.is_null() {
+            return None;
+        }
+        unsafe { ffi::FLAC__metadata_iterator_init(iterator, chain) };
+        Some(BlockIterator(iterator))
+    }
+
+    fn block(&self) -> *mut ffi::FLAC__StreamMetadata {
+        unsafe { ffi::FLAC__metadata_iterator_get_block(self.0) }
+    }
+
+    fn next(&self) -> bool {
+        unsafe { ffi::FLAC__metadata_iterator_next(self.0) != 0 }
+    }
+}
+
+impl Drop for BlockIterator {
+    fn drop(&mut self) {
+        unsafe { ffi::FLAC__metadata_iterator_delete(self.0) };
+    }
+}
+
+/// I/O callbacks over a `Cursor<Vec<u8>>`, with stdio semantics.
+const CALLBACKS: ffi::FLAC__IOCallbacks = ffi::FLAC__IOCallbacks {
+    read: Some(read_callback),
+    write: Some(write_callback),
+    seek: Some(seek_callback),
+    tell: Some(tell_callback),
+    eof: Some(eof_callback),
+    close: None,
+};
+
+unsafe fn cursor<'a>(handle: ffi::FLAC__IOHandle) -> &'a mut Cursor<Vec<u8>> {
+    &mut *(handle as *mut Cursor<Vec<u8>>)
+}
+
+unsafe extern "C" fn read_callback(
+    ptr: *mut c_void,
+    size: usize,
+    nmemb: usize,
+    handle: ffi::FLAC__IOHandle,
+) -> usize {
+    if size == 0 {
+        return 0;
+    }
+    let buffer = slice::from_raw_parts_mut(ptr as *mut u8, size.saturating_mul(nmemb));
+    cursor(handle).read(buffer).unwrap_or(0) / size
+}
+
+unsafe extern "C" fn write_callback(
+    ptr: *const c_void,
+    size: usize,
+    nmemb: usize,
+    handle: ffi::FLAC__IOHandle,
+) -> usize {
+    let data = slice::from_raw_parts(ptr as *const u8, size.saturating_mul(nmemb));
+    match cursor(handle).write_all(data) {
+        Ok(()) => nmemb,
+        Err(_) => 0,
+    }
+}
+
+unsafe extern "C" fn seek_callback(
+    handle: ffi::FLAC__IOHandle,
+    offset: ffi::FLAC__int64,
+    whence: libc::c_int,
+) -> libc::c_int {
+    let cursor = cursor(handle);
+    let base = match whence {
+        libc::SEEK_SET => 0,
+        libc::SEEK_CUR => cursor.position() as i64,
+        libc::SEEK_END => cursor.get_ref().len() as i64,
+        _ => return -1,
+    };
+    match base.checked_add(offset) {
+        Some(position) if position >= 0 => {
+            cursor.set_position(position as u64);
+            0
+        }
+        _ => -1,
+    }
+}
+
+unsafe extern "C" fn tell_callback(handle: ffi::FLAC__IOHandle) -> ffi::FLAC__int64 {
+    cursor(handle).position() as i64
+}
+
+unsafe extern "C" fn eof_callback(handle: ffi::FLAC__IOHandle) -> libc::c_int {
+    let cursor = cursor(handle);
+    (cursor.position() >= cursor.get_ref().len() as u64) as libc::c_int
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::sine_sweep;
+    use crate::{decode_robust, read_metadata_only, EncoderConfig, FlacEncoder};
+
+    fn encode(tags: &[(&str, &str)], padding: u32) -> (Vec<u8>, Vec<i32>) {
+        let samples = sine_sweep(16, 2, 10_000);
+        let mut encoder = FlacEncoder::from_config(&EncoderConfig::new(44100, 2, 16));
+        for (name, value) in tags {
+            encoder.add_tag(name, value).unwrap();
+        }
+        encoder.set_padding(padding).unwrap();
+        encoder.restart().unwrap();
+        encoder.process(&samples).unwrap();
+        encoder.finish_stream().unwrap();
+        (encoder.take_encoded(), samples)
+    }
+
+    #[test]
+    fn test_edit_within_padding() {
+        let (encoded, samples) = encode(&[("ARTIST", "someone"), ("TITLE", "old")], 4096);
+        let mut chain = MetadataChain::from_bytes(&encoded).unwrap();
+        assert_eq!(chain.blocks()[0], (ffi::FLAC__METADATA_TYPE_STREAMINFO, 34));
+        let padding = chain.padding();
+        assert!(padding > 0);
+
+        chain.set_tag("title", "new").unwrap();
+        chain.set_tag("ALBUM", "an album").unwrap();
+        assert_eq!(chain.remove_tag("artist"), Ok(1));
+        assert_eq!(
+            chain.get_tags(),
+            vec![
+                ("title".to_string(), "new".to_string()),
+                ("ALBUM".to_string(), "an album".to_string())
+            ]
+        );
+        assert!(!chain.needs_rewrite());
+        chain.save().unwrap();
+
+        // the tags took space from the padding; the audio didn't move
+        let edited = chain.stream().unwrap();
+        assert_eq!(edited.len(), encoded.len());
+        assert!(chain.padding() < padding);
+        let audio_offset = read_metadata_only(&encoded, false).unwrap().audio_offset as usize;
+        assert_eq!(edited[audio_offset..], encoded[audio_offset..]);
+        assert_eq!(decode_robust(edited).unwrap().samples, samples);
+        assert_eq!(
+            MetadataChain::from_bytes(edited).unwrap().get_tags(),
+            chain.get_tags()
+        );
+    }
+
+    #[test]
+    fn test_edit_without_padding_rewrites() {
+        let (encoded, samples) = encode(&[], 0);
+        let mut chain = MetadataChain::from_bytes(&encoded).unwrap();
+        chain.set_tag("COMMENT", &"x".repeat(1000)).unwrap();
+        assert!(chain.needs_rewrite());
+        chain.save().unwrap();
+
+        let edited = chain.stream().unwrap();
+        assert!(edited.len() > encoded.len());
+        assert_eq!(decode_robust(edited).unwrap().samples, samples);
+        assert_eq!(chain.get_tags()[0].1.len(), 1000);
+    }
+
+    #[test]
+    fn test_open_file() {
+        let (encoded, _) = encode(&[("TITLE", "old")], 1024);
+        let path =
+            std::env::temp_dir().join(format!("soundkit-flac-chain-{}.flac", std::process::id()));
+        std::fs::write(&path, &encoded).unwrap();
+
+        let mut chain = MetadataChain::open(&path).unwrap();
+        assert!(chain.stream().is_none());
+        chain.set_tag("TITLE", "new").unwrap();
+        chain.save().unwrap();
+        drop(chain);
+
+        let edited = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(edited.len(), encoded.len());
+        assert_eq!(
+            MetadataChain::from_bytes(&edited).unwrap().get_tags(),
+            vec![("TITLE".to_string(), "new".to_string())]
+        );
+        assert!(matches!(
+            MetadataChain::open(&path),
+            Err(FlacError::InvalidMetadata(_))
+        ));
+    }
+}
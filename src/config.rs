@@ -0,0 +1,922 @@
+use crate::version::{flac_version_at_least, ogg_supported};
+use crate::{flac_version, BitDepth, Channels, FlacError};
+use std::fmt;
+use std::time::Duration;
+
+/// libFLAC's hard limit on the LPC order.
+pub const MAX_LPC_ORDER: u32 = 32;
+
+/// The format's limit on the residual partition order: 4 bits in the
+/// residual header.
+pub const MAX_RESIDUAL_PARTITION_ORDER: u32 = 15;
+
+/// Streamable subset limits. Streams at up to 48 kHz are held to the lower
+/// block size and LPC order; faster ones only to the block size cap.
+const SUBSET_MAX_BLOCK_SIZE: u32 = 16384;
+const SUBSET_MAX_BLOCK_SIZE_48K: u32 = 4608;
+const SUBSET_MAX_LPC_ORDER_48K: u32 = 12;
+const SUBSET_MAX_RESIDUAL_PARTITION_ORDER: u32 = 8;
+
+/// The format's block size limits, in samples per channel.
+const MIN_BLOCK_SIZE: u32 = 16;
+pub(crate) const MAX_BLOCK_SIZE: u32 = 65535;
+
+/// The maximum LPC order of each libFLAC compression preset, 0 to 8. Higher
+/// levels are treated as 8.
+const PRESET_MAX_LPC_ORDER: [u32; 9] = [0, 0, 0, 6, 8, 8, 8, 12, 12];
+
+/// The residual partition orders of each preset: the minimum is always 0.
+const PRESET_MAX_RESIDUAL_PARTITION_ORDER: [u32; 9] = [3, 3, 3, 4, 4, 5, 6, 6, 6];
+
+/// The stereo decorrelation (mid/side, loose mid/side) and apodization of
+/// each preset, as in libFLAC 1.4. The presets use neither QLP precision
+/// search, nor escape coding, nor a Rice parameter search distance.
+const PRESET_STEREO: [(bool, bool); 9] = [
+    (false, false),
+    (true, true),
+    (true, false),
+    (false, false),
+    (true, true),
+    (true, false),
+    (true, false),
+    (true, false),
+    (true, false),
+];
+const PRESET_APODIZATION: [&str; 9] = [
+    "tukey(5e-1)",
+    "tukey(5e-1)",
+    "tukey(5e-1)",
+    "tukey(5e-1)",
+    "tukey(5e-1)",
+    "tukey(5e-1)",
+    "subdivide_tukey(2)",
+    "subdivide_tukey(2)",
+    "subdivide_tukey(3)",
+];
+
+/// Every setting a compression preset controls, spelled out; see
+/// [`EncoderConfig::deterministic`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PinnedSettings {
+    pub block_size: u32,
+    pub mid_side: bool,
+    pub loose_mid_side: bool,
+    pub apodization: &'static str,
+    pub max_lpc_order: u32,
+    pub min_residual_partition_order: u32,
+    pub max_residual_partition_order: u32,
+}
+
+/// The container the encoded stream is written in.
+///
+/// Each variant only exists when its cargo feature is enabled:
+/// `container-native` (the default) for plain `.flac` streams and
+/// `container-ogg` for Ogg FLAC. Builds that only ever write one of them can
+/// leave the other feature off; with both enabled the choice is made per
+/// stream through [`EncoderConfig::container`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Container {
+    #[cfg(feature = "container-native")]
+    Native,
+    #[cfg(feature = "container-ogg")]
+    Ogg,
+}
+
+impl Container {
+    /// Whether this is a plain FLAC stream. Only native streams can have
+    /// STREAMINFO and seek points back-patched, or be read back by
+    /// [`FlacDecoder`](crate::FlacDecoder).
+    pub fn is_native(self) -> bool {
+        match self {
+            #[cfg(feature = "container-native")]
+            Container::Native => true,
+            #[cfg(feature = "container-ogg")]
+            Container::Ogg => false,
+        }
+    }
+}
+
+impl Default for Container {
+    /// `Native` when it is available, otherwise `Ogg`.
+    fn default() -> Self {
+        #[cfg(feature = "container-native")]
+        return Container::Native;
+        #[cfg(not(feature = "container-native"))]
+        return Container::Ogg;
+    }
+}
+
+/// How much checking the encoder does, traded against speed.
+///
+/// Each level sets these libFLAC encoder options at `init()`:
+///
+/// | level       | `set_verify` | `set_do_exhaustive_model_search` |
+/// |-------------|--------------|----------------------------------|
+/// | `Fast`      | false        | preset (false)                   |
+/// | `Paranoid`  | true         | preset (false)                   |
+/// | `Archival`  | true         | true                             |
+///
+/// Verification runs a second decoder inside libFLAC that decodes every frame
+/// as it is written and fails the stream on a mismatch; `Fast` skips it and
+/// the allocation it needs. The exhaustive search tries every residual coding
+/// model and is several times slower for slightly smaller output. It is set
+/// after the compression preset, which would otherwise reset it.
+///
+/// The MD5 of the input audio is computed at every level; it is switched off
+/// separately with [`EncoderConfig::write_md5`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum TrustLevel {
+    Fast,
+    #[default]
+    Paranoid,
+    Archival,
+}
+
+impl TrustLevel {
+    /// Whether libFLAC's verify decoder is enabled.
+    pub fn verify(self) -> bool {
+        self != TrustLevel::Fast
+    }
+
+    /// Whether the exhaustive model search is forced on over the preset.
+    pub fn exhaustive_model_search(self) -> bool {
+        self == TrustLevel::Archival
+    }
+}
+
+/// A setting [`EncoderConfig::auto_subset`] lowered to stay in the
+/// streamable subset.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubsetAdjustment {
+    BlockSize { requested: u32, applied: u32 },
+    MaxLpcOrder { requested: u32, applied: u32 },
+    MinResidualPartitionOrder { requested: u32, applied: u32 },
+    MaxResidualPartitionOrder { requested: u32, applied: u32 },
+}
+
+/// A setting the linked libFLAC can't honour; see
+/// [`EncoderConfig::check_support`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UnsupportedFeature {
+    /// More than 24 bits per sample, which needs libFLAC 1.4. `init()`
+    /// fails with [`FlacError::Unsupported`].
+    BitsPerSample(u32),
+    /// The Ogg container, with a libFLAC built without Ogg. `init()` fails.
+    Ogg,
+    /// An apodization [`EncoderConfig::deterministic`] would pin that is
+    /// only known to libFLAC 1.4 and later. `init()` fails.
+    Apodization(&'static str),
+    /// A `min_lpc_order` above 1, which no libFLAC release can apply. It is
+    /// ignored with a warning rather than failing the stream.
+    MinLpcOrder(u32),
+    /// `write_md5(false)` in a build without the `md5-toggle` feature.
+    /// `init()` fails.
+    WriteMd5,
+}
+
+impl fmt::Display for UnsupportedFeature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            UnsupportedFeature::BitsPerSample(bits) => write!(
+                f,
+                "{}-bit samples need libFLAC 1.4, linked version is {}",
+                bits,
+                flac_version()
+            ),
+            UnsupportedFeature::Ogg => write!(f, "The linked libFLAC has no Ogg support"),
+            UnsupportedFeature::Apodization(apodization) => write!(
+                f,
+                "Apodization {} needs libFLAC 1.4, linked version is {}",
+                apodization,
+                flac_version()
+            ),
+            UnsupportedFeature::MinLpcOrder(order) => {
+                write!(f, "min_lpc_order {} can't be applied by libFLAC", order)
+            }
+            UnsupportedFeature::WriteMd5 => {
+                write!(f, "Turning off the MD5 needs the md5-toggle feature")
+            }
+        }
+    }
+}
+
+/// The settings an encoder was initialised with, from
+/// [`EncoderConfig::applied`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppliedConfig {
+    pub config: EncoderConfig,
+    /// What was changed from the requested config; empty if nothing was.
+    pub adjustments: Vec<SubsetAdjustment>,
+}
+
+/// Stream settings for a [`FlacEncoder`](crate::FlacEncoder).
+///
+/// `EncoderConfig::new(44100, 2, 16)` gives a 44.1 kHz stereo 16-bit stream
+/// with libFLAC's default block size and compression level 5; the other
+/// settings can be changed with the builder methods.
+///
+/// libFLAC's `set_compression_level` resets the LPC order, residual partition
+/// orders, apodization and model search to the preset's values, wiping
+/// anything set before it. The encoder therefore always applies
+/// `compression_level` first and the individual overrides after it, so the
+/// builder methods can be called in any order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncoderConfig {
+    pub sample_rate: u32,
+    pub channels: u32,
+    pub bits_per_sample: u32,
+    /// Samples per channel in each frame; 0 lets libFLAC choose, from the
+    /// compression preset. Either way the stream uses FLAC's fixed blocking
+    /// strategy: libFLAC's encoder has no variable block size mode, so every
+    /// frame but the last holds exactly this many samples. See
+    /// [`fixed_block_size`](Self::fixed_block_size).
+    pub block_size: u32,
+    /// libFLAC preset, 0 (fastest) to 8 (smallest).
+    pub compression_level: u32,
+    /// Lower bound of the LPC order search. Recorded and validated, but no
+    /// libFLAC release so far can apply it; see [`lpc_order`](Self::lpc_order).
+    pub min_lpc_order: u32,
+    /// Upper bound of the LPC order search; `None` keeps the preset's value.
+    pub max_lpc_order: Option<u32>,
+    /// Bounds of the residual partition order search; `None` keeps the
+    /// preset's value. See
+    /// [`min_residual_partition_order`](Self::min_residual_partition_order).
+    pub min_residual_partition_order: Option<u32>,
+    pub max_residual_partition_order: Option<u32>,
+    /// In debug builds, warn when encoded samples don't look like they are
+    /// in the native range for `bits_per_sample` (e.g. left-justified in the
+    /// `i32`). Off by default and compiled out of release builds.
+    pub check_scaling: bool,
+    /// Track per-channel peak and RMS levels while encoding; see
+    /// [`FlacEncoder::channel_stats`](crate::FlacEncoder::channel_stats).
+    pub channel_stats: bool,
+    /// Count the subframes libFLAC removed wasted bits from; see
+    /// [`FlacEncoder::wasted_bits_stats`](crate::FlacEncoder::wasted_bits_stats).
+    /// Every frame is parsed as it is written, so this is off by default.
+    pub wasted_bits_stats: bool,
+    /// Collect a min/max waveform overview with this many bins per second;
+    /// see [`generate_peaks`](Self::generate_peaks).
+    pub peak_bins_per_second: Option<u32>,
+    /// Keep every encoded byte of the stream, so that
+    /// [`FlacEncoder::accumulated_output`](crate::FlacEncoder::accumulated_output)
+    /// returns the whole stream. The encode methods still copy out, and
+    /// return the length of, only the bytes added since the previous call.
+    /// Memory then grows with the stream rather than being bounded by
+    /// `set_process_chunk_samples`.
+    pub accumulate_output: bool,
+    /// Lower settings that would take the stream out of the streamable
+    /// subset, instead of libFLAC refusing them at `init()`; see
+    /// [`applied`](Self::applied).
+    pub auto_subset: bool,
+    pub container: Container,
+    /// Samples per channel the stream will hold, if known up front. It is
+    /// written to STREAMINFO at init, so players see the length even when the
+    /// output can't be back-patched, e.g. a live HTTP response. In that case
+    /// the header is final once sent, and finishing with a different count
+    /// fails with [`FlacError::TotalSamplesMismatch`]. With a seekable sink
+    /// libFLAC rewrites it with the actual count instead.
+    pub total_samples: Option<u64>,
+    /// Verification and search effort; see [`TrustLevel`].
+    pub trust_level: TrustLevel,
+    /// Compute the MD5 of the input audio and store it in STREAMINFO. When
+    /// off, the field is left zeroed, which is what decoders take to mean
+    /// "unknown": the audio can't be fingerprinted by it, and a decoder can
+    /// no longer check that it reproduced the original samples, so turn it
+    /// off only where nothing will verify the stream. Skipping it also saves
+    /// the hashing cost, which `cargo bench --bench encode` measures; it is
+    /// most noticeable at fast levels, e.g. for real-time encoding.
+    /// This is libFLAC's `do_md5` setting. On by default. Turning it off
+    /// needs the `md5-toggle` feature, also on by default, as libFLAC only
+    /// has the setter in a protected header; without it `init()` fails with
+    /// [`FlacError::Unsupported`].
+    pub write_md5: bool,
+    /// Set every encoder parameter explicitly instead of through the
+    /// compression preset; see [`deterministic`](Self::deterministic).
+    pub deterministic: bool,
+    /// Written as the VORBIS_COMMENT vendor string instead of libFLAC's;
+    /// see [`vendor_string`](Self::vendor_string).
+    pub vendor_string: Option<String>,
+}
+
+impl EncoderConfig {
+    pub fn new(sample_rate: u32, channels: u32, bits_per_sample: u32) -> Self {
+        EncoderConfig {
+            sample_rate,
+            channels,
+            bits_per_sample,
+            block_size: 0,
+            compression_level: 5,
+            min_lpc_order: 0,
+            max_lpc_order: None,
+            min_residual_partition_order: None,
+            max_residual_partition_order: None,
+            check_scaling: false,
+            channel_stats: false,
+            wasted_bits_stats: false,
+            peak_bins_per_second: None,
+            accumulate_output: false,
+            auto_subset: false,
+            container: Container::default(),
+            total_samples: None,
+            trust_level: TrustLevel::default(),
+            write_md5: true,
+            deterministic: false,
+            vendor_string: None,
+        }
+    }
+
+    /// Like [`new`](Self::new), with types that can't be swapped by
+    /// mistake and that only hold values FLAC supports.
+    pub fn with_format(sample_rate: u32, channels: Channels, bits_per_sample: BitDepth) -> Self {
+        EncoderConfig::new(sample_rate, channels.into(), bits_per_sample.into())
+    }
+
+    pub fn block_size(mut self, block_size: u32) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    /// Sets the block size explicitly, for muxers that need to know the
+    /// sample count of every frame up front, e.g. to place segment
+    /// boundaries. All frames but the last hold exactly `block_size` samples
+    /// and STREAMINFO's minimum and maximum block sizes are both
+    /// `block_size`. This is [`block_size`](Self::block_size) under a name
+    /// that states the guarantee; 0 still leaves the size to the preset,
+    /// which is just as fixed but not known up front. A variable-blocksize
+    /// frame would fail the stream with [`FlacError::Encoder`] rather than
+    /// be written.
+    pub fn fixed_block_size(mut self, block_size: u32) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    /// Sets the block size from a frame duration, e.g. 20 ms for a
+    /// streaming sender whose latency budget is given in time.
+    ///
+    /// The duration is converted at the configured sample rate to the
+    /// nearest whole number of samples, then brought into the streamable
+    /// subset: at least 16 samples, and at most 4608 at rates up to 48 kHz
+    /// or 16384 above. So 20 ms at 44100 Hz gives 882 samples, and 500 ms
+    /// at 48 kHz gives 4608 rather than 24000. The samples chosen are in the
+    /// `block_size` field. Set the sample rate before calling this; as with
+    /// [`block_size`](Self::block_size), every frame but the last holds
+    /// exactly that many samples.
+    pub fn block_duration(mut self, duration: Duration) -> Self {
+        let samples = (duration.as_secs_f64() * self.sample_rate as f64).round();
+        let max_block_size = match self.sample_rate <= 48000 {
+            true => SUBSET_MAX_BLOCK_SIZE_48K,
+            false => SUBSET_MAX_BLOCK_SIZE,
+        };
+        self.block_size = (samples.min(max_block_size as f64) as u32).max(MIN_BLOCK_SIZE);
+        self
+    }
+
+    /// Picks the libFLAC preset the other settings start from; overrides such
+    /// as [`lpc_order`](Self::lpc_order) are kept whichever is set first.
+    pub fn compression_level(mut self, compression_level: u32) -> Self {
+        self.compression_level = compression_level;
+        self
+    }
+
+    /// Restricts the LPC order search to `min..=max`, overriding the preset.
+    ///
+    /// Availability per libFLAC version:
+    ///
+    /// - `max`: every version (`FLAC__stream_encoder_set_max_lpc_order`).
+    ///   0 disables LPC and leaves only the fixed predictors.
+    /// - `min`: no libFLAC release up to 1.5 has a setter, so it is only
+    ///   checked for consistency and a warning is logged at the encoder's
+    ///   first `init()` if it is above 1, not again on `reset()`;
+    ///   [`check_support`](Self::check_support) reports it as well. libFLAC
+    ///   always considers every order up to `max`.
+    ///
+    /// Both must be at most [`MAX_LPC_ORDER`] with `min <= max`. Streams
+    /// within the streamable subset are further limited to order 12 at
+    /// sample rates up to 48 kHz.
+    pub fn lpc_order(mut self, min: u32, max: u32) -> Self {
+        self.min_lpc_order = min;
+        self.max_lpc_order = Some(max);
+        self
+    }
+
+    /// Sets the lowest residual partition order libFLAC tries, overriding
+    /// the preset's 0.
+    ///
+    /// The residual of each subframe is split into `2^order` partitions,
+    /// each with its own Rice parameter; libFLAC searches every order from
+    /// the minimum to the maximum and keeps the smallest. The two bounds are
+    /// independent: setting one leaves the other at the preset's value,
+    /// which for the maximum is 3 at levels 0 to 2, 4 at 3 and 4, 5 at 5 and
+    /// 6 above. Both must be at most [`MAX_RESIDUAL_PARTITION_ORDER`] with
+    /// the minimum no higher than the maximum, counting a preset value.
+    /// libFLAC refuses orders above 8 at `init()` as outside the streamable
+    /// subset; [`auto_subset`](Self::auto_subset) clamps them instead.
+    pub fn min_residual_partition_order(mut self, order: u32) -> Self {
+        self.min_residual_partition_order = Some(order);
+        self
+    }
+
+    /// Sets the highest residual partition order libFLAC tries, overriding
+    /// the preset's; see
+    /// [`min_residual_partition_order`](Self::min_residual_partition_order).
+    pub fn max_residual_partition_order(mut self, order: u32) -> Self {
+        self.max_residual_partition_order = Some(order);
+        self
+    }
+
+    /// Enables the debug-build input scaling check described on the
+    /// `check_scaling` field.
+    pub fn check_scaling(mut self, enabled: bool) -> Self {
+        self.check_scaling = enabled;
+        self
+    }
+
+    pub fn channel_stats(mut self, enabled: bool) -> Self {
+        self.channel_stats = enabled;
+        self
+    }
+
+    /// Collects the lowest and highest sample in each of `bins_per_second`
+    /// time bins while encoding, across all channels, for drawing a
+    /// waveform without decoding the stream again; see
+    /// [`FlacEncoder::peaks`](crate::FlacEncoder::peaks). It must be
+    /// between 1 and the sample rate.
+    pub fn generate_peaks(mut self, bins_per_second: u32) -> Self {
+        self.peak_bins_per_second = Some(bins_per_second);
+        self
+    }
+
+    pub fn wasted_bits_stats(mut self, enabled: bool) -> Self {
+        self.wasted_bits_stats = enabled;
+        self
+    }
+
+    pub fn accumulate_output(mut self, enabled: bool) -> Self {
+        self.accumulate_output = enabled;
+        self
+    }
+
+    pub fn auto_subset(mut self, enabled: bool) -> Self {
+        self.auto_subset = enabled;
+        self
+    }
+
+    /// The settings the encoder will actually use.
+    ///
+    /// Without [`auto_subset`](Self::auto_subset) that is this config as is.
+    /// With it, values outside the streamable subset are clamped down to the
+    /// largest legal one:
+    ///
+    /// - `block_size` to 4608 at sample rates up to 48 kHz and to 16384
+    ///   above; 0 (libFLAC's choice) is always legal.
+    /// - `max_lpc_order` to 12 at sample rates up to 48 kHz.
+    /// - both residual partition orders to 8.
+    ///
+    /// Each change is listed in [`AppliedConfig::adjustments`]. Sample rates
+    /// and bit depths outside the subset can't be fixed this way and are
+    /// left to fail at `init()`.
+    pub fn applied(&self) -> AppliedConfig {
+        let mut config = self.clone();
+        let mut adjustments = Vec::new();
+        if self.auto_subset {
+            let low_rate = self.sample_rate <= 48000;
+            let max_block_size = match low_rate {
+                true => SUBSET_MAX_BLOCK_SIZE_48K,
+                false => SUBSET_MAX_BLOCK_SIZE,
+            };
+            if self.block_size > max_block_size {
+                config.block_size = max_block_size;
+                adjustments.push(SubsetAdjustment::BlockSize {
+                    requested: self.block_size,
+                    applied: max_block_size,
+                });
+            }
+            match self.max_lpc_order {
+                Some(order) if low_rate && order > SUBSET_MAX_LPC_ORDER_48K => {
+                    config.max_lpc_order = Some(SUBSET_MAX_LPC_ORDER_48K);
+                    adjustments.push(SubsetAdjustment::MaxLpcOrder {
+                        requested: order,
+                        applied: SUBSET_MAX_LPC_ORDER_48K,
+                    });
+                }
+                _ => {}
+            }
+            let cap = SUBSET_MAX_RESIDUAL_PARTITION_ORDER;
+            if let Some(order) = self.min_residual_partition_order.filter(|&o| o > cap) {
+                config.min_residual_partition_order = Some(cap);
+                adjustments.push(SubsetAdjustment::MinResidualPartitionOrder {
+                    requested: order,
+                    applied: cap,
+                });
+            }
+            if let Some(order) = self.max_residual_partition_order.filter(|&o| o > cap) {
+                config.max_residual_partition_order = Some(cap);
+                adjustments.push(SubsetAdjustment::MaxResidualPartitionOrder {
+                    requested: order,
+                    applied: cap,
+                });
+            }
+        }
+        AppliedConfig {
+            config,
+            adjustments,
+        }
+    }
+
+    pub fn container(mut self, container: Container) -> Self {
+        self.container = container;
+        self
+    }
+
+    pub fn total_samples(mut self, total_samples: u64) -> Self {
+        self.total_samples = Some(total_samples);
+        self
+    }
+
+    pub fn trust_level(mut self, trust_level: TrustLevel) -> Self {
+        self.trust_level = trust_level;
+        self
+    }
+
+    pub fn write_md5(mut self, enabled: bool) -> Self {
+        self.write_md5 = enabled;
+        self
+    }
+
+    /// Pins the settings that decide the encoded bytes, for content-addressed
+    /// storage and caches that need the same input to give the same output.
+    ///
+    /// With the same libFLAC and config, encoding is already reproducible:
+    /// libFLAC has no randomness, writes no timestamps, and this crate never
+    /// turns on the multithreading of libFLAC 1.5, leaving it at one thread.
+    /// What can move is the preset. `compression_level` stands for block
+    /// size, stereo decorrelation, apodization, LPC order and partition
+    /// orders that differ between libFLAC releases (1.4 changed the
+    /// apodization of levels 6 to 8). With this set they are all applied
+    /// one by one from this crate's copy of the libFLAC 1.4 preset, with
+    /// the crate's other overrides on top, so a libFLAC upgrade can't change
+    /// them silently.
+    ///
+    /// That is not a guarantee of identical bytes across libFLAC versions:
+    /// the search and coding inside libFLAC can still change, and the
+    /// vendor string it writes into the VORBIS_COMMENT block names its own
+    /// version unless replaced with [`vendor_string`](Self::vendor_string).
+    /// Pin libFLAC too if the output must never change.
+    pub fn deterministic(mut self, enabled: bool) -> Self {
+        self.deterministic = enabled;
+        self
+    }
+
+    /// Replaces the vendor string libFLAC writes into the VORBIS_COMMENT
+    /// block, `reference libFLAC 1.4.3 20230623` or similar, e.g. to
+    /// whitelabel the output or keep it identical across libFLAC upgrades.
+    ///
+    /// libFLAC always writes its own vendor string and a VORBIS_COMMENT
+    /// block even when there are no tags, so the block is rewritten as it
+    /// is output. That only works when the bytes pass through this crate:
+    /// `init_file` and the Ogg container are refused with
+    /// [`FlacError::InvalidConfig`]. The string must be at most 16 MiB
+    /// less 9 bytes, the most a metadata block holds.
+    pub fn vendor_string(mut self, vendor: &str) -> Self {
+        self.vendor_string = Some(vendor.to_string());
+        self
+    }
+
+    /// The values [`deterministic`](Self::deterministic) applies.
+    pub(crate) fn pinned_settings(&self) -> PinnedSettings {
+        let level = self.compression_level.min(8) as usize;
+        let (min_partition_order, max_partition_order) = self.effective_residual_partition_orders();
+        PinnedSettings {
+            block_size: self.effective_block_size(),
+            // libFLAC drops mid/side stereo for anything but two channels
+            mid_side: self.channels == 2 && PRESET_STEREO[level].0,
+            loose_mid_side: self.channels == 2 && PRESET_STEREO[level].1,
+            apodization: PRESET_APODIZATION[level],
+            max_lpc_order: self.effective_max_lpc_order(),
+            min_residual_partition_order: min_partition_order,
+            max_residual_partition_order: max_partition_order,
+        }
+    }
+
+    /// The maximum LPC order libFLAC will use: the override if there is one,
+    /// otherwise the compression preset's.
+    pub(crate) fn effective_max_lpc_order(&self) -> u32 {
+        self.max_lpc_order
+            .unwrap_or(PRESET_MAX_LPC_ORDER[self.compression_level.min(8) as usize])
+    }
+
+    /// The residual partition order bounds libFLAC will use, overrides
+    /// taking the place of the preset's values.
+    pub(crate) fn effective_residual_partition_orders(&self) -> (u32, u32) {
+        let preset_max =
+            PRESET_MAX_RESIDUAL_PARTITION_ORDER[self.compression_level.min(8) as usize];
+        (
+            self.min_residual_partition_order.unwrap_or(0),
+            self.max_residual_partition_order.unwrap_or(preset_max),
+        )
+    }
+
+    /// The block size libFLAC will use: `block_size`, or when that is 0 the
+    /// one it picks at init, 1152 without LPC and 4096 with it.
+    pub(crate) fn effective_block_size(&self) -> u32 {
+        match (self.block_size, self.effective_max_lpc_order()) {
+            (0, 0) => 1152,
+            (0, _) => 4096,
+            (block_size, _) => block_size,
+        }
+    }
+
+    /// Checks the settings against what the linked libFLAC
+    /// ([`flac_version`]) can do, listing every one it can't, e.g. to grey
+    /// out options in a UI before any encoder exists.
+    ///
+    /// This complements [`validate`](Self::validate), which checks the
+    /// values themselves. Features that would fail `init()` and ones that
+    /// would only be ignored are both listed; see [`UnsupportedFeature`].
+    pub fn check_support(&self) -> Result<(), Vec<UnsupportedFeature>> {
+        let mut unsupported = Vec::new();
+        let libflac_1_4 = flac_version_at_least(1, 4);
+        if self.bits_per_sample > 24 && !libflac_1_4 {
+            unsupported.push(UnsupportedFeature::BitsPerSample(self.bits_per_sample));
+        }
+        if !self.container.is_native() && !ogg_supported() {
+            unsupported.push(UnsupportedFeature::Ogg);
+        }
+        let apodization = self.pinned_settings().apodization;
+        if self.deterministic && apodization.starts_with("subdivide_tukey") && !libflac_1_4 {
+            unsupported.push(UnsupportedFeature::Apodization(apodization));
+        }
+        if self.min_lpc_order > 1 {
+            unsupported.push(UnsupportedFeature::MinLpcOrder(self.min_lpc_order));
+        }
+        if !self.write_md5 && !cfg!(feature = "md5-toggle") {
+            unsupported.push(UnsupportedFeature::WriteMd5);
+        }
+        match unsupported.is_empty() {
+            true => Ok(()),
+            false => Err(unsupported),
+        }
+    }
+
+    /// Checks the settings that don't depend on the linked libFLAC.
+    pub fn validate(&self) -> Result<(), FlacError> {
+        if self.block_size != 0 && !(MIN_BLOCK_SIZE..=MAX_BLOCK_SIZE).contains(&self.block_size) {
+            return Err(FlacError::InvalidConfig(format!(
+                "block_size {} is outside {}..={}",
+                self.block_size, MIN_BLOCK_SIZE, MAX_BLOCK_SIZE
+            )));
+        }
+
+        let max = self.max_lpc_order.unwrap_or(MAX_LPC_ORDER);
+        if max > MAX_LPC_ORDER {
+            return Err(FlacError::InvalidConfig(format!(
+                "max_lpc_order {} is above {}",
+                max, MAX_LPC_ORDER
+            )));
+        }
+        if self.min_lpc_order > max {
+            return Err(FlacError::InvalidConfig(format!(
+                "min_lpc_order {} is above max_lpc_order {}",
+                self.min_lpc_order, max
+            )));
+        }
+
+        if let Some(bins) = self.peak_bins_per_second {
+            if bins == 0 || bins > self.sample_rate {
+                return Err(FlacError::InvalidConfig(format!(
+                    "{} peak bins per second; must be 1 to the sample rate, {}",
+                    bins, self.sample_rate
+                )));
+            }
+        }
+
+        if let Some(vendor) = &self.vendor_string {
+            if vendor.len() > crate::metadata::MAX_VENDOR_LEN {
+                return Err(FlacError::InvalidConfig(format!(
+                    "vendor_string of {} bytes doesn't fit in a metadata block",
+                    vendor.len()
+                )));
+            }
+        }
+
+        let (min, max) = self.effective_residual_partition_orders();
+        if max > MAX_RESIDUAL_PARTITION_ORDER {
+            return Err(FlacError::InvalidConfig(format!(
+                "max_residual_partition_order {} is above {}",
+                max, MAX_RESIDUAL_PARTITION_ORDER
+            )));
+        }
+        if min > max {
+            return Err(FlacError::InvalidConfig(format!(
+                "min_residual_partition_order {} is above max_residual_partition_order {}",
+                min, max
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trust_level_flags() {
+        assert_eq!(
+            EncoderConfig::new(44100, 2, 16).trust_level,
+            TrustLevel::Paranoid
+        );
+        let flags = |level: TrustLevel| (level.verify(), level.exhaustive_model_search());
+        assert_eq!(flags(TrustLevel::Fast), (false, false));
+        assert_eq!(flags(TrustLevel::Paranoid), (true, false));
+        assert_eq!(flags(TrustLevel::Archival), (true, true));
+    }
+
+    #[test]
+    fn test_auto_subset_clamps_to_the_subset() {
+        let strict = EncoderConfig::new(44100, 2, 16)
+            .block_size(8192)
+            .lpc_order(0, 16);
+        assert_eq!(strict.applied().config, strict);
+        assert!(strict.applied().adjustments.is_empty());
+
+        let applied = strict.clone().auto_subset(true).applied();
+        assert_eq!(applied.config.block_size, 4608);
+        assert_eq!(applied.config.max_lpc_order, Some(12));
+        assert_eq!(
+            applied.adjustments,
+            vec![
+                SubsetAdjustment::BlockSize {
+                    requested: 8192,
+                    applied: 4608
+                },
+                SubsetAdjustment::MaxLpcOrder {
+                    requested: 16,
+                    applied: 12
+                },
+            ]
+        );
+
+        // above 48 kHz only the block size cap applies
+        let hi_res = EncoderConfig::new(96000, 2, 24)
+            .block_size(32768)
+            .lpc_order(0, 16)
+            .auto_subset(true)
+            .applied();
+        assert_eq!(hi_res.config.block_size, 16384);
+        assert_eq!(hi_res.config.max_lpc_order, Some(16));
+        assert_eq!(hi_res.adjustments.len(), 1);
+
+        let legal = EncoderConfig::new(48000, 2, 16)
+            .block_size(4608)
+            .auto_subset(true);
+        assert!(legal.applied().adjustments.is_empty());
+    }
+
+    #[test]
+    fn test_lpc_order_validation() {
+        let config = EncoderConfig::new(44100, 2, 16);
+        assert_eq!(config.clone().lpc_order(0, 32).validate(), Ok(()));
+        assert_eq!(config.clone().lpc_order(4, 4).validate(), Ok(()));
+        assert!(matches!(
+            config.clone().lpc_order(0, 33).validate(),
+            Err(FlacError::InvalidConfig(_))
+        ));
+        assert!(matches!(
+            config.lpc_order(8, 4).validate(),
+            Err(FlacError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn test_residual_partition_order_bounds() {
+        let config = EncoderConfig::new(44100, 2, 16);
+        assert_eq!(config.effective_residual_partition_orders(), (0, 5));
+        assert_eq!(
+            config
+                .clone()
+                .compression_level(0)
+                .effective_residual_partition_orders(),
+            (0, 3)
+        );
+
+        // setting one bound keeps the preset's other one
+        let min_only = config.clone().min_residual_partition_order(2);
+        assert_eq!(min_only.effective_residual_partition_orders(), (2, 5));
+        let max_only = config.clone().max_residual_partition_order(1);
+        assert_eq!(max_only.effective_residual_partition_orders(), (0, 1));
+
+        let orders = |min: u32, max: u32| {
+            config
+                .clone()
+                .min_residual_partition_order(min)
+                .max_residual_partition_order(max)
+        };
+        assert_eq!(orders(0, 0).validate(), Ok(()));
+        assert_eq!(orders(15, 15).validate(), Ok(()));
+        assert_eq!(orders(0, 15).validate(), Ok(()));
+        assert!(matches!(
+            orders(0, 16).validate(),
+            Err(FlacError::InvalidConfig(_))
+        ));
+        assert!(matches!(
+            orders(4, 3).validate(),
+            Err(FlacError::InvalidConfig(_))
+        ));
+        // a minimum above the preset's maximum of 5 conflicts with it
+        assert_eq!(
+            config.clone().min_residual_partition_order(5).validate(),
+            Ok(())
+        );
+        assert!(matches!(
+            config.clone().min_residual_partition_order(6).validate(),
+            Err(FlacError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn test_auto_subset_clamps_residual_partition_orders() {
+        let at_cap = EncoderConfig::new(96000, 2, 24)
+            .min_residual_partition_order(8)
+            .max_residual_partition_order(8)
+            .auto_subset(true)
+            .applied();
+        assert!(at_cap.adjustments.is_empty());
+
+        let above = EncoderConfig::new(96000, 2, 24)
+            .min_residual_partition_order(9)
+            .max_residual_partition_order(15)
+            .auto_subset(true)
+            .applied();
+        assert_eq!(above.config.effective_residual_partition_orders(), (8, 8));
+        assert_eq!(
+            above.adjustments,
+            vec![
+                SubsetAdjustment::MinResidualPartitionOrder {
+                    requested: 9,
+                    applied: 8
+                },
+                SubsetAdjustment::MaxResidualPartitionOrder {
+                    requested: 15,
+                    applied: 8
+                },
+            ]
+        );
+
+        // only the bound that was set is touched
+        let max_only = EncoderConfig::new(44100, 2, 16)
+            .max_residual_partition_order(12)
+            .auto_subset(true)
+            .applied();
+        assert_eq!(max_only.config.min_residual_partition_order, None);
+        assert_eq!(max_only.config.max_residual_partition_order, Some(8));
+    }
+
+    #[test]
+    fn test_check_support() {
+        let config = EncoderConfig::new(44100, 2, 16);
+        assert_eq!(config.check_support(), Ok(()));
+
+        // everything is listed, not just the first problem
+        let config = EncoderConfig::new(44100, 2, 32).lpc_order(4, 8);
+        let unsupported = config.check_support().unwrap_err();
+        assert!(unsupported.contains(&UnsupportedFeature::MinLpcOrder(4)));
+        let needs_1_4 = unsupported.contains(&UnsupportedFeature::BitsPerSample(32));
+        assert_eq!(needs_1_4, !flac_version_at_least(1, 4));
+        assert_eq!(unsupported.len(), 1 + needs_1_4 as usize);
+    }
+
+    #[test]
+    fn test_with_format() {
+        let config = EncoderConfig::with_format(96000, Channels::new(6).unwrap(), BitDepth::S24);
+        assert_eq!(config, EncoderConfig::new(96000, 6, 24));
+    }
+
+    #[test]
+    fn test_block_duration() {
+        let block_size = |rate, millis| {
+            EncoderConfig::new(rate, 2, 16)
+                .block_duration(Duration::from_millis(millis))
+                .block_size
+        };
+        assert_eq!(block_size(44100, 20), 882);
+        assert_eq!(block_size(48000, 10), 480);
+        // capped at the subset limits, raised to the format's minimum
+        assert_eq!(block_size(48000, 500), 4608);
+        assert_eq!(block_size(96000, 500), 16384);
+        assert_eq!(block_size(8000, 1), 16);
+
+        assert!(EncoderConfig::new(44100, 2, 16)
+            .block_size(8)
+            .validate()
+            .is_err());
+        assert!(EncoderConfig::new(44100, 2, 16)
+            .block_size(65536)
+            .validate()
+            .is_err());
+        assert!(EncoderConfig::new(44100, 2, 16)
+            .block_size(0)
+            .validate()
+            .is_ok());
+    }
+}
@@ -0,0 +1,546 @@
+//! One-shot decoding of complete, possibly untrusted, FLAC streams.
+
+use crate::header::parse_streaminfo;
+use crate::{decoder_state_string, status_string, FlacError, StreamInfo};
+use libflac_sys as ffi;
+use std::slice;
+use std::time::Duration;
+
+/// Interleaved PCM decoded from a whole stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodedAudio {
+    pub sample_rate: u32,
+    pub channels: u32,
+    pub bits_per_sample: u32,
+    /// Interleaved samples in the native range of `bits_per_sample`.
+    pub samples: Vec<i32>,
+}
+
+/// Upper bound on the samples reserved up front, whatever STREAMINFO
+/// claims. Larger outputs still decode; they just grow as frames arrive.
+const MAX_RESERVE_SAMPLES: usize = 1 << 22;
+
+/// The most interleaved samples, 1 GiB of them, that
+/// [`decode_robust`] decodes before failing: a small untrusted input can
+/// otherwise expand without limit, e.g. frames of constant subframes cost a
+/// few bytes each whatever their block size. That is 50 minutes of 44.1 kHz
+/// stereo; use [`decode_robust_with_limit`] for longer streams.
+pub const MAX_DECODED_SAMPLES: u64 = 1 << 28;
+
+/// A complete stream in memory, read by libFLAC through [`read_callback`].
+pub(crate) struct SliceInput<'a> {
+    data: &'a [u8],
+    position: usize,
+}
+
+impl<'a> SliceInput<'a> {
+    pub(crate) fn new(data: &'a [u8]) -> Self {
+        SliceInput { data, position: 0 }
+    }
+}
+
+/// Decoder state whose stream is a [`SliceInput`].
+pub(crate) trait ReadsSlice<'a> {
+    fn input(&mut self) -> &mut SliceInput<'a>;
+}
+
+/// State for one `decode_robust` call, shared with the callbacks.
+struct RobustState<'a> {
+    input: SliceInput<'a>,
+    info: StreamInfo,
+    samples: Vec<i32>,
+    /// Interleaved samples the stream may produce, from STREAMINFO's total
+    /// sample count; `None` when it is unknown.
+    limit: Option<u64>,
+    /// Interleaved samples the caller allows, whatever STREAMINFO says.
+    max_samples: u64,
+    error: Option<FlacError>,
+}
+
+/// Decodes a complete FLAC stream, treating the input as untrusted.
+///
+/// Any malformed input produces an error rather than a panic or partial
+/// output: lost sync, bad headers, CRC and MD5 mismatches all fail the
+/// decode, as do frames that contradict STREAMINFO (different channel count
+/// or bit depth, blocks above its maximum block size, or more samples than
+/// its total). Sizes declared in the stream are never used to allocate more
+/// than a bounded amount ahead of the data actually decoded.
+///
+/// A stream cut short at a frame boundary can only be told apart from a
+/// complete one when STREAMINFO records the total sample count; streams from
+/// a non-seekable encoder leave it at 0.
+///
+/// The output is capped at [`MAX_DECODED_SAMPLES`] interleaved samples,
+/// whether or not STREAMINFO declares a total; longer streams fail.
+///
+/// The `fuzz/` directory has a cargo-fuzz target for this function.
+pub fn decode_robust(data: &[u8]) -> Result<DecodedAudio, FlacError> {
+    decode_robust_with_limit(data, MAX_DECODED_SAMPLES)
+}
+
+/// [`decode_robust`] with the output capped at `max_samples` interleaved
+/// samples instead of [`MAX_DECODED_SAMPLES`]. A stream whose STREAMINFO
+/// declares more fails before anything is decoded, and one that doesn't
+/// fails at the first frame that would go over.
+pub fn decode_robust_with_limit(data: &[u8], max_samples: u64) -> Result<DecodedAudio, FlacError> {
+    let info = parse_streaminfo(data)?;
+    if info.sample_rate == 0 {
+        return Err(FlacError::InvalidStreamInfo("sample rate is 0".to_string()));
+    }
+    if info.max_block_size < 16 || info.min_block_size > info.max_block_size {
+        return Err(FlacError::InvalidStreamInfo(format!(
+            "block sizes {}..{} are invalid",
+            info.min_block_size, info.max_block_size
+        )));
+    }
+
+    let limit = match info.total_samples {
+        0 => None,
+        total => Some(total.saturating_mul(info.channels as u64)),
+    };
+    if let Some(limit) = limit.filter(|&limit| limit > max_samples) {
+        return Err(FlacError::Decoder(format!(
+            "STREAMINFO declares {} samples, more than the {} allowed",
+            limit, max_samples
+        )));
+    }
+    let reserve = limit.unwrap_or(0).min(MAX_RESERVE_SAMPLES as u64) as usize;
+
+    let mut state = RobustState {
+        input: SliceInput::new(data),
+        info,
+        samples: Vec::with_capacity(reserve),
+        limit,
+        max_samples,
+        error: None,
+    };
+
+    let decoder = unsafe { ffi::FLAC__stream_decoder_new() };
+    if decoder.is_null() {
+        return Err(FlacError::Decoder("Failed to allocate decoder".to_string()));
+    }
+
+    let result = unsafe { run(decoder, &mut state) };
+    unsafe { ffi::FLAC__stream_decoder_delete(decoder) };
+    result?;
+
+    if let Some(limit) = state.limit {
+        if (state.samples.len() as u64) < limit {
+            return Err(FlacError::Decoder(format!(
+                "Stream ended after {} of {} samples",
+                state.samples.len(),
+                limit
+            )));
+        }
+    }
+
+    Ok(DecodedAudio {
+        sample_rate: state.info.sample_rate,
+        channels: state.info.channels as u32,
+        bits_per_sample: state.info.bits_per_sample as u32,
+        samples: std::mem::take(&mut state.samples),
+    })
+}
+
+unsafe fn run(
+    decoder: *mut ffi::FLAC__StreamDecoder,
+    state: &mut RobustState<'_>,
+) -> Result<(), FlacError> {
+    ffi::FLAC__stream_decoder_set_md5_checking(decoder, true as i32);
+    ffi::FLAC__stream_decoder_set_metadata_ignore_all(decoder);
+
+    let status = ffi::FLAC__stream_decoder_init_stream(
+        decoder,
+        Some(read_callback::<RobustState>),
+        None,
+        None,
+        None,
+        None,
+        Some(write_callback),
+        None,
+        Some(error_callback),
+        state as *mut RobustState<'_> as *mut libc::c_void,
+    );
+    if status != ffi::FLAC__STREAM_DECODER_INIT_STATUS_OK {
+        return Err(FlacError::Decoder(format!(
+            "Failed to initialize the decoder. Status: {}",
+            status_string(&ffi::FLAC__StreamDecoderInitStatusString, status)
+        )));
+    }
+
+    let ok = ffi::FLAC__stream_decoder_process_until_end_of_stream(decoder);
+    if let Some(e) = state.error.take() {
+        ffi::FLAC__stream_decoder_finish(decoder);
+        return Err(e);
+    }
+    if ok == 0 {
+        let message = format!(
+            "Failed to decode FLAC stream, decoder state: {}",
+            decoder_state_string(decoder)
+        );
+        ffi::FLAC__stream_decoder_finish(decoder);
+        return Err(FlacError::Decoder(message));
+    }
+
+    // finish() is where the MD5 of the decoded audio is compared
+    if ffi::FLAC__stream_decoder_finish(decoder) == 0 {
+        return Err(FlacError::Decoder(
+            "MD5 of decoded audio doesn't match STREAMINFO".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// The read callback of every decoder reading a [`SliceInput`]; the client
+/// data is the `S` holding it.
+pub(crate) unsafe extern "C" fn read_callback<'a, S: ReadsSlice<'a>>(
+    _decoder: *const ffi::FLAC__StreamDecoder,
+    buffer: *mut ffi::FLAC__byte,
+    bytes: *mut usize,
+    client_data: *mut libc::c_void,
+) -> ffi::FLAC__StreamDecoderReadStatus {
+    let input = (*(client_data as *mut S)).input();
+    let remaining = &input.data[input.position..];
+    let to_read = (*bytes).min(remaining.len());
+
+    if to_read == 0 {
+        *bytes = 0;
+        return ffi::FLAC__STREAM_DECODER_READ_STATUS_END_OF_STREAM;
+    }
+
+    std::ptr::copy_nonoverlapping(remaining.as_ptr(), buffer, to_read);
+    input.position += to_read;
+    *bytes = to_read;
+    ffi::FLAC__STREAM_DECODER_READ_STATUS_CONTINUE
+}
+
+impl<'a> ReadsSlice<'a> for RobustState<'a> {
+    fn input(&mut self) -> &mut SliceInput<'a> {
+        &mut self.input
+    }
+}
+
+unsafe extern "C" fn write_callback(
+    _decoder: *const ffi::FLAC__StreamDecoder,
+    frame: *const ffi::FLAC__Frame,
+    buffer: *const *const ffi::FLAC__int32,
+    client_data: *mut libc::c_void,
+) -> ffi::FLAC__StreamDecoderWriteStatus {
+    let state = &mut *(client_data as *mut RobustState<'_>);
+    let header = &(*frame).header;
+
+    if state.error.is_some() {
+        return ffi::FLAC__STREAM_DECODER_WRITE_STATUS_ABORT;
+    }
+    if let Err(e) = check_frame(state, header) {
+        state.error = Some(e);
+        return ffi::FLAC__STREAM_DECODER_WRITE_STATUS_ABORT;
+    }
+
+    let channels = header.channels as usize;
+    let blocksize = header.blocksize as usize;
+    let planes = slice::from_raw_parts(buffer, channels);
+    let planes: Vec<&[i32]> = planes
+        .iter()
+        .map(|&plane| slice::from_raw_parts(plane, blocksize))
+        .collect();
+
+    state.samples.reserve(blocksize * channels);
+    for i in 0..blocksize {
+        for plane in &planes {
+            state.samples.push(plane[i]);
+        }
+    }
+    ffi::FLAC__STREAM_DECODER_WRITE_STATUS_CONTINUE
+}
+
+/// Rejects frames that don't agree with STREAMINFO before their samples are
+/// stored.
+fn check_frame(state: &RobustState<'_>, header: &ffi::FLAC__FrameHeader) -> Result<(), FlacError> {
+    let info = &state.info;
+    if header.channels != info.channels as u32
+        || header.bits_per_sample != info.bits_per_sample as u32
+    {
+        return Err(FlacError::Decoder(format!(
+            "Frame format {} channels / {} bits differs from STREAMINFO {} / {}",
+            header.channels, header.bits_per_sample, info.channels, info.bits_per_sample
+        )));
+    }
+    if header.blocksize > info.max_block_size as u32 {
+        return Err(FlacError::Decoder(format!(
+            "Frame of {} samples exceeds the maximum block size {}",
+            header.blocksize, info.max_block_size
+        )));
+    }
+    let after = state.samples.len() as u64 + header.blocksize as u64 * header.channels as u64;
+    if after > state.max_samples {
+        return Err(FlacError::Decoder(format!(
+            "Stream decodes to more than the {} samples allowed",
+            state.max_samples
+        )));
+    }
+    if let Some(limit) = state.limit {
+        if after > limit {
+            return Err(FlacError::Decoder(format!(
+                "Stream has more than the {} samples declared in STREAMINFO",
+                limit
+            )));
+        }
+    }
+    Ok(())
+}
+
+unsafe extern "C" fn error_callback(
+    _decoder: *const ffi::FLAC__StreamDecoder,
+    status: ffi::FLAC__StreamDecoderErrorStatus,
+    client_data: *mut libc::c_void,
+) {
+    let state = &mut *(client_data as *mut RobustState<'_>);
+    // keep the first error; later ones are usually a consequence of it
+    if state.error.is_none() {
+        state.error = Some(FlacError::Decoder(format!(
+            "Corrupt stream: {}",
+            status_string(&ffi::FLAC__StreamDecoderErrorStatusString, status)
+        )));
+    }
+}
+
+/// State for one `decode_range` call, shared with the callbacks.
+struct RangeState<'a> {
+    input: SliceInput<'a>,
+    channels: u32,
+    /// Sample numbers, per channel, of the first sample kept and the one
+    /// after the last.
+    first: u64,
+    end: u64,
+    samples: Vec<i32>,
+    done: bool,
+    error: Option<FlacError>,
+}
+
+/// Decodes the samples between `start` and `end` of a complete FLAC stream,
+/// interleaved, e.g. to cut a preview clip.
+///
+/// The times are converted to sample numbers, rounding down, and the result
+/// holds exactly the samples from `start` up to but not including `end`,
+/// however they fall across frames. An `end` past the end of the stream
+/// returns what there is. libFLAC seeks to `start` using the seek table if
+/// there is one and a binary search otherwise; streams it can't seek in are
+/// decoded from the beginning and the samples before `start` dropped.
+pub fn decode_range(data: &[u8], start: Duration, end: Duration) -> Result<Vec<i32>, FlacError> {
+    if start > end {
+        return Err(FlacError::InvalidInput(format!(
+            "range start {:?} is after its end {:?}",
+            start, end
+        )));
+    }
+    let info = parse_streaminfo(data)?;
+    if info.sample_rate == 0 {
+        return Err(FlacError::InvalidStreamInfo("sample rate is 0".to_string()));
+    }
+
+    let first = sample_at(start, info.sample_rate);
+    let mut end = sample_at(end, info.sample_rate);
+    if info.total_samples > 0 {
+        end = end.min(info.total_samples);
+    }
+    if first >= end {
+        return Ok(Vec::new());
+    }
+
+    let wanted = (end - first).saturating_mul(info.channels as u64);
+    let mut state = RangeState {
+        input: SliceInput::new(data),
+        channels: info.channels as u32,
+        first,
+        end,
+        samples: Vec::with_capacity(wanted.min(MAX_RESERVE_SAMPLES as u64) as usize),
+        done: false,
+        error: None,
+    };
+
+    let decoder = unsafe { ffi::FLAC__stream_decoder_new() };
+    if decoder.is_null() {
+        return Err(FlacError::Decoder("Failed to allocate decoder".to_string()));
+    }
+
+    let result = unsafe { run_range(decoder, &mut state) };
+    unsafe { ffi::FLAC__stream_decoder_delete(decoder) };
+    result?;
+    Ok(state.samples)
+}
+
+/// The number of the sample playing at `time`, rounding down.
+fn sample_at(time: Duration, sample_rate: u32) -> u64 {
+    let rate = sample_rate as u64;
+    let secs = time.as_secs().saturating_mul(rate);
+    secs.saturating_add(time.subsec_nanos() as u64 * rate / 1_000_000_000)
+}
+
+unsafe fn run_range(
+    decoder: *mut ffi::FLAC__StreamDecoder,
+    state: &mut RangeState<'_>,
+) -> Result<(), FlacError> {
+    ffi::FLAC__stream_decoder_set_metadata_ignore_all(decoder);
+
+    let status = ffi::FLAC__stream_decoder_init_stream(
+        decoder,
+        Some(read_callback::<RangeState>),
+        Some(range_seek_callback),
+        Some(range_tell_callback),
+        Some(range_length_callback),
+        Some(range_eof_callback),
+        Some(range_write_callback),
+        None,
+        Some(range_error_callback),
+        state as *mut RangeState<'_> as *mut libc::c_void,
+    );
+    if status != ffi::FLAC__STREAM_DECODER_INIT_STATUS_OK {
+        return Err(FlacError::Decoder(format!(
+            "Failed to initialize the decoder. Status: {}",
+            status_string(&ffi::FLAC__StreamDecoderInitStatusString, status)
+        )));
+    }
+
+    // The seek delivers the frame holding `first`, trimmed to start at it.
+    // After a failed seek the decoder must be reset, which rewinds it to the
+    // start of the stream; the write callback then skips ahead itself.
+    if state.first > 0 && ffi::FLAC__stream_decoder_seek_absolute(decoder, state.first) == 0 {
+        state.samples.clear();
+        state.done = false;
+        state.error = None;
+        if ffi::FLAC__stream_decoder_reset(decoder) == 0 {
+            ffi::FLAC__stream_decoder_finish(decoder);
+            return Err(FlacError::Decoder(
+                "Failed to rewind the decoder after a failed seek".to_string(),
+            ));
+        }
+    }
+
+    while !state.done && state.error.is_none() {
+        if ffi::FLAC__stream_decoder_process_single(decoder) == 0 {
+            break;
+        }
+        if ffi::FLAC__stream_decoder_get_state(decoder) == ffi::FLAC__STREAM_DECODER_END_OF_STREAM {
+            break;
+        }
+    }
+
+    let result = match state.error.take() {
+        Some(e) => Err(e),
+        None if !state.done
+            && ffi::FLAC__stream_decoder_get_state(decoder)
+                != ffi::FLAC__STREAM_DECODER_END_OF_STREAM =>
+        {
+            Err(FlacError::Decoder(format!(
+                "Failed to decode FLAC stream, decoder state: {}",
+                decoder_state_string(decoder)
+            )))
+        }
+        None => Ok(()),
+    };
+    ffi::FLAC__stream_decoder_finish(decoder);
+    result
+}
+
+impl<'a> ReadsSlice<'a> for RangeState<'a> {
+    fn input(&mut self) -> &mut SliceInput<'a> {
+        &mut self.input
+    }
+}
+
+unsafe extern "C" fn range_seek_callback(
+    _decoder: *const ffi::FLAC__StreamDecoder,
+    absolute_byte_offset: ffi::FLAC__uint64,
+    client_data: *mut libc::c_void,
+) -> ffi::FLAC__StreamDecoderSeekStatus {
+    let state = &mut *(client_data as *mut RangeState<'_>);
+    if absolute_byte_offset > state.input.data.len() as u64 {
+        return ffi::FLAC__STREAM_DECODER_SEEK_STATUS_ERROR;
+    }
+    state.input.position = absolute_byte_offset as usize;
+    ffi::FLAC__STREAM_DECODER_SEEK_STATUS_OK
+}
+
+unsafe extern "C" fn range_tell_callback(
+    _decoder: *const ffi::FLAC__StreamDecoder,
+    absolute_byte_offset: *mut ffi::FLAC__uint64,
+    client_data: *mut libc::c_void,
+) -> ffi::FLAC__StreamDecoderTellStatus {
+    let state = &*(client_data as *const RangeState<'_>);
+    *absolute_byte_offset = state.input.position as u64;
+    ffi::FLAC__STREAM_DECODER_TELL_STATUS_OK
+}
+
+unsafe extern "C" fn range_length_callback(
+    _decoder: *const ffi::FLAC__StreamDecoder,
+    stream_length: *mut ffi::FLAC__uint64,
+    client_data: *mut libc::c_void,
+) -> ffi::FLAC__StreamDecoderLengthStatus {
+    let state = &*(client_data as *const RangeState<'_>);
+    *stream_length = state.input.data.len() as u64;
+    ffi::FLAC__STREAM_DECODER_LENGTH_STATUS_OK
+}
+
+unsafe extern "C" fn range_eof_callback(
+    _decoder: *const ffi::FLAC__StreamDecoder,
+    client_data: *mut libc::c_void,
+) -> ffi::FLAC__bool {
+    let state = &*(client_data as *const RangeState<'_>);
+    (state.input.position >= state.input.data.len()) as ffi::FLAC__bool
+}
+
+unsafe extern "C" fn range_write_callback(
+    _decoder: *const ffi::FLAC__StreamDecoder,
+    frame: *const ffi::FLAC__Frame,
+    buffer: *const *const ffi::FLAC__int32,
+    client_data: *mut libc::c_void,
+) -> ffi::FLAC__StreamDecoderWriteStatus {
+    let state = &mut *(client_data as *mut RangeState<'_>);
+    let header = &(*frame).header;
+
+    if state.error.is_some() || state.done {
+        return ffi::FLAC__STREAM_DECODER_WRITE_STATUS_ABORT;
+    }
+    if header.channels != state.channels {
+        state.error = Some(FlacError::Decoder(format!(
+            "Frame has {} channels, STREAMINFO {}",
+            header.channels, state.channels
+        )));
+        return ffi::FLAC__STREAM_DECODER_WRITE_STATUS_ABORT;
+    }
+
+    // libFLAC always reports the position as a sample number here
+    let frame_start = header.number.sample_number;
+    let blocksize = header.blocksize as u64;
+    let from = state.first.saturating_sub(frame_start).min(blocksize) as usize;
+    let to = state.end.saturating_sub(frame_start).min(blocksize) as usize;
+
+    let planes = slice::from_raw_parts(buffer, state.channels as usize);
+    let planes: Vec<&[i32]> = planes
+        .iter()
+        .map(|&plane| slice::from_raw_parts(plane, blocksize as usize))
+        .collect();
+    for i in from..to {
+        for plane in &planes {
+            state.samples.push(plane[i]);
+        }
+    }
+
+    state.done = frame_start + blocksize >= state.end;
+    ffi::FLAC__STREAM_DECODER_WRITE_STATUS_CONTINUE
+}
+
+unsafe extern "C" fn range_error_callback(
+    _decoder: *const ffi::FLAC__StreamDecoder,
+    status: ffi::FLAC__StreamDecoderErrorStatus,
+    client_data: *mut libc::c_void,
+) {
+    let state = &mut *(client_data as *mut RangeState<'_>);
+    if state.error.is_none() {
+        state.error = Some(FlacError::Decoder(format!(
+            "Corrupt stream: {}",
+            status_string(&ffi::FLAC__StreamDecoderErrorStatusString, status)
+        )));
+    }
+}
@@ -0,0 +1,256 @@
+use std::fmt;
+use std::sync::Arc;
+
+#[derive(Debug, Clone)]
+pub enum FlacError {
+    /// The encoder was used before `init()`.
+    NotInitialized,
+    /// The setting can't be changed while a stream is in progress.
+    AlreadyInitialized,
+    /// The stream has already been finished; `reset()` starts a new one.
+    AlreadyFinished,
+    /// The caller's buffer can't hold the pending encoded bytes. They are kept
+    /// and returned by the next call that has room for them.
+    OutputTooSmall { needed: usize, available: usize },
+    /// The data doesn't start with the `fLaC` stream marker.
+    BadMarker,
+    /// A metadata block starting at `offset` runs past the end of the data.
+    TruncatedBlock { offset: usize },
+    /// No STREAMINFO block was found among the metadata blocks.
+    MissingStreamInfo,
+    /// The metadata blocks aren't followed by a frame sync code at `offset`.
+    MissingFrameSync { offset: usize },
+    /// A STREAMINFO block is malformed.
+    InvalidStreamInfo(String),
+    /// A metadata block couldn't be built from the configured values.
+    InvalidMetadata(String),
+    /// A user-provided callback panicked inside libFLAC's write callback. The
+    /// stream is aborted and the payload message, if any, is kept.
+    CallbackPanicked(String),
+    /// The samples passed in don't fit the configured stream, e.g. the wrong
+    /// number of channels.
+    InvalidInput(String),
+    /// An encoder setting is out of range or inconsistent with another.
+    InvalidConfig(String),
+    /// libFLAC refused an encoder setting; `name` is the
+    /// [`EncoderConfig`](crate::EncoderConfig) setting and `value` what it was
+    /// set to.
+    SettingRejected { name: &'static str, value: String },
+    /// The linked libFLAC doesn't support the requested configuration.
+    Unsupported(String),
+    /// libFLAC reported a failure.
+    Encoder(String),
+    /// libFLAC failed to decode the stream.
+    Decoder(String),
+    /// A frame failed its header or frame CRC check while decoding; see
+    /// [`FlacDecoder::set_check_frame_crc`](crate::FlacDecoder::set_check_frame_crc).
+    FrameCrcMismatch,
+    /// Decoding the encoded stream didn't reproduce the input; `sample` is the
+    /// index of the first differing interleaved sample. Raised both by
+    /// [`encode_and_verify`](crate::encode_and_verify) and by libFLAC's own
+    /// verify decoder while encoding.
+    VerifyMismatch { sample: usize },
+    /// libFLAC's verify decoder couldn't decode a frame the encoder wrote;
+    /// holds the verify decoder's state.
+    VerifyDecoder(String),
+    /// libFLAC ran out of memory; the stream can't continue.
+    OutOfMemory,
+    /// The stream was finished with `samples` left over that don't make up a
+    /// whole frame across all channels.
+    PartialFrame { samples: usize },
+    /// The stream ended with a different number of samples per channel than
+    /// the [`total_samples`](crate::EncoderConfig::total_samples) already
+    /// written to a STREAMINFO that can't be back-patched.
+    TotalSamplesMismatch { declared: u64, actual: u64 },
+    /// [`reset_with`](crate::FlacEncoder::reset_with) would change the
+    /// channel count or bit depth, given as `(channels, bits_per_sample)`,
+    /// while a stream with samples in it is unfinished. Call `finish()`
+    /// first, so the samples aren't dropped along with the stream.
+    FormatChangeMidStream { from: (u32, u32), to: (u32, u32) },
+    /// The sink returned `WouldBlock`, e.g. a non-blocking socket that is
+    /// full. Nothing is lost: the bytes it didn't take are kept for the next
+    /// call; see [`encode_from_iter`](crate::FlacEncoder::encode_from_iter).
+    WouldBlock,
+    /// The stream's STREAMINFO, or one of its frames, has blocks of
+    /// `block_size` samples, more than the decoder's
+    /// [`set_max_block_size_allowed`](crate::FlacDecoder::set_max_block_size_allowed)
+    /// limit of `max`.
+    BlockSizeTooLarge { block_size: u32, max: u32 },
+    /// Reading input or writing output failed. Holds the underlying I/O
+    /// error, which is also the error's [`source`](std::error::Error::source);
+    /// it is shared so that `FlacError` stays `Clone`.
+    Io(Arc<std::io::Error>),
+}
+
+impl fmt::Display for FlacError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FlacError::NotInitialized => write!(f, "Encoder used before init()"),
+            FlacError::AlreadyInitialized => write!(
+                f,
+                "Encoder already initialized; settings must be changed before init()"
+            ),
+            FlacError::AlreadyFinished => {
+                write!(
+                    f,
+                    "Stream already finished; call reset() to start a new one"
+                )
+            }
+            FlacError::OutputTooSmall { needed, available } => write!(
+                f,
+                "Output buffer of len {} too small for encoded data of len {}",
+                available, needed
+            ),
+            FlacError::BadMarker => write!(f, "Missing fLaC stream marker"),
+            FlacError::TruncatedBlock { offset } => {
+                write!(f, "Metadata block at offset {} is truncated", offset)
+            }
+            FlacError::MissingStreamInfo => write!(f, "No STREAMINFO block found"),
+            FlacError::MissingFrameSync { offset } => {
+                write!(f, "No frame sync code at offset {}", offset)
+            }
+            FlacError::InvalidStreamInfo(msg) => write!(f, "Invalid STREAMINFO: {}", msg),
+            FlacError::InvalidMetadata(msg) => write!(f, "Invalid metadata: {}", msg),
+            FlacError::CallbackPanicked(msg) => write!(f, "Callback panicked: {}", msg),
+            FlacError::InvalidInput(msg) => write!(f, "Invalid input: {}", msg),
+            FlacError::InvalidConfig(msg) => write!(f, "Invalid config: {}", msg),
+            FlacError::SettingRejected { name, value } => {
+                write!(f, "libFLAC rejected setting {} = {}", name, value)
+            }
+            FlacError::Unsupported(msg) => write!(f, "Unsupported: {}", msg),
+            FlacError::Encoder(msg) => write!(f, "{}", msg),
+            FlacError::Decoder(msg) => write!(f, "{}", msg),
+            FlacError::FrameCrcMismatch => write!(f, "Frame failed its CRC check"),
+            FlacError::PartialFrame { samples } => write!(
+                f,
+                "{} trailing samples don't make up a whole frame",
+                samples
+            ),
+            FlacError::TotalSamplesMismatch { declared, actual } => write!(
+                f,
+                "STREAMINFO declares {} samples but the stream has {}",
+                declared, actual
+            ),
+            FlacError::FormatChangeMidStream { from, to } => write!(
+                f,
+                "Can't change from {} channels, {}-bit to {} channels, {}-bit before the stream is finished",
+                from.0, from.1, to.0, to.1
+            ),
+            FlacError::WouldBlock => write!(f, "Sink would block; retry once it is writable"),
+            FlacError::BlockSizeTooLarge { block_size, max } => write!(
+                f,
+                "Block size {} is above the allowed maximum of {}",
+                block_size, max
+            ),
+            FlacError::Io(e) => write!(f, "I/O error: {}", e),
+            FlacError::VerifyMismatch { sample } => {
+                write!(f, "Decoded output differs from input at sample {}", sample)
+            }
+            FlacError::VerifyDecoder(state) => {
+                write!(f, "Verify decoder failed to decode a frame: {}", state)
+            }
+            FlacError::OutOfMemory => write!(f, "libFLAC failed to allocate memory"),
+        }
+    }
+}
+
+/// `std::io::Error` has no `PartialEq`, so two [`Io`](FlacError::Io) errors
+/// are equal when they have the same kind and message.
+impl PartialEq for FlacError {
+    fn eq(&self, other: &Self) -> bool {
+        use FlacError::*;
+        match (self, other) {
+            (
+                OutputTooSmall { needed, available },
+                OutputTooSmall {
+                    needed: other_needed,
+                    available: other_available,
+                },
+            ) => needed == other_needed && available == other_available,
+            (TruncatedBlock { offset }, TruncatedBlock { offset: other })
+            | (MissingFrameSync { offset }, MissingFrameSync { offset: other }) => offset == other,
+            (VerifyMismatch { sample }, VerifyMismatch { sample: other })
+            | (PartialFrame { samples: sample }, PartialFrame { samples: other }) => {
+                sample == other
+            }
+            (InvalidStreamInfo(msg), InvalidStreamInfo(other))
+            | (InvalidMetadata(msg), InvalidMetadata(other))
+            | (CallbackPanicked(msg), CallbackPanicked(other))
+            | (InvalidInput(msg), InvalidInput(other))
+            | (InvalidConfig(msg), InvalidConfig(other))
+            | (Unsupported(msg), Unsupported(other))
+            | (Encoder(msg), Encoder(other))
+            | (Decoder(msg), Decoder(other))
+            | (VerifyDecoder(msg), VerifyDecoder(other)) => msg == other,
+            (
+                SettingRejected { name, value },
+                SettingRejected {
+                    name: other_name,
+                    value: other_value,
+                },
+            ) => name == other_name && value == other_value,
+            (
+                TotalSamplesMismatch { declared, actual },
+                TotalSamplesMismatch {
+                    declared: other_declared,
+                    actual: other_actual,
+                },
+            ) => declared == other_declared && actual == other_actual,
+            (
+                FormatChangeMidStream { from, to },
+                FormatChangeMidStream {
+                    from: other_from,
+                    to: other_to,
+                },
+            ) => from == other_from && to == other_to,
+            (
+                BlockSizeTooLarge { block_size, max },
+                BlockSizeTooLarge {
+                    block_size: other_block_size,
+                    max: other_max,
+                },
+            ) => block_size == other_block_size && max == other_max,
+            (Io(e), Io(other)) => e.kind() == other.kind() && e.to_string() == other.to_string(),
+            // every variant with fields is matched above, so this only
+            // compares the unit variants
+            _ => std::mem::discriminant(self) == std::mem::discriminant(other),
+        }
+    }
+}
+
+impl Eq for FlacError {}
+
+impl std::error::Error for FlacError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            FlacError::Io(e) => Some(&**e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for FlacError {
+    fn from(error: std::io::Error) -> Self {
+        FlacError::Io(Arc::new(error))
+    }
+}
+
+/// For `?` in functions returning [`std::io::Result`]. [`Io`](FlacError::Io)
+/// keeps the original error's kind, [`WouldBlock`](FlacError::WouldBlock)
+/// maps to [`ErrorKind::WouldBlock`](std::io::ErrorKind::WouldBlock),
+/// [`OutOfMemory`](FlacError::OutOfMemory) maps to
+/// [`ErrorKind::OutOfMemory`](std::io::ErrorKind::OutOfMemory), and every
+/// other variant maps to [`ErrorKind::InvalidData`](std::io::ErrorKind::InvalidData).
+/// The `FlacError` itself is kept as the inner error, so it can be got back
+/// with [`std::io::Error::get_ref`] and a downcast.
+impl From<FlacError> for std::io::Error {
+    fn from(error: FlacError) -> Self {
+        let kind = match &error {
+            FlacError::Io(e) => e.kind(),
+            FlacError::WouldBlock => std::io::ErrorKind::WouldBlock,
+            FlacError::OutOfMemory => std::io::ErrorKind::OutOfMemory,
+            _ => std::io::ErrorKind::InvalidData,
+        };
+        std::io::Error::new(kind, error)
+    }
+}
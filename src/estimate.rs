@@ -0,0 +1,33 @@
+use crate::{EncoderConfig, FlacEncoder, FlacError, TrustLevel};
+
+/// The exact size in bytes of the stream `config` produces for `samples`,
+/// e.g. for a `Content-Length` header before streaming the real encode.
+///
+/// The audio is encoded once and the output only counted, so memory use
+/// doesn't grow with the input. Settings that don't change the bytes
+/// written are turned off to make the pass cheaper: libFLAC's verify
+/// decoder (`Paranoid` is run as `Fast`) and, with the `md5-toggle`
+/// feature, the MD5, whose STREAMINFO field has a fixed size. `Archival` keeps its exhaustive model search and
+/// so still verifies, as that search does change the output.
+///
+/// The count holds for any encode of the same samples with the same
+/// [`EncoderConfig`], whether the output is streamed in pieces, collected
+/// with [`EncoderConfig::accumulate_output`] or written to a seekable sink,
+/// because libFLAC back-patches the header in place. Metadata added to an
+/// encoder through its own setters (tags, pictures, seek points) is not part
+/// of the config and is not counted.
+pub fn two_pass_size_estimate(config: &EncoderConfig, samples: &[i32]) -> Result<u64, FlacError> {
+    let mut config = config.clone().accumulate_output(false);
+    if cfg!(feature = "md5-toggle") {
+        config = config.write_md5(false);
+    }
+    if config.trust_level == TrustLevel::Paranoid {
+        config = config.trust_level(TrustLevel::Fast);
+    }
+
+    let mut encoder = FlacEncoder::from_config(&config);
+    encoder.restart()?;
+    encoder.encode_chunked(samples, FlacEncoder::clear_output)?;
+    let bytes = encoder.write_state.borrow().stream_bytes;
+    Ok(bytes)
+}
@@ -0,0 +1,267 @@
+use crate::version::flac_version_at_least;
+use crate::FlacError;
+use libflac_sys as ffi;
+use std::ops::RangeInclusive;
+
+/// Sample rates with a dedicated code in the FLAC frame header, plus 11025 Hz
+/// which, while not coded, is common enough not to warrant a warning.
+const COMMON_SAMPLE_RATES: [u32; 12] = [
+    8000, 11025, 16000, 22050, 24000, 32000, 44100, 48000, 88200, 96000, 176400, 192000,
+];
+
+/// How a sample rate relates to what libFLAC and the streamable subset accept.
+///
+/// FLAC stores whatever rate it is given, so a stream configured for 44100 Hz
+/// but fed 48000 Hz audio encodes fine and plays back at the wrong speed. The
+/// crate can't detect that, but it can flag rates that are unlikely to be
+/// intentional.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SampleRateClass {
+    /// A standard rate that every decoder handles.
+    Common,
+    /// Valid and within the streamable subset, but unusual (e.g. 12345 Hz).
+    Uncommon,
+    /// Valid FLAC, but outside the streamable subset.
+    NonSubset,
+    /// Rejected by libFLAC.
+    Invalid,
+}
+
+impl SampleRateClass {
+    pub fn of(sample_rate: u32) -> Self {
+        unsafe {
+            if ffi::FLAC__format_sample_rate_is_valid(sample_rate) == 0 {
+                SampleRateClass::Invalid
+            } else if ffi::FLAC__format_sample_rate_is_subset(sample_rate) == 0 {
+                SampleRateClass::NonSubset
+            } else if COMMON_SAMPLE_RATES.contains(&sample_rate) {
+                SampleRateClass::Common
+            } else {
+                SampleRateClass::Uncommon
+            }
+        }
+    }
+}
+
+/// FLAC's channel limit.
+const MAX_CHANNELS: u8 = 8;
+
+/// A channel count FLAC can store, 1 to 8; see [`EncoderConfig::with_format`].
+///
+/// [`EncoderConfig::with_format`]: crate::EncoderConfig::with_format
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Channels(u8);
+
+impl Channels {
+    pub const MONO: Channels = Channels(1);
+    pub const STEREO: Channels = Channels(2);
+
+    pub fn new(channels: u8) -> Result<Self, FlacError> {
+        match channels {
+            1..=MAX_CHANNELS => Ok(Channels(channels)),
+            _ => Err(FlacError::InvalidConfig(format!(
+                "{} channels; FLAC supports 1 to {}",
+                channels, MAX_CHANNELS
+            ))),
+        }
+    }
+
+    pub fn get(self) -> u8 {
+        self.0
+    }
+}
+
+impl TryFrom<u8> for Channels {
+    type Error = FlacError;
+
+    fn try_from(channels: u8) -> Result<Self, FlacError> {
+        Channels::new(channels)
+    }
+}
+
+/// For counts read from e.g. a WAV header's `u16` or a `u32` field.
+impl TryFrom<u32> for Channels {
+    type Error = FlacError;
+
+    fn try_from(channels: u32) -> Result<Self, FlacError> {
+        Channels::new(u8::try_from(channels).unwrap_or(u8::MAX))
+    }
+}
+
+impl From<Channels> for u32 {
+    fn from(channels: Channels) -> u32 {
+        channels.0 as u32
+    }
+}
+
+/// A bit depth with its own code in the FLAC frame header; see
+/// [`EncoderConfig::with_format`]. Other depths FLAC allows, such as 18
+/// bits, can still be given to [`EncoderConfig::new`].
+///
+/// [`EncoderConfig::with_format`]: crate::EncoderConfig::with_format
+/// [`EncoderConfig::new`]: crate::EncoderConfig::new
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum BitDepth {
+    S8,
+    S12,
+    S16,
+    S20,
+    S24,
+    /// Needs libFLAC 1.4 or newer.
+    S32,
+}
+
+impl BitDepth {
+    pub fn bits(self) -> u32 {
+        match self {
+            BitDepth::S8 => 8,
+            BitDepth::S12 => 12,
+            BitDepth::S16 => 16,
+            BitDepth::S20 => 20,
+            BitDepth::S24 => 24,
+            BitDepth::S32 => 32,
+        }
+    }
+}
+
+impl TryFrom<u32> for BitDepth {
+    type Error = FlacError;
+
+    fn try_from(bits: u32) -> Result<Self, FlacError> {
+        match bits {
+            8 => Ok(BitDepth::S8),
+            12 => Ok(BitDepth::S12),
+            16 => Ok(BitDepth::S16),
+            20 => Ok(BitDepth::S20),
+            24 => Ok(BitDepth::S24),
+            32 => Ok(BitDepth::S32),
+            _ => Err(FlacError::InvalidConfig(format!(
+                "No BitDepth for {}-bit samples; use EncoderConfig::new for them",
+                bits
+            ))),
+        }
+    }
+}
+
+impl From<BitDepth> for u32 {
+    fn from(bits: BitDepth) -> u32 {
+        bits.bits()
+    }
+}
+
+/// What the encoder accepts, for a transcoding layer choosing a codec for
+/// its source; see [`FlacEncoder::capabilities`].
+///
+/// [`FlacEncoder::capabilities`]: crate::FlacEncoder::capabilities
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodecCapabilities {
+    /// In Hz. Rates outside the streamable subset are included; see
+    /// [`SampleRateClass`].
+    pub sample_rates: RangeInclusive<u32>,
+    /// Integer PCM bit depths.
+    pub bits_per_sample: RangeInclusive<u32>,
+    pub max_channels: u32,
+    pub lossless: bool,
+}
+
+impl CodecCapabilities {
+    /// The capabilities of the linked libFLAC: 1.4 added 32-bit samples and
+    /// raised the sample rate limit from 655350 Hz to 1048575 Hz.
+    pub(crate) fn of_linked_libflac() -> Self {
+        let libflac_1_4 = flac_version_at_least(1, 4);
+        CodecCapabilities {
+            sample_rates: 1..=if libflac_1_4 { 1_048_575 } else { 655_350 },
+            bits_per_sample: 4..=if libflac_1_4 { 32 } else { 24 },
+            max_channels: MAX_CHANNELS as u32,
+            lossless: true,
+        }
+    }
+
+    /// Whether PCM in this format can be encoded.
+    pub fn supports(&self, sample_rate: u32, channels: u32, bits_per_sample: u32) -> bool {
+        self.sample_rates.contains(&sample_rate)
+            && (1..=self.max_channels).contains(&channels)
+            && self.bits_per_sample.contains(&bits_per_sample)
+    }
+}
+
+/// The largest a frame header can be: sync code and codes, a 7-byte coded
+/// sample number, explicit block size and sample rate, and the CRC-8.
+const MAX_FRAME_HEADER_LEN: usize = 16;
+
+/// An upper bound on the size of one encoded FLAC frame of `block_size`
+/// samples per channel, header and CRC-16 footer included.
+///
+/// FLAC never stores a block in more space than its raw samples need: when
+/// no predictor helps, as with white noise, libFLAC falls back to verbatim
+/// subframes. The bound is that worst case, one verbatim subframe per
+/// channel, with the extra bit a stereo side channel needs and the largest
+/// frame header, so at 16 bits it is a little over the PCM size of the
+/// block. It holds for frames in the native container; Ogg adds its page
+/// headers on top.
+pub fn max_frame_size(block_size: u32, channels: u32, bits_per_sample: u32) -> usize {
+    let samples = block_size as usize;
+    // each subframe has a 1-byte header, wasted bits only ever shrink it
+    let mut bits = channels as usize * (8 + bits_per_sample as usize * samples);
+    if channels == 2 {
+        bits += samples;
+    }
+    MAX_FRAME_HEADER_LEN + bits.div_ceil(8) + 2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_rate_class() {
+        assert_eq!(SampleRateClass::of(44100), SampleRateClass::Common);
+        assert_eq!(SampleRateClass::of(48000), SampleRateClass::Common);
+        assert_eq!(SampleRateClass::of(12345), SampleRateClass::Uncommon);
+        assert_eq!(SampleRateClass::of(65537), SampleRateClass::NonSubset);
+        assert_eq!(SampleRateClass::of(0), SampleRateClass::Invalid);
+    }
+
+    #[test]
+    fn test_max_frame_size() {
+        // 4096 stereo 16-bit samples are 16384 bytes of PCM
+        assert_eq!(
+            max_frame_size(4096, 2, 16),
+            16 + (2 * 8 + 33 * 4096) / 8 + 2
+        );
+        assert!(max_frame_size(4096, 2, 16) > 16384);
+        assert_eq!(max_frame_size(192, 1, 8), 16 + 1 + 192 + 2);
+    }
+
+    #[test]
+    fn test_channels_and_bit_depth() {
+        assert_eq!(Channels::new(2).unwrap(), Channels::STEREO);
+        assert!(Channels::new(0).is_err());
+        assert!(Channels::new(9).is_err());
+        assert_eq!(Channels::try_from(8u32).unwrap().get(), 8);
+        assert!(Channels::try_from(258u32).is_err());
+        assert_eq!(u32::from(Channels::MONO), 1);
+
+        assert_eq!(BitDepth::try_from(24).unwrap(), BitDepth::S24);
+        assert!(BitDepth::try_from(18).is_err());
+        assert_eq!(u32::from(BitDepth::S12), 12);
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let capabilities = CodecCapabilities::of_linked_libflac();
+        assert!(capabilities.lossless);
+        assert!(capabilities.supports(44100, 2, 16));
+        assert!(capabilities.supports(384000, 8, 24));
+        assert!(!capabilities.supports(44100, 9, 16));
+        assert!(!capabilities.supports(0, 2, 16));
+        assert_eq!(
+            capabilities.supports(48000, 2, 32),
+            flac_version_at_least(1, 4)
+        );
+        // the range ends where libFLAC's own check does
+        let max_rate = *capabilities.sample_rates.end();
+        assert_ne!(SampleRateClass::of(max_rate), SampleRateClass::Invalid);
+        assert_eq!(SampleRateClass::of(max_rate + 1), SampleRateClass::Invalid);
+    }
+}
@@ -0,0 +1,240 @@
+//! Pure-Rust reading of encoded FLAC frames, as far as the subframe headers.
+//! The subframe bodies are skipped over, not decoded.
+
+/// The fields of a frame header needed to walk the frame.
+struct FrameHeader {
+    block_size: usize,
+    /// `None` when the bit depth is taken from STREAMINFO.
+    bits: Option<u32>,
+    channels: usize,
+    /// The channel holding a stereo side signal, which needs one more bit.
+    side: Option<usize>,
+    /// Bytes in the header, CRC-8 included.
+    len: usize,
+}
+
+fn parse_header(frame: &[u8]) -> Option<FrameHeader> {
+    if frame.len() < 4 || frame[0] != 0xff || frame[1] & 0xfe != 0xf8 {
+        return None;
+    }
+    let (block_size_code, sample_rate_code) = (frame[2] >> 4, frame[2] & 0x0f);
+    let (assignment, sample_size_code) = (frame[3] >> 4, (frame[3] >> 1) & 0x07);
+
+    // the frame or sample number, UTF-8 style: the leading ones give the length
+    let mut pos = 4;
+    pos += match frame.get(pos)?.leading_ones() {
+        0 => 1,
+        len @ 2..=7 => len as usize,
+        _ => return None,
+    };
+    let block_size = match block_size_code {
+        0 => return None,
+        1 => 192,
+        2..=5 => 576 << (block_size_code - 2),
+        6 => {
+            pos += 1;
+            *frame.get(pos - 1)? as usize + 1
+        }
+        7 => {
+            pos += 2;
+            u16::from_be_bytes([*frame.get(pos - 2)?, *frame.get(pos - 1)?]) as usize + 1
+        }
+        _ => 256 << (block_size_code - 8),
+    };
+    pos += match sample_rate_code {
+        12 => 1,
+        13 | 14 => 2,
+        15 => return None,
+        _ => 0,
+    };
+
+    let bits = match sample_size_code {
+        0 => None,
+        1 => Some(8),
+        2 => Some(12),
+        4 => Some(16),
+        5 => Some(20),
+        6 => Some(24),
+        7 => Some(32),
+        _ => return None,
+    };
+    let (channels, side) = match assignment {
+        0..=7 => (assignment as usize + 1, None),
+        8 | 10 => (2, Some(1)),
+        9 => (2, Some(0)),
+        _ => return None,
+    };
+    Some(FrameHeader {
+        block_size,
+        bits,
+        channels,
+        side,
+        len: pos + 1,
+    })
+}
+
+/// CRC-8 with polynomial x^8 + x^2 + x + 1, as used for frame headers.
+fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0, |crc, &byte| {
+        (0..8).fold(crc ^ byte, |crc, _| match crc & 0x80 {
+            0 => crc << 1,
+            _ => (crc << 1) ^ 0x07,
+        })
+    })
+}
+
+/// The offset of the first frame header in `data` at or after `from`: a
+/// sync code followed by a header whose CRC-8 checks out, which is what
+/// libFLAC looks for when it regains sync.
+pub(crate) fn find_frame(data: &[u8], from: usize) -> Option<usize> {
+    (from..data.len()).find(|&start| {
+        let candidate = &data[start..];
+        match parse_header(candidate) {
+            Some(header) => candidate
+                .get(header.len - 1)
+                .is_some_and(|&crc| crc8(&candidate[..header.len - 1]) == crc),
+            None => false,
+        }
+    })
+}
+
+/// The wasted bits of each subframe of `frame`, one complete native FLAC
+/// frame, in channel order. `None` if the frame can't be parsed.
+///
+/// `stream_bits` is the stream's bit depth, used when the frame header
+/// refers to STREAMINFO for it.
+pub(crate) fn subframe_wasted_bits(frame: &[u8], stream_bits: u32) -> Option<Vec<u32>> {
+    let header = parse_header(frame)?;
+    let bits = header.bits.unwrap_or(stream_bits);
+    let mut reader = BitReader {
+        data: frame.get(header.len..)?,
+        pos: 0,
+    };
+    (0..header.channels)
+        .map(|channel| {
+            let bits = bits + (header.side == Some(channel)) as u32;
+            reader.subframe(bits, header.block_size)
+        })
+        .collect()
+}
+
+struct BitReader<'a> {
+    data: &'a [u8],
+    /// In bits from the start of `data`.
+    pos: usize,
+}
+
+impl BitReader<'_> {
+    fn read(&mut self, bits: u32) -> Option<u64> {
+        let mut value = 0u64;
+        for _ in 0..bits {
+            let byte = *self.data.get(self.pos / 8)?;
+            value = (value << 1) | ((byte >> (7 - self.pos % 8)) & 1) as u64;
+            self.pos += 1;
+        }
+        Some(value)
+    }
+
+    fn skip(&mut self, bits: usize) -> Option<()> {
+        self.pos += bits;
+        (self.pos <= self.data.len() * 8).then_some(())
+    }
+
+    /// Counts zero bits up to and including the next one bit.
+    fn unary(&mut self) -> Option<u32> {
+        let mut zeros = 0;
+        loop {
+            if self.pos & 7 == 0 && self.data.get(self.pos / 8) == Some(&0) {
+                zeros += 8;
+                self.pos += 8;
+                continue;
+            }
+            if self.read(1)? == 1 {
+                return Some(zeros);
+            }
+            zeros += 1;
+        }
+    }
+
+    /// Reads a subframe of `bits`-bit samples, returning its wasted bits.
+    fn subframe(&mut self, bits: u32, block_size: usize) -> Option<u32> {
+        if self.read(1)? != 0 {
+            return None;
+        }
+        let kind = self.read(6)?;
+        let wasted = match self.read(1)? {
+            1 => self.unary()? + 1,
+            _ => 0,
+        };
+        let bits = bits.checked_sub(wasted)? as usize;
+        match kind {
+            0 => self.skip(bits)?,
+            1 => self.skip(bits * block_size)?,
+            8..=12 => {
+                let order = kind as usize - 8;
+                self.skip(order * bits)?;
+                self.residual(order, block_size)?;
+            }
+            32..=63 => {
+                let order = kind as usize - 31;
+                self.skip(order * bits)?;
+                let precision = match self.read(4)? {
+                    15 => return None,
+                    precision => precision as usize + 1,
+                };
+                self.skip(5 + order * precision)?; // shift, coefficients
+                self.residual(order, block_size)?;
+            }
+            _ => return None,
+        }
+        Some(wasted)
+    }
+
+    fn residual(&mut self, order: usize, block_size: usize) -> Option<()> {
+        let parameter_bits = match self.read(2)? {
+            0 => 4,
+            1 => 5,
+            _ => return None,
+        };
+        let escape = (1 << parameter_bits) - 1;
+        let partition_order = self.read(4)?;
+        for partition in 0..1usize << partition_order {
+            let samples = (block_size >> partition_order).checked_sub(match partition {
+                0 => order,
+                _ => 0,
+            })?;
+            match self.read(parameter_bits)? {
+                parameter if parameter == escape => {
+                    let raw_bits = self.read(5)? as usize;
+                    self.skip(raw_bits * samples)?;
+                }
+                parameter => {
+                    for _ in 0..samples {
+                        self.unary()?;
+                        self.skip(parameter as usize)?;
+                    }
+                }
+            }
+        }
+        Some(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_frame() {
+        // frame 0, 4096 samples, 44.1 kHz, stereo, 16-bit, then the CRC-8
+        let mut header = vec![0xff, 0xf8, 0xc9, 0x18, 0x00];
+        header.push(crc8(&header));
+        assert_eq!(parse_header(&header).unwrap().len, 6);
+
+        let mut data = vec![0x12, 0xff, 0xf8, 0xc9, 0x18, 0x00, 0x00];
+        data.extend_from_slice(&header);
+        // a sync code with a bad CRC is passed over
+        assert_eq!(find_frame(&data, 0), Some(7));
+        assert_eq!(find_frame(&data, 8), None);
+    }
+}
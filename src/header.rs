@@ -0,0 +1,508 @@
+//! Pure-Rust parsing of the FLAC stream header: the `fLaC` marker followed by
+//! metadata blocks. None of this touches libFLAC.
+
+use crate::{FlacError, SeekPoint};
+use std::ops::Range;
+use std::time::Duration;
+
+pub(crate) const STREAM_MARKER: &[u8; 4] = b"fLaC";
+pub(crate) const BLOCK_TYPE_STREAMINFO: u8 = 0;
+const BLOCK_TYPE_SEEKTABLE: u8 = 3;
+pub(crate) const STREAMINFO_LEN: usize = 34;
+const SEEK_POINT_LEN: usize = 18;
+/// The sample number that marks an unused placeholder seek point.
+pub(crate) const PLACEHOLDER_SAMPLE: u64 = u64::MAX;
+
+/// The fields of a STREAMINFO metadata block.
+///
+/// Zero frame sizes and zero `total_samples` mean "unknown"; an all-zero
+/// `md5` means the encoder didn't compute one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StreamInfo {
+    pub min_block_size: u16,
+    pub max_block_size: u16,
+    pub min_frame_size: u32,
+    pub max_frame_size: u32,
+    pub sample_rate: u32,
+    pub channels: u8,
+    pub bits_per_sample: u8,
+    pub total_samples: u64,
+    pub md5: [u8; 16],
+}
+
+impl StreamInfo {
+    /// Playing time of the stream, or `None` if the total sample count is
+    /// unknown.
+    pub fn duration(&self) -> Option<Duration> {
+        duration_of(self.total_samples, self.sample_rate)
+    }
+
+    /// The block body `from_bytes` reads, for patching a header in place.
+    pub(crate) fn to_bytes(&self) -> [u8; STREAMINFO_LEN] {
+        let mut body = [0u8; STREAMINFO_LEN];
+        body[0..2].copy_from_slice(&self.min_block_size.to_be_bytes());
+        body[2..4].copy_from_slice(&self.max_block_size.to_be_bytes());
+        body[4..7].copy_from_slice(&self.min_frame_size.to_be_bytes()[1..]);
+        body[7..10].copy_from_slice(&self.max_frame_size.to_be_bytes()[1..]);
+        let packed = (self.sample_rate as u64) << 44
+            | ((self.channels as u64 - 1) << 41)
+            | ((self.bits_per_sample as u64 - 1) << 36)
+            | (self.total_samples & 0xf_ffff_ffff);
+        body[10..18].copy_from_slice(&packed.to_be_bytes());
+        body[18..34].copy_from_slice(&self.md5);
+        body
+    }
+
+    fn from_bytes(body: &[u8]) -> Result<Self, FlacError> {
+        if body.len() != STREAMINFO_LEN {
+            return Err(FlacError::InvalidStreamInfo(format!(
+                "block is {} bytes, expected {}",
+                body.len(),
+                STREAMINFO_LEN
+            )));
+        }
+
+        let u24 = |b: &[u8]| u32::from_be_bytes([0, b[0], b[1], b[2]]);
+        let packed = u64::from_be_bytes(body[10..18].try_into().unwrap());
+        let mut md5 = [0u8; 16];
+        md5.copy_from_slice(&body[18..34]);
+
+        Ok(StreamInfo {
+            min_block_size: u16::from_be_bytes([body[0], body[1]]),
+            max_block_size: u16::from_be_bytes([body[2], body[3]]),
+            min_frame_size: u24(&body[4..7]),
+            max_frame_size: u24(&body[7..10]),
+            sample_rate: (packed >> 44) as u32,
+            channels: ((packed >> 41) & 0x7) as u8 + 1,
+            bits_per_sample: ((packed >> 36) & 0x1f) as u8 + 1,
+            total_samples: packed & 0xf_ffff_ffff,
+            md5,
+        })
+    }
+}
+
+/// Converts a sample count per channel to a duration, rounding down to the
+/// nanosecond. Integer arithmetic keeps it exact where `f64` seconds would
+/// lose precision for very long streams. A count or rate of 0 means unknown.
+pub(crate) fn duration_of(total_samples: u64, sample_rate: u32) -> Option<Duration> {
+    if total_samples == 0 || sample_rate == 0 {
+        return None;
+    }
+    let rate = sample_rate as u64;
+    let secs = total_samples / rate;
+    // the remainder is below the rate (at most 2^20), so this can't overflow
+    let nanos = (total_samples % rate) * 1_000_000_000 / rate;
+    Some(Duration::new(secs, nanos as u32))
+}
+
+/// A metadata block borrowed from the input.
+pub(crate) struct MetadataBlock<'a> {
+    pub block_type: u8,
+    pub body: &'a [u8],
+}
+
+/// Iterates over the metadata blocks after the `fLaC` marker, stopping after
+/// the block flagged as last or at the first truncated block.
+pub(crate) struct MetadataBlocks<'a> {
+    data: &'a [u8],
+    pos: usize,
+    done: bool,
+}
+
+impl<'a> MetadataBlocks<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Self, FlacError> {
+        if !data.starts_with(STREAM_MARKER) {
+            return Err(FlacError::BadMarker);
+        }
+        Ok(MetadataBlocks {
+            data,
+            pos: STREAM_MARKER.len(),
+            done: false,
+        })
+    }
+}
+
+impl<'a> Iterator for MetadataBlocks<'a> {
+    type Item = Result<MetadataBlock<'a>, FlacError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let offset = self.pos;
+        let header = match self.data.get(offset..offset + 4) {
+            Some(header) => header,
+            None => {
+                self.done = true;
+                return Some(Err(FlacError::TruncatedBlock { offset }));
+            }
+        };
+
+        let is_last = header[0] & 0x80 != 0;
+        let block_type = header[0] & 0x7f;
+        let length = u32::from_be_bytes([0, header[1], header[2], header[3]]) as usize;
+        let body = match self.data.get(offset + 4..offset + 4 + length) {
+            Some(body) => body,
+            None => {
+                self.done = true;
+                return Some(Err(FlacError::TruncatedBlock { offset }));
+            }
+        };
+
+        self.pos = offset + 4 + length;
+        self.done = is_last;
+        Some(Ok(MetadataBlock { block_type, body }))
+    }
+}
+
+/// Reads STREAMINFO from the start of an encoded stream without going through
+/// libFLAC. Only the header bytes are needed, not the whole file.
+pub fn parse_streaminfo(data: &[u8]) -> Result<StreamInfo, FlacError> {
+    for block in MetadataBlocks::new(data)? {
+        let block = block?;
+        if block.block_type == BLOCK_TYPE_STREAMINFO {
+            return StreamInfo::from_bytes(block.body);
+        }
+    }
+    Err(FlacError::MissingStreamInfo)
+}
+
+/// Reads the SEEKTABLE of an encoded stream, e.g. one finished to a file or
+/// seekable sink so libFLAC could fill the table in.
+///
+/// Placeholder points are skipped. Byte offsets are converted from the
+/// table's, which count from the first frame, to offsets from the start of
+/// the stream like [`FlacEncoder::seek_index`](crate::FlacEncoder::seek_index)
+/// reports, so they can be used for range requests as they are. That needs
+/// the whole header in `data`, not just up to the table. A stream without a
+/// SEEKTABLE gives an empty list.
+pub fn read_seek_table(data: &[u8]) -> Result<Vec<SeekPoint>, FlacError> {
+    let mut blocks = MetadataBlocks::new(data)?;
+    let mut table = None;
+    for block in blocks.by_ref() {
+        let block = block?;
+        if block.block_type == BLOCK_TYPE_SEEKTABLE {
+            table = Some(block.body);
+        }
+    }
+    let table = match table {
+        Some(table) => table,
+        None => return Ok(Vec::new()),
+    };
+    if table.len() % SEEK_POINT_LEN != 0 {
+        return Err(FlacError::InvalidMetadata(format!(
+            "SEEKTABLE length {} is not a multiple of {}",
+            table.len(),
+            SEEK_POINT_LEN
+        )));
+    }
+
+    let first_frame = blocks.pos as u64;
+    Ok(table
+        .chunks_exact(SEEK_POINT_LEN)
+        .map(|point| SeekPoint {
+            sample_number: u64::from_be_bytes(point[..8].try_into().unwrap()),
+            byte_offset: u64::from_be_bytes(point[8..16].try_into().unwrap()) + first_frame,
+            frame_samples: u16::from_be_bytes([point[16], point[17]]) as u32,
+        })
+        .filter(|point| point.sample_number != PLACEHOLDER_SAMPLE)
+        .collect())
+}
+
+/// Checks that `data` is structurally a FLAC stream without decoding any
+/// audio: the `fLaC` marker, metadata blocks that fit in the buffer up to the
+/// one flagged as last, a STREAMINFO among them and a frame sync code right
+/// after them. The first problem found is returned. A stream with no frames
+/// at all fails with [`FlacError::MissingFrameSync`].
+pub fn validate_flac(data: &[u8]) -> Result<(), FlacError> {
+    let mut blocks = MetadataBlocks::new(data)?;
+    let mut has_streaminfo = false;
+    for block in blocks.by_ref() {
+        has_streaminfo |= block?.block_type == BLOCK_TYPE_STREAMINFO;
+    }
+    if !has_streaminfo {
+        return Err(FlacError::MissingStreamInfo);
+    }
+
+    // 14 sync bits, then a reserved 0 bit; the blocking strategy bit is free
+    let offset = blocks.pos;
+    match data.get(offset..offset + 2) {
+        Some(&[0xff, second]) if second & 0xfe == 0xf8 => Ok(()),
+        _ => Err(FlacError::MissingFrameSync { offset }),
+    }
+}
+
+/// Finds the streams in `data` made of FLAC streams laid end to end, e.g. the
+/// output of a segmenting encoder, returning the byte range of each.
+///
+/// A stream starts at a `fLaC` marker that is directly followed by a
+/// plausible STREAMINFO block (the first block, 34 bytes, with a nonzero
+/// sample rate and sane block sizes), which rules out the marker bytes
+/// turning up by chance inside frame data. Each range runs to the start of
+/// the next stream or the end of `data`; bytes before the first stream are
+/// not included. The streams themselves aren't decoded or validated.
+pub fn split_concatenated(data: &[u8]) -> Vec<Range<usize>> {
+    let mut starts = Vec::new();
+    let mut pos = 0;
+    while let Some(found) = find_marker(&data[pos..]) {
+        let start = pos + found;
+        if starts_stream(&data[start..]) {
+            starts.push(start);
+        }
+        pos = start + STREAM_MARKER.len();
+    }
+
+    let ends = starts.iter().skip(1).copied().chain([data.len()]);
+    starts
+        .iter()
+        .zip(ends)
+        .map(|(&start, end)| start..end)
+        .collect()
+}
+
+fn find_marker(data: &[u8]) -> Option<usize> {
+    data.windows(STREAM_MARKER.len())
+        .position(|window| window == STREAM_MARKER)
+}
+
+/// Whether `data` opens with the marker and a believable STREAMINFO.
+fn starts_stream(data: &[u8]) -> bool {
+    let header = match data.get(4..8) {
+        Some(header) => header,
+        None => return false,
+    };
+    if header[0] & 0x7f != BLOCK_TYPE_STREAMINFO || header[1..] != [0, 0, STREAMINFO_LEN as u8] {
+        return false;
+    }
+    match data.get(8..8 + STREAMINFO_LEN).map(StreamInfo::from_bytes) {
+        Some(Ok(info)) => {
+            info.sample_rate > 0
+                && info.min_block_size >= 16
+                && info.min_block_size <= info.max_block_size
+        }
+        _ => false,
+    }
+}
+
+/// The length of an ID3v2 tag at the start of `data`, footer included, or
+/// `None` if there isn't one. Only the 10-byte tag header is read, so the
+/// length can run past the end of `data`.
+pub(crate) fn id3v2_len(data: &[u8]) -> Option<usize> {
+    let header = data.get(..10)?;
+    // the four size bytes are "syncsafe": 7 bits each, top bit clear
+    if &header[..3] != b"ID3" || header[3] == 0xff || header[6..].iter().any(|b| b & 0x80 != 0) {
+        return None;
+    }
+    let size = header[6..]
+        .iter()
+        .fold(0usize, |size, &b| (size << 7) | b as usize);
+    let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+    Some(10 + size + footer)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// "fLaC" followed by a last-block STREAMINFO for 44.1 kHz stereo 16-bit.
+    pub(crate) fn streaminfo_header(total_samples: u64) -> Vec<u8> {
+        let mut data = b"fLaC".to_vec();
+        data.extend_from_slice(&[0x80, 0, 0, 34]);
+        data.extend_from_slice(&4096u16.to_be_bytes());
+        data.extend_from_slice(&4096u16.to_be_bytes());
+        data.extend_from_slice(&[0, 0, 14, 0, 0x1a, 0x2b]);
+        let packed = (44100u64 << 44) | (1 << 41) | (15 << 36) | total_samples;
+        data.extend_from_slice(&packed.to_be_bytes());
+        data.extend_from_slice(&[0xab; 16]);
+        data
+    }
+
+    #[test]
+    fn test_parse_streaminfo() {
+        let info = parse_streaminfo(&streaminfo_header(0x1_2345_6789)).unwrap();
+        assert_eq!(
+            info,
+            StreamInfo {
+                min_block_size: 4096,
+                max_block_size: 4096,
+                min_frame_size: 14,
+                max_frame_size: 0x1a2b,
+                sample_rate: 44100,
+                channels: 2,
+                bits_per_sample: 16,
+                total_samples: 0x1_2345_6789,
+                md5: [0xab; 16],
+            }
+        );
+    }
+
+    #[test]
+    fn test_streaminfo_to_bytes_round_trips() {
+        let data = streaminfo_header(0x1_2345_6789);
+        let info = parse_streaminfo(&data).unwrap();
+        assert_eq!(info.to_bytes()[..], data[8..]);
+    }
+
+    #[test]
+    fn test_duration_of() {
+        assert_eq!(duration_of(0, 44100), None);
+        assert_eq!(duration_of(44100, 0), None);
+        assert_eq!(duration_of(44100, 44100), Some(Duration::from_secs(1)));
+        assert_eq!(duration_of(22050, 44100), Some(Duration::from_millis(500)));
+        // beyond u32 samples: 2^36 - 1 at 44.1 kHz is about 18 days
+        assert_eq!(
+            duration_of(0xf_ffff_ffff, 44100),
+            Some(Duration::new(1_558_264, 778_571_428))
+        );
+        // unusual rates, including ones that don't divide a second evenly
+        assert_eq!(duration_of(1, 1), Some(Duration::from_secs(1)));
+        assert_eq!(duration_of(1, 3), Some(Duration::new(0, 333_333_333)));
+        assert_eq!(
+            duration_of(11025 * 3 + 1, 11025),
+            Some(Duration::new(3, 90_702))
+        );
+        assert_eq!(
+            StreamInfo {
+                total_samples: 88200,
+                ..parse_streaminfo(&streaminfo_header(0)).unwrap()
+            }
+            .duration(),
+            Some(Duration::from_secs(2))
+        );
+    }
+
+    #[test]
+    fn test_validate_flac() {
+        let mut data = streaminfo_header(0);
+        assert_eq!(
+            validate_flac(&data),
+            Err(FlacError::MissingFrameSync { offset: 42 })
+        );
+        data.extend_from_slice(&[0xff, 0xf8, 0x69, 0x08]);
+        assert_eq!(validate_flac(&data), Ok(()));
+        data[43] = 0xf9; // variable block size
+        assert_eq!(validate_flac(&data), Ok(()));
+        data[43] = 0xfa;
+        assert_eq!(
+            validate_flac(&data),
+            Err(FlacError::MissingFrameSync { offset: 42 })
+        );
+
+        assert_eq!(validate_flac(b"ID3\x04"), Err(FlacError::BadMarker));
+        assert_eq!(
+            validate_flac(&data[..30]),
+            Err(FlacError::TruncatedBlock { offset: 4 })
+        );
+        let mut padding_only = b"fLaC".to_vec();
+        padding_only.extend_from_slice(&[0x81, 0, 0, 0, 0xff, 0xf8]);
+        assert_eq!(
+            validate_flac(&padding_only),
+            Err(FlacError::MissingStreamInfo)
+        );
+    }
+
+    #[test]
+    fn test_split_concatenated() {
+        let mut first = streaminfo_header(100);
+        // frame-like data that happens to contain the marker
+        first.extend_from_slice(&[0xff, 0xf8, 0x00]);
+        first.extend_from_slice(b"fLaC");
+        first.extend_from_slice(&[0x80, 0, 0, 34, 0, 0]);
+        let mut second = streaminfo_header(200);
+        second.extend_from_slice(&[0xff, 0xf8, 1, 2, 3]);
+
+        let mut data = first.clone();
+        data.extend_from_slice(&second);
+        data.extend_from_slice(&second);
+        let a = first.len();
+        let b = a + second.len();
+        assert_eq!(split_concatenated(&data), vec![0..a, a..b, b..data.len()]);
+
+        // leading junk is skipped, and a marker too close to the end to
+        // carry STREAMINFO isn't a stream
+        let mut data = b"junk".to_vec();
+        data.extend_from_slice(&second);
+        data.extend_from_slice(b"fLaC");
+        assert_eq!(split_concatenated(&data), vec![4..data.len()]);
+        assert!(split_concatenated(b"no streams here").is_empty());
+    }
+
+    #[test]
+    fn test_read_seek_table() {
+        let mut data = streaminfo_header(8192);
+        data[4] &= 0x7f; // STREAMINFO is no longer the last block
+        data.extend_from_slice(&[0x83, 0, 0, 3 * 18]);
+        for (sample, offset) in [(0u64, 0u64), (4096, 5000), (u64::MAX, 0)] {
+            data.extend_from_slice(&sample.to_be_bytes());
+            data.extend_from_slice(&offset.to_be_bytes());
+            data.extend_from_slice(&4096u16.to_be_bytes());
+        }
+        let first_frame = data.len() as u64;
+        let point = |sample_number, offset| SeekPoint {
+            sample_number,
+            byte_offset: first_frame + offset,
+            frame_samples: 4096,
+        };
+        assert_eq!(
+            read_seek_table(&data),
+            Ok(vec![point(0, 0), point(4096, 5000)])
+        );
+
+        assert_eq!(read_seek_table(&streaminfo_header(0)), Ok(Vec::new()));
+        data[44..46].copy_from_slice(&[0, 50]);
+        assert!(matches!(
+            read_seek_table(&data[..46 + 50]),
+            Err(FlacError::InvalidMetadata(_))
+        ));
+        assert_eq!(read_seek_table(b"OggS"), Err(FlacError::BadMarker));
+    }
+
+    #[test]
+    fn test_id3v2_len() {
+        // version 2.4, size 0x01 0x7f in syncsafe bits = 255
+        let tag = [b'I', b'D', b'3', 4, 0, 0, 0, 0, 0x01, 0x7f];
+        assert_eq!(id3v2_len(&tag), Some(10 + 255));
+        let mut with_footer = tag;
+        with_footer[5] = 0x10;
+        assert_eq!(id3v2_len(&with_footer), Some(10 + 255 + 10));
+
+        let mut not_syncsafe = tag;
+        not_syncsafe[9] = 0x80;
+        assert_eq!(id3v2_len(&not_syncsafe), None);
+        assert_eq!(id3v2_len(&tag[..9]), None);
+        assert_eq!(id3v2_len(&streaminfo_header(0)), None);
+    }
+
+    #[test]
+    fn test_parse_streaminfo_skips_leading_blocks() {
+        let mut data = b"fLaC".to_vec();
+        data.extend_from_slice(&[1, 0, 0, 3, 0, 0, 0]); // 3 bytes of padding
+        let streaminfo = streaminfo_header(1000);
+        data.extend_from_slice(&streaminfo[4..]);
+        assert_eq!(parse_streaminfo(&data).unwrap().total_samples, 1000);
+    }
+
+    #[test]
+    fn test_parse_streaminfo_rejects_bad_input() {
+        assert_eq!(parse_streaminfo(b"RIFF"), Err(FlacError::BadMarker));
+        assert_eq!(parse_streaminfo(b""), Err(FlacError::BadMarker));
+
+        let data = streaminfo_header(0);
+        assert_eq!(
+            parse_streaminfo(&data[..20]),
+            Err(FlacError::TruncatedBlock { offset: 4 })
+        );
+        assert_eq!(
+            parse_streaminfo(&data[..6]),
+            Err(FlacError::TruncatedBlock { offset: 4 })
+        );
+
+        let mut padding_only = b"fLaC".to_vec();
+        padding_only.extend_from_slice(&[0x81, 0, 0, 0]);
+        assert_eq!(
+            parse_streaminfo(&padding_only),
+            Err(FlacError::MissingStreamInfo)
+        );
+    }
+}
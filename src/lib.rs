@@ -1,433 +1,5650 @@
 use core::slice;
 use libflac_sys as ffi;
 use libflac_sys::*;
+use soundkit::audio_bytes::{f32le_to_s24, s16le_to_i32, s24le_to_i32};
 use soundkit::audio_packet::{Decoder, Encoder};
-use std::cell::RefCell;
-use std::rc::Rc;
-use tracing::{debug, error, info};
+use soundkit::audio_types::AudioData;
+use std::cell::{Ref, RefCell};
+use std::io::{Seek, Write};
+use std::panic::{self, AssertUnwindSafe};
+use std::time::{Duration, Instant};
+use tracing::{debug, error, warn};
 
+use config::MAX_BLOCK_SIZE;
+use metadata::MetadataConfig;
+use stats::{ChannelLevels, Peaks, Throughput};
+
+#[cfg(not(any(feature = "container-native", feature = "container-ogg")))]
+compile_error!("enable at least one of the `container-native` and `container-ogg` features");
+
+mod bounded;
+mod chain;
+mod config;
+mod decode;
+mod error;
+mod estimate;
+mod format;
+mod frame;
+mod header;
+mod metadata;
+mod mp4;
+mod pool;
+mod rechunk;
+mod recompress;
+mod scan;
+mod stats;
+#[cfg(test)]
+mod test_util;
+mod verify;
+mod version;
+mod wav;
+
+pub use bounded::encode_streaming_bounded;
+pub use chain::MetadataChain;
+pub use config::{
+    AppliedConfig, Container, EncoderConfig, SubsetAdjustment, TrustLevel, UnsupportedFeature,
+    MAX_LPC_ORDER, MAX_RESIDUAL_PARTITION_ORDER,
+};
+pub use decode::{
+    decode_range, decode_robust, decode_robust_with_limit, DecodedAudio, MAX_DECODED_SAMPLES,
+};
+pub use error::FlacError;
+pub use estimate::two_pass_size_estimate;
+pub use format::{max_frame_size, BitDepth, Channels, CodecCapabilities, SampleRateClass};
+pub use header::{
+    parse_streaminfo, read_seek_table, split_concatenated, validate_flac, StreamInfo,
+};
+pub use metadata::{ApplicationId, MetadataKind, Picture, VorbisComment};
+pub use mp4::decode_mp4_flac;
+pub use pool::{FlacEncoderPool, PooledEncoder};
+pub use rechunk::RechunkingEncoder;
+pub use recompress::{concatenate_flac, recompress};
+pub use scan::{read_metadata_only, FlacMetadata};
+#[cfg(feature = "timing")]
+pub use stats::TimingStats;
+pub use stats::{ChannelStat, WastedBitsStat};
+pub use verify::encode_and_verify;
+pub use version::flac_version;
+pub use wav::{flac_to_wav_with_foreign, wav_to_flac};
+
+/// Encoder lifecycle records (init, reset, finish and failures) for the
+/// `log` feature. Without it the arguments are only type-checked, never
+/// evaluated, so the records cost nothing.
+macro_rules! lifecycle {
+    ($level:ident, $($arg:tt)+) => {{
+        #[cfg(feature = "log")]
+        log::$level!(target: "soundkit_flac::encoder", $($arg)+);
+        #[cfg(not(feature = "log"))]
+        if false {
+            let _ = format_args!($($arg)+);
+        }
+    }};
+}
+
+/// Default batch size, in samples per channel, for `encode_from_iter`.
+const ITER_CHUNK_SAMPLES: usize = 4096;
+
+/// Samples per channel handed to libFLAC per call when a whole buffer is
+/// encoded at once, as by `encode_and_verify` and `two_pass_size_estimate`.
+const CHUNK_SAMPLES: usize = 4096;
+
+/// Most samples per channel passed to one libFLAC process call; larger inputs
+/// are split rather than letting the `u32` count wrap.
+const MAX_PROCESS_FRAMES: usize = u32::MAX as usize;
+
+/// Where the encoder is in its stream lifecycle:
+/// `new → init → encode* → finish → [reset → encode* → finish ...]`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum StreamState {
+    Uninitialized,
+    Encoding,
+    Finished,
+}
+
+/// Streaming FLAC encoder over libFLAC.
+///
+/// # Metadata ordering
+///
+/// libFLAC only accepts metadata before a stream is initialised, so tags,
+/// pictures, seek table placeholders and padding must be added before
+/// `init()`, or after `finish()` to take effect at the next `reset()`.
+/// Adding them while a stream is in progress fails with
+/// [`FlacError::AlreadyInitialized`] rather than being silently ignored.
+///
+/// # Dropping
+///
+/// Dropping an encoder mid-stream finishes the stream first. With a
+/// [`seekable sink`](Self::set_seekable_sink) the final frame and the header
+/// rewrite go to the sink, which is then flushed, so nothing is lost.
+/// Otherwise the output is only held by the encoder and the final frame,
+/// along with anything not yet taken, is discarded: call `finish()` and
+/// collect what it returns before dropping. Debug builds log a warning when
+/// that happens.
 pub struct FlacEncoder {
+    /// Created and configured by `init()` (and each later reset) from
+    /// `config`; null until then, so there is never a libFLAC encoder with
+    /// settings other than the ones in `config`.
     encoder: *mut ffi::FLAC__StreamEncoder,
-    sample_rate: u32,
-    channels: u32,
-    bits_per_sample: u32,
-    buffer: Rc<RefCell<Vec<u8>>>,
-    frame_length: u32,
-    compression_level: u32,
+    config: EncoderConfig,
+    /// Boxed so its address, which libFLAC holds as client data, stays put
+    /// when the encoder moves. It outlives the libFLAC encoder because
+    /// `Drop` deletes that first.
+    write_state: Box<RefCell<WriteState>>,
+    state: StreamState,
+    metadata: MetadataConfig,
+    /// Blocks handed to libFLAC; they must outlive the stream using them.
+    /// Must be dropped after `encoder` is deleted; see [`metadata::Blocks`].
+    metadata_blocks: metadata::Blocks,
+    process_chunk_samples: u32,
+    /// Reused by `encode_from_iter`; holds any trailing partial frame between
+    /// calls.
+    scratch: Vec<i32>,
+    seek_index: Vec<SeekPoint>,
+    /// Reused by `encode_channels` to interleave planar input; can be
+    /// swapped with `set_scratch_buffer`.
+    interleave_scratch: Vec<i32>,
+    /// One entry per channel with `channel_stats` enabled, otherwise empty.
+    levels: Vec<ChannelLevels>,
+    /// Set with `generate_peaks`.
+    peaks: Option<Peaks>,
+    /// The stream was started with `init_file`, so libFLAC writes it itself.
+    writing_file: bool,
+    /// What the current stream was initialised with; see `applied_config`.
+    applied: AppliedConfig,
+    /// Encode speed in the current stream, for `estimated_time_remaining`.
+    throughput: Throughput,
+    #[cfg(feature = "timing")]
+    timing: TimingStats,
+    /// Float samples `encode_f32` clamped in the current stream.
+    clip_count: u64,
+    on_clip: Option<ClipCallback>,
 }
 
-extern "C" fn write_callback(
-    _encoder: *const ffi::FLAC__StreamEncoder,
-    buffer: *const ffi::FLAC__byte,
-    bytes: usize,
-    _samples: u32,
-    _current_frame: u32,
-    client_data: *mut libc::c_void,
-) -> ffi::FLAC__StreamEncoderWriteStatus {
-    unsafe {
-        let output = &mut *(client_data as *mut RefCell<Vec<u8>>);
-        let slice = std::slice::from_raw_parts(buffer, bytes);
-        output.borrow_mut().extend_from_slice(slice);
-    }
-    ffi::FLAC__STREAM_ENCODER_WRITE_STATUS_OK
+/// Details of one encoded frame, as reported by libFLAC's write callback.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameInfo {
+    pub frame_number: u32,
+    /// Samples per channel in the frame.
+    pub samples: u32,
+    /// Encoded size of the frame in bytes.
+    pub bytes: usize,
 }
 
-impl Encoder for FlacEncoder {
-    fn new(
-        sample_rate: u32,
-        bits_per_sample: u32,
-        channels: u32,
-        frame_length: u32,
-        compression_level: u32,
-    ) -> Self {
-        let buffer = Rc::new(RefCell::new(Vec::new()));
+/// Where a frame starts in the encoded output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SeekPoint {
+    /// Index, in samples per channel, of the frame's first sample.
+    pub sample_number: u64,
+    /// Byte offset of the frame from the start of the stream, i.e. counting
+    /// the `fLaC` marker and metadata. (SEEKTABLE offsets are relative to the
+    /// first frame instead.)
+    pub byte_offset: u64,
+    /// Samples per channel in the frame.
+    pub frame_samples: u32,
+}
 
-        let encoder = unsafe {
-            let encoder = ffi::FLAC__stream_encoder_new();
-            encoder
-        };
+/// A stretch of input [`FlacDecoder`] passed over after losing sync, e.g.
+/// where a capture dropped or corrupted bytes.
+///
+/// Offsets count from the start of the input fed since `init()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyncLossEvent {
+    /// Where the damage starts: the end of the last frame decoded before it.
+    /// Before the first frame, that is the end of STREAMINFO or of the last
+    /// metadata block passed to a handler.
+    pub byte_offset: u64,
+    /// Where the frame the decoder locked onto again starts.
+    pub resync_offset: u64,
+    /// Samples per channel between the two frames, from their sample
+    /// numbers: the audio lost along with the bytes.
+    pub lost_samples: u64,
+}
 
-        FlacEncoder {
-            encoder,
-            sample_rate,
-            channels,
-            bits_per_sample,
-            buffer,
-            frame_length,
-            compression_level,
+/// The decoder's progress through its input, for [`SyncLossEvent`]s.
+#[derive(Debug, Default)]
+struct SyncState {
+    /// Input offset just past the last frame or metadata block decoded.
+    frame_end: u64,
+    /// The sample number the next frame should start at.
+    next_sample: u64,
+    /// While sync is lost, where the loss started and where the frame to
+    /// resynchronise on can start at the earliest.
+    lost_at: Option<u64>,
+    search_from: u64,
+    events: Vec<SyncLossEvent>,
+}
+
+type FrameCallback = Box<dyn FnMut(FrameInfo) + Send>;
+type ClipCallback = Box<dyn FnMut(u64) + Send>;
+
+/// An output libFLAC can seek back into, such as a `File` or
+/// `Cursor<Vec<u8>>`. See [`FlacEncoder::set_seekable_sink`].
+pub trait WriteSeek: Write + Seek + Send {}
+
+impl<T: Write + Seek + Send> WriteSeek for T {}
+
+/// State shared with the write callback through libFLAC's client data.
+struct WriteState {
+    buffer: Vec<u8>,
+    /// How much of `buffer` has been handed out. Only nonzero with
+    /// `accumulate` set; otherwise handed-out bytes are removed.
+    drained: usize,
+    /// Keep the whole stream in `buffer`; see
+    /// [`EncoderConfig::accumulate_output`].
+    accumulate: bool,
+    on_frame: Option<FrameCallback>,
+    /// Set when a user callback panics; reported by the next encoder call.
+    callback_error: Option<FlacError>,
+    /// Bytes and samples per channel written so far in the current stream.
+    stream_bytes: u64,
+    stream_samples: u64,
+    /// Bytes of audio frames only, leaving out the header and metadata.
+    frame_bytes: u64,
+    /// Smallest and largest frame written, in bytes.
+    frame_size_range: Option<(u32, u32)>,
+    /// Frames written since `FlacEncoder::seek_index` last collected them.
+    seek_points: Vec<SeekPoint>,
+    /// When set, encoded bytes go here instead of `buffer`.
+    sink: Option<Box<dyn WriteSeek>>,
+    /// The STREAMINFO libFLAC reports once the stream is finished.
+    final_stream_info: Option<StreamInfo>,
+    /// The stream's bit depth and the wasted bits seen so far, with
+    /// `wasted_bits_stats` set and the native container.
+    wasted_bits: Option<(u32, WastedBitsStat)>,
+    /// Swapped into the VORBIS_COMMENT block as it is written.
+    vendor: Option<String>,
+    /// Whether the stream is in the native container, where each write
+    /// with samples is one whole frame. Ogg writes are pages, which can
+    /// start mid-frame or hold several frames.
+    native: bool,
+}
+
+impl WriteState {
+    fn new() -> Self {
+        WriteState {
+            buffer: Vec::new(),
+            drained: 0,
+            accumulate: false,
+            on_frame: None,
+            callback_error: None,
+            stream_bytes: 0,
+            stream_samples: 0,
+            frame_bytes: 0,
+            frame_size_range: None,
+            seek_points: Vec::new(),
+            sink: None,
+            final_stream_info: None,
+            wasted_bits: None,
+            vendor: None,
+            native: true,
         }
     }
 
-    fn init(&mut self) -> Result<(), String> {
-        return self.reset();
+    /// Forgets everything about the previous stream.
+    fn clear(&mut self) {
+        self.buffer.clear();
+        self.drained = 0;
+        self.callback_error = None;
+        self.stream_bytes = 0;
+        self.stream_samples = 0;
+        self.frame_bytes = 0;
+        self.frame_size_range = None;
+        self.seek_points.clear();
+        self.final_stream_info = None;
     }
 
-    fn encode_i16(&mut self, input: &[i16], output: &mut [u8]) -> Result<usize, String> {
-        Err("Not implemented.".to_string())
+    /// Encoded bytes not yet handed out.
+    fn pending(&self) -> &[u8] {
+        &self.buffer[self.drained..]
     }
 
-    fn encode_i32(&mut self, input: &[i32], output: &mut [u8]) -> Result<usize, String> {
-        self.buffer.borrow_mut().clear(); // Clear previous encoded data
+    /// Marks the first `len` pending bytes as handed out.
+    fn consume_front(&mut self, len: usize) {
+        if self.accumulate {
+            self.drained += len;
+        } else {
+            self.buffer.drain(..len);
+        }
+    }
 
-        unsafe {
-            let success = ffi::FLAC__stream_encoder_process_interleaved(
-                self.encoder,
-                input.as_ptr() as *const libflac_sys::FLAC__int32,
-                (input.len() / self.channels as usize) as u32,
-            );
+    /// Marks the pending bytes as handed out.
+    fn consume(&mut self) {
+        if self.accumulate {
+            self.drained = self.buffer.len();
+        } else {
+            self.buffer.clear();
+        }
+    }
 
-            if success == 0 {
-                let state = ffi::FLAC__stream_encoder_get_state(self.encoder);
-                return Err(format!(
-                    "Failed to process samples, encoder state: {:?}",
-                    state
-                ));
-            }
+    /// Undoes `consume` for `bytes`, the last bytes handed out, which are
+    /// put back in front of any pending since.
+    fn unconsume(&mut self, bytes: &[u8]) {
+        if self.accumulate {
+            self.drained -= bytes.len();
+        } else {
+            self.buffer.splice(0..0, bytes.iter().copied());
         }
+    }
+}
 
-        let encoded_data = self.buffer.borrow();
-        let encoded_len = encoded_data.len();
+/// Runs a user callback, converting a panic into an error so it never unwinds
+/// across the libFLAC stack frames above us.
+fn call_user_callback<F: FnOnce()>(callback: F) -> Result<(), FlacError> {
+    panic::catch_unwind(AssertUnwindSafe(callback)).map_err(|payload| {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|s| s.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        FlacError::CallbackPanicked(message)
+    })
+}
 
-        if output.len() < encoded_len {
-            return Err(format!(
-                "Output buffer of len {} too small for encoded data of len {}; input len was {}",
-                output.len(),
-                encoded_len,
-                input.len(),
+/// Copies a static C string owned by libFLAC.
+fn lossy_c_str(ptr: *const libc::c_char) -> String {
+    if ptr.is_null() {
+        return "unknown".to_string();
+    }
+    unsafe { std::ffi::CStr::from_ptr(ptr) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Looks up the name of a status code in one of libFLAC's `*String` tables,
+/// e.g. "FLAC__STREAM_ENCODER_INIT_STATUS_INVALID_SAMPLE_RATE".
+fn status_string(table: &'static [*const libc::c_char; 0], status: u32) -> String {
+    lossy_c_str(unsafe { *table.as_ptr().add(status as usize) })
+}
+
+/// The encoder state as text. For verify errors this is the verify decoder's
+/// state rather than the generic "FLAC__STREAM_ENCODER_VERIFY_DECODER_ERROR".
+fn encoder_state_string(encoder: *const ffi::FLAC__StreamEncoder) -> String {
+    lossy_c_str(unsafe { ffi::FLAC__stream_encoder_get_resolved_state_string(encoder) })
+}
+
+fn decoder_state_string(decoder: *const ffi::FLAC__StreamDecoder) -> String {
+    lossy_c_str(unsafe { ffi::FLAC__stream_decoder_get_resolved_state_string(decoder) })
+}
+
+/// Samples inspected per call by the `check_scaling` debug check.
+#[cfg(debug_assertions)]
+const SCALING_CHECK_SAMPLES: usize = 4096;
+
+/// Looks for signs that `input` isn't in the native range of `bits`-bit PCM,
+/// typically samples left-justified in the `i32` instead of right-aligned.
+/// Only the start of the input is inspected.
+#[cfg(debug_assertions)]
+fn scaling_problem(input: &[i32], bits: u32) -> Option<String> {
+    let input = &input[..input.len().min(SCALING_CHECK_SAMPLES)];
+
+    if bits < 32 {
+        let max = (1i64 << (bits - 1)) - 1;
+        let min = -(1i64 << (bits - 1));
+        if let Some(&sample) = input
+            .iter()
+            .find(|&&x| (x as i64) < min || (x as i64) > max)
+        {
+            return Some(format!(
+                "sample {} is out of range for {}-bit audio; samples may be left-justified",
+                sample, bits
             ));
         }
+    }
 
-        output[..encoded_len].copy_from_slice(&encoded_data);
-        Ok(encoded_len)
+    // Real audio exercises the low bits. If they never change, the samples
+    // were most likely shifted up, e.g. 16-bit audio in a 24-bit stream.
+    let nonzero = input.iter().filter(|&&x| x != 0).count();
+    let zero_bits = input.iter().fold(0i32, |acc, &x| acc | x).trailing_zeros();
+    if bits >= 16 && nonzero >= 64 && zero_bits >= 8 {
+        return Some(format!(
+            "the low {} bits of every sample are zero; samples may be scaled up",
+            zero_bits
+        ));
     }
+    None
+}
 
-    fn reset(&mut self) -> Result<(), String> {
-        unsafe {
-            ffi::FLAC__stream_encoder_finish(self.encoder);
-            ffi::FLAC__stream_encoder_delete(self.encoder);
-
-            self.encoder = ffi::FLAC__stream_encoder_new();
-            ffi::FLAC__stream_encoder_set_blocksize(self.encoder, self.frame_length);
-            ffi::FLAC__stream_encoder_set_verify(self.encoder, true as i32);
-            ffi::FLAC__stream_encoder_set_compression_level(self.encoder, self.compression_level);
-            ffi::FLAC__stream_encoder_set_channels(self.encoder, self.channels);
-
-            ffi::FLAC__stream_encoder_set_bits_per_sample(self.encoder, self.bits_per_sample);
-            ffi::FLAC__stream_encoder_set_sample_rate(self.encoder, self.sample_rate);
-
-            let status = ffi::FLAC__stream_encoder_init_stream(
-                self.encoder,
-                Some(write_callback),
-                None, // seek callback
-                None, // tell callback
-                None,
-                Rc::into_raw(self.buffer.clone()) as *mut libc::c_void,
-            );
+extern "C" fn write_callback(
+    _encoder: *const ffi::FLAC__StreamEncoder,
+    buffer: *const ffi::FLAC__byte,
+    bytes: usize,
+    samples: u32,
+    current_frame: u32,
+    client_data: *mut libc::c_void,
+) -> ffi::FLAC__StreamEncoderWriteStatus {
+    unsafe {
+        let output = &*(client_data as *const RefCell<WriteState>);
+        let mut slice = std::slice::from_raw_parts(buffer, bytes);
+        let mut output = output.borrow_mut();
+        let output = &mut *output;
+        let rewritten;
+        if let (0, Some(vendor)) = (samples, output.vendor.as_deref()) {
+            match metadata::replace_vendor(slice, vendor) {
+                Some(Ok(block)) => {
+                    rewritten = block;
+                    slice = &rewritten;
+                }
+                Some(Err(e)) => {
+                    output.callback_error = Some(e);
+                    return ffi::FLAC__STREAM_ENCODER_WRITE_STATUS_FATAL_ERROR;
+                }
+                None => {}
+            }
+        }
+        match output.sink.as_mut() {
+            Some(sink) => {
+                if let Err(e) = sink.write_all(slice) {
+                    output.callback_error = Some(e.into());
+                    return ffi::FLAC__STREAM_ENCODER_WRITE_STATUS_FATAL_ERROR;
+                }
+            }
+            None => output.buffer.extend_from_slice(slice),
+        }
+        let start = output.stream_bytes;
+        output.stream_bytes += slice.len() as u64;
 
-            if status != ffi::FLAC__STREAM_ENCODER_INIT_STATUS_OK {
-                let state: u32 = ffi::FLAC__stream_encoder_get_state(self.encoder);
-                return Err(format!(
-                    "Failed to reset encoder, encoder state: {:?}",
-                    state
+        // metadata writes report zero samples; only frames are of interest
+        if samples > 0 && output.native {
+            // the low bit after the sync code is the blocking strategy
+            if slice.get(1).is_some_and(|byte| byte & 1 != 0) {
+                output.callback_error = Some(FlacError::Encoder(
+                    "libFLAC wrote a variable-blocksize frame".to_string(),
                 ));
+                return ffi::FLAC__STREAM_ENCODER_WRITE_STATUS_FATAL_ERROR;
+            }
+            output.seek_points.push(SeekPoint {
+                sample_number: output.stream_samples,
+                byte_offset: start,
+                frame_samples: samples,
+            });
+            let size = bytes as u32;
+            output.frame_size_range = Some(match output.frame_size_range {
+                Some((min, max)) => (min.min(size), max.max(size)),
+                None => (size, size),
+            });
+        }
+        if samples > 0 {
+            output.stream_samples += samples as u64;
+            output.frame_bytes += bytes as u64;
+            if let Some((bits, stat)) = output.wasted_bits.as_mut() {
+                match frame::subframe_wasted_bits(slice, *bits) {
+                    Some(wasted) => stat.add(&wasted),
+                    None => {
+                        // only a statistic, so it goes rather than the stream
+                        warn!(
+                            "Couldn't parse frame {} libFLAC wrote; no wasted bits stats for this stream",
+                            current_frame
+                        );
+                        output.wasted_bits = None;
+                    }
+                }
+            }
+            if let Some(on_frame) = output.on_frame.as_mut() {
+                let info = FrameInfo {
+                    frame_number: current_frame,
+                    samples,
+                    bytes,
+                };
+                if let Err(e) = call_user_callback(|| on_frame(info)) {
+                    output.callback_error = Some(e);
+                    return ffi::FLAC__STREAM_ENCODER_WRITE_STATUS_FATAL_ERROR;
+                }
             }
         }
+    }
+    ffi::FLAC__STREAM_ENCODER_WRITE_STATUS_OK
+}
 
-        Ok(())
+/// Called by libFLAC when the stream is finished, with STREAMINFO as it
+/// should read now that the totals, MD5 and frame sizes are known.
+unsafe extern "C" fn metadata_callback(
+    _encoder: *const ffi::FLAC__StreamEncoder,
+    metadata: *const ffi::FLAC__StreamMetadata,
+    client_data: *mut libc::c_void,
+) {
+    if (*metadata).type_ == ffi::FLAC__METADATA_TYPE_STREAMINFO {
+        let output = &*(client_data as *const RefCell<WriteState>);
+        output.borrow_mut().final_stream_info = Some(metadata::stream_info_from_ffi(
+            &(*metadata).data.stream_info,
+        ));
     }
 }
 
-impl Drop for FlacEncoder {
-    fn drop(&mut self) {
-        unsafe {
-            ffi::FLAC__stream_encoder_finish(self.encoder);
-            ffi::FLAC__stream_encoder_delete(self.encoder);
-        }
+#[cfg(feature = "container-native")]
+unsafe extern "C" fn seek_callback(
+    _encoder: *const ffi::FLAC__StreamEncoder,
+    absolute_byte_offset: u64,
+    client_data: *mut libc::c_void,
+) -> ffi::FLAC__StreamEncoderSeekStatus {
+    let output = &*(client_data as *const RefCell<WriteState>);
+    match output.borrow_mut().sink.as_mut() {
+        Some(sink) => match sink.seek(std::io::SeekFrom::Start(absolute_byte_offset)) {
+            Ok(_) => ffi::FLAC__STREAM_ENCODER_SEEK_STATUS_OK,
+            Err(_) => ffi::FLAC__STREAM_ENCODER_SEEK_STATUS_ERROR,
+        },
+        None => ffi::FLAC__STREAM_ENCODER_SEEK_STATUS_UNSUPPORTED,
     }
 }
 
-pub struct FlacDecoder {
-    decoder: *mut ffi::FLAC__StreamDecoder,
-    output_buffer: Vec<i32>,
-    input_buffer: Vec<u8>,
-    input_position: usize,
+#[cfg(feature = "container-native")]
+unsafe extern "C" fn tell_callback(
+    _encoder: *const ffi::FLAC__StreamEncoder,
+    absolute_byte_offset: *mut u64,
+    client_data: *mut libc::c_void,
+) -> ffi::FLAC__StreamEncoderTellStatus {
+    let output = &*(client_data as *const RefCell<WriteState>);
+    match output.borrow_mut().sink.as_mut() {
+        Some(sink) => match sink.stream_position() {
+            Ok(position) => {
+                *absolute_byte_offset = position;
+                ffi::FLAC__STREAM_ENCODER_TELL_STATUS_OK
+            }
+            Err(_) => ffi::FLAC__STREAM_ENCODER_TELL_STATUS_ERROR,
+        },
+        None => ffi::FLAC__STREAM_ENCODER_TELL_STATUS_UNSUPPORTED,
+    }
 }
 
-impl FlacDecoder {
-    pub fn new() -> Self {
-        let decoder = unsafe { ffi::FLAC__stream_decoder_new() };
-        FlacDecoder {
-            decoder,
-            output_buffer: Vec::new(),
-            input_buffer: Vec::new(),
-            input_position: 0,
+impl FlacEncoder {
+    /// Creates an encoder from `config`; call `init()` before encoding. No
+    /// libFLAC encoder is allocated until then.
+    pub fn from_config(config: &EncoderConfig) -> Self {
+        FlacEncoder {
+            encoder: std::ptr::null_mut(),
+            config: config.clone(),
+            write_state: Box::new(RefCell::new(WriteState::new())),
+            state: StreamState::Uninitialized,
+            metadata: MetadataConfig::default(),
+            metadata_blocks: metadata::Blocks::default(),
+            process_chunk_samples: 0,
+            scratch: Vec::new(),
+            seek_index: Vec::new(),
+            interleave_scratch: Vec::new(),
+            levels: Vec::new(),
+            peaks: None,
+            writing_file: false,
+            applied: config.applied(),
+            throughput: Throughput::default(),
+            #[cfg(feature = "timing")]
+            timing: TimingStats::default(),
+            clip_count: 0,
+            on_clip: None,
         }
     }
 
-    pub fn init(&mut self) -> Result<(), String> {
-        unsafe {
-            ffi::FLAC__stream_decoder_set_metadata_ignore_all(self.decoder);
-            let decoder_status = ffi::FLAC__stream_decoder_init_stream(
-                self.decoder,
-                Some(read_callback_decode),
-                None,
-                None,
-                None,
-                None,
-                Some(write_callback_decode),
-                None,
-                Some(error_callback_decode),
-                self as *mut _ as *mut libc::c_void,
-            );
+    /// The formats FLAC encoding accepts with the linked libFLAC, without
+    /// creating an encoder. [`EncoderConfig::check_support`] checks a whole
+    /// config the same way.
+    pub fn capabilities() -> CodecCapabilities {
+        CodecCapabilities::of_linked_libflac()
+    }
 
-            match decoder_status {
-                ffi::FLAC__STREAM_DECODER_INIT_STATUS_OK => Ok(()),
-                _ => Err(format!(
-                    "Failed to initialize the decoder. Status: {:?}",
-                    decoder_status
-                )),
-            }
-        }
+    /// The settings the next stream will be initialised with.
+    pub fn config(&self) -> &EncoderConfig {
+        &self.config
     }
-}
-impl Decoder for FlacDecoder {
-    fn decode_i16(
-        &mut self,
-        input: &[u8],
-        output: &mut [i16],
-        _fec: bool,
-    ) -> Result<usize, String> {
-        Err("not implemented.".to_string())
+
+    /// The settings libFLAC was given for the current stream, after any
+    /// [`auto_subset`](EncoderConfig::auto_subset) adjustments. Before the
+    /// first `init()` it shows what `init()` would apply.
+    pub fn applied_config(&self) -> &AppliedConfig {
+        &self.applied
     }
 
-    fn decode_i32(
-        &mut self,
-        input: &[u8],
-        output: &mut [i32],
-        _fec: bool,
-    ) -> Result<usize, String> {
-        // Reset internal buffers
-        self.output_buffer.clear();
-        self.input_buffer.clear();
-        self.input_position = 0;
+    /// Peak and RMS level of each channel over all samples encoded in the
+    /// current stream, in channel order. They stay available after
+    /// `finish()` until the next stream starts. Empty unless
+    /// [`EncoderConfig::channel_stats`] is set.
+    pub fn channel_stats(&self) -> Vec<ChannelStat> {
+        self.levels
+            .iter()
+            .map(|level| level.stat(self.config.bits_per_sample))
+            .collect()
+    }
 
-        // Copy input data to internal buffer
-        self.input_buffer.extend_from_slice(input);
+    /// The lowest and highest sample of each time bin encoded so far in the
+    /// current stream, across all channels, for drawing a waveform. Like
+    /// `channel_stats` it stays available after `finish()` until the next
+    /// stream starts. Empty unless [`EncoderConfig::generate_peaks`] is set;
+    /// it describes how the samples are binned.
+    pub fn peaks(&self) -> Vec<(i32, i32)> {
+        self.peaks
+            .as_ref()
+            .map_or_else(Vec::new, |peaks| peaks.bins.clone())
+    }
 
-        unsafe {
-            // Process the entire input
-            let result = ffi::FLAC__stream_decoder_process_single(self.decoder);
-            if result == 0 {
-                let state = ffi::FLAC__stream_decoder_get_state(self.decoder);
-                return Err(format!(
-                    "Failed to decode FLAC block, decoder state: {:?}",
-                    state
-                ));
-            }
-        }
+    /// How many subframes of the current stream had wasted bits removed, for
+    /// telling full-resolution input from audio padded to a higher bit
+    /// depth. Like `channel_stats` it stays available after `finish()`
+    /// until the next stream starts. `None` unless
+    /// [`EncoderConfig::wasted_bits_stats`] is set and the container is
+    /// native: Ogg pages would have to be unwrapped to find the frames. Also
+    /// `None` for the rest of the stream if a frame couldn't be parsed; the
+    /// encode itself carries on.
+    pub fn wasted_bits_stats(&self) -> Option<WastedBitsStat> {
+        self.write_state.borrow().wasted_bits.map(|(_, stat)| stat)
+    }
 
-        // Copy decoded samples to the output buffer
-        let decoded_len = self.output_buffer.len();
-        if output.len() < decoded_len {
-            return Err("Output buffer too small".to_string());
-        }
+    /// Time spent in libFLAC's process calls in the current stream, e.g. to
+    /// spot inputs that encode pathologically slowly in production, such
+    /// as noise at level 8 with an exhaustive model search. Every call is
+    /// counted, including the ones an encode method makes when it splits its
+    /// input into chunks. It stays available after `finish()` until the
+    /// next stream starts; the final flush in `finish()` isn't included.
+    ///
+    /// Requires the `timing` feature, so builds without it make no clock
+    /// calls for this.
+    #[cfg(feature = "timing")]
+    pub fn encode_timing_stats(&self) -> TimingStats {
+        self.timing
+    }
 
-        output[..decoded_len].copy_from_slice(&self.output_buffer);
-        Ok(decoded_len)
+    /// Bit rate of the uncompressed PCM, in bits per second: the most a
+    /// FLAC stream with these settings should need.
+    pub fn max_bitrate(&self) -> u32 {
+        let config = &self.config;
+        let rate =
+            config.sample_rate as u64 * config.bits_per_sample as u64 * config.channels as u64;
+        rate.min(u32::MAX as u64) as u32
     }
-}
 
-impl Drop for FlacDecoder {
-    fn drop(&mut self) {
-        unsafe {
-            ffi::FLAC__stream_decoder_finish(self.decoder);
-            ffi::FLAC__stream_decoder_delete(self.decoder);
+    /// Average bit rate of the audio frames written so far in the current
+    /// stream, in bits per second, or `None` before the first frame. The
+    /// header and metadata blocks are not counted.
+    pub fn achieved_bitrate(&self) -> Option<u32> {
+        let write_state = self.write_state.borrow();
+        if write_state.stream_samples == 0 {
+            return None;
         }
+        let bits = write_state.frame_bytes as u128 * 8 * self.config.sample_rate as u128;
+        let rate = bits / write_state.stream_samples as u128;
+        Some(rate.min(u32::MAX as u128) as u32)
     }
-}
 
-unsafe extern "C" fn read_callback_decode(
-    _decoder: *const ffi::FLAC__StreamDecoder,
-    buffer: *mut ffi::FLAC__byte,
-    bytes: *mut usize,
-    client_data: *mut std::ffi::c_void,
-) -> ffi::FLAC__StreamDecoderReadStatus {
-    let decoder = &mut *(client_data as *mut FlacDecoder);
-    let remaining = decoder.input_buffer.len() - decoder.input_position;
-    let to_read = std::cmp::min(*bytes, remaining);
+    /// Playing time of the stream from the configured
+    /// [`total_samples`](EncoderConfig::total_samples), or `None` when that
+    /// isn't set.
+    pub fn duration(&self) -> Option<Duration> {
+        header::duration_of(self.config.total_samples?, self.config.sample_rate)
+    }
 
-    if to_read == 0 {
-        *bytes = 0;
-        return ffi::FLAC__STREAM_DECODER_READ_STATUS_END_OF_STREAM;
+    /// How much longer the current stream will take to encode, from the
+    /// configured [`total_samples`](EncoderConfig::total_samples) and the
+    /// speed of the encode calls so far.
+    ///
+    /// The speed is a moving average over windows of about 100 ms of
+    /// encoding time, weighted towards recent windows, so it follows gradual
+    /// changes (e.g. a busier machine) within a second or two and one slow
+    /// call barely moves it. Only time spent inside the encode calls counts:
+    /// time the caller spends reading or producing input is not included, so
+    /// when that dominates the real time left is longer. `None` until the
+    /// first window has been timed, and when `total_samples` isn't set.
+    pub fn estimated_time_remaining(&self) -> Option<Duration> {
+        self.throughput.remaining(self.config.total_samples?)
     }
 
-    let src = decoder.input_buffer[decoder.input_position..].as_ptr();
-    std::ptr::copy_nonoverlapping(src, buffer, to_read);
+    /// Classifies the configured sample rate; see [`SampleRateClass`].
+    pub fn sample_rate_class(&self) -> SampleRateClass {
+        SampleRateClass::of(self.config.sample_rate)
+    }
 
-    decoder.input_position += to_read;
-    *bytes = to_read;
+    /// Whether the encoder writes to a sink libFLAC can seek back into.
+    ///
+    /// Without one the stream is initialised without seek/tell callbacks, so
+    /// libFLAC can't back-patch STREAMINFO when the encoder finishes: total
+    /// samples, MD5 and min/max frame sizes stay as they were when the header
+    /// was first written, and seek points can't be filled in.
+    pub fn is_seekable_output(&self) -> bool {
+        self.write_state.borrow().sink.is_some()
+    }
 
-    ffi::FLAC__STREAM_DECODER_READ_STATUS_CONTINUE
-}
+    /// Writes encoded streams to `sink` instead of returning the bytes.
+    ///
+    /// libFLAC then gets seek and tell callbacks, and on `finish()` it goes
+    /// back to fill in STREAMINFO and the offsets of any seek points added
+    /// with [`add_seek_points`](Self::add_seek_points). The encode methods
+    /// return 0 bytes while a sink is set. The sink is kept across `reset()`,
+    /// so the next stream is written after the current one; use
+    /// [`take_sink`](Self::take_sink) to get it back. Like metadata, the sink
+    /// can only be changed between streams.
+    pub fn set_seekable_sink<W: Write + Seek + Send + 'static>(
+        &mut self,
+        sink: W,
+    ) -> Result<(), FlacError> {
+        self.ensure_configurable()?;
+        self.write_state.borrow_mut().sink = Some(Box::new(sink));
+        Ok(())
+    }
 
-unsafe extern "C" fn write_callback_decode(
-    _decoder: *const FLAC__StreamDecoder,
-    frame: *const FLAC__Frame,
-    buffer: *const *const FLAC__int32,
-    client_data: *mut std::ffi::c_void,
-) -> FLAC__StreamDecoderWriteStatus {
-    let decoder = &mut *(client_data as *mut FlacDecoder);
+    /// Removes the sink set by [`set_seekable_sink`](Self::set_seekable_sink)
+    /// so later streams are returned as bytes again.
+    pub fn take_sink(&mut self) -> Result<Option<Box<dyn WriteSeek>>, FlacError> {
+        self.ensure_configurable()?;
+        Ok(self.write_state.borrow_mut().sink.take())
+    }
 
-    let channels = (*frame).header.channels as usize;
-    let blocksize = (*frame).header.blocksize as usize;
+    /// Pins the LPC order search to `min..=max`; see
+    /// [`EncoderConfig::lpc_order`]. Takes effect at the next `init()` or
+    /// `reset()`.
+    pub fn set_lpc_order(&mut self, min: u32, max: u32) -> Result<(), FlacError> {
+        self.ensure_configurable()?;
+        let config = self.config.clone().lpc_order(min, max);
+        config.validate()?;
+        self.config = config;
+        Ok(())
+    }
 
-    let buffer = slice::from_raw_parts(buffer, channels);
-    let buffer = buffer
-        .iter()
-        .map(|x| slice::from_raw_parts(*x, blocksize))
-        .collect::<Vec<&[i32]>>();
+    /// The maximum LPC order libFLAC is using, whether it came from the
+    /// compression preset or [`set_lpc_order`](Self::set_lpc_order). Before
+    /// the first `init()` it is the order `init()` would apply.
+    pub fn max_lpc_order(&self) -> u32 {
+        match self.encoder.is_null() {
+            true => self.applied.config.effective_max_lpc_order(),
+            false => unsafe { ffi::FLAC__stream_encoder_get_max_lpc_order(self.encoder) },
+        }
+    }
 
-    for i in 0..blocksize {
-        for j in 0..channels {
-            decoder.output_buffer.push(buffer[j][i]);
+    /// The most samples per channel that can be waiting inside libFLAC
+    /// before their frame is written: the block size, resolved the way
+    /// libFLAC resolves a `block_size` of 0. Before the first `init()` it is
+    /// the size `init()` would use.
+    ///
+    /// This is a hard floor on latency. FLAC frames hold one whole block, so
+    /// a sample can only be output once its block is full, and libFLAC also
+    /// waits for the first sample of the next block before encoding it.
+    /// Nothing short of `finish()` writes a partial block; for lower latency
+    /// use a smaller block size, at some cost in compression.
+    pub fn max_latency_samples(&self) -> u32 {
+        match self.encoder.is_null() {
+            true => self.applied.config.effective_block_size(),
+            false => unsafe { ffi::FLAC__stream_encoder_get_blocksize(self.encoder) },
         }
     }
 
-    FLAC__STREAM_DECODER_WRITE_STATUS_CONTINUE
-}
+    /// Copies out every frame libFLAC has finished so far, returning the
+    /// bytes written. Samples of a block still being filled stay buffered:
+    /// FLAC can't emit a partial block, so at most
+    /// [`max_latency_samples`](Self::max_latency_samples) samples per channel
+    /// are held back. The same as encoding empty input.
+    pub fn flush_now(&mut self, output: &mut [u8]) -> Result<usize, FlacError> {
+        self.encode_interleaved(&[], output)
+    }
 
-unsafe extern "C" fn error_callback_decode(
-    _decoder: *const ffi::FLAC__StreamDecoder,
-    status: ffi::FLAC__StreamDecoderErrorStatus,
-    _client_data: *mut std::ffi::c_void,
+    /// A size for the `output` of an encode call with `samples_per_channel`
+    /// samples of input per channel, or of `finish()` with 0, that is never
+    /// too small.
+    ///
+    /// Such a call completes at most one frame more than the input fills,
+    /// since up to a block of earlier samples can be waiting inside libFLAC,
+    /// and each frame is at most [`max_frame_size`] bytes. Bytes already
+    /// pending are added, which covers the header straight after `init()`.
+    /// Ogg streams also get room for the page headers around each frame.
+    pub fn max_output_len(&self, samples_per_channel: usize) -> usize {
+        let config = &self.config;
+        let block_size = self.max_latency_samples();
+        let mut frame = max_frame_size(block_size, config.channels, config.bits_per_sample);
+        if !config.container.is_native() {
+            // a page header with its full 255-entry lacing table, a page
+            // per 4 KiB of frame plus one either side
+            frame += (27 + 255) * (frame / 4096 + 2);
+        }
+        let frames = samples_per_channel / block_size as usize + 1;
+        self.write_state.borrow().pending().len() + frames * frame
+    }
+
+    /// Adds a Vorbis comment, e.g. `add_tag("ARTIST", "...")`.
+    pub fn add_tag(&mut self, name: &str, value: &str) -> Result<(), FlacError> {
+        self.ensure_configurable()?;
+        self.metadata
+            .tags
+            .push((name.to_string(), value.to_string()));
+        Ok(())
+    }
+
+    /// Embeds a picture such as cover art.
+    pub fn add_picture(&mut self, picture: Picture) -> Result<(), FlacError> {
+        self.ensure_configurable()?;
+        self.metadata.pictures.push(picture);
+        Ok(())
+    }
+
+    /// Adds an APPLICATION block holding `data` for the application `id`.
+    /// Each call adds its own block; they are written together.
+    pub fn add_application(&mut self, id: ApplicationId, data: Vec<u8>) -> Result<(), FlacError> {
+        self.ensure_configurable()?;
+        self.metadata.applications.push((id, data));
+        Ok(())
+    }
+
+    /// Reserves a seek table with `count` placeholder points.
+    pub fn add_seek_placeholders(&mut self, count: u32) -> Result<(), FlacError> {
+        self.ensure_configurable()?;
+        self.metadata.seek_placeholders += count;
+        Ok(())
+    }
+
+    /// Adds seek points every `interval` samples per channel for a stream of
+    /// `total_samples`, which libFLAC fills in with real byte offsets when
+    /// the stream is finished. This needs a seekable sink; `init()` fails
+    /// without one rather than writing a seek table of unfilled points.
+    pub fn add_seek_points(&mut self, interval: u32, total_samples: u64) -> Result<(), FlacError> {
+        self.ensure_configurable()?;
+        if interval == 0 || total_samples == 0 {
+            return Err(FlacError::InvalidMetadata(
+                "Seek point interval and total samples must be non-zero".to_string(),
+            ));
+        }
+        self.metadata.seek_spacing = Some((interval, total_samples));
+        Ok(())
+    }
+
+    /// Writes a PADDING block of `bytes` so tags can later be edited in place.
+    pub fn set_padding(&mut self, bytes: u32) -> Result<(), FlacError> {
+        self.ensure_configurable()?;
+        self.metadata.padding = Some(bytes);
+        Ok(())
+    }
+
+    /// Sets the relative order of the metadata blocks after STREAMINFO,
+    /// which libFLAC always writes first. Kinds left out keep the default
+    /// order after the listed ones; each kind may be listed once.
+    pub fn set_metadata_order(&mut self, order: &[MetadataKind]) -> Result<(), FlacError> {
+        self.ensure_configurable()?;
+        metadata::validate_order(order)?;
+        self.metadata.order = order.to_vec();
+        Ok(())
+    }
+
+    fn ensure_configurable(&self) -> Result<(), FlacError> {
+        match self.state {
+            StreamState::Encoding => Err(FlacError::AlreadyInitialized),
+            StreamState::Uninitialized | StreamState::Finished => Ok(()),
+        }
+    }
+
+    /// Registers a callback invoked for every frame the encoder emits, e.g. to
+    /// build a seek index or bitrate graph. It runs inside libFLAC's write
+    /// callback and must not call back into the encoder. In the Ogg
+    /// container it runs once per page holding audio instead.
+    pub fn set_frame_callback<F>(&mut self, callback: F)
+    where
+        F: FnMut(FrameInfo) + Send + 'static,
+    {
+        self.write_state.borrow_mut().on_frame = Some(Box::new(callback));
+    }
+
+    /// Encodes interleaved samples, copying any complete frames into `output`.
+    ///
+    /// Samples must already be in the native range of the configured bit
+    /// depth: a 16-bit stream expects -32768..=32767, not values shifted up to
+    /// fill the `i32`. 32-bit integer PCM is passed through as-is (libFLAC 1.4
+    /// or newer). 32-bit float audio is not integer PCM; convert it first,
+    /// e.g. with soundkit's `f32le_to_s24`, and configure a 24-bit stream.
+    /// [`encode_audio_data`](Self::encode_audio_data) does this for soundkit
+    /// audio.
+    /// With [`EncoderConfig::check_scaling`] set, debug builds log a warning
+    /// when the input looks left-justified or scaled up.
+    ///
+    /// Empty input is allowed at any point in a stream and never reaches
+    /// libFLAC. It only copies out bytes that are already pending, which is
+    /// the header straight after `init()` or bytes held back by an earlier
+    /// [`FlacError::OutputTooSmall`]; otherwise it returns `Ok(0)`. The
+    /// encoded stream is the same with or without such calls.
+    ///
+    /// # Output
+    ///
+    /// `Ok(n)` is the number of bytes written to `output`, and `Ok(0)` is a
+    /// success, not an error or the end of the stream. libFLAC only emits
+    /// whole frames, so input that doesn't complete a block stays buffered
+    /// and comes out in the `Ok` of a later call, or of
+    /// [`finish`](Self::finish) for the last partial block. A caller
+    /// streaming to a socket should send `output[..n]` when `n > 0` and
+    /// otherwise just carry on; errors are only ever reported as `Err`.
+    /// The same holds for `encode_i32` and the other encode methods.
+    pub fn encode_interleaved(
+        &mut self,
+        input: &[i32],
+        output: &mut [u8],
+    ) -> Result<usize, FlacError> {
+        self.ensure_encoding()?;
+        if input.is_empty() {
+            return self.drain_into(output);
+        }
+
+        #[cfg(debug_assertions)]
+        if self.config.check_scaling {
+            if let Some(problem) = scaling_problem(input, self.config.bits_per_sample) {
+                warn!("Suspicious input for encode_interleaved: {}", problem);
+            }
+        }
+
+        let chunk_len = match self.process_chunk_samples {
+            0 => input.len(),
+            samples => samples as usize * self.config.channels as usize,
+        };
+
+        let mut written = 0;
+        let mut overflowed = false;
+        for chunk in input.chunks(chunk_len) {
+            self.process(chunk)?;
+            if overflowed {
+                continue;
+            }
+            match self.drain_into(&mut output[written..]) {
+                Ok(len) => written += len,
+                Err(FlacError::OutputTooSmall { .. }) => {
+                    // Hand back what was already copied so the call stays
+                    // all-or-nothing, and keep encoding the rest of the input.
+                    self.write_state.borrow_mut().unconsume(&output[..written]);
+                    overflowed = true;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        if overflowed {
+            return Err(FlacError::OutputTooSmall {
+                needed: self.write_state.borrow().pending().len(),
+                available: output.len(),
+            });
+        }
+        Ok(written)
+    }
+
+    /// Like [`encode_interleaved`](Self::encode_interleaved), but also returns
+    /// the seek index of the stream so far: one [`SeekPoint`] per frame
+    /// written, mapping sample positions to byte offsets in the output.
+    ///
+    /// The index needs neither a SEEKTABLE block nor a seekable sink, so it
+    /// can be used to answer byte-range requests for a sample range.
+    pub fn encode_i32_indexed(
+        &mut self,
+        input: &[i32],
+        output: &mut [u8],
+    ) -> Result<(usize, &[SeekPoint]), FlacError> {
+        let len = self.encode_interleaved(input, output)?;
+        Ok((len, self.seek_index()))
+    }
+
+    /// Every frame written so far in the current stream, including those
+    /// flushed by `finish()`. Empty in the Ogg container, where libFLAC
+    /// writes pages that don't line up with frames.
+    pub fn seek_index(&mut self) -> &[SeekPoint] {
+        let mut write_state = self.write_state.borrow_mut();
+        self.seek_index.append(&mut write_state.seek_points);
+        drop(write_state);
+        &self.seek_index
+    }
+
+    /// Encodes planar audio, one buffer per channel, e.g. the owned
+    /// `Vec<Vec<i32>>` a decoder produces or a `&[&[i32]]` of slices.
+    ///
+    /// The buffers must match the configured channel count and all have the
+    /// same length. They are interleaved into a scratch buffer kept by the
+    /// encoder, so repeated calls don't allocate; see
+    /// [`set_scratch_buffer`](Self::set_scratch_buffer) to supply it.
+    pub fn encode_channels<C: AsRef<[i32]>>(
+        &mut self,
+        channels: &[C],
+        output: &mut [u8],
+    ) -> Result<usize, FlacError> {
+        if channels.len() != self.config.channels as usize {
+            return Err(FlacError::InvalidInput(format!(
+                "Got {} channels, encoder is configured for {}",
+                channels.len(),
+                self.config.channels
+            )));
+        }
+        let frames = channels.first().map_or(0, |c| c.as_ref().len());
+        if let Some(i) = channels.iter().position(|c| c.as_ref().len() != frames) {
+            return Err(FlacError::InvalidInput(format!(
+                "Channel {} has {} samples, channel 0 has {}",
+                i,
+                channels[i].as_ref().len(),
+                frames
+            )));
+        }
+
+        let mut interleaved = std::mem::take(&mut self.interleave_scratch);
+        interleaved.clear();
+        interleaved.reserve(frames * channels.len());
+        for i in 0..frames {
+            interleaved.extend(channels.iter().map(|c| c.as_ref()[i]));
+        }
+        let result = self.encode_interleaved(&interleaved, output);
+        self.interleave_scratch = interleaved;
+        result
+    }
+
+    /// Encodes interleaved float samples, full scale being -1.0 to 1.0,
+    /// converted to the configured bit depth by scaling with
+    /// `2^(bits_per_sample - 1)`.
+    ///
+    /// Samples outside full scale are always clamped to the largest value
+    /// the depth holds, never wrapped, so the stream stays valid. Clamping
+    /// usually points at a gain-staging problem upstream, so each one is
+    /// counted in [`clip_count`](Self::clip_count) and reported to the
+    /// [`clip callback`](Self::set_clip_callback). NaN encodes as silence
+    /// and counts as clipped. Conversion uses the
+    /// [`scratch buffer`](Self::set_scratch_buffer), like `encode_channels`.
+    pub fn encode_f32(&mut self, input: &[f32], output: &mut [u8]) -> Result<usize, FlacError> {
+        // checked before converting, so a call that fails reports no clips
+        self.ensure_encoding()?;
+        if !input.len().is_multiple_of(self.config.channels as usize) {
+            return Err(FlacError::InvalidInput(format!(
+                "{} samples aren't a whole number of frames of {} channels",
+                input.len(),
+                self.config.channels
+            )));
+        }
+        let full_scale = (1u64 << (self.config.bits_per_sample - 1)) as f64;
+        let (min, max) = (-full_scale, full_scale - 1.0);
+        let mut clipped = 0;
+        let mut converted = std::mem::take(&mut self.interleave_scratch);
+        converted.clear();
+        converted.extend(input.iter().map(|&sample| {
+            if !(-1.0..=1.0).contains(&sample) {
+                clipped += 1;
+            }
+            match sample.is_nan() {
+                true => 0,
+                false => (sample as f64 * full_scale).clamp(min, max) as i32,
+            }
+        }));
+        if clipped > 0 {
+            self.clip_count += clipped;
+            if let Some(on_clip) = self.on_clip.as_mut() {
+                on_clip(clipped);
+            }
+        }
+        let result = self.encode_interleaved(&converted, output);
+        self.interleave_scratch = converted;
+        result
+    }
+
+    /// Float samples [`encode_f32`](Self::encode_f32) had to clamp in the
+    /// current stream. It stays available after `finish()` until the next
+    /// stream starts. Integer input is never clamped and isn't counted.
+    pub fn clip_count(&self) -> u64 {
+        self.clip_count
+    }
+
+    /// Registers a callback invoked after each `encode_f32` call that had
+    /// to clamp samples, with the number clamped in that call.
+    pub fn set_clip_callback<F>(&mut self, callback: F)
+    where
+        F: FnMut(u64) + Send + 'static,
+    {
+        self.on_clip = Some(Box::new(callback));
+    }
+
+    /// Gives the encoder `buffer` to use as its interleaving scratch space,
+    /// e.g. one taken from another encoder with
+    /// [`take_scratch_buffer`](Self::take_scratch_buffer), so that callers
+    /// can pool the allocation. Its contents are discarded; only the
+    /// capacity matters. Any buffer the encoder had is dropped.
+    ///
+    /// [`encode_channels`](Self::encode_channels) clears the buffer and
+    /// grows it to hold the whole call's interleaved input if it is too
+    /// small, so its capacity ends up at the largest input seen. It is
+    /// never shrunk; a buffer big enough for the largest call means no
+    /// allocation at all.
+    pub fn set_scratch_buffer(&mut self, mut buffer: Vec<i32>) {
+        buffer.clear();
+        self.interleave_scratch = buffer;
+    }
+
+    /// Takes back the interleaving scratch buffer, leaving an empty one that
+    /// allocates again on the next call that needs it. The contents are
+    /// whatever the last call left and have no meaning.
+    pub fn take_scratch_buffer(&mut self) -> Vec<i32> {
+        std::mem::take(&mut self.interleave_scratch)
+    }
+
+    /// Encodes a soundkit [`AudioData`], converting its little-endian bytes
+    /// to samples according to the depth it reports:
+    ///
+    /// - 16 and 24 bits: signed PCM, for a stream of the same depth.
+    /// - 32 bits: `AudioData` doesn't say whether this is float or integer,
+    ///   so the stream decides. A 24-bit stream takes it as float and
+    ///   converts it with `f32le_to_s24`; a 32-bit stream takes it as
+    ///   integer PCM.
+    ///
+    /// The sample rate and channel count must match the encoder's; any
+    /// mismatch is [`FlacError::InvalidInput`].
+    pub fn encode_audio_data(
+        &mut self,
+        audio: &AudioData,
+        output: &mut [u8],
+    ) -> Result<usize, FlacError> {
+        if audio.sampling_rate() != self.config.sample_rate
+            || audio.channel_count() as u32 != self.config.channels
+        {
+            return Err(FlacError::InvalidInput(format!(
+                "Audio is {} Hz with {} channels, encoder is configured for {} Hz with {}",
+                audio.sampling_rate(),
+                audio.channel_count(),
+                self.config.sample_rate,
+                self.config.channels
+            )));
+        }
+        let samples = match (audio.bits_per_sample(), self.config.bits_per_sample) {
+            (16, 16) => s16le_to_i32(audio.data()),
+            (24, 24) => s24le_to_i32(audio.data()),
+            (32, 24) => f32le_to_s24(audio.data()),
+            (32, 32) => audio
+                .data()
+                .chunks_exact(4)
+                .map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect(),
+            (bits, stream_bits) => {
+                return Err(FlacError::InvalidInput(format!(
+                    "Can't encode {}-bit audio into a {}-bit stream",
+                    bits, stream_bits
+                )))
+            }
+        };
+        self.encode_interleaved(&samples, output)
+    }
+
+    /// Encodes `input` like [`encode_interleaved`](Self::encode_interleaved)
+    /// but leaves the output buffered, to be collected with
+    /// [`drain_complete_frames`](Self::drain_complete_frames).
+    pub fn encode_buffered(&mut self, input: &[i32]) -> Result<(), FlacError> {
+        self.ensure_encoding()?;
+        self.process(input)
+    }
+
+    /// Takes the encoded bytes buffered so far, which always end on a frame
+    /// boundary.
+    ///
+    /// No parsing is needed for this: libFLAC hands the write callback
+    /// exactly one frame, or one piece of the header, per call, so the
+    /// buffer never holds a partial frame. Samples that don't yet fill a
+    /// block stay inside libFLAC until more input or `finish()` arrives.
+    ///
+    /// The first call after `init()` also returns the stream header: the
+    /// `fLaC` marker and all metadata blocks, followed by whatever frames
+    /// are complete. Calling this straight after `init()` returns the header
+    /// alone, which suits senders that transmit it as its own packet.
+    pub fn drain_complete_frames(&mut self) -> Vec<u8> {
+        self.take_encoded()
+    }
+
+    /// Limits how many samples per channel are passed to libFLAC at once; 0
+    /// (the default) hands over the whole input in one call.
+    ///
+    /// Encoded bytes are buffered until they are copied into the caller's
+    /// output, so one huge `encode_interleaved` call buffers its whole
+    /// encoded size. With chunking the buffer is drained after every chunk
+    /// and peak memory is bounded by one chunk's output, at the cost of an
+    /// extra copy and libFLAC call per chunk. Values of a few block sizes
+    /// (e.g. 16384) keep the overhead negligible.
+    pub fn set_process_chunk_samples(&mut self, samples: u32) {
+        self.process_chunk_samples = samples;
+    }
+
+    /// Pulls interleaved samples from `samples` and writes the encoded bytes
+    /// to `sink`, returning how many bytes were written.
+    ///
+    /// Samples are gathered into a scratch buffer of
+    /// [`set_process_chunk_samples`](Self::set_process_chunk_samples)
+    /// samples per channel (4096 when unset) that is reused across calls, so
+    /// the input is never collected in full. A trailing partial frame is kept
+    /// until the next call completes it; if one is still pending, `finish()`
+    /// fails with [`FlacError::PartialFrame`] and the stream stays open.
+    ///
+    /// A non-blocking `sink` that returns [`std::io::ErrorKind::WouldBlock`]
+    /// fails the call with [`FlacError::WouldBlock`], which isn't fatal: the
+    /// samples taken so far are encoded, the bytes the sink didn't accept
+    /// are kept, and the next call writes them first. Call again with the
+    /// rest of the input, or an empty iterator to only flush, once the sink
+    /// is writable. Only the samples needed for the current batch are taken
+    /// from `samples`, so pass the same iterator by reference to resume.
+    /// This is separate from a [`seekable sink`](Self::set_seekable_sink),
+    /// which libFLAC writes itself and where any error is fatal.
+    pub fn encode_from_iter<I, W>(&mut self, samples: I, sink: &mut W) -> Result<usize, FlacError>
+    where
+        I: IntoIterator<Item = i32>,
+        W: Write,
+    {
+        self.ensure_encoding()?;
+
+        let channels = self.config.channels as usize;
+        let batch = match self.process_chunk_samples {
+            0 => ITER_CHUNK_SAMPLES,
+            samples => samples as usize,
+        } * channels;
+
+        let mut scratch = std::mem::take(&mut self.scratch);
+        let mut samples = samples.into_iter();
+        let mut written = 0;
+        let result = loop {
+            scratch.extend(samples.by_ref().take(batch - scratch.len()));
+            let whole = scratch.len() - scratch.len() % channels;
+            if whole > 0 {
+                if let Err(e) = self.process(&scratch[..whole]) {
+                    break Err(e);
+                }
+            }
+            // encoded now, whether or not the sink takes the output
+            scratch.drain(..whole);
+            match self.write_encoded(sink) {
+                Ok(len) => written += len,
+                Err(e) => break Err(e),
+            }
+
+            if whole < batch {
+                break Ok(written);
+            }
+        };
+
+        self.scratch = scratch;
+        result
+    }
+
+    /// Writes out the pending bytes, keeping whatever `sink` doesn't take
+    /// when it would block.
+    fn write_encoded<W: Write>(&self, sink: &mut W) -> Result<usize, FlacError> {
+        let mut write_state = self.write_state.borrow_mut();
+        let mut written = 0;
+        while !write_state.pending().is_empty() {
+            match sink.write(write_state.pending()) {
+                Ok(0) => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::WriteZero,
+                        "Sink accepted no bytes",
+                    )
+                    .into())
+                }
+                Ok(len) => {
+                    write_state.consume_front(len);
+                    written += len;
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    return Err(FlacError::WouldBlock)
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(written)
+    }
+
+    fn process(&mut self, input: &[i32]) -> Result<(), FlacError> {
+        ChannelLevels::accumulate(&mut self.levels, input);
+        if let Some(peaks) = self.peaks.as_mut() {
+            peaks.accumulate(input, self.config.channels as usize);
+        }
+        let started = Instant::now();
+        self.process_batched(input, MAX_PROCESS_FRAMES)?;
+        let samples = (input.len() / self.config.channels as usize) as u64;
+        self.throughput.record(samples, started.elapsed());
+        Ok(())
+    }
+
+    /// Hands `input` to libFLAC at most `max_frames` samples per channel at a
+    /// time, since its sample count argument is a `u32`.
+    fn process_batched(&mut self, input: &[i32], max_frames: usize) -> Result<(), FlacError> {
+        let channels = self.config.channels as usize;
+        for batch in input.chunks(max_frames.saturating_mul(channels)) {
+            #[cfg(feature = "timing")]
+            let started = Instant::now();
+            unsafe {
+                let success = ffi::FLAC__stream_encoder_process_interleaved(
+                    self.encoder,
+                    batch.as_ptr() as *const libflac_sys::FLAC__int32,
+                    (batch.len() / channels) as u32,
+                );
+                #[cfg(feature = "timing")]
+                self.timing.record(started.elapsed());
+
+                if success == 0 {
+                    let error = match self.take_callback_error() {
+                        Err(e) => e,
+                        Ok(()) => self.state_error("process samples"),
+                    };
+                    lifecycle!(debug, "encoding failed: {}", error);
+                    return Err(error);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Flushes the final partial block and ends the stream. The remaining
+    /// encoded bytes are copied into `output`.
+    pub fn finish(&mut self, output: &mut [u8]) -> Result<usize, FlacError> {
+        self.finish_stream()?;
+        self.drain_into(output)
+    }
+
+    fn finish_stream(&mut self) -> Result<(), FlacError> {
+        let result = self.end_stream();
+        match &result {
+            Ok(()) => lifecycle!(
+                debug,
+                "finished stream: {} samples, {} bytes, state {:?}",
+                self.write_state.borrow().stream_samples,
+                self.write_state.borrow().stream_bytes,
+                self.state
+            ),
+            Err(e) => lifecycle!(debug, "finishing stream failed: {}", e),
+        }
+        result
+    }
+
+    fn end_stream(&mut self) -> Result<(), FlacError> {
+        self.ensure_encoding()?;
+        if !self.scratch.is_empty() {
+            return Err(FlacError::PartialFrame {
+                samples: self.scratch.len(),
+            });
+        }
+        self.state = StreamState::Finished;
+
+        unsafe {
+            if ffi::FLAC__stream_encoder_finish(self.encoder) == 0 {
+                self.take_callback_error()?;
+                return Err(self.state_error("finish stream"));
+            }
+        }
+
+        if let (Some(declared), false) = (self.config.total_samples, self.back_patched()) {
+            let actual = self.write_state.borrow().stream_samples;
+            if actual != declared {
+                return Err(FlacError::TotalSamplesMismatch { declared, actual });
+            }
+        }
+        Ok(())
+    }
+
+    fn ensure_encoding(&self) -> Result<(), FlacError> {
+        self.take_callback_error()?;
+        match self.state {
+            StreamState::Uninitialized => Err(FlacError::NotInitialized),
+            StreamState::Encoding => Ok(()),
+            StreamState::Finished => Err(FlacError::AlreadyFinished),
+        }
+    }
+
+    /// The error for a libFLAC call that failed while encoding, based on the
+    /// state it left the encoder in. Errors raised by our own write callback
+    /// are taken first by the caller, so a client error here means the
+    /// stream was aborted without one being recorded.
+    fn state_error(&self, action: &str) -> FlacError {
+        if self.encoder.is_null() {
+            return FlacError::NotInitialized;
+        }
+        let state = unsafe { ffi::FLAC__stream_encoder_get_state(self.encoder) };
+        match state {
+            ffi::FLAC__STREAM_ENCODER_UNINITIALIZED => FlacError::NotInitialized,
+            ffi::FLAC__STREAM_ENCODER_VERIFY_MISMATCH_IN_AUDIO_DATA => {
+                let (mut absolute_sample, mut frame, mut channel, mut sample) = (0, 0, 0, 0);
+                let (mut expected, mut got) = (0, 0);
+                unsafe {
+                    ffi::FLAC__stream_encoder_get_verify_decoder_error_stats(
+                        self.encoder,
+                        &mut absolute_sample,
+                        &mut frame,
+                        &mut channel,
+                        &mut sample,
+                        &mut expected,
+                        &mut got,
+                    );
+                }
+                error!(
+                    "Verify mismatch in frame {}, channel {}: expected {}, got {}",
+                    frame, channel, expected, got
+                );
+                FlacError::VerifyMismatch {
+                    sample: (absolute_sample * self.config.channels as u64 + channel as u64)
+                        as usize,
+                }
+            }
+            ffi::FLAC__STREAM_ENCODER_VERIFY_DECODER_ERROR => {
+                FlacError::VerifyDecoder(encoder_state_string(self.encoder))
+            }
+            ffi::FLAC__STREAM_ENCODER_MEMORY_ALLOCATION_ERROR => FlacError::OutOfMemory,
+            ffi::FLAC__STREAM_ENCODER_IO_ERROR => FlacError::from(std::io::Error::other(format!(
+                "libFLAC failed to write the output file while trying to {}",
+                action
+            ))),
+            ffi::FLAC__STREAM_ENCODER_CLIENT_ERROR => FlacError::Encoder(format!(
+                "Failed to {}: the write callback aborted the stream",
+                action
+            )),
+            ffi::FLAC__STREAM_ENCODER_OGG_ERROR => FlacError::Encoder(format!(
+                "Failed to {}: the Ogg layer failed; the linked libFLAC may lack Ogg support",
+                action
+            )),
+            _ => FlacError::Encoder(format!(
+                "Failed to {}, encoder state: {}",
+                action,
+                encoder_state_string(self.encoder)
+            )),
+        }
+    }
+
+    fn take_callback_error(&self) -> Result<(), FlacError> {
+        match self.write_state.borrow_mut().callback_error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    // The buffer may already hold the stream header written by init(), so it
+    // is only drained once its contents have been copied out.
+    fn drain_into(&self, output: &mut [u8]) -> Result<usize, FlacError> {
+        let mut write_state = self.write_state.borrow_mut();
+        let encoded_data = write_state.pending();
+        let encoded_len = encoded_data.len();
+
+        if output.len() < encoded_len {
+            return Err(FlacError::OutputTooSmall {
+                needed: encoded_len,
+                available: output.len(),
+            });
+        }
+
+        output[..encoded_len].copy_from_slice(encoded_data);
+        write_state.consume();
+        Ok(encoded_len)
+    }
+
+    /// Encodes `samples` as the rest of the stream, [`CHUNK_SAMPLES`] per
+    /// channel at a time, and finishes it. `drain` is called after each chunk
+    /// and after finishing to take the output, so it doesn't pile up in the
+    /// encoder.
+    pub(crate) fn encode_chunked(
+        &mut self,
+        samples: &[i32],
+        mut drain: impl FnMut(&mut Self),
+    ) -> Result<(), FlacError> {
+        for chunk in samples.chunks(CHUNK_SAMPLES * self.config.channels as usize) {
+            self.process(chunk)?;
+            drain(self);
+        }
+        self.finish_stream()?;
+        drain(self);
+        Ok(())
+    }
+
+    /// Takes all encoded bytes buffered so far.
+    fn take_encoded(&self) -> Vec<u8> {
+        let mut write_state = self.write_state.borrow_mut();
+        if !write_state.accumulate {
+            return std::mem::take(&mut write_state.buffer);
+        }
+        let pending = write_state.pending().to_vec();
+        write_state.consume();
+        pending
+    }
+
+    /// Encoded bytes waiting to be copied out: the size `output` needs on
+    /// the next call, before any new input is added. After
+    /// [`FlacError::OutputTooSmall`] it equals the error's `needed`, so a
+    /// caller can grow its buffer and retry with empty input.
+    pub fn last_pending_len(&self) -> usize {
+        self.write_state.borrow().pending().len()
+    }
+
+    /// Drops the encoded bytes waiting to be copied out, keeping the stream
+    /// going: libFLAC's state, the samples it holds for the next frame and
+    /// the seek index are untouched, so the next frames carry on from where
+    /// the dropped ones left off.
+    ///
+    /// Pending bytes always end on a frame boundary, but they can include
+    /// the stream header if it hasn't been copied out yet, and that is
+    /// dropped with the rest. A receiver then has to get the header some
+    /// other way (e.g. `parse_streaminfo` on a copy kept earlier), and a
+    /// receiver that already has it sees a gap of whole frames. Only the
+    /// encoder's own buffer is affected; bytes already written to a sink are
+    /// not, and with [`EncoderConfig::accumulate_output`] the dropped bytes
+    /// are removed from the accumulated stream too.
+    pub fn clear_output(&mut self) {
+        let mut write_state = self.write_state.borrow_mut();
+        let drained = write_state.drained;
+        write_state.buffer.truncate(drained);
+    }
+
+    /// Every byte of the current stream so far, header included, when
+    /// [`EncoderConfig::accumulate_output`] is set; otherwise just the bytes
+    /// not yet copied out. Bytes written to a
+    /// [`seekable sink`](Self::set_seekable_sink) aren't kept.
+    pub fn accumulated_output(&self) -> Ref<'_, [u8]> {
+        Ref::map(self.write_state.borrow(), |write_state| {
+            if write_state.accumulate {
+                &write_state.buffer[..]
+            } else {
+                write_state.pending()
+            }
+        })
+    }
+
+    /// STREAMINFO as libFLAC completed it when the stream was finished, with
+    /// the actual total samples, MD5 and frame sizes. The header already
+    /// written to a sink that can't be back-patched lacks these, so this is
+    /// the only record of them. `None` until `finish()` and for streams
+    /// started with `init_file`, whose file is patched instead.
+    pub fn final_stream_info(&self) -> Option<StreamInfo> {
+        self.write_state.borrow().final_stream_info.clone()
+    }
+
+    /// The smallest and largest frame of the current stream in bytes, the
+    /// values STREAMINFO's minimum and maximum frame size fields should
+    /// hold, for sizing buffers downstream. They are tracked as frames are
+    /// written, so they don't depend on the header being back-patched, and
+    /// are final once `finish()` has written the last frame. Like
+    /// `final_stream_info` they stay available until the next stream starts.
+    ///
+    /// `None` before the first frame, for streams started with `init_file`,
+    /// and in the Ogg container, where the output comes in pages rather
+    /// than frames.
+    pub fn frame_size_range(&self) -> Option<(u32, u32)> {
+        if !self.config.container.is_native() {
+            return None;
+        }
+        self.write_state.borrow().frame_size_range
+    }
+
+    /// Takes the stream's header, the `fLaC` marker and every metadata
+    /// block, out of the pending output, so it can be sent on its own
+    /// before any audio, e.g. to each client that connects to a live
+    /// stream. Later encode calls then return only frames.
+    ///
+    /// libFLAC writes the whole header when the stream starts, so it can be
+    /// taken straight after `init()` or `reset()`, and also later as long as
+    /// none of the stream's output has been handed out yet: once an encode
+    /// call has returned the header mixed with the first frames this
+    /// returns `None`, as it does for a second call. Keep the bytes for
+    /// clients that connect later; they don't change, as STREAMINFO is only
+    /// back-patched through a seekable sink. `None` too for streams written
+    /// to a seekable sink or with `init_file`, whose output isn't returned,
+    /// and in the Ogg container, where the header shares pages with audio.
+    pub fn take_header(&mut self) -> Option<Vec<u8>> {
+        if !self.config.container.is_native() {
+            return None;
+        }
+        let mut write_state = self.write_state.borrow_mut();
+        let header_len = (write_state.stream_bytes - write_state.frame_bytes) as usize;
+        // nothing handed out yet, and nothing of an earlier stream in front
+        if header_len == 0 || write_state.pending().len() as u64 != write_state.stream_bytes {
+            return None;
+        }
+        let header = write_state.pending()[..header_len].to_vec();
+        write_state.consume_front(header_len);
+        Some(header)
+    }
+
+    /// Finishes and deletes the libFLAC encoder. libFLAC can call
+    /// `write_callback` until finish returns.
+    fn delete_encoder(&mut self) {
+        unsafe {
+            if !self.encoder.is_null() {
+                ffi::FLAC__stream_encoder_finish(self.encoder);
+                ffi::FLAC__stream_encoder_delete(self.encoder);
+            }
+        }
+        self.encoder = std::ptr::null_mut();
+    }
+
+    fn client_data(&self) -> *mut libc::c_void {
+        &*self.write_state as *const RefCell<WriteState> as *mut libc::c_void
+    }
+
+    /// Starts a new stream with `config`, replacing the settings given at
+    /// construction (block size, compression level and so on) without
+    /// recreating the encoder. Like `reset()` this discards any stream in
+    /// progress. The config is checked first, so an invalid one fails without
+    /// touching the current stream or settings.
+    ///
+    /// The audio format can change between tracks, but only on a clean
+    /// boundary: a different channel count or bit depth while the current
+    /// stream has samples in it and isn't finished fails with
+    /// [`FlacError::FormatChangeMidStream`]. Other settings can still be
+    /// changed at any point.
+    pub fn reset_with(&mut self, config: EncoderConfig) -> Result<(), FlacError> {
+        Self::check_config(&config)?;
+        let (from, to) = (
+            (self.config.channels, self.config.bits_per_sample),
+            (config.channels, config.bits_per_sample),
+        );
+        let has_samples = self.throughput.samples > 0 || !self.scratch.is_empty();
+        if from != to && self.state == StreamState::Encoding && has_samples {
+            return Err(FlacError::FormatChangeMidStream { from, to });
+        }
+        self.config = config;
+        self.reinit(None)
+    }
+
+    /// Switches to compression level `level` without the caller having to
+    /// restart, e.g. to spend less CPU while a shared server is loaded.
+    ///
+    /// libFLAC can't change settings once a stream has started, so mid-stream
+    /// this finishes the current stream, flushing libFLAC's partial block as
+    /// a short final frame, and starts a new one at `level` with the same
+    /// metadata. The output is then a series of concatenated FLAC streams,
+    /// each with its own header, which [`split_concatenated`] separates and
+    /// most players and `flac -d` decode in sequence; decoders that stop at
+    /// the end of the first stream will miss the rest. Nothing is lost or
+    /// reordered: bytes of the finished stream not yet handed out come out
+    /// first, with the new header after them, whether they are copied out or
+    /// [accumulated](EncoderConfig::accumulate_output). Per-stream state
+    /// starts over at the switch, including [`seek_index`](Self::seek_index)
+    /// and [`final_stream_info`](Self::final_stream_info).
+    ///
+    /// Outside a stream the level is only stored for the next one. A
+    /// mid-stream switch needs the native container and a write-only output:
+    /// a seekable sink or [`init_file`](Self::init_file) would have the new
+    /// stream's header written over the old one, and Ogg needs a new serial
+    /// number per stream, so they fail with [`FlacError::Unsupported`]. A
+    /// declared [`total_samples`](EncoderConfig::total_samples) can't hold
+    /// for both streams and fails with [`FlacError::InvalidConfig`].
+    pub fn set_compression_level_adaptive(&mut self, level: u32) -> Result<(), FlacError> {
+        let config = self.config.clone().compression_level(level);
+        Self::check_config(&config)?;
+        if self.state != StreamState::Encoding {
+            self.config = config;
+            return Ok(());
+        }
+        if level == self.config.compression_level {
+            return Ok(());
+        }
+        if !self.config.container.is_native() || self.writing_file || self.is_seekable_output() {
+            return Err(FlacError::Unsupported(
+                "Changing the compression level mid-stream needs native FLAC written to a \
+                 write-only output"
+                    .to_string(),
+            ));
+        }
+        if self.config.total_samples.is_some() {
+            return Err(FlacError::InvalidConfig(
+                "Changing the compression level mid-stream splits a stream with a declared \
+                 total_samples"
+                    .to_string(),
+            ));
+        }
+
+        self.finish_stream()?;
+        let (buffer, drained) = {
+            let mut write_state = self.write_state.borrow_mut();
+            (std::mem::take(&mut write_state.buffer), write_state.drained)
+        };
+        self.config = config;
+        self.reinit(None)?;
+        // put the finished stream's bytes back in front of the new header
+        let mut write_state = self.write_state.borrow_mut();
+        let header = std::mem::replace(&mut write_state.buffer, buffer);
+        write_state.buffer.extend(header);
+        write_state.drained = drained;
+        Ok(())
+    }
+
+    /// Rejects configs libFLAC would refuse at init, and warns about sample
+    /// rates that are legal but suspicious.
+    fn check_config(config: &EncoderConfig) -> Result<(), FlacError> {
+        config.validate()?;
+
+        match SampleRateClass::of(config.sample_rate) {
+            SampleRateClass::Common => {}
+            SampleRateClass::Uncommon => warn!(
+                "Unusual sample rate {} Hz; check it matches the source audio",
+                config.sample_rate
+            ),
+            SampleRateClass::NonSubset => warn!(
+                "Sample rate {} Hz is outside the FLAC streamable subset",
+                config.sample_rate
+            ),
+            SampleRateClass::Invalid => {
+                return Err(FlacError::InvalidConfig(format!(
+                    "Invalid sample rate {} Hz",
+                    config.sample_rate
+                )))
+            }
+        }
+
+        if !config.write_md5 && !cfg!(feature = "md5-toggle") {
+            return Err(FlacError::Unsupported(
+                "write_md5(false) needs the md5-toggle feature".to_string(),
+            ));
+        }
+        if config.bits_per_sample > 24 && !version::flac_version_at_least(1, 4) {
+            return Err(FlacError::Unsupported(format!(
+                "{}-bit samples require libFLAC 1.4 or newer, linked version is {}",
+                config.bits_per_sample,
+                flac_version()
+            )));
+        }
+        Ok(())
+    }
+
+    fn restart(&mut self) -> Result<(), FlacError> {
+        Self::check_config(&self.config)?;
+        self.reinit(None)
+    }
+
+    /// Writes the stream straight to the file at `path`, in place of
+    /// `init()`, using libFLAC's own file output.
+    ///
+    /// libFLAC opens the file for reading and writing, so on `finish()` it
+    /// goes back to fill in STREAMINFO (total samples, MD5, frame sizes) and
+    /// the seek table, for Ogg FLAC as well as native streams, with no sink
+    /// needed. It is file-only: the encode methods return 0 bytes, and as
+    /// the write callback isn't used there is no
+    /// [`seek_index`](Self::seek_index), frame callback or
+    /// [`achieved_bitrate`](Self::achieved_bitrate). The file applies to this
+    /// stream only; the next `reset()` goes back to the usual output.
+    ///
+    /// Requires the `file-encoder` feature.
+    #[cfg(feature = "file-encoder")]
+    pub fn init_file(&mut self, path: &std::path::Path) -> Result<(), FlacError> {
+        let path = path
+            .to_str()
+            .and_then(|path| std::ffi::CString::new(path).ok())
+            .ok_or_else(|| {
+                FlacError::InvalidInput(format!("Unusable file path {}", path.display()))
+            })?;
+        Self::check_config(&self.config)?;
+        self.reinit(Some(&path))
+    }
+
+    /// Writes the stream to the already-open file descriptor `fd`, in place
+    /// of `init()`, e.g. in a sandbox that hands the encoder a descriptor
+    /// but forbids opening paths.
+    ///
+    /// The descriptor is wrapped in a [`File`](std::fs::File) and set as a
+    /// [`seekable sink`](Self::set_seekable_sink), so the encode methods
+    /// return 0 bytes and, for native streams, the header is back-patched on
+    /// `finish()`. An Ogg stream's header is written once and not patched,
+    /// as libFLAC would have to read its pages back. The descriptor needs to
+    /// be writable and seekable, i.e. a regular file; write-only opening is
+    /// enough, as libFLAC never reads native output back. For a
+    /// pipe or socket, wrap it in a `File` and pass it to
+    /// [`encode_from_iter`](Self::encode_from_iter) instead.
+    ///
+    /// The encoder takes ownership of `fd` once the call gets past its
+    /// checks: it is closed when the sink is dropped, with the encoder or
+    /// after [`take_sink`](Self::take_sink), and must not be closed by the
+    /// caller. If the encoder is mid-stream or `fd` can't seek, the call
+    /// fails without touching `fd`, which stays the caller's to close.
+    ///
+    /// # Safety
+    ///
+    /// `fd` must be an open file descriptor that nothing else owns or
+    /// closes, as for [`FromRawFd::from_raw_fd`](std::os::fd::FromRawFd).
+    #[cfg(unix)]
+    pub unsafe fn init_from_raw_fd(&mut self, fd: std::os::fd::RawFd) -> Result<(), FlacError> {
+        use std::os::fd::FromRawFd;
+
+        self.ensure_configurable()?;
+        if libc::lseek(fd, 0, libc::SEEK_CUR) < 0 {
+            return Err(FlacError::Unsupported(format!(
+                "File descriptor {} can't seek: {}",
+                fd,
+                std::io::Error::last_os_error()
+            )));
+        }
+        self.set_seekable_sink(std::fs::File::from_raw_fd(fd))?;
+        self.restart()
+    }
+
+    /// The Windows counterpart of `init_from_raw_fd`: writes the stream to
+    /// the already-open file `handle`, which the encoder takes ownership of
+    /// and closes when the sink is dropped. The handle isn't checked up
+    /// front; one that can't seek fails on `finish()`.
+    ///
+    /// # Safety
+    ///
+    /// `handle` must be an open file handle that nothing else owns or
+    /// closes, as for
+    /// [`FromRawHandle::from_raw_handle`](std::os::windows::io::FromRawHandle).
+    #[cfg(windows)]
+    pub unsafe fn init_from_raw_handle(
+        &mut self,
+        handle: std::os::windows::io::RawHandle,
+    ) -> Result<(), FlacError> {
+        use std::os::windows::io::FromRawHandle;
+
+        self.ensure_configurable()?;
+        self.set_seekable_sink(std::fs::File::from_raw_handle(handle))?;
+        self.restart()
+    }
+
+    /// Whether libFLAC can go back and rewrite the header when the stream is
+    /// finished. It needs a read callback to patch Ogg pages, which a
+    /// write-only sink can't provide, so only its own file output can.
+    fn back_patched(&self) -> bool {
+        self.writing_file || (self.is_seekable_output() && self.config.container.is_native())
+    }
+
+    fn reinit(&mut self, file: Option<&std::ffi::CStr>) -> Result<(), FlacError> {
+        lifecycle!(
+            debug,
+            "{} encoder ({}): {:?}",
+            if self.encoder.is_null() {
+                "initialising"
+            } else {
+                "resetting"
+            },
+            if file.is_some() {
+                "file output"
+            } else {
+                "stream output"
+            },
+            self.config
+        );
+        let result = self.start_stream(file);
+        match &result {
+            Ok(()) => lifecycle!(
+                trace,
+                "stream started, back-patched {}, applied {:?}",
+                self.back_patched(),
+                self.applied
+            ),
+            Err(e) => lifecycle!(debug, "starting stream failed: {}", e),
+        }
+        result
+    }
+
+    fn start_stream(&mut self, file: Option<&std::ffi::CStr>) -> Result<(), FlacError> {
+        self.state = StreamState::Uninitialized;
+        if !self.encoder.is_null() {
+            // After finish libFLAC takes settings and an init again, so the
+            // encoder is only created once.
+            unsafe { ffi::FLAC__stream_encoder_finish(self.encoder) };
+        }
+        self.writing_file = file.is_some();
+        unsafe {
+            // Drop the previous stream's tail so the new header starts clean.
+            let mut write_state = self.write_state.borrow_mut();
+            write_state.clear();
+            write_state.accumulate = self.config.accumulate_output;
+            write_state.native = self.config.container.is_native();
+            write_state.vendor = self.config.vendor_string.clone();
+            write_state.wasted_bits = match self.config.wasted_bits_stats {
+                true if self.config.container.is_native() => {
+                    Some((self.config.bits_per_sample, WastedBitsStat::default()))
+                }
+                _ => None,
+            };
+            drop(write_state);
+            self.seek_index.clear();
+            self.scratch.clear();
+            self.throughput = Throughput::default();
+            self.clip_count = 0;
+            #[cfg(feature = "timing")]
+            {
+                self.timing = TimingStats::default();
+            }
+            self.levels = match self.config.channel_stats {
+                true => vec![ChannelLevels::default(); self.config.channels as usize],
+                false => Vec::new(),
+            };
+            self.peaks = self
+                .config
+                .peak_bins_per_second
+                .map(|bins| Peaks::new(self.config.sample_rate, bins));
+            self.metadata_blocks = metadata::Blocks::default();
+
+            if self.encoder.is_null() {
+                self.encoder = ffi::FLAC__stream_encoder_new();
+                // once per encoder, not on every reset; check_support reports it too
+                if self.config.min_lpc_order > 1 {
+                    warn!(
+                        "libFLAC has no minimum LPC order setting; ignoring min_lpc_order {}",
+                        self.config.min_lpc_order
+                    );
+                }
+            }
+
+            let seekable = self.back_patched();
+            if self.metadata.seek_spacing.is_some() && !seekable {
+                return Err(FlacError::InvalidConfig(
+                    "Seek points need a file, or a seekable sink and the native container"
+                        .to_string(),
+                ));
+            }
+            if self.config.vendor_string.is_some()
+                && (file.is_some() || !self.config.container.is_native())
+            {
+                return Err(FlacError::InvalidConfig(
+                    "vendor_string needs native output through the encoder, not a file or Ogg"
+                        .to_string(),
+                ));
+            }
+            let total_samples = self
+                .config
+                .total_samples
+                .or(self.metadata.seek_spacing.map(|(_, total)| total));
+            self.applied = self.config.applied();
+            for adjustment in &self.applied.adjustments {
+                warn!(
+                    "Adjusted to stay in the streamable subset: {:?}",
+                    adjustment
+                );
+            }
+            apply_settings(self.encoder, &self.applied.config, total_samples)?;
+
+            self.metadata_blocks = self.metadata.build()?;
+            if !self.metadata_blocks.is_empty()
+                && ffi::FLAC__stream_encoder_set_metadata(
+                    self.encoder,
+                    self.metadata_blocks.as_mut_ptr(),
+                    self.metadata_blocks.len() as u32,
+                ) == 0
+            {
+                return Err(FlacError::SettingRejected {
+                    name: "metadata",
+                    value: format!("{} blocks", self.metadata_blocks.len()),
+                });
+            }
+
+            let client_data = self.client_data();
+            let status = match (file, self.config.container) {
+                #[cfg(feature = "container-native")]
+                (Some(path), Container::Native) => ffi::FLAC__stream_encoder_init_file(
+                    self.encoder,
+                    path.as_ptr(),
+                    None,
+                    std::ptr::null_mut(),
+                ),
+                #[cfg(feature = "container-ogg")]
+                (Some(path), Container::Ogg) => ffi::FLAC__stream_encoder_init_ogg_file(
+                    self.encoder,
+                    path.as_ptr(),
+                    None,
+                    std::ptr::null_mut(),
+                ),
+                #[cfg(feature = "container-native")]
+                (None, Container::Native) => ffi::FLAC__stream_encoder_init_stream(
+                    self.encoder,
+                    Some(write_callback),
+                    if seekable { Some(seek_callback) } else { None },
+                    if seekable { Some(tell_callback) } else { None },
+                    Some(metadata_callback),
+                    client_data,
+                ),
+                #[cfg(feature = "container-ogg")]
+                (None, Container::Ogg) => ffi::FLAC__stream_encoder_init_ogg_stream(
+                    self.encoder,
+                    None, // read callback
+                    Some(write_callback),
+                    None,
+                    None,
+                    Some(metadata_callback),
+                    client_data,
+                ),
+            };
+
+            if status != ffi::FLAC__STREAM_ENCODER_INIT_STATUS_OK {
+                return Err(FlacError::Encoder(format!(
+                    "Failed to reset encoder, init status: {}, encoder state: {}",
+                    status_string(&ffi::FLAC__StreamEncoderInitStatusString, status),
+                    encoder_state_string(self.encoder)
+                )));
+            }
+        }
+
+        self.state = StreamState::Encoding;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "md5-toggle")]
+extern "C" {
+    // Only declared in libFLAC's protected header, so libflac-sys has no
+    // binding for it; see the md5-toggle feature in Cargo.toml.
+    fn FLAC__stream_encoder_set_do_md5(
+        encoder: *mut ffi::FLAC__StreamEncoder,
+        value: FLAC__bool,
+    ) -> FLAC__bool;
+}
+
+/// Applies each setting of the compression preset explicitly, for
+/// [`EncoderConfig::deterministic`].
+unsafe fn pin_settings(
+    encoder: *mut ffi::FLAC__StreamEncoder,
+    pinned: &config::PinnedSettings,
+    check: &mut impl FnMut(&'static str, &dyn std::fmt::Display, FLAC__bool),
 ) {
+    check(
+        "block_size",
+        &pinned.block_size,
+        ffi::FLAC__stream_encoder_set_blocksize(encoder, pinned.block_size),
+    );
+    check(
+        "mid_side",
+        &pinned.mid_side,
+        ffi::FLAC__stream_encoder_set_do_mid_side_stereo(encoder, pinned.mid_side as i32),
+    );
+    check(
+        "loose_mid_side",
+        &pinned.loose_mid_side,
+        ffi::FLAC__stream_encoder_set_loose_mid_side_stereo(encoder, pinned.loose_mid_side as i32),
+    );
+    // The preset strings contain no NUL, so this can't fail.
+    let apodization = std::ffi::CString::new(pinned.apodization).unwrap_or_default();
+    check(
+        "apodization",
+        &pinned.apodization,
+        ffi::FLAC__stream_encoder_set_apodization(encoder, apodization.as_ptr()),
+    );
+    check(
+        "max_lpc_order",
+        &pinned.max_lpc_order,
+        ffi::FLAC__stream_encoder_set_max_lpc_order(encoder, pinned.max_lpc_order),
+    );
+    check(
+        "qlp_coeff_precision",
+        &0,
+        ffi::FLAC__stream_encoder_set_qlp_coeff_precision(encoder, 0),
+    );
+    check(
+        "qlp_coeff_precision_search",
+        &false,
+        ffi::FLAC__stream_encoder_set_do_qlp_coeff_prec_search(encoder, false as i32),
+    );
+    check(
+        "escape_coding",
+        &false,
+        ffi::FLAC__stream_encoder_set_do_escape_coding(encoder, false as i32),
+    );
+    check(
+        "exhaustive_model_search",
+        &false,
+        ffi::FLAC__stream_encoder_set_do_exhaustive_model_search(encoder, false as i32),
+    );
+    check(
+        "min_residual_partition_order",
+        &pinned.min_residual_partition_order,
+        ffi::FLAC__stream_encoder_set_min_residual_partition_order(
+            encoder,
+            pinned.min_residual_partition_order,
+        ),
+    );
+    check(
+        "max_residual_partition_order",
+        &pinned.max_residual_partition_order,
+        ffi::FLAC__stream_encoder_set_max_residual_partition_order(
+            encoder,
+            pinned.max_residual_partition_order,
+        ),
+    );
+    check(
+        "rice_parameter_search_dist",
+        &0,
+        ffi::FLAC__stream_encoder_set_rice_parameter_search_dist(encoder, 0),
+    );
+}
+
+/// Passes the stream settings to an uninitialized libFLAC encoder. Every
+/// setter is tried; the first one libFLAC refuses is returned and any
+/// others are logged. The setters only refuse values once the encoder is
+/// initialized — values it can't use are reported by init instead.
+unsafe fn apply_settings(
+    encoder: *mut ffi::FLAC__StreamEncoder,
+    config: &EncoderConfig,
+    total_samples: Option<u64>,
+) -> Result<(), FlacError> {
+    let mut rejected = Vec::new();
+    let mut check = |name: &'static str, value: &dyn std::fmt::Display, ok: FLAC__bool| {
+        if ok == 0 {
+            rejected.push((name, value.to_string()));
+        }
+    };
+
+    check(
+        "block_size",
+        &config.block_size,
+        ffi::FLAC__stream_encoder_set_blocksize(encoder, config.block_size),
+    );
+    let verify = config.trust_level.verify();
+    check(
+        "verify",
+        &verify,
+        ffi::FLAC__stream_encoder_set_verify(encoder, verify as i32),
+    );
+    check(
+        "compression_level",
+        &config.compression_level,
+        ffi::FLAC__stream_encoder_set_compression_level(encoder, config.compression_level),
+    );
+    if config.deterministic {
+        pin_settings(encoder, &config.pinned_settings(), &mut check);
+    }
+    // The preset sets the LPC order and model search, so overrides must come
+    // after it.
+    if config.trust_level.exhaustive_model_search() {
+        check(
+            "exhaustive_model_search",
+            &true,
+            ffi::FLAC__stream_encoder_set_do_exhaustive_model_search(encoder, true as i32),
+        );
+    }
+    if let Some(max_lpc_order) = config.max_lpc_order {
+        check(
+            "max_lpc_order",
+            &max_lpc_order,
+            ffi::FLAC__stream_encoder_set_max_lpc_order(encoder, max_lpc_order),
+        );
+    }
+    if let Some(order) = config.min_residual_partition_order {
+        check(
+            "min_residual_partition_order",
+            &order,
+            ffi::FLAC__stream_encoder_set_min_residual_partition_order(encoder, order),
+        );
+    }
+    if let Some(order) = config.max_residual_partition_order {
+        check(
+            "max_residual_partition_order",
+            &order,
+            ffi::FLAC__stream_encoder_set_max_residual_partition_order(encoder, order),
+        );
+    }
+    check(
+        "channels",
+        &config.channels,
+        ffi::FLAC__stream_encoder_set_channels(encoder, config.channels),
+    );
+    check(
+        "bits_per_sample",
+        &config.bits_per_sample,
+        ffi::FLAC__stream_encoder_set_bits_per_sample(encoder, config.bits_per_sample),
+    );
+    check(
+        "sample_rate",
+        &config.sample_rate,
+        ffi::FLAC__stream_encoder_set_sample_rate(encoder, config.sample_rate),
+    );
+    if let Some(total_samples) = total_samples {
+        check(
+            "total_samples",
+            &total_samples,
+            ffi::FLAC__stream_encoder_set_total_samples_estimate(encoder, total_samples),
+        );
+    }
+    #[cfg(feature = "md5-toggle")]
+    if !config.write_md5 {
+        check(
+            "write_md5",
+            &false,
+            FLAC__stream_encoder_set_do_md5(encoder, false as i32),
+        );
+    }
+
+    let mut rejected = rejected.into_iter();
+    match rejected.next() {
+        None => Ok(()),
+        Some((name, value)) => {
+            for (name, value) in rejected {
+                warn!("libFLAC also rejected {} = {}", name, value);
+            }
+            Err(FlacError::SettingRejected { name, value })
+        }
+    }
+}
+
+impl Encoder for FlacEncoder {
+    fn new(
+        sample_rate: u32,
+        bits_per_sample: u32,
+        channels: u32,
+        frame_length: u32,
+        compression_level: u32,
+    ) -> Self {
+        FlacEncoder::from_config(
+            &EncoderConfig::new(sample_rate, channels, bits_per_sample)
+                .block_size(frame_length)
+                .compression_level(compression_level),
+        )
+    }
+
+    fn init(&mut self) -> Result<(), String> {
+        return self.reset();
+    }
+
+    fn encode_i16(&mut self, input: &[i16], output: &mut [u8]) -> Result<usize, String> {
+        Err("Not implemented.".to_string())
+    }
+
+    fn encode_i32(&mut self, input: &[i32], output: &mut [u8]) -> Result<usize, String> {
+        self.encode_interleaved(input, output)
+            .map_err(|e| e.to_string())
+    }
+
+    fn reset(&mut self) -> Result<(), String> {
+        self.restart().map_err(|e| e.to_string())
+    }
+}
+
+// SAFETY: the libFLAC encoder and metadata blocks are only reached through
+// `&mut self` and aren't tied to the thread that created them; everything
+// else the encoder owns, user callbacks and sinks included, is `Send`.
+unsafe impl Send for FlacEncoder {}
+
+impl Drop for FlacEncoder {
+    fn drop(&mut self) {
+        // the fields, metadata_blocks among them, drop after this
+        self.delete_encoder();
+        let mut write_state = self.write_state.borrow_mut();
+        if let Some(sink) = write_state.sink.as_mut() {
+            if let Err(e) = sink.flush() {
+                warn!("Flushing the sink of a dropped encoder failed: {}", e);
+            }
+        }
+        if cfg!(debug_assertions) && !write_state.pending().is_empty() {
+            warn!(
+                "FlacEncoder dropped with {} encoded bytes never taken; call finish() and collect its output first",
+                write_state.pending().len()
+            );
+        }
+    }
+}
+
+type MetadataHandler<T> = Option<Box<dyn FnMut(&T)>>;
+
+/// Handlers for metadata blocks, called from libFLAC's metadata callback
+/// while the decoder reads the stream header.
+#[derive(Default)]
+struct DecodeEvents {
+    on_streaminfo: MetadataHandler<StreamInfo>,
+    on_vorbis_comment: MetadataHandler<VorbisComment>,
+    on_picture: MetadataHandler<Picture>,
+}
+
+pub struct FlacDecoder {
+    decoder: *mut ffi::FLAC__StreamDecoder,
+    output_buffer: Vec<i32>,
+    output_position: usize,
+    input_buffer: Vec<u8>,
+    input_position: usize,
+    events: DecodeEvents,
+    stream_info: Option<StreamInfo>,
+    /// Set when a metadata handler panics; decoding is aborted and the error
+    /// returned by the decode call in progress.
+    callback_error: Option<FlacError>,
+    /// Whether a CRC failure aborts decoding; see `set_check_frame_crc`.
+    check_frame_crc: bool,
+    crc_errors: u64,
+    /// While `decode_planar` runs, frames are appended here per channel
+    /// instead of to `output_buffer`.
+    planar_output: Option<Vec<Vec<i32>>>,
+    /// Whether a leading ID3v2 tag is skipped; see `set_skip_id3`.
+    skip_id3: bool,
+    /// Largest block size accepted; see `set_max_block_size_allowed`.
+    max_block_size_allowed: u32,
+    /// Output channel `i` is the stream's channel `map[i]`; see
+    /// `set_channel_map`.
+    channel_map: Option<Vec<usize>>,
+    sync: SyncState,
+}
+
+impl FlacDecoder {
+    pub fn new() -> Self {
+        let decoder = unsafe { ffi::FLAC__stream_decoder_new() };
+        FlacDecoder {
+            decoder,
+            output_buffer: Vec::new(),
+            output_position: 0,
+            input_buffer: Vec::new(),
+            input_position: 0,
+            events: DecodeEvents::default(),
+            stream_info: None,
+            callback_error: None,
+            check_frame_crc: true,
+            crc_errors: 0,
+            planar_output: None,
+            skip_id3: false,
+            max_block_size_allowed: MAX_BLOCK_SIZE,
+            channel_map: None,
+            sync: SyncState::default(),
+        }
+    }
+
+    /// Calls `handler` with the STREAMINFO block as soon as it is parsed,
+    /// before any audio is decoded. Must be registered before `init()`.
+    ///
+    /// Like the other metadata handlers it runs once per block, from inside
+    /// libFLAC, and only sees the parsed block; it has no way back into the
+    /// decoder. A panic aborts decoding with [`FlacError::CallbackPanicked`].
+    pub fn on_streaminfo<F: FnMut(&StreamInfo) + 'static>(
+        &mut self,
+        handler: F,
+    ) -> Result<(), FlacError> {
+        self.ensure_uninitialized()?;
+        self.events.on_streaminfo = Some(Box::new(handler));
+        Ok(())
+    }
+
+    /// Calls `handler` with each VORBIS_COMMENT block (tags). Must be
+    /// registered before `init()`.
+    pub fn on_vorbis_comment<F: FnMut(&VorbisComment) + 'static>(
+        &mut self,
+        handler: F,
+    ) -> Result<(), FlacError> {
+        self.ensure_uninitialized()?;
+        self.events.on_vorbis_comment = Some(Box::new(handler));
+        Ok(())
+    }
+
+    /// Calls `handler` with each PICTURE block, e.g. cover art. Must be
+    /// registered before `init()`.
+    pub fn on_picture<F: FnMut(&Picture) + 'static>(
+        &mut self,
+        handler: F,
+    ) -> Result<(), FlacError> {
+        self.ensure_uninitialized()?;
+        self.events.on_picture = Some(Box::new(handler));
+        Ok(())
+    }
+
+    /// The stream's STREAMINFO, once decoding has read past it.
+    pub fn stream_info(&self) -> Option<&StreamInfo> {
+        self.stream_info.as_ref()
+    }
+
+    /// Playing time from STREAMINFO's total sample count; `None` before it
+    /// has been read or when the stream doesn't record its length.
+    pub fn duration(&self) -> Option<Duration> {
+        self.stream_info.as_ref()?.duration()
+    }
+
+    /// Chooses what a frame that fails its CRC check does to decoding.
+    ///
+    /// libFLAC checks the CRC-8 of every frame header and the CRC-16 of
+    /// every frame, a cheap integrity check compared with the MD5 of the
+    /// whole stream. When on (the default), a failure aborts the decode call
+    /// with [`FlacError::FrameCrcMismatch`]. When off, failures are only
+    /// logged and counted: a frame whose header fails is skipped, and one
+    /// whose contents fail is output as silence, so the output stays the
+    /// right length.
+    pub fn set_check_frame_crc(&mut self, enabled: bool) {
+        self.check_frame_crc = enabled;
+    }
+
+    /// Skips an ID3v2 tag found at the very start of the input, before the
+    /// `fLaC` marker. Off by default.
+    ///
+    /// Tags placed there aren't part of the FLAC format, but taggers write
+    /// them and players accept them, so files in the wild have them. This is
+    /// a leniency for those files: the tag's declared size is read from its
+    /// header and everything up to the marker is passed over unparsed. The
+    /// whole tag must have been fed before decoding starts.
+    pub fn set_skip_id3(&mut self, enabled: bool) {
+        self.skip_id3 = enabled;
+    }
+
+    /// Rejects streams with blocks larger than `max` samples per channel,
+    /// e.g. to bound the memory an untrusted upload can make the decoder
+    /// use. The default is 65535, the largest block FLAC allows, so nothing
+    /// is rejected.
+    ///
+    /// A STREAMINFO declaring a larger maximum block size fails the decode
+    /// call with [`FlacError::BlockSizeTooLarge`] before any audio is
+    /// output. Frames are checked too, as nothing makes a stream keep to
+    /// what its STREAMINFO declares.
+    pub fn set_max_block_size_allowed(&mut self, max: u32) {
+        self.max_block_size_allowed = max;
+    }
+
+    /// Reorders the decoded channels: output channel `i`, interleaved or
+    /// planar, is the stream's channel `map[i]`. `None`, the default, keeps
+    /// the stream's order.
+    ///
+    /// The map must be a permutation of `0..n` for the stream's `n`
+    /// channels; one that isn't a permutation fails here with
+    /// [`FlacError::InvalidConfig`], and one for a different channel count
+    /// fails the decode call the same way at the first frame. A FLAC
+    /// stream's channels are in a fixed order, the same as a
+    /// `WAVE_FORMAT_EXTENSIBLE` file's, which a map is built from:
+    ///
+    /// | Channels | Order |
+    /// |---|---|
+    /// | 1 | mono |
+    /// | 2 | left, right |
+    /// | 3 | left, right, centre |
+    /// | 4 | front left, front right, back left, back right |
+    /// | 5 | front left, front right, centre, back left, back right |
+    /// | 6 | front left, front right, centre, LFE, back left, back right |
+    /// | 7 | front left, front right, centre, LFE, back centre, side left, side right |
+    /// | 8 | front left, front right, centre, LFE, back left, back right, side left, side right |
+    ///
+    /// For example a renderer expecting 5.1 in film order (left, centre,
+    /// right, left surround, right surround, LFE) takes
+    /// `vec![0, 2, 1, 4, 5, 3]`.
+    pub fn set_channel_map(&mut self, map: Option<Vec<usize>>) -> Result<(), FlacError> {
+        if let Some(map) = &map {
+            let mut sorted = map.clone();
+            sorted.sort_unstable();
+            if sorted.is_empty() || sorted.iter().enumerate().any(|(i, &c)| i != c) {
+                return Err(FlacError::InvalidConfig(format!(
+                    "Channel map {:?} is not a permutation of 0..{}",
+                    map,
+                    map.len()
+                )));
+            }
+        }
+        self.channel_map = map;
+        Ok(())
+    }
+
+    /// Frames that failed a CRC check so far, whether or not that was fatal.
+    /// libFLAC reports a header CRC failure as a bad header, so other header
+    /// corruption is counted too.
+    pub fn crc_error_count(&self) -> u64 {
+        self.crc_errors
+    }
+
+    /// Every place so far where libFLAC lost sync with the stream and found
+    /// it again further on, in stream order.
+    ///
+    /// libFLAC reports lost sync when the bytes after a frame aren't the
+    /// start of another, and a bad header when they look like one but
+    /// don't parse; either way it scans ahead for the next frame header.
+    /// Neither aborts decoding (though a bad header does while
+    /// [`set_check_frame_crc`](Self::set_check_frame_crc) is on), so this
+    /// records where it happened. A loss the stream never recovers from is
+    /// not reported, as there is no resync point. Frames whose contents fail
+    /// their CRC don't lose sync and are counted by
+    /// [`crc_error_count`](Self::crc_error_count) instead.
+    pub fn sync_losses(&self) -> &[SyncLossEvent] {
+        &self.sync.events
+    }
+
+    fn ensure_uninitialized(&self) -> Result<(), FlacError> {
+        let state = unsafe { ffi::FLAC__stream_decoder_get_state(self.decoder) };
+        match state {
+            ffi::FLAC__STREAM_DECODER_UNINITIALIZED => Ok(()),
+            _ => Err(FlacError::AlreadyInitialized),
+        }
+    }
+
+    fn take_callback_error(&mut self) -> Result<(), FlacError> {
+        match self.callback_error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        }
+    }
+
+    pub fn init(&mut self) -> Result<(), String> {
+        unsafe {
+            ffi::FLAC__stream_decoder_set_metadata_ignore_all(self.decoder);
+            // STREAMINFO is always kept for stream_info() and duration()
+            let wanted = [
+                (true, ffi::FLAC__METADATA_TYPE_STREAMINFO),
+                (
+                    self.events.on_vorbis_comment.is_some(),
+                    ffi::FLAC__METADATA_TYPE_VORBIS_COMMENT,
+                ),
+                (
+                    self.events.on_picture.is_some(),
+                    ffi::FLAC__METADATA_TYPE_PICTURE,
+                ),
+            ];
+            for (_, block_type) in wanted.iter().filter(|(wanted, _)| *wanted) {
+                ffi::FLAC__stream_decoder_set_metadata_respond(self.decoder, *block_type);
+            }
+            let decoder_status = ffi::FLAC__stream_decoder_init_stream(
+                self.decoder,
+                Some(read_callback_decode),
+                None,
+                // only for FLAC__stream_decoder_get_decode_position
+                Some(tell_callback_decode),
+                None,
+                None,
+                Some(write_callback_decode),
+                Some(metadata_callback_decode),
+                Some(error_callback_decode),
+                self as *mut _ as *mut libc::c_void,
+            );
+
+            match decoder_status {
+                ffi::FLAC__STREAM_DECODER_INIT_STATUS_OK => Ok(()),
+                _ => Err(format!(
+                    "Failed to initialize the decoder. Status: {}",
+                    status_string(&ffi::FLAC__StreamDecoderInitStatusString, decoder_status)
+                )),
+            }
+        }
+    }
+
+    /// Appends encoded bytes for [`next_block`](Self::next_block) to read.
+    /// The decoder treats running out of input as the end of the stream, so
+    /// the whole stream should be fed before pulling blocks.
+    pub fn feed(&mut self, data: &[u8]) {
+        self.input_buffer.extend_from_slice(data);
+    }
+
+    /// Returns up to `max_samples` interleaved samples, or `None` once the
+    /// stream is exhausted.
+    ///
+    /// Samples from FLAC frames that don't fit are kept for the next call, so
+    /// the output is contiguous whatever block size the stream was encoded
+    /// with. Every block is exactly `max_samples` long except the last; pass a
+    /// multiple of the channel count to keep blocks aligned to whole frames.
+    pub fn next_block(&mut self, max_samples: usize) -> Result<Option<Vec<i32>>, FlacError> {
+        if max_samples == 0 {
+            return Ok(Some(Vec::new()));
+        }
+
+        self.output_buffer.drain(..self.output_position);
+        self.output_position = 0;
+
+        while self.output_buffer.len() < max_samples && !self.is_end_of_stream() {
+            let ok = unsafe { ffi::FLAC__stream_decoder_process_single(self.decoder) };
+            self.take_callback_error()?;
+            if ok == 0 {
+                return Err(FlacError::Decoder(format!(
+                    "Failed to decode FLAC frame, decoder state: {}",
+                    decoder_state_string(self.decoder)
+                )));
+            }
+        }
+
+        if self.output_buffer.is_empty() {
+            return Ok(None);
+        }
+
+        let len = max_samples.min(self.output_buffer.len());
+        self.output_position = len;
+        Ok(Some(self.output_buffer[..len].to_vec()))
+    }
+
+    /// Decodes everything fed so far and returns it as interleaved samples,
+    /// including any left over from [`next_block`](Self::next_block).
+    pub fn decode_remaining(&mut self) -> Result<Vec<i32>, FlacError> {
+        let ok = unsafe { ffi::FLAC__stream_decoder_process_until_end_of_stream(self.decoder) };
+        self.take_callback_error()?;
+        if ok == 0 {
+            return Err(FlacError::Decoder(format!(
+                "Failed to decode FLAC stream, decoder state: {}",
+                decoder_state_string(self.decoder)
+            )));
+        }
+
+        let mut samples = std::mem::take(&mut self.output_buffer);
+        samples.drain(..self.output_position);
+        self.output_position = 0;
+        Ok(samples)
+    }
+
+    /// Decodes the next `n` FLAC frames of what has been fed and returns
+    /// their interleaved samples, e.g. to draw a waveform piece by piece.
+    ///
+    /// Frames are the stream's own blocks, so the sample count depends on
+    /// its block size; use [`next_block`](Self::next_block) for fixed-size
+    /// output. Metadata blocks are read along the way without counting.
+    /// Samples left over from `next_block` come first. When fewer than `n`
+    /// frames remain, the rest are returned; once the stream is exhausted
+    /// the result is empty.
+    pub fn decode_n_frames(&mut self, n: usize) -> Result<Vec<i32>, FlacError> {
+        self.output_buffer.drain(..self.output_position);
+        self.output_position = 0;
+
+        let mut frames = 0;
+        while frames < n && !self.is_end_of_stream() {
+            let before = self.output_buffer.len();
+            let ok = unsafe { ffi::FLAC__stream_decoder_process_single(self.decoder) };
+            self.take_callback_error()?;
+            if ok == 0 {
+                return Err(FlacError::Decoder(format!(
+                    "Failed to decode FLAC frame, decoder state: {}",
+                    decoder_state_string(self.decoder)
+                )));
+            }
+            // only audio frames produce samples
+            if self.output_buffer.len() > before {
+                frames += 1;
+            }
+        }
+        Ok(std::mem::take(&mut self.output_buffer))
+    }
+
+    /// Like [`decode_remaining`](Self::decode_remaining) after feeding
+    /// `data`, but returns one buffer per channel, the layout libFLAC
+    /// decodes to, so DSP code doesn't have to undo an interleave.
+    ///
+    /// Channels are in FLAC order, which follows WAVE: left and right for
+    /// stereo; front left, front right, centre, LFE, back left and back
+    /// right for 5.1, unless [`set_channel_map`](Self::set_channel_map)
+    /// reorders them. Samples left over from
+    /// [`next_block`](Self::next_block) come first. Every channel has the
+    /// same length; a stream whose frames change channel count fails with
+    /// [`FlacError::Decoder`].
+    pub fn decode_planar(&mut self, data: &[u8]) -> Result<Vec<Vec<i32>>, FlacError> {
+        self.feed(data);
+        let mut channels = vec![
+            Vec::new();
+            self.stream_info
+                .as_ref()
+                .map_or(0, |info| info.channels as usize)
+        ];
+        let leftover = &self.output_buffer[self.output_position..];
+        // anything left over means STREAMINFO, and so the channel count, is known
+        if !channels.is_empty() {
+            for frame in leftover.chunks_exact(channels.len()) {
+                for (channel, &sample) in channels.iter_mut().zip(frame) {
+                    channel.push(sample);
+                }
+            }
+        }
+        self.output_buffer.clear();
+        self.output_position = 0;
+
+        self.planar_output = Some(channels);
+        let ok = unsafe { ffi::FLAC__stream_decoder_process_until_end_of_stream(self.decoder) };
+        let channels = self.planar_output.take().unwrap_or_default();
+        self.take_callback_error()?;
+        if ok == 0 {
+            return Err(FlacError::Decoder(format!(
+                "Failed to decode FLAC stream, decoder state: {}",
+                decoder_state_string(self.decoder)
+            )));
+        }
+        if let Some(first) = channels.first() {
+            if let Some(i) = channels.iter().position(|c| c.len() != first.len()) {
+                return Err(FlacError::Decoder(format!(
+                    "Channel {} has {} samples, channel 0 has {}",
+                    i,
+                    channels[i].len(),
+                    first.len()
+                )));
+            }
+        }
+        Ok(channels)
+    }
+
+    fn is_end_of_stream(&self) -> bool {
+        let state = unsafe { ffi::FLAC__stream_decoder_get_state(self.decoder) };
+        state == ffi::FLAC__STREAM_DECODER_END_OF_STREAM
+    }
+}
+impl Decoder for FlacDecoder {
+    fn decode_i16(
+        &mut self,
+        input: &[u8],
+        output: &mut [i16],
+        _fec: bool,
+    ) -> Result<usize, String> {
+        Err("not implemented.".to_string())
+    }
+
+    fn decode_i32(
+        &mut self,
+        input: &[u8],
+        output: &mut [i32],
+        _fec: bool,
+    ) -> Result<usize, String> {
+        // Reset internal buffers
+        self.output_buffer.clear();
+        self.output_position = 0;
+        self.input_buffer.clear();
+        self.input_position = 0;
+
+        // Copy input data to internal buffer
+        self.input_buffer.extend_from_slice(input);
+
+        unsafe {
+            // Process the entire input
+            let result = ffi::FLAC__stream_decoder_process_single(self.decoder);
+            self.take_callback_error().map_err(|e| e.to_string())?;
+            if result == 0 {
+                return Err(format!(
+                    "Failed to decode FLAC block, decoder state: {}",
+                    decoder_state_string(self.decoder)
+                ));
+            }
+        }
+
+        // Copy decoded samples to the output buffer
+        let decoded_len = self.output_buffer.len();
+        if output.len() < decoded_len {
+            return Err("Output buffer too small".to_string());
+        }
+
+        output[..decoded_len].copy_from_slice(&self.output_buffer);
+        Ok(decoded_len)
+    }
+}
+
+impl Drop for FlacDecoder {
+    fn drop(&mut self) {
+        unsafe {
+            ffi::FLAC__stream_decoder_finish(self.decoder);
+            ffi::FLAC__stream_decoder_delete(self.decoder);
+        }
+    }
+}
+
+unsafe extern "C" fn read_callback_decode(
+    _decoder: *const ffi::FLAC__StreamDecoder,
+    buffer: *mut ffi::FLAC__byte,
+    bytes: *mut usize,
+    client_data: *mut std::ffi::c_void,
+) -> ffi::FLAC__StreamDecoderReadStatus {
+    let decoder = &mut *(client_data as *mut FlacDecoder);
+    if decoder.skip_id3 && decoder.input_position == 0 {
+        if let Some(tag_len) = header::id3v2_len(&decoder.input_buffer) {
+            decoder.input_position = tag_len.min(decoder.input_buffer.len());
+        }
+    }
+    let remaining = decoder.input_buffer.len() - decoder.input_position;
+    let to_read = std::cmp::min(*bytes, remaining);
+
+    if to_read == 0 {
+        *bytes = 0;
+        return ffi::FLAC__STREAM_DECODER_READ_STATUS_END_OF_STREAM;
+    }
+
+    let src = decoder.input_buffer[decoder.input_position..].as_ptr();
+    std::ptr::copy_nonoverlapping(src, buffer, to_read);
+
+    decoder.input_position += to_read;
+    *bytes = to_read;
+
+    ffi::FLAC__STREAM_DECODER_READ_STATUS_CONTINUE
+}
+
+unsafe extern "C" fn tell_callback_decode(
+    _decoder: *const ffi::FLAC__StreamDecoder,
+    absolute_byte_offset: *mut u64,
+    client_data: *mut std::ffi::c_void,
+) -> ffi::FLAC__StreamDecoderTellStatus {
+    *absolute_byte_offset = (*(client_data as *const FlacDecoder)).input_position as u64;
+    ffi::FLAC__STREAM_DECODER_TELL_STATUS_OK
+}
+
+/// How far libFLAC has got through the input: what it has read, less what
+/// it still holds unparsed. Must be called before the callback takes its
+/// own reference to the `FlacDecoder`, as it calls `tell_callback_decode`.
+unsafe fn decode_position(decoder: *const ffi::FLAC__StreamDecoder) -> Option<u64> {
+    let mut position = 0;
+    match ffi::FLAC__stream_decoder_get_decode_position(decoder, &mut position) {
+        0 => None,
+        _ => Some(position),
+    }
+}
+
+unsafe extern "C" fn write_callback_decode(
+    flac_decoder: *const FLAC__StreamDecoder,
+    frame: *const FLAC__Frame,
+    buffer: *const *const FLAC__int32,
+    client_data: *mut std::ffi::c_void,
+) -> FLAC__StreamDecoderWriteStatus {
+    let position = decode_position(flac_decoder);
+    let decoder = &mut *(client_data as *mut FlacDecoder);
+    if decoder.callback_error.is_some() {
+        return FLAC__STREAM_DECODER_WRITE_STATUS_ABORT;
+    }
+
+    let channels = (*frame).header.channels as usize;
+    let blocksize = (*frame).header.blocksize as usize;
+    if blocksize as u32 > decoder.max_block_size_allowed {
+        decoder.callback_error = Some(FlacError::BlockSizeTooLarge {
+            block_size: blocksize as u32,
+            max: decoder.max_block_size_allowed,
+        });
+        return FLAC__STREAM_DECODER_WRITE_STATUS_ABORT;
+    }
+
+    // libFLAC always reports the position as a sample number here
+    let first_sample = (*frame).header.number.sample_number;
+    let sync = &mut decoder.sync;
+    if let Some(lost_at) = sync.lost_at.take() {
+        let resync = frame::find_frame(&decoder.input_buffer, sync.search_from as usize);
+        sync.events.push(SyncLossEvent {
+            byte_offset: lost_at,
+            resync_offset: resync.map_or(lost_at, |offset| offset as u64),
+            lost_samples: first_sample.saturating_sub(sync.next_sample),
+        });
+    }
+    sync.frame_end = position.unwrap_or(sync.frame_end);
+    sync.next_sample = first_sample + blocksize as u64;
+
+    let buffer = slice::from_raw_parts(buffer, channels);
+    let buffer: Vec<*const FLAC__int32> = match decoder.channel_map.as_deref() {
+        None => buffer.to_vec(),
+        Some(map) if map.len() == channels => map.iter().map(|&channel| buffer[channel]).collect(),
+        Some(map) => {
+            decoder.callback_error = Some(FlacError::InvalidConfig(format!(
+                "Channel map {:?} is for {} channels, stream has {}",
+                map,
+                map.len(),
+                channels
+            )));
+            return FLAC__STREAM_DECODER_WRITE_STATUS_ABORT;
+        }
+    };
+
+    if let Some(planar) = decoder.planar_output.as_mut() {
+        if planar.is_empty() {
+            planar.resize(channels, Vec::new());
+        }
+        if planar.len() != channels {
+            decoder.callback_error = Some(FlacError::Decoder(format!(
+                "Frame has {} channels, stream has {}",
+                channels,
+                planar.len()
+            )));
+            return FLAC__STREAM_DECODER_WRITE_STATUS_ABORT;
+        }
+        for (output, &channel) in planar.iter_mut().zip(&buffer) {
+            output.extend_from_slice(slice::from_raw_parts(channel, blocksize));
+        }
+        return FLAC__STREAM_DECODER_WRITE_STATUS_CONTINUE;
+    }
+
+    let buffer = buffer
+        .iter()
+        .map(|x| slice::from_raw_parts(*x, blocksize))
+        .collect::<Vec<&[i32]>>();
+
+    for i in 0..blocksize {
+        for j in 0..channels {
+            decoder.output_buffer.push(buffer[j][i]);
+        }
+    }
+
+    FLAC__STREAM_DECODER_WRITE_STATUS_CONTINUE
+}
+
+unsafe extern "C" fn metadata_callback_decode(
+    flac_decoder: *const ffi::FLAC__StreamDecoder,
+    metadata: *const ffi::FLAC__StreamMetadata,
+    client_data: *mut std::ffi::c_void,
+) {
+    let position = decode_position(flac_decoder);
+    let decoder = &mut *(client_data as *mut FlacDecoder);
+    if decoder.callback_error.is_some() {
+        return;
+    }
+    decoder.sync.frame_end = position.unwrap_or(decoder.sync.frame_end);
+
+    let events = &mut decoder.events;
+    let data = &(*metadata).data;
+    let result = match (*metadata).type_ {
+        ffi::FLAC__METADATA_TYPE_STREAMINFO => {
+            let info = metadata::stream_info_from_ffi(&data.stream_info);
+            let max = decoder.max_block_size_allowed;
+            let result = match events.on_streaminfo.as_mut() {
+                _ if info.max_block_size as u32 > max => Err(FlacError::BlockSizeTooLarge {
+                    block_size: info.max_block_size as u32,
+                    max,
+                }),
+                Some(handler) => call_user_callback(|| handler(&info)),
+                None => Ok(()),
+            };
+            decoder.stream_info = Some(info);
+            result
+        }
+        ffi::FLAC__METADATA_TYPE_VORBIS_COMMENT => match events.on_vorbis_comment.as_mut() {
+            Some(handler) => {
+                let comment = metadata::vorbis_comment_from_ffi(&data.vorbis_comment);
+                call_user_callback(|| handler(&comment))
+            }
+            None => Ok(()),
+        },
+        ffi::FLAC__METADATA_TYPE_PICTURE => match events.on_picture.as_mut() {
+            Some(handler) => {
+                let picture = metadata::picture_from_ffi(&data.picture);
+                call_user_callback(|| handler(&picture))
+            }
+            None => Ok(()),
+        },
+        _ => Ok(()),
+    };
+
+    // The metadata callback can't stop libFLAC; the write callback aborts
+    // on the next frame instead.
+    if let Err(e) = result {
+        decoder.callback_error = Some(e);
+    }
+}
+
+unsafe extern "C" fn error_callback_decode(
+    flac_decoder: *const ffi::FLAC__StreamDecoder,
+    status: ffi::FLAC__StreamDecoderErrorStatus,
+    client_data: *mut std::ffi::c_void,
+) {
+    let position = decode_position(flac_decoder);
+    let decoder = &mut *(client_data as *mut FlacDecoder);
+    if matches!(
+        status,
+        ffi::FLAC__STREAM_DECODER_ERROR_STATUS_LOST_SYNC
+            | ffi::FLAC__STREAM_DECODER_ERROR_STATUS_BAD_HEADER
+    ) {
+        let sync = &mut decoder.sync;
+        sync.lost_at.get_or_insert(sync.frame_end);
+        // the byte libFLAC holds back to check for a sync code may be one
+        sync.search_from = position
+            .map_or(sync.frame_end, |p| p.saturating_sub(1))
+            .max(sync.frame_end);
+    }
+    if matches!(
+        status,
+        ffi::FLAC__STREAM_DECODER_ERROR_STATUS_BAD_HEADER
+            | ffi::FLAC__STREAM_DECODER_ERROR_STATUS_FRAME_CRC_MISMATCH
+    ) {
+        decoder.crc_errors += 1;
+        // the write callback aborts before the damaged frame is output
+        if decoder.check_frame_crc && decoder.callback_error.is_none() {
+            decoder.callback_error = Some(FlacError::FrameCrcMismatch);
+        }
+    }
+
     match status {
         ffi::FLAC__STREAM_DECODER_ERROR_STATUS_LOST_SYNC => {
             debug!("Decoder error: Lost sync with FLAC stream");
         }
-        ffi::FLAC__STREAM_DECODER_ERROR_STATUS_BAD_HEADER => {
-            error!("Decoder error: Bad FLAC stream header");
+        ffi::FLAC__STREAM_DECODER_ERROR_STATUS_BAD_HEADER => {
+            error!("Decoder error: Bad FLAC stream header");
+        }
+        ffi::FLAC__STREAM_DECODER_ERROR_STATUS_FRAME_CRC_MISMATCH => {
+            error!("Decoder error: Frame CRC mismatch");
+        }
+        ffi::FLAC__STREAM_DECODER_ERROR_STATUS_UNPARSEABLE_STREAM => {
+            error!("Decoder error: Unparseable stream");
+        }
+        _ => {
+            error!(
+                "Decoder error: {}",
+                status_string(&ffi::FLAC__StreamDecoderErrorStatusString, status)
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{decode_all, encode_all, sine_sweep, white_noise};
+    use soundkit::wav::WavStreamProcessor;
+    use std::fs::File;
+    use std::io::Read;
+    use std::io::Write;
+    use std::rc::Rc;
+    use std::sync::{Arc, Mutex};
+
+    fn wav_is_float(wav: &[u8]) -> bool {
+        const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+        const WAVE_FORMAT_EXTENSIBLE: u16 = 0xfffe;
+
+        let mut pos = 12; // past "RIFF", size, "WAVE"
+        while pos + 8 <= wav.len() {
+            let id = &wav[pos..pos + 4];
+            let size = u32::from_le_bytes([wav[pos + 4], wav[pos + 5], wav[pos + 6], wav[pos + 7]]);
+            let body = &wav[pos + 8..];
+            if id == b"fmt " {
+                let tag = match u16::from_le_bytes([body[0], body[1]]) {
+                    // the sub-format GUID starts with the real format tag
+                    WAVE_FORMAT_EXTENSIBLE => u16::from_le_bytes([body[24], body[25]]),
+                    tag => tag,
+                };
+                return tag == WAVE_FORMAT_IEEE_FLOAT;
+            }
+            pos += 8 + size as usize + (size as usize & 1);
+        }
+        false
+    }
+
+    fn run_flac_encoder_with_wav_file(file_path: &str) {
+        let mut decoder = FlacDecoder::new();
+        decoder.init().expect("Decoder initialization failed");
+
+        let frame_size = 3600;
+        let mut file = File::open(file_path).unwrap();
+        let mut file_buffer = Vec::new();
+        file.read_to_end(&mut file_buffer).unwrap();
+
+        let mut processor = WavStreamProcessor::new();
+        let audio_data = processor.add(&file_buffer).unwrap().unwrap();
+
+        dbg!(file_path, audio_data.sampling_rate());
+
+        // 32-bit float WAV is converted to 24-bit integers; 32-bit integer
+        // PCM goes to the encoder untouched
+        let is_float = wav_is_float(&file_buffer);
+        let bits_per_sample = match (audio_data.bits_per_sample(), is_float) {
+            (32, true) => 24,
+            (bits, _) => bits as u32,
+        };
+
+        let mut encoder = FlacEncoder::new(
+            audio_data.sampling_rate(),
+            bits_per_sample,
+            audio_data.channel_count() as u32,
+            0 as u32,
+            5,
+        );
+        encoder.init().expect("Failed to initialize FLAC encoder");
+
+        let i32_samples = match audio_data.bits_per_sample() {
+            16 => {
+                // this doesn't scale the 16 bit samples - important!
+                s16le_to_i32(audio_data.data())
+            }
+            24 => s24le_to_i32(audio_data.data()),
+            32 if is_float => f32le_to_s24(audio_data.data()),
+            32 => audio_data
+                .data()
+                .chunks_exact(4)
+                .map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+                .collect(),
+            _ => {
+                unreachable!()
+            }
+        };
+
+        let mut encoded_data = Vec::new();
+        let chunk_size = frame_size * audio_data.channel_count() as usize;
+        let mut decoded_samples = vec![0i32; chunk_size * 4];
+        let mut n = 0;
+        for (i, chunk) in i32_samples.chunks(chunk_size).enumerate() {
+            let frames = chunk.len() / audio_data.channel_count() as usize;
+            let mut output_buffer = vec![0u8; encoder.max_output_len(frames)];
+            match encoder.encode_i32(chunk, &mut output_buffer) {
+                Ok(encoded_len) => {
+                    if encoded_len > 0 {
+                        n += 1;
+                        match decoder.decode_i32(
+                            &output_buffer[..encoded_len],
+                            &mut decoded_samples,
+                            false,
+                        ) {
+                            Ok(samples_read) => {
+                                println!(
+                                    "Decoded {} samples of {} data successfully.",
+                                    samples_read, encoded_len
+                                );
+                            }
+                            Err(e) => panic!("Decoding failed: {}", e),
+                        }
+                    }
+                    encoded_data.extend_from_slice(&output_buffer[..encoded_len]);
+                }
+                Err(e) => {
+                    panic!("Failed to encode chunk {}: {:?}", i, e);
+                }
+            }
+        }
+
+        dbg!(n);
+
+        let mut file =
+            File::create(file_path.to_owned() + ".flac").expect("Failed to create output file");
+        file.write_all(&encoded_data)
+            .expect("Failed to write to output file");
+
+        encoder.reset().expect("Failed to reset encoder");
+    }
+
+    #[test]
+    fn test_flac_encoder_with_wave_16bit() {
+        run_flac_encoder_with_wav_file("testdata/s16le.wav");
+    }
+
+    #[test]
+    fn test_flac_encoder_with_wave_24bit() {
+        run_flac_encoder_with_wav_file("testdata/s24le.wav");
+    }
+
+    #[test]
+    fn test_flac_encoder_with_wave_32bit() {
+        run_flac_encoder_with_wav_file("testdata/f32le.wav");
+    }
+
+    // #[test]
+    fn test_flac_encoder_with_wave_lori() {
+        run_flac_encoder_with_wav_file("testdata/lori.wav");
+    }
+
+    //    #[test]
+    fn test_flac_encoder_with_wave_s32bit() {
+        run_flac_encoder_with_wav_file("testdata/s32le.wav");
+    }
+
+    fn silence(channels: usize, frames: usize) -> Vec<i32> {
+        vec![0; frames * channels]
+    }
+
+    fn full_scale_square(bits: u32, channels: usize, frames: usize) -> Vec<i32> {
+        let max = ((1i64 << (bits - 1)) - 1) as i32;
+        let min = -(1i64 << (bits - 1)) as i32;
+        (0..frames)
+            .flat_map(|i| {
+                let sample = if (i / 50) % 2 == 0 { max } else { min };
+                std::iter::repeat_n(sample, channels)
+            })
+            .collect()
+    }
+
+    fn assert_round_trip(name: &str, samples: &[i32], bits: u32, channels: u32) {
+        let encoded = encode_all(samples, bits, channels);
+        assert!(
+            encoded.starts_with(b"fLaC"),
+            "{}: encoded stream is missing the fLaC header",
+            name
+        );
+
+        let decoded = decode_all(&encoded);
+        assert_eq!(decoded.len(), samples.len(), "{}: sample count", name);
+        if let Some(i) = decoded.iter().zip(samples).position(|(a, b)| a != b) {
+            panic!(
+                "{}: first mismatch at sample {}: expected {}, got {}",
+                name, i, samples[i], decoded[i]
+            );
+        }
+    }
+
+    #[test]
+    fn test_round_trip_synthetic_signals() {
+        let frames = 44100 + 123; // deliberately not a multiple of the block size
+        for bits in [16, 24] {
+            for channels in [1usize, 2] {
+                let signals = [
+                    ("sine sweep", sine_sweep(bits, channels, frames)),
+                    ("white noise", white_noise(bits, channels, frames)),
+                    ("silence", silence(channels, frames)),
+                    ("square", full_scale_square(bits, channels, frames)),
+                ];
+                for (name, samples) in signals.iter() {
+                    let name = format!("{} {}-bit {}ch", name, bits, channels);
+                    assert_round_trip(&name, samples, bits, channels as u32);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_lifecycle_illegal_transitions() {
+        let input = vec![0i32; 4096 * 2];
+        let mut output = vec![0u8; 1 << 16];
+
+        let mut encoder = FlacEncoder::new(44100, 16, 2, 0, 5);
+        assert_eq!(
+            encoder.encode_interleaved(&input, &mut output),
+            Err(FlacError::NotInitialized)
+        );
+        assert_eq!(encoder.finish(&mut output), Err(FlacError::NotInitialized));
+
+        encoder.init().unwrap();
+        encoder.encode_interleaved(&input, &mut output).unwrap();
+        encoder.finish(&mut output).unwrap();
+        assert_eq!(encoder.finish(&mut output), Err(FlacError::AlreadyFinished));
+        assert_eq!(
+            encoder.encode_interleaved(&input, &mut output),
+            Err(FlacError::AlreadyFinished)
+        );
+        assert!(encoder.encode_i32(&input, &mut output).is_err());
+
+        encoder.reset().unwrap();
+        encoder.encode_interleaved(&input, &mut output).unwrap();
+        encoder.finish(&mut output).unwrap();
+    }
+
+    #[test]
+    fn test_frame_callback_reports_every_frame() {
+        let frames = Arc::new(Mutex::new(Vec::new()));
+        let mut encoder = FlacEncoder::new(44100, 16, 2, 4096, 5);
+        let sink = frames.clone();
+        encoder.set_frame_callback(move |info| sink.lock().unwrap().push(info));
+        encoder.init().unwrap();
+
+        let samples = white_noise(16, 2, 44100);
+        let mut output = vec![0u8; 1 << 20];
+        let mut encoded = Vec::new();
+        for chunk in samples.chunks(1000 * 2) {
+            let len = encoder.encode_interleaved(chunk, &mut output).unwrap();
+            encoded.extend_from_slice(&output[..len]);
+        }
+        let len = encoder.finish(&mut output).unwrap();
+        encoded.extend_from_slice(&output[..len]);
+
+        let frames = frames.lock().unwrap();
+        assert_eq!(frames.len(), 44100_usize.div_ceil(4096));
+        for (i, frame) in frames.iter().enumerate() {
+            assert_eq!(frame.frame_number, i as u32);
+        }
+        let total_samples: u32 = frames.iter().map(|f| f.samples).sum();
+        assert_eq!(total_samples, 44100);
+
+        let frame_bytes: usize = frames.iter().map(|f| f.bytes).sum();
+        let header_len = encoded.len() - frame_bytes;
+        assert_eq!(&encoded[header_len..header_len + 2], &[0xff, 0xf8]);
+    }
+
+    #[test]
+    fn test_round_trip_32bit_integer() {
+        if !version::flac_version_at_least(1, 4) {
+            return;
+        }
+        let frames = 10_000;
+        for channels in [1usize, 2] {
+            let samples = white_noise(32, channels, frames);
+            assert_round_trip("white noise 32-bit", &samples, 32, channels as u32);
+            let samples = full_scale_square(32, channels, frames);
+            assert_round_trip("square 32-bit", &samples, 32, channels as u32);
+        }
+    }
+
+    #[test]
+    fn test_panicking_frame_callback_is_reported() {
+        let mut encoder = FlacEncoder::new(44100, 16, 2, 4096, 5);
+        encoder.set_frame_callback(|_| panic!("boom"));
+        encoder.init().unwrap();
+
+        let samples = silence(2, 8192);
+        let mut output = vec![0u8; 1 << 16];
+        match encoder.encode_interleaved(&samples, &mut output) {
+            Err(FlacError::CallbackPanicked(msg)) => assert_eq!(msg, "boom"),
+            other => panic!("expected CallbackPanicked, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_metadata_must_be_added_before_init() {
+        let mut encoder = FlacEncoder::new(44100, 16, 2, 0, 5);
+        encoder.add_tag("ARTIST", "Someone").unwrap();
+        encoder
+            .add_picture(Picture::front_cover("image/png", vec![1, 2, 3]))
+            .unwrap();
+        encoder.add_seek_placeholders(4).unwrap();
+        encoder.set_padding(512).unwrap();
+        encoder.init().unwrap();
+
+        assert_eq!(
+            encoder.add_tag("TITLE", "Late"),
+            Err(FlacError::AlreadyInitialized)
+        );
+        assert_eq!(encoder.set_padding(16), Err(FlacError::AlreadyInitialized));
+
+        let mut output = vec![0u8; 1 << 16];
+        let len = encoder
+            .encode_interleaved(&silence(2, 4096), &mut output)
+            .unwrap();
+        let header = &output[..len];
+        assert!(header
+            .windows(b"ARTIST=Someone".len())
+            .any(|w| w == b"ARTIST=Someone"));
+        assert!(header
+            .windows(b"image/png".len())
+            .any(|w| w == b"image/png"));
+
+        encoder.finish(&mut output).unwrap();
+        assert_eq!(encoder.add_tag("TITLE", "Next track"), Ok(()));
+    }
+
+    #[test]
+    fn test_next_block_pulls_fixed_size_blocks() {
+        let samples = sine_sweep(16, 2, 44100 + 123);
+        let encoded = encode_all(&samples, 16, 2);
+
+        let mut decoder = FlacDecoder::new();
+        decoder.init().expect("Decoder initialization failed");
+        decoder.feed(&encoded);
+
+        let mut decoded = Vec::new();
+        while let Some(block) = decoder.next_block(512).unwrap() {
+            assert!(!block.is_empty());
+            if block.len() < 512 {
+                assert_eq!(decoded.len() + block.len(), samples.len());
+            }
+            decoded.extend_from_slice(&block);
+        }
+
+        assert_eq!(decoded, samples);
+        assert_eq!(decoder.next_block(512).unwrap(), None);
+    }
+
+    #[test]
+    fn test_chunked_processing_matches_single_call() {
+        let samples = white_noise(16, 2, 100_000);
+        let encode = |chunk_samples: u32, output_len: usize| {
+            let mut encoder = FlacEncoder::new(44100, 16, 2, 0, 5);
+            encoder.set_process_chunk_samples(chunk_samples);
+            encoder.init().unwrap();
+            let mut output = vec![0u8; output_len];
+            let result = encoder.encode_interleaved(&samples, &mut output);
+            (result, output)
+        };
+
+        let (whole, whole_output) = encode(0, 1 << 20);
+        let whole_len = whole.unwrap();
+        let (chunked, chunked_output) = encode(1000, 1 << 20);
+        assert_eq!(chunked, Ok(whole_len));
+        assert_eq!(chunked_output[..whole_len], whole_output[..whole_len]);
+
+        // Running out of room part way through reports everything as pending.
+        let (result, _) = encode(1000, 4096);
+        assert_eq!(
+            result,
+            Err(FlacError::OutputTooSmall {
+                needed: whole_len,
+                available: 4096
+            })
+        );
+    }
+
+    #[test]
+    fn test_process_splits_batches_at_the_frame_limit() {
+        // Stands in for an input of more than u32::MAX samples per channel,
+        // which would be too large to allocate in a test.
+        let samples = white_noise(16, 2, 10_001);
+        let encode = |max_frames: usize| {
+            let mut encoder = FlacEncoder::new(44100, 16, 2, 0, 5);
+            encoder.init().unwrap();
+            encoder.process_batched(&samples, max_frames).unwrap();
+            let mut output = vec![0u8; 1 << 20];
+            let mut len = encoder.drain_into(&mut output).unwrap();
+            len += encoder.finish(&mut output[len..]).unwrap();
+            output.truncate(len);
+            output
+        };
+
+        let whole = encode(MAX_PROCESS_FRAMES);
+        assert_eq!(encode(999), whole);
+        assert_eq!(decode_all(&whole), samples);
+    }
+
+    #[test]
+    fn test_encode_and_verify() {
+        let samples = white_noise(24, 2, 30_000);
+        let config = EncoderConfig::new(48000, 2, 24).compression_level(8);
+        let encoded = encode_and_verify(&config, &samples).unwrap();
+        assert_eq!(parse_streaminfo(&encoded).unwrap().bits_per_sample, 24);
+        assert_eq!(decode_all(&encoded), samples);
+
+        // A trailing half frame can't be encoded, so it never comes back.
+        let mut odd = samples.clone();
+        odd.push(1);
+        assert_eq!(
+            encode_and_verify(&config, &odd),
+            Err(FlacError::VerifyMismatch {
+                sample: samples.len()
+            })
+        );
+    }
+
+    #[test]
+    fn test_encode_from_iter() {
+        let samples = sine_sweep(16, 2, 10_000);
+        let mut encoder = FlacEncoder::new(44100, 16, 2, 0, 5);
+        encoder.set_process_chunk_samples(1000);
+        encoder.init().unwrap();
+
+        // Split mid-frame so the trailing sample waits for the next call.
+        let (head, tail) = samples.split_at(5001);
+        let mut encoded = Vec::new();
+        encoder
+            .encode_from_iter(head.iter().copied(), &mut encoded)
+            .unwrap();
+        let mut output = vec![0u8; 1 << 16];
+        assert_eq!(
+            encoder.finish(&mut output),
+            Err(FlacError::PartialFrame { samples: 1 })
+        );
+
+        encoder
+            .encode_from_iter(tail.iter().copied(), &mut encoded)
+            .unwrap();
+        let len = encoder.finish(&mut output).unwrap();
+        encoded.extend_from_slice(&output[..len]);
+        assert_eq!(decode_all(&encoded), samples);
+    }
+
+    #[test]
+    fn test_max_lpc_order_overrides_preset() {
+        let mut encoder = FlacEncoder::new(44100, 16, 2, 0, 8);
+        encoder.set_lpc_order(0, 4).unwrap();
+        encoder.init().unwrap();
+        assert_eq!(encoder.max_lpc_order(), 4);
+
+        let mut output = vec![0u8; 1 << 20];
+        let len = encoder
+            .encode_interleaved(&sine_sweep(16, 2, 8192), &mut output)
+            .unwrap();
+        assert!(len > 0);
+        assert_eq!(
+            encoder.set_lpc_order(0, 8),
+            Err(FlacError::AlreadyInitialized)
+        );
+    }
+
+    #[test]
+    fn test_overrides_survive_a_later_compression_level() {
+        let config = EncoderConfig::new(44100, 2, 16)
+            .lpc_order(0, 4)
+            .trust_level(TrustLevel::Archival)
+            .compression_level(8);
+        let mut encoder = FlacEncoder::from_config(&config);
+        encoder.init().unwrap();
+        // level 8 alone would give order 12 and no exhaustive search
+        assert_eq!(encoder.max_lpc_order(), 4);
+        assert_ne!(
+            unsafe { ffi::FLAC__stream_encoder_get_do_exhaustive_model_search(encoder.encoder) },
+            0
+        );
+
+        encoder.reset_with(config.compression_level(8)).unwrap();
+        assert_eq!(encoder.max_lpc_order(), 4);
+        let preset = EncoderConfig::new(44100, 2, 16).compression_level(8);
+        encoder.reset_with(preset).unwrap();
+        assert_eq!(encoder.max_lpc_order(), 12);
+    }
+
+    // Miri can't run this (it calls into libFLAC), so the leak check is done
+    // by counting the references to something the write state owns.
+    #[test]
+    fn test_reset_and_drop_free_the_write_state() {
+        let tracker = Arc::new(());
+        let mut encoder = FlacEncoder::new(44100, 16, 2, 0, 0);
+        let held = tracker.clone();
+        encoder.set_frame_callback(move |_| {
+            let _ = &held;
+        });
+        encoder.init().unwrap();
+        let client_data = encoder.client_data();
+
+        let input = vec![0i32; 1024];
+        let mut output = vec![0u8; 1 << 16];
+        for _ in 0..10_000 {
+            encoder.encode_i32(&input, &mut output).unwrap();
+            encoder.reset().unwrap();
+            assert_eq!(Arc::strong_count(&tracker), 2);
+        }
+
+        // moving the encoder doesn't move what libFLAC points at
+        let mut moved = vec![encoder];
+        assert_eq!(moved[0].client_data(), client_data);
+        moved[0].encode_i32(&input, &mut output).unwrap();
+        drop(moved);
+        assert_eq!(Arc::strong_count(&tracker), 1);
+    }
+
+    #[test]
+    fn test_encoder_moves_across_threads() {
+        fn assert_send<T: Send>() {}
+        assert_send::<FlacEncoder>();
+
+        let samples = white_noise(16, 2, 30_000);
+        let expected = encode_all(&samples, 16, 2);
+        let mut encoder = FlacEncoder::new(44100, 16, 2, 0, 5);
+        encoder.init().unwrap();
+        let first = samples.len() / 2;
+        let mut output = vec![0u8; 1 << 20];
+        let len = encoder
+            .encode_interleaved(&samples[..first], &mut output)
+            .unwrap();
+        output.truncate(len);
+
+        let rest = samples[first..].to_vec();
+        let encoded = std::thread::spawn(move || {
+            let mut tail = vec![0u8; 1 << 20];
+            let mut len = encoder.encode_interleaved(&rest, &mut tail).unwrap();
+            len += encoder.finish(&mut tail[len..]).unwrap();
+            output.extend_from_slice(&tail[..len]);
+            output
+        })
+        .join()
+        .unwrap();
+        assert_eq!(encoded, expected);
+    }
+
+    #[test]
+    fn test_seek_index_maps_samples_to_offsets() {
+        let samples = white_noise(16, 2, 20_000);
+        let mut encoder = FlacEncoder::new(44100, 16, 2, 1024, 5);
+        encoder.init().unwrap();
+
+        let mut encoded = Vec::new();
+        let mut output = vec![0u8; 1 << 20];
+        for chunk in samples.chunks(3000 * 2) {
+            let (len, index) = encoder.encode_i32_indexed(chunk, &mut output).unwrap();
+            assert!(index
+                .iter()
+                .all(|p| p.byte_offset < (encoded.len() + len) as u64));
+            encoded.extend_from_slice(&output[..len]);
+        }
+        let len = encoder.finish(&mut output).unwrap();
+        encoded.extend_from_slice(&output[..len]);
+
+        let index = encoder.seek_index().to_vec();
+        assert_eq!(index.len(), 20_000usize.div_ceil(1024));
+        let mut sample_number = 0;
+        for point in &index {
+            assert_eq!(point.sample_number, sample_number);
+            // every frame starts with the fixed-blocksize sync code
+            let offset = point.byte_offset as usize;
+            assert_eq!(&encoded[offset..offset + 2], &[0xff, 0xf8]);
+            sample_number += point.frame_samples as u64;
+        }
+        assert_eq!(sample_number, 20_000);
+    }
+
+    #[test]
+    fn test_reset_with_changes_block_size() {
+        let samples = white_noise(16, 2, 20_000);
+        let mut encoder = FlacEncoder::new(44100, 16, 2, 1024, 5);
+        let mut output = vec![0u8; 1 << 20];
+
+        let mut encode = |encoder: &mut FlacEncoder| {
+            let mut encoded = Vec::new();
+            let len = encoder.encode_interleaved(&samples, &mut output).unwrap();
+            encoded.extend_from_slice(&output[..len]);
+            let len = encoder.finish(&mut output).unwrap();
+            encoded.extend_from_slice(&output[..len]);
+            encoded
+        };
+
+        encoder.init().unwrap();
+        let first = encode(&mut encoder);
+        assert_eq!(parse_streaminfo(&first).unwrap().max_block_size, 1024);
+
+        let config = EncoderConfig::new(44100, 2, 16).block_size(4608);
+        encoder.reset_with(config.clone()).unwrap();
+        let second = encode(&mut encoder);
+        assert_eq!(parse_streaminfo(&second).unwrap().max_block_size, 4608);
+        assert_eq!(decode_all(&second), samples);
+
+        // A rejected config leaves the current settings in place.
+        assert!(encoder.reset_with(config.clone().lpc_order(0, 40)).is_err());
+        assert_eq!(encoder.config(), &config);
+    }
+
+    #[cfg(debug_assertions)]
+    #[test]
+    fn test_scaling_problem_detects_left_justified_input() {
+        let samples = white_noise(16, 2, 2048);
+        assert_eq!(scaling_problem(&samples, 16), None);
+        assert_eq!(scaling_problem(&silence(2, 2048), 24), None);
+
+        // 16-bit values shifted into the top of the i32
+        let shifted: Vec<i32> = samples.iter().map(|&x| x << 16).collect();
+        assert!(scaling_problem(&shifted, 16)
+            .unwrap()
+            .contains("out of range"));
+
+        // 16-bit values in a 24-bit stream
+        let padded: Vec<i32> = samples.iter().map(|&x| x << 8).collect();
+        assert!(scaling_problem(&padded, 24).unwrap().contains("low 8 bits"));
+    }
+
+    #[test]
+    fn test_decode_robust_round_trip() {
+        let samples = sine_sweep(24, 2, 10_000);
+        let decoded = decode_robust(&encode_all(&samples, 24, 2)).unwrap();
+        assert_eq!(
+            decoded,
+            DecodedAudio {
+                sample_rate: 44100,
+                channels: 2,
+                bits_per_sample: 24,
+                samples,
+            }
+        );
+    }
+
+    #[test]
+    fn test_decode_robust_caps_the_output() {
+        let samples = white_noise(16, 2, 10_000);
+        let encoded = encode_all(&samples, 16, 2);
+        // a streaming encoder leaves STREAMINFO's total at 0
+        assert_eq!(parse_streaminfo(&encoded).unwrap().total_samples, 0);
+        assert_eq!(
+            decode_robust_with_limit(&encoded, 20_000).unwrap().samples,
+            samples
+        );
+        assert!(matches!(
+            decode_robust_with_limit(&encoded, 19_999),
+            Err(FlacError::Decoder(_))
+        ));
+
+        // a declared total above the cap fails up front
+        let mut declared = encoded.clone();
+        // total_samples is the low 36 bits of STREAMINFO bytes 13..18
+        declared[8 + 17] = 0x10;
+        assert_eq!(parse_streaminfo(&declared).unwrap().total_samples, 0x10);
+        assert!(matches!(
+            decode_robust_with_limit(&declared, 16),
+            Err(FlacError::Decoder(msg)) if msg.contains("declares")
+        ));
+    }
+
+    // A cheap stand-in for the fuzz target that runs with the normal tests.
+    #[test]
+    fn test_decode_robust_survives_corrupt_input() {
+        let encoded = encode_all(&white_noise(16, 2, 10_000), 16, 2);
+        let mut rng = 0x9e3779b9u32;
+        let mut next = || {
+            rng ^= rng << 13;
+            rng ^= rng >> 17;
+            rng ^= rng << 5;
+            rng
+        };
+
+        for _ in 0..200 {
+            let mut corrupt = encoded.clone();
+            for _ in 0..1 + next() % 8 {
+                let i = next() as usize % corrupt.len();
+                corrupt[i] ^= 1 << (next() % 8);
+            }
+            corrupt.truncate(next() as usize % corrupt.len() + 1);
+            // only a panic fails the test; most inputs are errors
+            let _ = decode_robust(&corrupt);
+        }
+
+        let random: Vec<u8> = (0..4096).map(|_| next() as u8).collect();
+        assert_eq!(decode_robust(&random), Err(FlacError::BadMarker));
+    }
+
+    fn metadata_block(data: &[u8], block_type: u8) -> Option<Vec<u8>> {
+        header::MetadataBlocks::new(data)
+            .unwrap()
+            .map(|block| block.unwrap())
+            .find(|block| block.block_type == block_type)
+            .map(|block| block.body.to_vec())
+    }
+
+    #[test]
+    fn test_seekable_sink_fills_seek_table() {
+        let frames = 100_000;
+        let samples = white_noise(16, 2, frames);
+        let path =
+            std::env::temp_dir().join(format!("soundkit-flac-seek-{}.flac", std::process::id()));
+
+        let mut encoder = FlacEncoder::new(44100, 16, 2, 4096, 5);
+        assert!(!encoder.is_seekable_output());
+        assert!(matches!(
+            encoder
+                .add_seek_points(10_000, frames as u64)
+                .and_then(|_| encoder.restart()),
+            Err(FlacError::InvalidConfig(_))
+        ));
+
+        encoder
+            .set_seekable_sink(File::create(&path).unwrap())
+            .unwrap();
+        assert!(encoder.is_seekable_output());
+        encoder.init().unwrap();
+        let mut output = vec![0u8; 1 << 16];
+        assert_eq!(encoder.encode_interleaved(&samples, &mut output), Ok(0));
+        assert_eq!(encoder.finish(&mut output), Ok(0));
+        drop(encoder.take_sink().unwrap());
+
+        let encoded = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(decode_all(&encoded), samples);
+        assert_eq!(
+            parse_streaminfo(&encoded).unwrap().total_samples,
+            frames as u64
+        );
+
+        let seek_table = metadata_block(&encoded, 3).expect("no SEEKTABLE");
+        assert_eq!(seek_table.len(), 10 * 18);
+        let mut last_offset = None;
+        for point in seek_table.chunks(18) {
+            let sample_number = u64::from_be_bytes(point[..8].try_into().unwrap());
+            let offset = u64::from_be_bytes(point[8..16].try_into().unwrap());
+            let frame_samples = u16::from_be_bytes([point[16], point[17]]);
+            assert_ne!(sample_number, u64::MAX, "placeholder left in seek table");
+            assert_ne!(frame_samples, 0, "seek point {} not filled", sample_number);
+            assert!(last_offset.map_or(offset == 0, |last| offset > last));
+            last_offset = Some(offset);
+        }
+
+        // every filled point is a frame of the encoder's own index
+        let points = read_seek_table(&encoded).unwrap();
+        assert_eq!(points.len(), 10);
+        let index = encoder.seek_index();
+        assert!(points.iter().all(|point| index.contains(point)));
+    }
+
+    #[test]
+    fn test_metadata_blocks_live_as_long_as_the_encoder() {
+        // libFLAC reads the blocks at init and writes the seek table into
+        // them at finish, so freeing them early would show up as lost tags
+        // or unfilled seek points in a later stream.
+        let frames = 50_000;
+        let samples = white_noise(16, 2, frames);
+        let mut encoder = FlacEncoder::new(44100, 16, 2, 4096, 5);
+        encoder.add_tag("TITLE", "kept").unwrap();
+        encoder
+            .add_picture(Picture::front_cover("image/png", vec![7; 64]))
+            .unwrap();
+        encoder.add_seek_points(10_000, frames as u64).unwrap();
+        encoder.set_padding(128).unwrap();
+
+        for round in 0..3 {
+            let path = std::env::temp_dir().join(format!(
+                "soundkit-flac-blocks-{}-{}.flac",
+                std::process::id(),
+                round
+            ));
+            encoder
+                .set_seekable_sink(File::create(&path).unwrap())
+                .unwrap();
+            encoder.restart().unwrap();
+            let mut output = vec![0u8; 1 << 16];
+            encoder.encode_interleaved(&samples, &mut output).unwrap();
+            encoder.finish(&mut output).unwrap();
+            drop(encoder.take_sink().unwrap());
+
+            let encoded = std::fs::read(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(decode_all(&encoded), samples);
+            let tags = metadata_block(&encoded, 4).expect("no VORBIS_COMMENT");
+            assert!(tags.windows(10).any(|w| w == b"TITLE=kept"));
+            let picture = metadata_block(&encoded, 6).expect("no PICTURE");
+            assert!(picture.ends_with(&[7; 64]));
+            let seek_table = metadata_block(&encoded, 3).expect("no SEEKTABLE");
+            assert!(seek_table
+                .chunks(18)
+                .all(|point| u16::from_be_bytes([point[16], point[17]]) != 0));
+        }
+        drop(encoder);
+    }
+
+    #[test]
+    fn test_drain_complete_frames_is_frame_aligned() {
+        let samples = white_noise(16, 2, 30_000);
+        let mut encoder = FlacEncoder::new(44100, 16, 2, 4096, 5);
+        encoder.init().unwrap();
+
+        let header = encoder.drain_complete_frames();
+        assert!(header.starts_with(b"fLaC"));
+        assert!(parse_streaminfo(&header).is_ok());
+
+        let mut stream = header.clone();
+        let mut output = vec![0u8; 1 << 16];
+        for chunk in samples.chunks(1000 * 2) {
+            assert_eq!(encoder.encode_buffered(chunk), Ok(()));
+            let packet = encoder.drain_complete_frames();
+            if !packet.is_empty() {
+                let offsets: Vec<u64> =
+                    encoder.seek_index().iter().map(|p| p.byte_offset).collect();
+                assert!(offsets.contains(&(stream.len() as u64)));
+                assert_eq!(&packet[..2], &[0xff, 0xf8]);
+            }
+            stream.extend_from_slice(&packet);
+        }
+        let len = encoder.finish(&mut output).unwrap();
+        stream.extend_from_slice(&output[..len]);
+        assert_eq!(decode_all(&stream), samples);
+    }
+
+    #[test]
+    fn test_metadata_order() {
+        let mut encoder = FlacEncoder::new(44100, 16, 2, 0, 5);
+        encoder.add_tag("TITLE", "Ordered").unwrap();
+        encoder
+            .add_picture(Picture::front_cover("image/png", vec![0; 16]))
+            .unwrap();
+        encoder.set_padding(64).unwrap();
+        encoder
+            .set_metadata_order(&[MetadataKind::Padding, MetadataKind::Picture])
+            .unwrap();
+        encoder.init().unwrap();
+
+        let header = encoder.drain_complete_frames();
+        let types: Vec<u8> = header::MetadataBlocks::new(&header)
+            .unwrap()
+            .map(|block| block.unwrap().block_type)
+            .collect();
+        // STREAMINFO, PADDING, PICTURE, then the unlisted VORBIS_COMMENT
+        assert_eq!(types, vec![0, 1, 6, 4]);
+    }
+
+    #[test]
+    fn test_application_blocks() {
+        let mut encoder = FlacEncoder::new(44100, 16, 2, 0, 5);
+        encoder
+            .add_application(ApplicationId::RIFF_CHUNKS, b"LIST1234".to_vec())
+            .unwrap();
+        encoder
+            .add_application(ApplicationId::from_bytes(*b"test"), Vec::new())
+            .unwrap();
+        encoder.set_padding(16).unwrap();
+        encoder.init().unwrap();
+
+        let header = encoder.drain_complete_frames();
+        let blocks: Vec<(u8, Vec<u8>)> = header::MetadataBlocks::new(&header)
+            .unwrap()
+            .map(|block| block.unwrap())
+            .map(|block| (block.block_type, block.body.to_vec()))
+            .collect();
+        let types: Vec<u8> = blocks.iter().map(|(block_type, _)| *block_type).collect();
+        assert_eq!(types, vec![0, 4, 2, 2, 1]);
+        assert_eq!(blocks[2].1, b"riffLIST1234");
+        assert_eq!(blocks[3].1, b"test");
+        assert_eq!(ApplicationId::XMCD.as_bytes(), *b"xmcd");
+    }
+
+    #[test]
+    fn test_encode_channels_matches_interleaved() {
+        let interleaved = sine_sweep(16, 2, 10_000);
+        let planar: Vec<Vec<i32>> = (0..2)
+            .map(|c| interleaved.iter().skip(c).step_by(2).copied().collect())
+            .collect();
+
+        let mut encoder = FlacEncoder::new(44100, 16, 2, 0, 5);
+        encoder.init().unwrap();
+        let mut encoded = Vec::new();
+        let mut output = vec![0u8; 1 << 20];
+        let len = encoder.encode_channels(&planar, &mut output).unwrap();
+        encoded.extend_from_slice(&output[..len]);
+        let len = encoder.finish(&mut output).unwrap();
+        encoded.extend_from_slice(&output[..len]);
+        assert_eq!(encoded, encode_all(&interleaved, 16, 2));
+
+        encoder.reset().unwrap();
+        let slices: Vec<&[i32]> = planar.iter().map(|c| &c[..100]).collect();
+        assert!(encoder.encode_channels(&slices, &mut output).is_ok());
+        assert!(matches!(
+            encoder.encode_channels(&planar[..1], &mut output),
+            Err(FlacError::InvalidInput(_))
+        ));
+        assert!(matches!(
+            encoder.encode_channels(&[&planar[0][..10], &planar[1][..9]], &mut output),
+            Err(FlacError::InvalidInput(_))
+        ));
+    }
+
+    #[test]
+    fn test_decoder_metadata_events_come_before_audio() {
+        let mut encoder = FlacEncoder::new(44100, 16, 2, 0, 5);
+        encoder.add_tag("TITLE", "Events").unwrap();
+        encoder
+            .add_picture(Picture::front_cover("image/jpeg", vec![7; 32]))
+            .unwrap();
+        encoder.init().unwrap();
+        let mut encoded = encoder.drain_complete_frames();
+        let mut output = vec![0u8; 1 << 20];
+        let len = encoder
+            .encode_interleaved(&sine_sweep(16, 2, 10_000), &mut output)
+            .unwrap();
+        encoded.extend_from_slice(&output[..len]);
+        let len = encoder.finish(&mut output).unwrap();
+        encoded.extend_from_slice(&output[..len]);
+
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let mut decoder = FlacDecoder::new();
+        let log = events.clone();
+        decoder
+            .on_streaminfo(move |info| log.borrow_mut().push(format!("rate {}", info.sample_rate)))
+            .unwrap();
+        let log = events.clone();
+        decoder
+            .on_vorbis_comment(move |comment| {
+                log.borrow_mut()
+                    .push(format!("tags {:?}", comment.comments))
+            })
+            .unwrap();
+        let log = events.clone();
+        decoder
+            .on_picture(move |picture| log.borrow_mut().push(picture.mime_type.clone()))
+            .unwrap();
+        decoder.init().unwrap();
+        assert_eq!(
+            decoder.on_picture(|_| {}),
+            Err(FlacError::AlreadyInitialized)
+        );
+
+        decoder.feed(&encoded);
+        assert!(decoder.next_block(512).unwrap().is_some());
+        let expected = vec![
+            "rate 44100".to_string(),
+            format!(
+                "tags {:?}",
+                vec![("TITLE".to_string(), "Events".to_string())]
+            ),
+            "image/jpeg".to_string(),
+        ];
+        assert_eq!(*events.borrow(), expected);
+
+        decoder.decode_remaining().unwrap();
+        assert_eq!(*events.borrow(), expected);
+    }
+
+    #[cfg(feature = "container-ogg")]
+    #[test]
+    fn test_ogg_container() {
+        let config = EncoderConfig::new(44100, 2, 16).container(Container::Ogg);
+        let mut encoder = FlacEncoder::from_config(&config);
+        encoder.init().unwrap();
+
+        let mut encoded = Vec::new();
+        let mut output = vec![0u8; 1 << 20];
+        let len = encoder
+            .encode_interleaved(&sine_sweep(16, 2, 10_000), &mut output)
+            .unwrap();
+        encoded.extend_from_slice(&output[..len]);
+        let len = encoder.finish(&mut output).unwrap();
+        encoded.extend_from_slice(&output[..len]);
+
+        assert!(encoded.starts_with(b"OggS"));
+        // the first packet is the Ogg FLAC mapping header wrapping "fLaC"
+        assert!(encoded.windows(5).any(|w| w == b"\x7fFLAC"));
+        assert!(encoded.windows(4).any(|w| w == b"fLaC"));
+    }
+
+    #[cfg(feature = "container-ogg")]
+    #[test]
+    fn test_ogg_frames_spanning_pages() {
+        // noisy 24-bit blocks are far larger than an Ogg page
+        let config = EncoderConfig::new(48000, 2, 24)
+            .block_size(4608)
+            .container(Container::Ogg);
+        let mut encoder = FlacEncoder::from_config(&config);
+        encoder.init().unwrap();
+
+        let mut encoded = Vec::new();
+        let mut output = vec![0u8; 1 << 22];
+        for chunk in white_noise(24, 2, 50_000).chunks(2 * 1000) {
+            let len = encoder.encode_interleaved(chunk, &mut output).unwrap();
+            encoded.extend_from_slice(&output[..len]);
+        }
+        let len = encoder.finish(&mut output).unwrap();
+        encoded.extend_from_slice(&output[..len]);
+
+        let pages = encoded.windows(4).filter(|w| w == b"OggS").count();
+        assert!(pages > 50_000 / 4608 + 2);
+        assert!(encoder.seek_index().is_empty());
+        assert_eq!(encoder.frame_size_range(), None);
+        assert!(encoder.achieved_bitrate().is_some());
+    }
+
+    #[test]
+    fn test_encoder_and_decoder_duration() {
+        let samples = sine_sweep(16, 2, 66_150);
+        let config = EncoderConfig::new(44100, 2, 16).total_samples(66_150);
+        assert_eq!(FlacEncoder::new(44100, 16, 2, 0, 5).duration(), None);
+
+        let mut encoder = FlacEncoder::from_config(&config);
+        assert_eq!(encoder.duration(), Some(Duration::from_millis(1500)));
+        encoder.init().unwrap();
+        let mut encoded = Vec::new();
+        let mut output = vec![0u8; 1 << 20];
+        let len = encoder.encode_interleaved(&samples, &mut output).unwrap();
+        encoded.extend_from_slice(&output[..len]);
+        let len = encoder.finish(&mut output).unwrap();
+        encoded.extend_from_slice(&output[..len]);
+
+        let mut decoder = FlacDecoder::new();
+        decoder.init().unwrap();
+        assert_eq!(decoder.duration(), None);
+        decoder.feed(&encoded);
+        decoder.next_block(1).unwrap();
+        assert_eq!(decoder.duration(), Some(Duration::from_millis(1500)));
+
+        // without a total the stream records 0 samples
+        let mut decoder = FlacDecoder::new();
+        decoder.init().unwrap();
+        decoder.feed(&encode_all(&samples, 16, 2));
+        decoder.next_block(1).unwrap();
+        assert!(decoder.stream_info().is_some());
+        assert_eq!(decoder.duration(), None);
+    }
+
+    #[test]
+    fn test_declared_total_samples_for_unseekable_output() {
+        let samples = sine_sweep(16, 2, 10_000);
+        let encode = |total_samples: u64| {
+            let config = EncoderConfig::new(44100, 2, 16)
+                .block_size(4096)
+                .total_samples(total_samples);
+            let mut encoder = FlacEncoder::from_config(&config);
+            encoder.init().unwrap();
+            let mut output = vec![0u8; 1 << 20];
+            let len = encoder.encode_interleaved(&samples, &mut output).unwrap();
+            // the header goes out with the first bytes and is never rewritten
+            let header = parse_streaminfo(&output[..len]).unwrap();
+            (header, encoder.finish(&mut output[len..]))
+        };
+
+        let (header, result) = encode(10_000);
+        assert!(result.is_ok());
+        assert_eq!(header.total_samples, 10_000);
+        assert_eq!((header.min_block_size, header.max_block_size), (4096, 4096));
+        assert_eq!(header.duration(), Some(Duration::new(0, 226_757_369)));
+
+        let (_, result) = encode(20_000);
+        assert_eq!(
+            result,
+            Err(FlacError::TotalSamplesMismatch {
+                declared: 20_000,
+                actual: 10_000
+            })
+        );
+    }
+
+    #[test]
+    fn test_bitrates() {
+        let mut encoder = FlacEncoder::new(44100, 16, 2, 4096, 5);
+        assert_eq!(encoder.max_bitrate(), 1_411_200);
+        encoder.init().unwrap();
+        assert_eq!(encoder.achieved_bitrate(), None);
+
+        // silence compresses far below the PCM rate
+        let mut output = vec![0u8; 1 << 20];
+        let mut len = encoder
+            .encode_interleaved(&vec![0; 44100 * 2], &mut output)
+            .unwrap();
+        let silent = encoder.achieved_bitrate().unwrap();
+        assert!(silent < encoder.max_bitrate() / 10, "{}", silent);
+
+        let noise = white_noise(16, 2, 44100);
+        len += encoder
+            .encode_interleaved(&noise, &mut output[len..])
+            .unwrap();
+        len += encoder.finish(&mut output[len..]).unwrap();
+        let average = encoder.achieved_bitrate().unwrap();
+        assert!(average > silent);
+        // two seconds of audio: frame bits over the duration
+        let frame_bytes = len - encoder.seek_index()[0].byte_offset as usize;
+        assert_eq!(average as usize, frame_bytes * 8 / 2);
+    }
+
+    #[test]
+    fn test_rejected_settings_are_reported() {
+        let config = EncoderConfig::new(44100, 2, 16);
+        let mut encoder = FlacEncoder::from_config(&config);
+        encoder.init().unwrap();
+        // an initialized libFLAC encoder refuses every setter
+        let result = unsafe { apply_settings(encoder.encoder, &config, Some(1000)) };
+        assert_eq!(
+            result,
+            Err(FlacError::SettingRejected {
+                name: "block_size",
+                value: "0".to_string()
+            })
+        );
+
+        let uninitialized = unsafe { ffi::FLAC__stream_encoder_new() };
+        let result = unsafe { apply_settings(uninitialized, &config, Some(1000)) };
+        unsafe { ffi::FLAC__stream_encoder_delete(uninitialized) };
+        assert_eq!(result, Ok(()));
+    }
+
+    #[test]
+    fn test_decode_range_trims_to_exact_samples() {
+        let frames = 44100 * 3;
+        let samples = white_noise(16, 2, frames);
+        let streamed = encode_all(&samples, 16, 2);
+        let ms = Duration::from_millis;
+        // 0.25 s and 1.1 s both fall inside 4096-sample blocks
+        let expected = &samples[11025 * 2..48510 * 2];
+        assert_eq!(
+            decode_range(&streamed, ms(250), ms(1100)).unwrap(),
+            expected
+        );
+
+        // with a seek table and known length libFLAC seeks instead of
+        // decoding from the start
+        let path =
+            std::env::temp_dir().join(format!("soundkit-flac-range-{}.flac", std::process::id()));
+        let mut encoder = FlacEncoder::new(44100, 16, 2, 4096, 5);
+        encoder.add_seek_points(4410, frames as u64).unwrap();
+        encoder
+            .set_seekable_sink(File::create(&path).unwrap())
+            .unwrap();
+        encoder.init().unwrap();
+        let mut output = vec![0u8; 1 << 16];
+        encoder.encode_interleaved(&samples, &mut output).unwrap();
+        encoder.finish(&mut output).unwrap();
+        drop(encoder.take_sink().unwrap());
+        let seekable = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(
+            decode_range(&seekable, ms(250), ms(1100)).unwrap(),
+            expected
+        );
+        assert_eq!(
+            decode_range(&seekable, ms(2500), ms(10_000)).unwrap(),
+            &samples[110_250 * 2..]
+        );
+        assert_eq!(
+            decode_range(&seekable, ms(0), ms(1)).unwrap(),
+            &samples[..44 * 2]
+        );
+        assert!(decode_range(&seekable, ms(500), ms(500))
+            .unwrap()
+            .is_empty());
+        assert!(decode_range(&seekable, ms(5000), ms(6000))
+            .unwrap()
+            .is_empty());
+        assert!(matches!(
+            decode_range(&seekable, ms(2), ms(1)),
+            Err(FlacError::InvalidInput(_))
+        ));
+    }
+
+    #[test]
+    fn test_validate_flac_accepts_encoder_output() {
+        let encoded = encode_all(&white_noise(16, 2, 10_000), 16, 2);
+        assert_eq!(validate_flac(&encoded), Ok(()));
+        let mut encoder = FlacEncoder::new(44100, 16, 2, 0, 5);
+        encoder.init().unwrap();
+        let header = encoder.drain_complete_frames();
+        assert!(matches!(
+            validate_flac(&header),
+            Err(FlacError::MissingFrameSync { .. })
+        ));
+    }
+
+    #[test]
+    fn test_channel_stats() {
+        let config = EncoderConfig::new(44100, 2, 16).channel_stats(true);
+        let mut encoder = FlacEncoder::from_config(&config);
+        encoder.init().unwrap();
+        let samples: Vec<i32> = (0..10_000).flat_map(|i| [i % 1001 - 500, 0]).collect();
+        let mut output = vec![0u8; 1 << 20];
+        let mut len = 0;
+        for chunk in samples.chunks(3000) {
+            len += encoder
+                .encode_interleaved(chunk, &mut output[len..])
+                .unwrap();
+        }
+        encoder.finish(&mut output[len..]).unwrap();
+
+        let stats = encoder.channel_stats();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].peak, 500);
+        assert!((stats[0].rms - 289.0).abs() < 1.0, "{}", stats[0].rms);
+        assert_eq!(stats[1].rms_dbfs, f64::NEG_INFINITY);
+
+        encoder.reset().unwrap();
+        assert_eq!(encoder.channel_stats()[0].peak, 0);
+        let mut plain = FlacEncoder::new(44100, 16, 2, 0, 5);
+        plain.init().unwrap();
+        assert!(plain.channel_stats().is_empty());
+    }
+
+    #[test]
+    fn test_accumulate_output() {
+        let samples = white_noise(16, 2, 30_000);
+        let expected = encode_all(&samples, 16, 2);
+        let config = EncoderConfig::new(44100, 2, 16).accumulate_output(true);
+        let mut encoder = FlacEncoder::from_config(&config);
+        encoder.init().unwrap();
+
+        let mut output = vec![0u8; 1 << 20];
+        let mut streamed = Vec::new();
+        for chunk in samples.chunks(7000) {
+            let len = encoder.encode_interleaved(chunk, &mut output).unwrap();
+            streamed.extend_from_slice(&output[..len]);
+            assert_eq!(*encoder.accumulated_output(), streamed[..]);
+        }
+        // a failed copy leaves the delta to be returned by the next call
+        assert!(matches!(
+            encoder.finish(&mut output[..1]),
+            Err(FlacError::OutputTooSmall { .. })
+        ));
+        streamed.extend_from_slice(&encoder.drain_complete_frames());
+
+        assert_eq!(streamed, expected);
+        assert_eq!(*encoder.accumulated_output(), expected[..]);
+        encoder.reset().unwrap();
+        assert!(encoder.accumulated_output().starts_with(b"fLaC"));
+        assert!(encoder.accumulated_output().len() < 100);
+    }
+
+    #[test]
+    fn test_empty_input_is_a_no_op() {
+        let samples = white_noise(16, 2, 20_000);
+        let expected = encode_all(&samples, 16, 2);
+        let mut encoder = FlacEncoder::new(44100, 16, 2, 0, 5);
+        encoder.init().unwrap();
+        let mut output = vec![0u8; 1 << 20];
+
+        // the only thing an empty call hands out is what is already pending
+        let header_len = encoder.encode_i32(&[], &mut output).unwrap();
+        assert!(parse_streaminfo(&output[..header_len]).is_ok());
+        let mut encoded = output[..header_len].to_vec();
+        assert_eq!(encoder.encode_i32(&[], &mut output), Ok(0));
+
+        for chunk in samples.chunks(5000) {
+            let len = encoder.encode_i32(chunk, &mut output).unwrap();
+            encoded.extend_from_slice(&output[..len]);
+            assert_eq!(encoder.encode_i32(&[], &mut output), Ok(0));
+            let no_channels: [&[i32]; 2] = [&[], &[]];
+            assert_eq!(encoder.encode_channels(&no_channels, &mut output), Ok(0));
+            assert_eq!(encoder.encode_buffered(&[]), Ok(()));
+            assert_eq!(
+                encoder.encode_from_iter(std::iter::empty(), &mut encoded),
+                Ok(0)
+            );
+        }
+        let len = encoder.finish(&mut output).unwrap();
+        encoded.extend_from_slice(&output[..len]);
+        assert_eq!(encoded, expected);
+        assert_eq!(
+            encoder.encode_i32(&[], &mut output),
+            Err("Stream already finished; call reset() to start a new one".to_string())
+        );
+    }
+
+    #[test]
+    fn test_last_pending_len_sizes_the_retry() {
+        let samples = white_noise(16, 2, 20_000);
+        let mut encoder = FlacEncoder::new(44100, 16, 2, 0, 5);
+        encoder.init().unwrap();
+        let header_len = encoder.last_pending_len();
+        assert!(header_len > 0);
+
+        let mut output = vec![0u8; 16];
+        let needed = match encoder.encode_interleaved(&samples, &mut output) {
+            Err(FlacError::OutputTooSmall { needed, .. }) => needed,
+            other => panic!("expected OutputTooSmall, got {:?}", other),
+        };
+        assert_eq!(encoder.last_pending_len(), needed);
+        assert!(needed > header_len);
+
+        output.resize(encoder.last_pending_len(), 0);
+        assert_eq!(encoder.encode_interleaved(&[], &mut output), Ok(needed));
+        assert_eq!(encoder.last_pending_len(), 0);
+    }
+
+    #[cfg(all(feature = "file-encoder", feature = "container-native"))]
+    #[test]
+    fn test_init_file_back_patches_the_header() {
+        let frames = 50_000;
+        let samples = white_noise(16, 2, frames);
+        let path =
+            std::env::temp_dir().join(format!("soundkit-flac-file-{}.flac", std::process::id()));
+
+        let mut encoder = FlacEncoder::new(44100, 16, 2, 4096, 5);
+        encoder.add_seek_points(10_000, frames as u64).unwrap();
+        assert!(matches!(
+            encoder.restart(),
+            Err(FlacError::InvalidConfig(_))
+        ));
+        encoder.init_file(&path).unwrap();
+        let mut output = vec![0u8; 1 << 16];
+        assert_eq!(encoder.encode_interleaved(&samples, &mut output), Ok(0));
+        assert_eq!(encoder.finish(&mut output), Ok(0));
+
+        let encoded = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(decode_all(&encoded), samples);
+        let info = parse_streaminfo(&encoded).unwrap();
+        assert_eq!(info.total_samples, frames as u64);
+        assert_ne!(info.md5, [0; 16]);
+        assert_ne!(info.min_frame_size, 0);
+        let seek_table = metadata_block(&encoded, 3).expect("no SEEKTABLE");
+        assert!(seek_table
+            .chunks(18)
+            .all(|point| u16::from_be_bytes([point[16], point[17]]) != 0));
+
+        let missing_dir = std::env::temp_dir().join("soundkit-flac-missing/out.flac");
+        assert!(matches!(
+            encoder.init_file(&missing_dir),
+            Err(FlacError::Encoder(_))
+        ));
+    }
+
+    #[test]
+    fn test_invalid_sample_rate_is_a_config_error() {
+        for rate in [0, 1_048_576] {
+            let mut encoder = FlacEncoder::from_config(&EncoderConfig::new(rate, 2, 16));
+            assert!(matches!(
+                encoder.restart(),
+                Err(FlacError::InvalidConfig(msg)) if msg.contains("sample rate")
+            ));
+        }
+    }
+
+    #[test]
+    fn test_auto_subset_lets_init_succeed() {
+        let config = EncoderConfig::new(44100, 2, 16).block_size(8192);
+        let mut encoder = FlacEncoder::from_config(&config);
+        assert!(encoder.restart().is_err());
+
+        encoder
+            .reset_with(config.clone().auto_subset(true))
+            .unwrap();
+        assert_eq!(encoder.applied_config().config.block_size, 4608);
+        assert_eq!(encoder.config().block_size, 8192);
+        let mut output = vec![0u8; 1 << 20];
+        let samples = white_noise(16, 2, 10_000);
+        let mut len = encoder.encode_interleaved(&samples, &mut output).unwrap();
+        len += encoder.finish(&mut output[len..]).unwrap();
+        assert_eq!(
+            parse_streaminfo(&output[..len]).unwrap().max_block_size,
+            4608
+        );
+        assert_eq!(decode_all(&output[..len]), samples);
+    }
+
+    #[cfg(feature = "md5-toggle")]
+    #[test]
+    fn test_write_md5_off_leaves_the_field_zeroed() {
+        let samples = sine_sweep(16, 2, 10_000);
+        for write_md5 in [true, false] {
+            let path = std::env::temp_dir().join(format!(
+                "soundkit-flac-md5-{}-{}.flac",
+                write_md5,
+                std::process::id()
+            ));
+            let config = EncoderConfig::new(44100, 2, 16).write_md5(write_md5);
+            let mut encoder = FlacEncoder::from_config(&config);
+            encoder
+                .set_seekable_sink(File::create(&path).unwrap())
+                .unwrap();
+            encoder.init().unwrap();
+            let mut output = vec![0u8; 1 << 16];
+            encoder.encode_interleaved(&samples, &mut output).unwrap();
+            encoder.finish(&mut output).unwrap();
+            drop(encoder.take_sink().unwrap());
+
+            let encoded = std::fs::read(&path).unwrap();
+            std::fs::remove_file(&path).unwrap();
+            assert_eq!(decode_all(&encoded), samples);
+            let info = parse_streaminfo(&encoded).unwrap();
+            assert_eq!(info.md5 == [0; 16], !write_md5);
+            assert_eq!(info.total_samples, 10_000);
+        }
+    }
+
+    #[test]
+    fn test_state_error_maps_the_encoder_state() {
+        let encoder = FlacEncoder::new(44100, 16, 2, 4096, 5);
+        assert_eq!(
+            encoder.state_error("process samples"),
+            FlacError::NotInitialized
+        );
+
+        let mut encoder = FlacEncoder::new(44100, 16, 2, 4096, 5);
+        encoder.init().unwrap();
+        let mut output = vec![0u8; 1 << 16];
+        encoder
+            .encode_interleaved(&sine_sweep(16, 2, 4096), &mut output)
+            .unwrap();
+        // A healthy encoder falls through to the generic message.
+        match encoder.state_error("finish stream") {
+            FlacError::Encoder(msg) => {
+                assert!(msg.starts_with("Failed to finish stream"), "{}", msg)
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_encode_audio_data() {
+        for (path, stream_bits) in [
+            ("testdata/s16le.wav", 16),
+            ("testdata/s24le.wav", 24),
+            ("testdata/f32le.wav", 24),
+        ] {
+            let wav = std::fs::read(path).unwrap();
+            let audio = WavStreamProcessor::new().add(&wav).unwrap().unwrap();
+            let expected = match audio.bits_per_sample() {
+                16 => s16le_to_i32(audio.data()),
+                24 => s24le_to_i32(audio.data()),
+                _ => f32le_to_s24(audio.data()),
+            };
+
+            let mut encoder = FlacEncoder::new(
+                audio.sampling_rate(),
+                stream_bits,
+                audio.channel_count() as u32,
+                4096,
+                5,
+            );
+            encoder.init().unwrap();
+            let mut output = vec![0u8; expected.len() * 4 + (1 << 16)];
+            let mut encoded = Vec::new();
+            let len = encoder.encode_audio_data(&audio, &mut output).unwrap();
+            encoded.extend_from_slice(&output[..len]);
+            let len = encoder.finish(&mut output).unwrap();
+            encoded.extend_from_slice(&output[..len]);
+            assert_eq!(decode_all(&encoded), expected, "{}", path);
+        }
+
+        let wav = std::fs::read("testdata/s16le.wav").unwrap();
+        let audio = WavStreamProcessor::new().add(&wav).unwrap().unwrap();
+        let mut output = vec![0u8; 1 << 16];
+        let channels = audio.channel_count() as u32;
+        let mut wrong_depth = FlacEncoder::new(audio.sampling_rate(), 24, channels, 4096, 5);
+        wrong_depth.init().unwrap();
+        assert!(matches!(
+            wrong_depth.encode_audio_data(&audio, &mut output),
+            Err(FlacError::InvalidInput(_))
+        ));
+        let mut wrong_rate = FlacEncoder::new(audio.sampling_rate() + 1, 16, channels, 4096, 5);
+        wrong_rate.init().unwrap();
+        assert!(matches!(
+            wrong_rate.encode_audio_data(&audio, &mut output),
+            Err(FlacError::InvalidInput(_))
+        ));
+    }
+
+    #[test]
+    fn test_first_stream_matches_the_config() {
+        let config = EncoderConfig::new(48000, 3, 24)
+            .block_size(2048)
+            .compression_level(8);
+        let mut encoder = FlacEncoder::from_config(&config);
+        assert!(encoder.encoder.is_null());
+        assert_eq!(encoder.max_lpc_order(), 12);
+        assert_eq!(
+            FlacEncoder::from_config(&config.clone().lpc_order(0, 3)).max_lpc_order(),
+            3
+        );
+
+        encoder.init().unwrap();
+        assert!(!encoder.encoder.is_null());
+        assert_eq!(encoder.max_lpc_order(), 12);
+        let samples = sine_sweep(24, 3, 10_000);
+        let mut output = vec![0u8; 1 << 20];
+        let len = encoder.encode_interleaved(&samples, &mut output).unwrap();
+        let mut encoded = output[..len].to_vec();
+        let len = encoder.finish(&mut output).unwrap();
+        encoded.extend_from_slice(&output[..len]);
+
+        let info = parse_streaminfo(&encoded).unwrap();
+        assert_eq!(
+            (info.sample_rate, info.channels, info.bits_per_sample),
+            (48000, 3, 24)
+        );
+        assert_eq!((info.min_block_size, info.max_block_size), (2048, 2048));
+        assert_eq!(decode_all(&encoded), samples);
+    }
+
+    #[test]
+    fn test_final_stream_info_has_the_real_totals() {
+        let samples = white_noise(16, 2, 10_000);
+        let mut encoder = FlacEncoder::new(44100, 16, 2, 4096, 5);
+        encoder.init().unwrap();
+        let mut output = vec![0u8; 1 << 20];
+        let len = encoder.encode_interleaved(&samples, &mut output).unwrap();
+        let mut encoded = output[..len].to_vec();
+        assert_eq!(encoder.final_stream_info(), None);
+        let len = encoder.finish(&mut output).unwrap();
+        encoded.extend_from_slice(&output[..len]);
+
+        // the streamed header was written before the totals were known
+        let written = parse_streaminfo(&encoded).unwrap();
+        assert_eq!((written.total_samples, written.md5), (0, [0; 16]));
+        let info = encoder.final_stream_info().unwrap();
+        assert_eq!(info.total_samples, 10_000);
+        assert_ne!(info.md5, [0; 16]);
+        assert_ne!(info.max_frame_size, 0);
+        assert_eq!(
+            encoder.frame_size_range(),
+            Some((info.min_frame_size, info.max_frame_size))
+        );
+
+        // it is what a back-patched header ends up with
+        let path =
+            std::env::temp_dir().join(format!("soundkit-flac-final-{}.flac", std::process::id()));
+        encoder
+            .set_seekable_sink(File::create(&path).unwrap())
+            .unwrap();
+        encoder.restart().unwrap();
+        assert_eq!(encoder.final_stream_info(), None);
+        assert_eq!(encoder.frame_size_range(), None);
+        encoder.encode_interleaved(&samples, &mut output).unwrap();
+        encoder.finish(&mut output).unwrap();
+        drop(encoder.take_sink().unwrap());
+        let patched = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(parse_streaminfo(&patched).unwrap(), info);
+        assert_eq!(encoder.final_stream_info(), Some(info));
+    }
+
+    #[test]
+    fn test_frame_crc_failures_are_fatal_or_counted() {
+        let samples = white_noise(16, 2, 4096 * 3);
+        let mut encoder = FlacEncoder::new(44100, 16, 2, 4096, 5);
+        encoder.init().unwrap();
+        let mut output = vec![0u8; 1 << 20];
+        let len = encoder.encode_interleaved(&samples, &mut output).unwrap();
+        let mut encoded = output[..len].to_vec();
+        let len = encoder.finish(&mut output).unwrap();
+        encoded.extend_from_slice(&output[..len]);
+        let second_frame_end = encoder.seek_index()[2].byte_offset as usize;
+        // flip a bit of the second frame's CRC-16 footer
+        encoded[second_frame_end - 1] ^= 1;
+
+        let mut decoder = FlacDecoder::new();
+        decoder.init().unwrap();
+        decoder.feed(&encoded);
+        assert_eq!(decoder.decode_remaining(), Err(FlacError::FrameCrcMismatch));
+        assert_eq!(decoder.crc_error_count(), 1);
+
+        let mut decoder = FlacDecoder::new();
+        decoder.set_check_frame_crc(false);
+        decoder.init().unwrap();
+        decoder.feed(&encoded);
+        let decoded = decoder.decode_remaining().unwrap();
+        assert_eq!(decoder.crc_error_count(), 1);
+        assert_eq!(decoded.len(), samples.len());
+        let (first, second) = (0..4096 * 2, 4096 * 2..4096 * 4);
+        assert_eq!(decoded[first.clone()], samples[first]);
+        assert!(decoded[second].iter().all(|&s| s == 0));
+        assert_eq!(decoded[4096 * 4..], samples[4096 * 4..]);
+    }
+
+    #[test]
+    fn test_recompress_keeps_audio_and_tags() {
+        let samples = sine_sweep(16, 2, 30_000);
+        let mut encoder =
+            FlacEncoder::from_config(&EncoderConfig::new(44100, 2, 16).compression_level(0));
+        encoder.add_tag("TITLE", "capture").unwrap();
+        encoder
+            .add_picture(Picture::front_cover("image/png", vec![9; 32]))
+            .unwrap();
+        encoder.init().unwrap();
+        let mut output = vec![0u8; 1 << 20];
+        let len = encoder.encode_interleaved(&samples, &mut output).unwrap();
+        let mut fast = output[..len].to_vec();
+        let len = encoder.finish(&mut output).unwrap();
+        fast.extend_from_slice(&output[..len]);
+        let expected_info = encoder.final_stream_info().unwrap();
+
+        let archival =
+            recompress(&fast, EncoderConfig::new(44100, 2, 16).compression_level(8)).unwrap();
+        assert!(archival.len() < fast.len());
+        assert_eq!(decode_all(&archival), samples);
+        // the header is complete even though no sink was involved
+        let info = parse_streaminfo(&archival).unwrap();
+        assert_eq!(info.total_samples, 30_000);
+        assert_eq!(info.md5, expected_info.md5);
+        let tags = metadata_block(&archival, 4).expect("no VORBIS_COMMENT");
+        assert!(tags.windows(13).any(|w| w == b"TITLE=capture"));
+        assert!(metadata_block(&archival, 6).is_some(), "picture dropped");
+
+        // with an MD5 in the input as well
+        let again = recompress(&archival, EncoderConfig::new(44100, 2, 16)).unwrap();
+        assert_eq!(decode_all(&again), samples);
+
+        assert!(matches!(
+            recompress(&fast, EncoderConfig::new(48000, 2, 16)),
+            Err(FlacError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn test_concatenate_flac() {
+        let first = sine_sweep(16, 2, 10_000);
+        let second = white_noise(16, 2, 5000);
+        let config = EncoderConfig::new(44100, 2, 16);
+        let a = encode_and_verify(&config.clone().compression_level(0), &first).unwrap();
+        let b = encode_and_verify(&config.clone().block_size(1024), &second).unwrap();
+
+        let joined = concatenate_flac(&[&a, &b], config.clone()).unwrap();
+        assert_eq!(decode_all(&joined), [first, second].concat());
+        assert_eq!(parse_streaminfo(&joined).unwrap().total_samples, 15_000);
+
+        let mono = encode_and_verify(&EncoderConfig::new(44100, 1, 16), &[0; 100]).unwrap();
+        assert!(matches!(
+            concatenate_flac(&[&a, &mono], config),
+            Err(FlacError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn test_fixed_block_size_frames() {
+        let config = EncoderConfig::new(48000, 2, 16).fixed_block_size(1152);
+        let mut encoder = FlacEncoder::from_config(&config);
+        encoder.init().unwrap();
+        let mut output = vec![0u8; 1 << 20];
+        let mut encoded = Vec::new();
+        // uneven input sizes don't change the framing
+        for chunk in white_noise(16, 2, 10_000).chunks(2 * 777) {
+            let len = encoder.encode_interleaved(chunk, &mut output).unwrap();
+            encoded.extend_from_slice(&output[..len]);
+        }
+        let len = encoder.finish(&mut output).unwrap();
+        encoded.extend_from_slice(&output[..len]);
+
+        let index = encoder.seek_index().to_vec();
+        let (last, rest) = index.split_last().unwrap();
+        assert_eq!(rest.len(), 10_000 / 1152);
+        assert!(rest.iter().all(|point| point.frame_samples == 1152));
+        assert_eq!(last.frame_samples, 10_000 % 1152);
+        for point in &index {
+            let second = encoded[point.byte_offset as usize + 1];
+            assert_eq!(second, 0xf8, "not a fixed-blocksize frame");
+        }
+        let info = parse_streaminfo(&encoded).unwrap();
+        assert_eq!((info.min_block_size, info.max_block_size), (1152, 1152));
+    }
+
+    #[test]
+    fn test_clear_output_keeps_the_stream_going() {
+        let samples = white_noise(16, 2, 4096 * 4);
+        // libFLAC encodes a block once the first sample of the next arrives
+        let split = (4096 * 2 + 1) * 2;
+        let mut encoder = FlacEncoder::new(44100, 16, 2, 4096, 5);
+        encoder.init().unwrap();
+        let header = encoder.drain_complete_frames();
+        // a send of the first two frames failed; drop them
+        encoder.encode_buffered(&samples[..split]).unwrap();
+        assert_ne!(encoder.last_pending_len(), 0);
+        encoder.clear_output();
+        assert_eq!(encoder.last_pending_len(), 0);
+        assert_eq!(encoder.seek_index().len(), 2);
+
+        encoder.encode_buffered(&samples[split..]).unwrap();
+        let mut output = vec![0u8; 1 << 20];
+        let len = encoder.finish(&mut output).unwrap();
+        let mut stream = header;
+        stream.extend_from_slice(&output[..len]);
+        let mut decoder = FlacDecoder::new();
+        decoder.init().unwrap();
+        decoder.feed(&stream);
+        assert_eq!(decoder.decode_remaining().unwrap(), samples[4096 * 2 * 2..]);
+    }
+
+    #[test]
+    fn test_scratch_buffer_is_reused() {
+        let left: Vec<i32> = (0..4096).collect();
+        let right: Vec<i32> = (0..4096).map(|x| -x).collect();
+        let mut output = vec![0u8; 1 << 16];
+
+        let buffer = Vec::with_capacity(4096 * 2);
+        let address = buffer.as_ptr();
+        let mut first = FlacEncoder::new(44100, 16, 2, 1024, 5);
+        first.set_scratch_buffer(buffer);
+        first.init().unwrap();
+        first
+            .encode_channels(&[&left, &right], &mut output)
+            .unwrap();
+        let buffer = first.take_scratch_buffer();
+        assert_eq!(buffer.as_ptr(), address);
+        assert_eq!(first.take_scratch_buffer().capacity(), 0);
+
+        // handed on to a second encoder, which still encodes correctly
+        let mut second = FlacEncoder::new(44100, 16, 2, 1024, 5);
+        second.set_scratch_buffer(buffer);
+        second.init().unwrap();
+        let len = second
+            .encode_channels(&[&left, &right], &mut output)
+            .unwrap();
+        let mut encoded = output[..len].to_vec();
+        let len = second.finish(&mut output).unwrap();
+        encoded.extend_from_slice(&output[..len]);
+        let interleaved: Vec<i32> = left
+            .iter()
+            .zip(&right)
+            .flat_map(|(&l, &r)| [l, r])
+            .collect();
+        assert_eq!(decode_all(&encoded), interleaved);
+        let buffer = second.take_scratch_buffer();
+        assert_eq!(buffer.as_ptr(), address);
+    }
+
+    #[test]
+    fn test_decode_planar() {
+        let samples = sine_sweep(16, 3, 10_000);
+        let encoded = encode_all(&samples, 16, 3);
+
+        let mut decoder = FlacDecoder::new();
+        decoder.init().unwrap();
+        let channels = decoder.decode_planar(&encoded).unwrap();
+        assert_eq!(channels.len(), 3);
+        for (c, channel) in channels.iter().enumerate() {
+            let expected: Vec<i32> = samples.iter().skip(c).step_by(3).copied().collect();
+            assert_eq!(*channel, expected, "channel {}", c);
+        }
+
+        // samples already pulled into next_block's buffer are included
+        let mut decoder = FlacDecoder::new();
+        decoder.init().unwrap();
+        decoder.feed(&encoded);
+        let head = decoder.next_block(300).unwrap().unwrap();
+        let rest = decoder.decode_planar(&[]).unwrap();
+        assert_eq!(head[..], samples[..300]);
+        assert_eq!(rest[0].len(), 10_000 - 100);
+        assert_eq!(rest[2][..3], [samples[302], samples[305], samples[308]]);
+    }
+
+    #[test]
+    fn test_max_latency_samples_and_flush_now() {
+        let latency = |config: EncoderConfig| {
+            let mut encoder = FlacEncoder::from_config(&config);
+            let before = encoder.max_latency_samples();
+            encoder.init().unwrap();
+            assert_eq!(encoder.max_latency_samples(), before);
+            before
+        };
+        let config = EncoderConfig::new(44100, 2, 16);
+        assert_eq!(latency(config.clone()), 4096);
+        assert_eq!(latency(config.clone().compression_level(0)), 1152);
+        assert_eq!(latency(config.clone().lpc_order(0, 0)), 1152);
+        assert_eq!(latency(config.clone().block_size(576)), 576);
+
+        let mut encoder = FlacEncoder::from_config(&config.block_size(576));
+        encoder.init().unwrap();
+        let mut output = vec![0u8; 1 << 16];
+        assert!(encoder.flush_now(&mut output).unwrap() > 0); // the header
+        let samples = white_noise(16, 2, 577);
+        encoder.encode_buffered(&samples[..576 * 2]).unwrap();
+        assert_eq!(encoder.flush_now(&mut output), Ok(0));
+        encoder.encode_buffered(&samples[576 * 2..]).unwrap();
+        assert!(encoder.flush_now(&mut output).unwrap() > 0);
+        assert_eq!(encoder.seek_index().len(), 1);
+    }
+
+    #[test]
+    fn test_two_pass_size_estimate() {
+        let samples = sine_sweep(16, 2, 30_000);
+        for config in [
+            EncoderConfig::new(44100, 2, 16),
+            EncoderConfig::new(44100, 2, 16).trust_level(TrustLevel::Archival),
+        ] {
+            let encoded = encode_and_verify(&config, &samples).unwrap();
+            assert_eq!(
+                two_pass_size_estimate(&config, &samples).unwrap(),
+                encoded.len() as u64
+            );
         }
-        ffi::FLAC__STREAM_DECODER_ERROR_STATUS_FRAME_CRC_MISMATCH => {
-            error!("Decoder error: Frame CRC mismatch");
+    }
+
+    #[test]
+    fn test_skip_id3() {
+        let samples = white_noise(16, 2, 10_000);
+        let encoded = encode_all(&samples, 16, 2);
+        // ID3v2.3 tag with a 300-byte body: a TIT2 frame, then padding
+        let mut tagged = vec![b'I', b'D', b'3', 3, 0, 0, 0, 0, 0x02, 0x2c];
+        let title = b"\x00Title";
+        tagged.extend_from_slice(b"TIT2");
+        tagged.extend_from_slice(&(title.len() as u32).to_be_bytes());
+        tagged.extend_from_slice(&[0, 0]);
+        tagged.extend_from_slice(title);
+        tagged.resize(10 + 300, 0);
+        tagged.extend_from_slice(&encoded);
+
+        let mut decoder = FlacDecoder::new();
+        decoder.set_skip_id3(true);
+        decoder.init().unwrap();
+        decoder.feed(&tagged);
+        assert_eq!(decoder.decode_remaining().unwrap(), samples);
+        assert_eq!(decoder.stream_info().unwrap().total_samples, 10_000);
+
+        // untagged input is unaffected
+        let mut decoder = FlacDecoder::new();
+        decoder.set_skip_id3(true);
+        decoder.init().unwrap();
+        decoder.feed(&encoded);
+        assert_eq!(decoder.decode_remaining().unwrap(), samples);
+    }
+
+    #[test]
+    fn test_wasted_bits_stats() {
+        let config = EncoderConfig::new(44100, 2, 24)
+            .block_size(4096)
+            .wasted_bits_stats(true);
+        let mut encoder = FlacEncoder::from_config(&config);
+        encoder.init().unwrap();
+        // 16-bit audio in a 24-bit stream: the low 8 bits are always zero
+        let padded: Vec<i32> = white_noise(16, 2, 40_960).iter().map(|s| s << 8).collect();
+        encoder.process(&padded).unwrap();
+        encoder.finish_stream().unwrap();
+        let stat = encoder.wasted_bits_stats().unwrap();
+        assert_eq!(stat.subframes, 2 * 10);
+        assert_eq!(stat.wasted_subframes, stat.subframes);
+        // a mid channel, (left + right) >> 1, can lose one of them
+        assert!((7..=8).contains(&stat.max_wasted_bits), "{:?}", stat);
+
+        encoder.restart().unwrap();
+        encoder.process(&white_noise(24, 2, 40_960)).unwrap();
+        encoder.finish_stream().unwrap();
+        let stat = encoder.wasted_bits_stats().unwrap();
+        assert_eq!((stat.subframes, stat.wasted_subframes), (20, 0));
+
+        let plain = FlacEncoder::from_config(&EncoderConfig::new(44100, 2, 24));
+        assert_eq!(plain.wasted_bits_stats(), None);
+    }
+
+    #[test]
+    fn test_residual_partition_orders_at_the_bounds() {
+        let samples = sine_sweep(16, 2, 10_000);
+        for (min, max) in [(0, 0), (0, 8), (8, 8)] {
+            let config = EncoderConfig::new(44100, 2, 16)
+                .min_residual_partition_order(min)
+                .max_residual_partition_order(max);
+            assert_eq!(
+                decode_all(&encode_and_verify(&config, &samples).unwrap()),
+                samples
+            );
         }
-        ffi::FLAC__STREAM_DECODER_ERROR_STATUS_UNPARSEABLE_STREAM => {
-            error!("Decoder error: Unparseable stream");
+
+        // above the subset's 8, libFLAC refuses the stream unless clamped
+        let config = EncoderConfig::new(44100, 2, 16).max_residual_partition_order(15);
+        let mut encoder = FlacEncoder::from_config(&config);
+        assert!(encoder.restart().is_err());
+        encoder.reset_with(config.auto_subset(true)).unwrap();
+        assert_eq!(
+            encoder.applied_config().config.max_residual_partition_order,
+            Some(8)
+        );
+    }
+
+    #[test]
+    fn test_max_output_len_is_enough() {
+        // white noise doesn't compress, so frames come out near the bound
+        let samples = white_noise(16, 2, 50_000);
+        let mut encoder = FlacEncoder::new(44100, 16, 2, 4096, 0);
+        encoder.init().unwrap();
+        let mut encoded = Vec::new();
+        for chunk in samples.chunks(3000 * 2) {
+            let mut output = vec![0u8; encoder.max_output_len(chunk.len() / 2)];
+            let len = encoder.encode_interleaved(chunk, &mut output).unwrap();
+            encoded.extend_from_slice(&output[..len]);
         }
-        _ => {
-            error!("Decoder error: Unknown error");
+        let mut output = vec![0u8; encoder.max_output_len(0)];
+        let len = encoder.finish(&mut output).unwrap();
+        encoded.extend_from_slice(&output[..len]);
+
+        let index = encoder.seek_index().to_vec();
+        let largest = index
+            .windows(2)
+            .map(|pair| (pair[1].byte_offset - pair[0].byte_offset) as usize)
+            .max()
+            .unwrap();
+        assert!(largest <= max_frame_size(4096, 2, 16));
+        assert!(largest > 4096 * 2 * 2, "noise should not compress");
+        assert_eq!(decode_all(&encoded), samples);
+    }
+
+    #[test]
+    fn test_deterministic_output_is_byte_identical() {
+        let samples = sine_sweep(16, 2, 30_000);
+        for level in [0, 5, 8] {
+            let config = EncoderConfig::new(44100, 2, 16)
+                .compression_level(level)
+                .deterministic(true);
+            let first = encode_and_verify(&config, &samples).unwrap();
+            let second = encode_and_verify(&config, &samples).unwrap();
+            assert_eq!(first, second, "level {}", level);
         }
+
+        // overrides still apply on top of the pinned preset
+        let config = EncoderConfig::new(44100, 2, 16)
+            .deterministic(true)
+            .lpc_order(0, 2)
+            .block_size(1024);
+        let mut encoder = FlacEncoder::from_config(&config);
+        encoder.init().unwrap();
+        assert_eq!(
+            (encoder.max_lpc_order(), encoder.max_latency_samples()),
+            (2, 1024)
+        );
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use soundkit::audio_bytes::{f32le_to_s24, s16le_to_i32, s24le_to_i32};
-    use soundkit::wav::WavStreamProcessor;
-    use std::fs::File;
-    use std::io::Read;
-    use std::io::Write;
+    #[test]
+    fn test_vendor_string() {
+        let samples = sine_sweep(16, 2, 10_000);
+        let config = EncoderConfig::new(44100, 2, 16).vendor_string("acme encoder 2.1");
+        let decode_comment = |encoded: &[u8]| {
+            let comments = Rc::new(RefCell::new(Vec::new()));
+            let sink = comments.clone();
+            let mut decoder = FlacDecoder::new();
+            decoder
+                .on_vorbis_comment(move |comment| sink.borrow_mut().push(comment.clone()))
+                .unwrap();
+            decoder.init().unwrap();
+            decoder.feed(encoded);
+            assert_eq!(decoder.decode_remaining().unwrap(), samples);
+            let comments = comments.borrow();
+            assert_eq!(comments.len(), 1);
+            comments[0].clone()
+        };
+
+        // libFLAC writes a comment block even without tags
+        let encoded = encode_and_verify(&config, &samples).unwrap();
+        assert_eq!(decode_comment(&encoded).vendor, "acme encoder 2.1");
+
+        // With tags, and a seekable sink: the block changes length, and the
+        // seek table libFLAC patches at finish must still land on frames.
+        let path =
+            std::env::temp_dir().join(format!("soundkit-flac-vendor-{}.flac", std::process::id()));
+        let mut encoder = FlacEncoder::from_config(&config.clone().block_size(4096));
+        encoder.add_tag("TITLE", "Sweep").unwrap();
+        encoder.add_seek_points(4096, 10_000).unwrap();
+        encoder
+            .set_seekable_sink(File::create(&path).unwrap())
+            .unwrap();
+        encoder.restart().unwrap();
+        encoder.process(&samples).unwrap();
+        encoder.finish_stream().unwrap();
+        drop(encoder.take_sink().unwrap());
+        let encoded = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let comment = decode_comment(&encoded);
+        assert_eq!(comment.vendor, "acme encoder 2.1");
+        assert_eq!(
+            comment.comments,
+            vec![("TITLE".to_string(), "Sweep".to_string())]
+        );
+        let seek_table = read_seek_table(&encoded).unwrap();
+        assert_eq!(seek_table.len(), 3);
+        assert!(seek_table
+            .iter()
+            .all(|point| encoded[point.byte_offset as usize] == 0xff));
+        assert_eq!(parse_streaminfo(&encoded).unwrap().total_samples, 10_000);
+
+        let too_long = "x".repeat(1 << 24);
+        assert!(matches!(
+            FlacEncoder::from_config(&config.vendor_string(&too_long)).restart(),
+            Err(FlacError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn test_sync_losses() {
+        let samples = white_noise(16, 2, 10 * 1024);
+        let mut encoder =
+            FlacEncoder::from_config(&EncoderConfig::new(44100, 2, 16).block_size(1024));
+        encoder.restart().unwrap();
+        encoder.process(&samples).unwrap();
+        encoder.finish_stream().unwrap();
+        let encoded = encoder.take_encoded();
+        let offsets: Vec<usize> = encoder
+            .seek_index()
+            .iter()
+            .map(|point| point.byte_offset as usize)
+            .collect();
+
+        // garbage between frames 2 and 3, and frame 6 with its header cut off
+        let (f3, f6, f7) = (offsets[3], offsets[6], offsets[7]);
+        let mut damaged = encoded[..f3].to_vec();
+        damaged.extend_from_slice(&[0x55; 100]);
+        damaged.extend_from_slice(&encoded[f3..f6]);
+        damaged.extend_from_slice(&encoded[f6 + 10..]);
 
-    fn run_flac_encoder_with_wav_file(file_path: &str) {
         let mut decoder = FlacDecoder::new();
-        decoder.init().expect("Decoder initialization failed");
+        decoder.init().unwrap();
+        decoder.feed(&damaged);
+        let decoded = decoder.decode_remaining().unwrap();
+        let mut expected = samples[..6 * 1024 * 2].to_vec();
+        expected.extend_from_slice(&samples[7 * 1024 * 2..]);
+        assert_eq!(decoded, expected);
+        assert_eq!(
+            decoder.sync_losses(),
+            &[
+                SyncLossEvent {
+                    byte_offset: f3 as u64,
+                    resync_offset: f3 as u64 + 100,
+                    lost_samples: 0,
+                },
+                SyncLossEvent {
+                    byte_offset: f6 as u64 + 100,
+                    resync_offset: f7 as u64 + 100 - 10,
+                    lost_samples: 1024,
+                },
+            ]
+        );
 
-        let frame_size = 3600;
-        let mut file = File::open(file_path).unwrap();
-        let mut file_buffer = Vec::new();
-        file.read_to_end(&mut file_buffer).unwrap();
+        let mut decoder = FlacDecoder::new();
+        decoder.init().unwrap();
+        decoder.feed(&encoded);
+        decoder.decode_remaining().unwrap();
+        assert!(decoder.sync_losses().is_empty());
+    }
 
-        let mut processor = WavStreamProcessor::new();
-        let audio_data = processor.add(&file_buffer).unwrap().unwrap();
+    #[test]
+    fn test_estimated_time_remaining() {
+        let samples = white_noise(16, 2, 44100 * 20);
+        let mut encoder = FlacEncoder::from_config(&EncoderConfig::new(44100, 2, 16));
+        encoder.init().unwrap();
+        assert_eq!(encoder.estimated_time_remaining(), None);
+        encoder.process(&samples).unwrap();
+        // no total, no estimate
+        assert_eq!(encoder.estimated_time_remaining(), None);
 
-        dbg!(file_path, audio_data.sampling_rate());
+        let total = 44100 * 3600;
+        let config = EncoderConfig::new(44100, 2, 16).total_samples(total);
+        let mut encoder = FlacEncoder::from_config(&config);
+        encoder.init().unwrap();
+        assert_eq!(encoder.estimated_time_remaining(), None);
+        let started = Instant::now();
+        let mut chunks = samples.chunks(4096 * 2).cycle();
+        while encoder.estimated_time_remaining().is_none() {
+            encoder.process(chunks.next().unwrap()).unwrap();
+            encoder.clear_output();
+        }
+        // in line with the speed so far, allowing for timing noise
+        let remaining = encoder.estimated_time_remaining().unwrap();
+        assert!(remaining > Duration::ZERO);
+        let done = encoder.throughput.samples as f64 / total as f64;
+        assert!(remaining.as_secs_f64() < started.elapsed().as_secs_f64() / done * 2.0);
 
-        let mut encoder = FlacEncoder::new(
-            audio_data.sampling_rate(),
-            audio_data.bits_per_sample() as u32,
-            audio_data.channel_count() as u32,
-            0 as u32,
-            5,
+        encoder.restart().unwrap();
+        assert_eq!(encoder.estimated_time_remaining(), None);
+    }
+
+    #[test]
+    fn test_drop_finishes_into_the_sink() {
+        let samples = white_noise(16, 2, 10_000);
+        let path =
+            std::env::temp_dir().join(format!("soundkit-flac-drop-{}.flac", std::process::id()));
+        let mut encoder = FlacEncoder::from_config(&EncoderConfig::new(44100, 2, 16));
+        encoder
+            .set_seekable_sink(File::create(&path).unwrap())
+            .unwrap();
+        encoder.restart().unwrap();
+        encoder.process(&samples).unwrap();
+        // no finish(): the last partial block is still inside libFLAC
+        drop(encoder);
+        let encoded = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(parse_streaminfo(&encoded).unwrap().total_samples, 10_000);
+        assert_eq!(decode_all(&encoded), samples);
+    }
+
+    #[test]
+    fn test_decode_n_frames() {
+        let samples = sine_sweep(16, 2, 10_000);
+        let config = EncoderConfig::new(44100, 2, 16).block_size(1024);
+        let encoded = encode_and_verify(&config, &samples).unwrap();
+        let mut decoder = FlacDecoder::new();
+        decoder.init().unwrap();
+        decoder.feed(&encoded);
+
+        // the metadata blocks are read with the first frames, not counted
+        assert_eq!(decoder.decode_n_frames(3).unwrap(), samples[..3 * 1024 * 2]);
+        assert_eq!(decoder.decode_n_frames(0).unwrap(), Vec::<i32>::new());
+        // ten frames in all, the last one short
+        assert_eq!(
+            decoder.decode_n_frames(100).unwrap(),
+            samples[3 * 1024 * 2..]
         );
-        encoder.init().expect("Failed to initialize FLAC encoder");
+        assert_eq!(decoder.decode_n_frames(1).unwrap(), Vec::<i32>::new());
+    }
 
-        let i32_samples = match audio_data.bits_per_sample() {
-            16 => {
-                // this doesn't scale the 16 bit samples - important!
-                s16le_to_i32(audio_data.data())
+    #[test]
+    fn test_encode_streaming_bounded() {
+        // 24-bit packed PCM round trip
+        let samples = white_noise(24, 2, 10_000);
+        let pcm: Vec<u8> = samples
+            .iter()
+            .flat_map(|s| s.to_le_bytes()[..3].to_vec())
+            .collect();
+        let config = EncoderConfig::new(44100, 2, 24).total_samples(10_000);
+        let mut encoded = Vec::new();
+        let len = encode_streaming_bounded(&pcm[..], &mut encoded, &config, 3000).unwrap();
+        assert_eq!(len, encoded.len() as u64);
+        assert_eq!(decode_all(&encoded), samples);
+
+        // Five minutes of 16-bit stereo generated on the fly: no write is
+        // bigger than a block's worth of frames, which shows nothing piles up.
+        struct Noise(u64, u32);
+        impl Read for Noise {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                let len = buf.len().min(self.0 as usize);
+                for byte in &mut buf[..len] {
+                    self.1 = self.1.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                    *byte = (self.1 >> 24) as u8;
+                }
+                self.0 -= len as u64;
+                Ok(len)
             }
-            24 => s24le_to_i32(audio_data.data()),
-            32 => f32le_to_s24(audio_data.data()),
-            _ => {
-                unreachable!()
+        }
+        struct Counter(u64, usize);
+        impl Write for Counter {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0 += buf.len() as u64;
+                self.1 = self.1.max(buf.len());
+                Ok(buf.len())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
             }
+        }
+        let frames = 44100 * 60 * 5u64;
+        let mut counter = Counter(0, 0);
+        let config = EncoderConfig::new(44100, 2, 16).compression_level(0);
+        let len =
+            encode_streaming_bounded(Noise(frames * 4, 1), &mut counter, &config, 8192).unwrap();
+        assert_eq!(len, counter.0);
+        assert!(len > frames * 4);
+        assert!(counter.1 <= 3 * max_frame_size(4096, 2, 16));
+
+        let odd = [0u8; 7];
+        assert!(matches!(
+            encode_streaming_bounded(&odd[..], Vec::new(), &config, 16),
+            Err(FlacError::InvalidInput(_))
+        ));
+    }
+
+    #[test]
+    fn test_encode_f32_clamps_and_counts() {
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let sink = reported.clone();
+        let mut encoder = FlacEncoder::from_config(&EncoderConfig::new(44100, 1, 16));
+        encoder.set_clip_callback(move |clipped| sink.lock().unwrap().push(clipped));
+        encoder.init().unwrap();
+        let mut output = vec![0u8; 1 << 16];
+        let mut encoded = Vec::new();
+        let input = [0.0, 0.5, -0.5, 1.0, -1.0, 1.5, -2.0, f32::NAN];
+        let len = encoder.encode_f32(&input, &mut output).unwrap();
+        encoded.extend_from_slice(&output[..len]);
+        let len = encoder.encode_f32(&[0.25; 8], &mut output).unwrap();
+        encoded.extend_from_slice(&output[..len]);
+        let len = encoder.finish(&mut output).unwrap();
+        encoded.extend_from_slice(&output[..len]);
+
+        assert_eq!(encoder.clip_count(), 3);
+        assert_eq!(*reported.lock().unwrap(), [3]);
+        let mut expected = vec![0, 16384, -16384, 32767, -32768, 32767, -32768, 0];
+        expected.extend([8192; 8]);
+        assert_eq!(decode_all(&encoded), expected);
+
+        encoder.restart().unwrap();
+        assert_eq!(encoder.clip_count(), 0);
+    }
+
+    #[test]
+    fn test_encode_f32_failures_count_no_clips() {
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let sink = reported.clone();
+        let mut encoder = FlacEncoder::from_config(&EncoderConfig::new(44100, 2, 16));
+        encoder.set_clip_callback(move |clipped| sink.lock().unwrap().push(clipped));
+        let mut output = vec![0u8; 1 << 16];
+
+        let loud = [2.0f32; 4];
+        assert_eq!(
+            encoder.encode_f32(&loud, &mut output),
+            Err(FlacError::NotInitialized)
+        );
+        encoder.init().unwrap();
+        assert!(matches!(
+            encoder.encode_f32(&loud[..3], &mut output),
+            Err(FlacError::InvalidInput(_))
+        ));
+        encoder.finish(&mut output).unwrap();
+        assert_eq!(
+            encoder.encode_f32(&loud, &mut output),
+            Err(FlacError::AlreadyFinished)
+        );
+
+        assert_eq!(encoder.clip_count(), 0);
+        assert!(reported.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_peaks() {
+        let samples = white_noise(16, 2, 44100 + 100);
+        let config = EncoderConfig::new(44100, 2, 16).generate_peaks(100);
+        let mut encoder = FlacEncoder::from_config(&config);
+        encoder.init().unwrap();
+        let mut output = vec![0u8; encoder.max_output_len(44100 + 100)];
+        encoder
+            .encode_interleaved(&samples[..1000], &mut output)
+            .unwrap();
+        encoder
+            .encode_interleaved(&samples[1000..], &mut output)
+            .unwrap();
+        encoder.finish(&mut output).unwrap();
+
+        // 441 frames per bin, and a partial bin for the last 100 frames
+        let peaks = encoder.peaks();
+        assert_eq!(peaks.len(), 101);
+        for (bin, &(min, max)) in peaks.iter().enumerate() {
+            let frames = &samples[bin * 441 * 2..((bin + 1) * 441 * 2).min(samples.len())];
+            assert_eq!(min, *frames.iter().min().unwrap());
+            assert_eq!(max, *frames.iter().max().unwrap());
+        }
+
+        assert!(FlacEncoder::from_config(&EncoderConfig::new(44100, 2, 16))
+            .peaks()
+            .is_empty());
+        assert!(EncoderConfig::new(8000, 1, 16)
+            .generate_peaks(8001)
+            .validate()
+            .is_err());
+    }
+
+    #[test]
+    fn test_read_metadata_only() {
+        let samples = sine_sweep(16, 2, 10_000);
+        let path =
+            std::env::temp_dir().join(format!("soundkit-flac-scan-{}.flac", std::process::id()));
+        let mut encoder =
+            FlacEncoder::from_config(&EncoderConfig::new(44100, 2, 16).block_size(4096));
+        encoder.add_tag("ARTIST", "Someone").unwrap();
+        encoder
+            .add_picture(Picture::front_cover("image/png", vec![7; 64]))
+            .unwrap();
+        encoder.add_seek_points(4096, 10_000).unwrap();
+        encoder
+            .set_seekable_sink(File::create(&path).unwrap())
+            .unwrap();
+        encoder.restart().unwrap();
+        encoder.process(&samples).unwrap();
+        encoder.finish_stream().unwrap();
+        drop(encoder.take_sink().unwrap());
+        let encoded = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let metadata = read_metadata_only(&encoded, false).unwrap();
+        assert_eq!(metadata.stream_info, parse_streaminfo(&encoded).unwrap());
+        let comment = metadata.vorbis_comment.unwrap();
+        assert_eq!(
+            comment.comments,
+            [("ARTIST".to_string(), "Someone".to_string())]
+        );
+        assert_eq!(metadata.pictures.len(), 1);
+        assert_eq!(metadata.pictures[0].mime_type, "image/png");
+        assert!(metadata.pictures[0].data.is_empty());
+        assert_eq!(metadata.seek_table, read_seek_table(&encoded).unwrap());
+        assert_eq!(metadata.seek_table.len(), 3);
+        // the first frame follows straight after the metadata
+        let first_frame = metadata.audio_offset as usize;
+        assert_eq!(metadata.seek_table[0].byte_offset, first_frame as u64);
+        assert_eq!(&encoded[first_frame..first_frame + 2], [0xff, 0xf8]);
+
+        // only the header is needed, and picture data on request
+        let header_only = read_metadata_only(&encoded[..first_frame], true).unwrap();
+        assert_eq!(header_only.pictures[0].data, vec![7; 64]);
+
+        assert!(read_metadata_only(b"not flac", false).is_err());
+        assert!(metadata.pre_emphasis_tracks.is_empty());
+    }
+
+    #[test]
+    fn test_read_metadata_only_reports_pre_emphasis() {
+        let samples = sine_sweep(16, 2, 1000);
+        let encoded = encode_and_verify(&EncoderConfig::new(44100, 2, 16), &samples).unwrap();
+
+        // a CUESHEET with track 1 flagged, and the lead-out track
+        let mut cue_sheet = vec![0u8; 128 + 8 + 259];
+        cue_sheet.push(2);
+        let track = |offset: u64, number: u8, flags: u8, indices: u8| {
+            let mut track = offset.to_be_bytes().to_vec();
+            track.push(number);
+            track.extend([0; 12]);
+            track.push(flags);
+            track.extend([0; 13]);
+            track.push(indices);
+            track
         };
+        cue_sheet.extend(track(0, 1, 0x40, 1));
+        cue_sheet.extend([0; 12]);
+        cue_sheet.extend(track(1000, 170, 0, 0));
+        let mut block = vec![5];
+        block.extend(&(cue_sheet.len() as u32).to_be_bytes()[1..]);
+        block.extend(cue_sheet);
 
-        let mut encoded_data = Vec::new();
-        let chunk_size = frame_size * audio_data.channel_count() as usize;
-        let mut decoded_samples = vec![0i32; chunk_size * 4];
-        let mut n = 0;
-        for (i, chunk) in i32_samples.chunks(chunk_size).enumerate() {
-            let mut output_buffer = vec![0u8; chunk.len() * std::mem::size_of::<i32>() * 2];
-            match encoder.encode_i32(chunk, &mut output_buffer) {
-                Ok(encoded_len) => {
-                    if encoded_len > 0 {
-                        n += 1;
-                        match decoder.decode_i32(
-                            &output_buffer[..encoded_len],
-                            &mut decoded_samples,
-                            false,
-                        ) {
-                            Ok(samples_read) => {
-                                println!(
-                                    "Decoded {} samples of {} data successfully.",
-                                    samples_read, encoded_len
-                                );
-                            }
-                            Err(e) => panic!("Decoding failed: {}", e),
-                        }
-                    }
-                    encoded_data.extend_from_slice(&output_buffer[..encoded_len]);
-                }
-                Err(e) => {
-                    panic!("Failed to encode chunk {}: {:?}", i, e);
+        // after STREAMINFO, which isn't the last block
+        let mut stream = encoded[..42].to_vec();
+        stream.extend(block);
+        stream.extend(&encoded[42..]);
+        let metadata = read_metadata_only(&stream, false).unwrap();
+        assert_eq!(metadata.pre_emphasis_tracks, [1]);
+        assert_eq!(decode_all(&stream), samples);
+    }
+
+    #[test]
+    fn test_reset_with_a_different_format_mid_stream() {
+        let mut encoder = FlacEncoder::from_config(&EncoderConfig::new(44100, 2, 16));
+        encoder.init().unwrap();
+        // nothing written yet: the format can still change
+        encoder
+            .reset_with(EncoderConfig::new(44100, 1, 16))
+            .unwrap();
+        encoder.encode_buffered(&[1, 2, 3, 4]).unwrap();
+
+        assert_eq!(
+            encoder.reset_with(EncoderConfig::new(44100, 2, 16)),
+            Err(FlacError::FormatChangeMidStream {
+                from: (1, 16),
+                to: (2, 16)
+            })
+        );
+        assert!(matches!(
+            encoder.reset_with(EncoderConfig::new(44100, 1, 24)),
+            Err(FlacError::FormatChangeMidStream { .. })
+        ));
+        // the stream is untouched, and other settings may still change
+        assert_eq!(encoder.config().channels, 1);
+        encoder
+            .reset_with(EncoderConfig::new(44100, 1, 16).compression_level(8))
+            .unwrap();
+
+        encoder.encode_buffered(&[1, 2, 3, 4]).unwrap();
+        encoder.finish_stream().unwrap();
+        encoder
+            .reset_with(EncoderConfig::new(48000, 2, 24))
+            .unwrap();
+        assert_eq!(encoder.config().channels, 2);
+    }
+
+    #[test]
+    fn test_encode_from_iter_would_block() {
+        // takes at most `capacity` bytes, then blocks until drained
+        struct Choked {
+            written: Vec<u8>,
+            capacity: usize,
+        }
+        impl Write for Choked {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                if self.capacity == 0 {
+                    return Err(std::io::ErrorKind::WouldBlock.into());
                 }
+                let len = buf.len().min(self.capacity);
+                self.written.extend_from_slice(&buf[..len]);
+                self.capacity -= len;
+                Ok(len)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
             }
         }
 
-        dbg!(n);
+        let samples = white_noise(16, 2, 20_000);
+        let mut encoder = FlacEncoder::from_config(&EncoderConfig::new(44100, 2, 16));
+        encoder.init().unwrap();
+        let mut sink = Choked {
+            written: Vec::new(),
+            capacity: 5000,
+        };
+        let mut input = samples.iter().copied();
+        let mut blocked = 0;
+        loop {
+            match encoder.encode_from_iter(&mut input, &mut sink) {
+                Ok(_) if input.len() == 0 => break,
+                Ok(_) => {}
+                Err(FlacError::WouldBlock) => blocked += 1,
+                Err(e) => panic!("{}", e),
+            }
+            sink.capacity = 5000;
+        }
+        assert!(blocked > 0);
+        encoder.finish_stream().unwrap();
+        sink.written.extend(encoder.take_encoded());
+        assert_eq!(decode_all(&sink.written), samples);
+    }
 
-        let mut file =
-            File::create(file_path.to_owned() + ".flac").expect("Failed to create output file");
-        file.write_all(&encoded_data)
-            .expect("Failed to write to output file");
+    #[cfg(feature = "timing")]
+    #[test]
+    fn test_encode_timing_stats() {
+        let samples = white_noise(16, 2, 10_000);
+        let mut encoder = FlacEncoder::from_config(&EncoderConfig::new(44100, 2, 16));
+        encoder.init().unwrap();
+        encoder.set_process_chunk_samples(4096);
+        assert_eq!(encoder.encode_timing_stats(), TimingStats::default());
+        let mut output = vec![0u8; encoder.max_output_len(10_000)];
+        encoder.encode_interleaved(&samples, &mut output).unwrap();
 
-        encoder.reset().expect("Failed to reset encoder");
+        let stats = encoder.encode_timing_stats();
+        assert_eq!(stats.calls, 3);
+        assert!(stats.max_nanos > 0);
+        assert!(stats.max_nanos <= stats.total_nanos);
+        encoder.restart().unwrap();
+        assert_eq!(encoder.encode_timing_stats().calls, 0);
     }
 
     #[test]
-    fn test_flac_encoder_with_wave_16bit() {
-        run_flac_encoder_with_wav_file("testdata/s16le.wav");
+    fn test_encode_returns_zero_until_a_block_completes() {
+        let samples = white_noise(16, 2, 4096 + 1000);
+        let mut encoder = FlacEncoder::new(44100, 16, 2, 4096, 5);
+        encoder.init().unwrap();
+        let mut output = vec![0u8; 1 << 20];
+
+        // the first call hands out the header, even without a frame
+        let header = encoder.encode_i32(&samples[..1000], &mut output).unwrap();
+        assert!(header > 0);
+        // still short of a block: a successful call with nothing to send
+        assert_eq!(encoder.encode_i32(&samples[1000..4000], &mut output), Ok(0));
+        // going past a whole block emits its frame (libFLAC waits for one
+        // sample more than the block before encoding it)
+        let frame = encoder
+            .encode_i32(&samples[4000..9000], &mut output)
+            .unwrap();
+        assert!(frame > 0);
+        assert_eq!(encoder.encode_i32(&samples[9000..], &mut output), Ok(0));
+        // the partial block left over is emitted by finish
+        assert!(encoder.finish(&mut output).unwrap() > 0);
     }
 
     #[test]
-    fn test_flac_encoder_with_wave_24bit() {
-        run_flac_encoder_with_wav_file("testdata/s24le.wav");
+    fn test_set_compression_level_adaptive_concatenates_streams() {
+        let samples = white_noise(16, 2, 20_000);
+        let mut encoder = FlacEncoder::new(44100, 16, 2, 4096, 8);
+        encoder.init().unwrap();
+        let mut encoded = Vec::new();
+        let mut output = vec![0u8; 1 << 20];
+        let (first, second) = samples.split_at(9000 * 2);
+        let len = encoder.encode_i32(first, &mut output).unwrap();
+        encoded.extend_from_slice(&output[..len]);
+        encoder.set_compression_level_adaptive(0).unwrap();
+        assert_eq!(encoder.config().compression_level, 0);
+        let len = encoder.encode_i32(second, &mut output).unwrap();
+        encoded.extend_from_slice(&output[..len]);
+        let len = encoder.finish(&mut output).unwrap();
+        encoded.extend_from_slice(&output[..len]);
+
+        let streams = split_concatenated(&encoded);
+        assert_eq!(streams.len(), 2);
+        let decoded: Vec<i32> = streams
+            .iter()
+            .flat_map(|range| decode_all(&encoded[range.clone()]))
+            .collect();
+        assert_eq!(decoded, samples);
+
+        // a seekable sink would have the second header written over the first
+        let mut encoder = FlacEncoder::new(44100, 16, 2, 4096, 8);
+        encoder
+            .set_seekable_sink(std::io::Cursor::new(Vec::new()))
+            .unwrap();
+        encoder.init().unwrap();
+        encoder.encode_i32(first, &mut output).unwrap();
+        assert!(matches!(
+            encoder.set_compression_level_adaptive(0),
+            Err(FlacError::Unsupported(_))
+        ));
     }
 
     #[test]
-    fn test_flac_encoder_with_wave_32bit() {
-        run_flac_encoder_with_wav_file("testdata/f32le.wav");
+    fn test_mono_24_bit() {
+        // field recorders commonly write mono 24-bit at 48 kHz
+        let samples = sine_sweep(24, 1, 48_000 + 77);
+        for level in [0, 5, 8] {
+            for deterministic in [false, true] {
+                let config = EncoderConfig::new(48000, 1, 24)
+                    .compression_level(level)
+                    .deterministic(deterministic);
+                assert!(!config.pinned_settings().mid_side);
+                let encoded = encode_and_verify(&config, &samples).unwrap();
+                let info = parse_streaminfo(&encoded).unwrap();
+                assert_eq!((info.channels, info.bits_per_sample), (1, 24));
+                assert_eq!(decode_all(&encoded), samples, "level {}", level);
+            }
+        }
     }
 
-    // #[test]
-    fn test_flac_encoder_with_wave_lori() {
-        run_flac_encoder_with_wav_file("testdata/lori.wav");
+    #[test]
+    fn test_max_block_size_allowed() {
+        let samples = white_noise(16, 2, 10_000);
+        let encoded = encode_all(&samples, 16, 2);
+        let decode = |max| {
+            let mut decoder = FlacDecoder::new();
+            decoder.set_max_block_size_allowed(max);
+            decoder.init().unwrap();
+            decoder.feed(&encoded);
+            let result = decoder.decode_remaining();
+            (result, decoder.output_buffer.len())
+        };
+
+        // level 5 writes 4096-sample blocks
+        assert_eq!(decode(4096).0, Ok(samples));
+        let (result, output) = decode(1024);
+        assert_eq!(
+            result,
+            Err(FlacError::BlockSizeTooLarge {
+                block_size: 4096,
+                max: 1024
+            })
+        );
+        assert_eq!(output, 0);
     }
 
-    //    #[test]
-    fn test_flac_encoder_with_wave_s32bit() {
-        run_flac_encoder_with_wav_file("testdata/s32le.wav");
+    #[cfg(unix)]
+    #[test]
+    fn test_init_from_raw_fd() {
+        use std::os::fd::IntoRawFd;
+
+        let samples = white_noise(16, 2, 10_000);
+        let path =
+            std::env::temp_dir().join(format!("soundkit-flac-raw-fd-{}.flac", std::process::id()));
+        // write-only, as a sandbox would hand it over
+        let fd = File::create(&path).unwrap().into_raw_fd();
+        let mut encoder = FlacEncoder::new(44100, 16, 2, 0, 5);
+        unsafe { encoder.init_from_raw_fd(fd) }.unwrap();
+        let mut output = vec![0u8; 1 << 16];
+        assert_eq!(encoder.encode_interleaved(&samples, &mut output), Ok(0));
+        assert_eq!(encoder.finish(&mut output), Ok(0));
+        drop(encoder);
+
+        let encoded = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(decode_all(&encoded), samples);
+        assert_eq!(parse_streaminfo(&encoded).unwrap().total_samples, 10_000);
+
+        // a pipe can't be back-patched and is left to the caller
+        let mut fds = [0; 2];
+        assert_eq!(unsafe { libc::pipe(fds.as_mut_ptr()) }, 0);
+        let mut encoder = FlacEncoder::new(44100, 16, 2, 0, 5);
+        assert!(matches!(
+            unsafe { encoder.init_from_raw_fd(fds[1]) },
+            Err(FlacError::Unsupported(_))
+        ));
+        assert!(!encoder.is_seekable_output());
+        for fd in fds {
+            assert_eq!(unsafe { libc::close(fd) }, 0);
+        }
+    }
+
+    #[test]
+    fn test_flac_error_into_io_error() {
+        struct Broken;
+        impl Write for Broken {
+            fn write(&mut self, _: &[u8]) -> std::io::Result<usize> {
+                Err(std::io::ErrorKind::BrokenPipe.into())
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+        fn encode_to_broken_sink() -> std::io::Result<usize> {
+            let mut encoder = FlacEncoder::new(44100, 16, 2, 0, 5);
+            encoder.init().map_err(std::io::Error::other)?;
+            Ok(encoder.encode_from_iter(white_noise(16, 2, 100), &mut Broken)?)
+        }
+
+        let error = encode_to_broken_sink().unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::BrokenPipe);
+        let source = error.get_ref().unwrap().downcast_ref::<FlacError>();
+        assert!(matches!(
+            source,
+            Some(FlacError::Io(e)) if e.kind() == std::io::ErrorKind::BrokenPipe
+        ));
+        let source = std::error::Error::source(source.unwrap()).unwrap();
+        assert!(source.downcast_ref::<std::io::Error>().is_some());
+
+        let would_block: std::io::Error = FlacError::WouldBlock.into();
+        assert_eq!(would_block.kind(), std::io::ErrorKind::WouldBlock);
+        let out_of_memory: std::io::Error = FlacError::OutOfMemory.into();
+        assert_eq!(out_of_memory.kind(), std::io::ErrorKind::OutOfMemory);
+        let invalid: std::io::Error = FlacError::MissingStreamInfo.into();
+        assert_eq!(invalid.kind(), std::io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_channel_map_reorders_six_channels() {
+        let samples = white_noise(16, 6, 5000);
+        let encoded = encode_all(&samples, 16, 6);
+        // 5.1 from FLAC order to film order
+        let map = vec![0, 2, 1, 4, 5, 3];
+        let expected: Vec<i32> = samples
+            .chunks(6)
+            .flat_map(|frame| map.iter().map(|&channel| frame[channel]))
+            .collect();
+
+        let mut decoder = FlacDecoder::new();
+        decoder.set_channel_map(Some(map.clone())).unwrap();
+        decoder.init().unwrap();
+        decoder.feed(&encoded);
+        assert_eq!(decoder.decode_remaining().unwrap(), expected);
+
+        let mut decoder = FlacDecoder::new();
+        decoder.set_channel_map(Some(map.clone())).unwrap();
+        decoder.init().unwrap();
+        let planar = decoder.decode_planar(&encoded).unwrap();
+        for (output, &channel) in planar.iter().zip(&map) {
+            let original: Vec<i32> = samples.iter().skip(channel).step_by(6).copied().collect();
+            assert_eq!(output, &original);
+        }
+
+        let mut decoder = FlacDecoder::new();
+        for bad in [vec![], vec![0, 0, 1], vec![1, 2]] {
+            assert!(matches!(
+                decoder.set_channel_map(Some(bad)),
+                Err(FlacError::InvalidConfig(_))
+            ));
+        }
+        // a permutation for another channel count fails at the first frame
+        decoder.set_channel_map(Some(vec![1, 0])).unwrap();
+        decoder.init().unwrap();
+        decoder.feed(&encoded);
+        assert!(matches!(
+            decoder.decode_remaining(),
+            Err(FlacError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn test_take_header_separates_metadata_from_frames() {
+        let samples = white_noise(16, 2, 10_000);
+        let mut encoder = FlacEncoder::new(44100, 16, 2, 0, 5);
+        encoder.add_tag("TITLE", "live").unwrap();
+        encoder.init().unwrap();
+
+        let header = encoder.take_header().unwrap();
+        assert!(header.starts_with(b"fLaC"));
+        assert_eq!(parse_streaminfo(&header).unwrap().channels, 2);
+        assert_eq!(encoder.take_header(), None);
+
+        // what follows is frames only
+        let mut output = vec![0u8; 1 << 20];
+        let mut frames = Vec::new();
+        let len = encoder.encode_i32(&samples, &mut output).unwrap();
+        frames.extend_from_slice(&output[..len]);
+        let len = encoder.finish(&mut output).unwrap();
+        frames.extend_from_slice(&output[..len]);
+        assert_eq!(&frames[..2], &[0xff, 0xf8]);
+        assert_eq!(decode_all(&[header, frames].concat()), samples);
+
+        // too late once the header has gone out with the first frames
+        encoder.reset().unwrap();
+        encoder.encode_i32(&samples, &mut output).unwrap();
+        assert_eq!(encoder.take_header(), None);
+    }
+
+    #[test]
+    fn test_trust_levels_encode_the_same_audio() {
+        let samples = sine_sweep(16, 2, 10_000);
+        for level in [TrustLevel::Fast, TrustLevel::Paranoid, TrustLevel::Archival] {
+            let config = EncoderConfig::new(44100, 2, 16).trust_level(level);
+            let mut encoder = FlacEncoder::from_config(&config);
+            encoder.init().unwrap();
+            let mut encoded = Vec::new();
+            let mut output = vec![0u8; 1 << 20];
+            let len = encoder.encode_interleaved(&samples, &mut output).unwrap();
+            encoded.extend_from_slice(&output[..len]);
+            let len = encoder.finish(&mut output).unwrap();
+            encoded.extend_from_slice(&output[..len]);
+
+            assert_eq!(decode_robust(&encoded).unwrap().samples, samples);
+        }
     }
 }
@@ -0,0 +1,539 @@
+use crate::{FlacError, StreamInfo};
+use libflac_sys as ffi;
+use std::ffi::{CStr, CString};
+use std::slice;
+
+/// An embedded picture (PICTURE block), e.g. cover art.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Picture {
+    /// ID3v2 APIC picture type; 3 is the front cover.
+    pub picture_type: u32,
+    pub mime_type: String,
+    pub description: String,
+    pub width: u32,
+    pub height: u32,
+    /// Colour depth in bits per pixel.
+    pub depth: u32,
+    /// Number of colours for indexed images, otherwise 0.
+    pub colors: u32,
+    pub data: Vec<u8>,
+}
+
+impl Picture {
+    /// A front cover picture with unknown dimensions.
+    pub fn front_cover(mime_type: &str, data: Vec<u8>) -> Self {
+        Picture {
+            picture_type: 3,
+            mime_type: mime_type.to_string(),
+            description: String::new(),
+            width: 0,
+            height: 0,
+            depth: 0,
+            colors: 0,
+            data,
+        }
+    }
+}
+
+/// The 4-byte ID of an APPLICATION block.
+///
+/// IDs are assigned by the FLAC project's application ID registry
+/// (<https://xiph.org/flac/id.html>) so that readers can tell whose data a
+/// block holds and skip what they don't understand. The constants cover
+/// common registered IDs; [`from_bytes`](Self::from_bytes) takes any other,
+/// which should be registered before it is used in published files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ApplicationId([u8; 4]);
+
+impl ApplicationId {
+    /// `riff`: RIFF chunks from a WAV source that FLAC has no field for.
+    pub const RIFF_CHUNKS: ApplicationId = ApplicationId(*b"riff");
+    /// `aiff`: the same for AIFF sources.
+    pub const AIFF_CHUNKS: ApplicationId = ApplicationId(*b"aiff");
+    /// `xmcd`: an xmcd (CDDB) record for the disc.
+    pub const XMCD: ApplicationId = ApplicationId(*b"xmcd");
+    /// `Cues`: GoldWave cue points.
+    pub const GOLDWAVE_CUES: ApplicationId = ApplicationId(*b"Cues");
+    /// `imag`: flac-image.
+    pub const FLAC_IMAGE: ApplicationId = ApplicationId(*b"imag");
+
+    pub const fn from_bytes(id: [u8; 4]) -> Self {
+        ApplicationId(id)
+    }
+
+    pub const fn as_bytes(&self) -> [u8; 4] {
+        self.0
+    }
+}
+
+/// A decoded VORBIS_COMMENT block.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct VorbisComment {
+    pub vendor: String,
+    /// `NAME=value` pairs in stream order. Entries without `=` have an empty
+    /// value.
+    pub comments: Vec<(String, String)>,
+}
+
+/// Converts the STREAMINFO libFLAC parsed into our own type.
+pub(crate) fn stream_info_from_ffi(info: &ffi::FLAC__StreamMetadata_StreamInfo) -> StreamInfo {
+    StreamInfo {
+        min_block_size: info.min_blocksize as u16,
+        max_block_size: info.max_blocksize as u16,
+        min_frame_size: info.min_framesize,
+        max_frame_size: info.max_framesize,
+        sample_rate: info.sample_rate,
+        channels: info.channels as u8,
+        bits_per_sample: info.bits_per_sample as u8,
+        total_samples: info.total_samples,
+        md5: info.md5sum,
+    }
+}
+
+unsafe fn entry_string(entry: &ffi::FLAC__StreamMetadata_VorbisComment_Entry) -> String {
+    if entry.entry.is_null() {
+        return String::new();
+    }
+    let bytes = slice::from_raw_parts(entry.entry, entry.length as usize);
+    String::from_utf8_lossy(bytes).into_owned()
+}
+
+/// Copies a VORBIS_COMMENT block out of libFLAC's memory.
+pub(crate) unsafe fn vorbis_comment_from_ffi(
+    comment: &ffi::FLAC__StreamMetadata_VorbisComment,
+) -> VorbisComment {
+    let entries = if comment.comments.is_null() {
+        &[][..]
+    } else {
+        slice::from_raw_parts(comment.comments, comment.num_comments as usize)
+    };
+
+    VorbisComment {
+        vendor: entry_string(&comment.vendor_string),
+        comments: entries
+            .iter()
+            .map(|entry| {
+                let entry = entry_string(entry);
+                match entry.split_once('=') {
+                    Some((name, value)) => (name.to_string(), value.to_string()),
+                    None => (entry, String::new()),
+                }
+            })
+            .collect(),
+    }
+}
+
+/// Copies a PICTURE block out of libFLAC's memory.
+pub(crate) unsafe fn picture_from_ffi(picture: &ffi::FLAC__StreamMetadata_Picture) -> Picture {
+    let c_string = |ptr: *const libc::c_char| {
+        if ptr.is_null() {
+            String::new()
+        } else {
+            CStr::from_ptr(ptr).to_string_lossy().into_owned()
+        }
+    };
+    let data = if picture.data.is_null() {
+        Vec::new()
+    } else {
+        slice::from_raw_parts(picture.data, picture.data_length as usize).to_vec()
+    };
+
+    Picture {
+        picture_type: picture.type_,
+        mime_type: c_string(picture.mime_type),
+        description: c_string(picture.description as *const libc::c_char),
+        width: picture.width,
+        height: picture.height,
+        depth: picture.depth,
+        colors: picture.colors,
+        data,
+    }
+}
+
+/// The kinds of metadata block the encoder can write after STREAMINFO.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MetadataKind {
+    VorbisComment,
+    Picture,
+    Application,
+    SeekTable,
+    Padding,
+}
+
+impl MetadataKind {
+    /// The order used for kinds a custom order leaves out.
+    pub const DEFAULT_ORDER: [MetadataKind; 5] = [
+        MetadataKind::VorbisComment,
+        MetadataKind::Picture,
+        MetadataKind::Application,
+        MetadataKind::SeekTable,
+        MetadataKind::Padding,
+    ];
+}
+
+/// Checks a block order for `FlacEncoder::set_metadata_order`. A stream may
+/// hold only one VORBIS_COMMENT and one SEEKTABLE, and all pictures and all
+/// APPLICATION blocks are written together, so every kind may appear at
+/// most once.
+pub(crate) fn validate_order(order: &[MetadataKind]) -> Result<(), FlacError> {
+    for (i, kind) in order.iter().enumerate() {
+        if order[..i].contains(kind) {
+            return Err(FlacError::InvalidMetadata(format!(
+                "{:?} appears more than once in the metadata order",
+                kind
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// The block length field is 24 bits and includes the 4-byte ID.
+const MAX_APPLICATION_DATA: u32 = (1 << 24) - 1 - 4;
+
+const BLOCK_TYPE_VORBIS_COMMENT: u8 = 4;
+const MAX_BLOCK_LEN: usize = (1 << 24) - 1;
+
+/// The longest vendor string that fits in a VORBIS_COMMENT block, leaving
+/// room for its own length and the comment count.
+pub(crate) const MAX_VENDOR_LEN: usize = MAX_BLOCK_LEN - 8;
+
+/// Replaces the vendor string in `block`, one serialised metadata block
+/// with its header, if it is a VORBIS_COMMENT block.
+///
+/// libFLAC ignores the vendor string of the block it is given and always
+/// writes its own, so a different one has to be swapped in on the way out.
+/// `None` means `block` is something else; an error that the new vendor
+/// makes the block too long.
+pub(crate) fn replace_vendor(block: &[u8], vendor: &str) -> Option<Result<Vec<u8>, FlacError>> {
+    if block.len() < 8 || block[0] & 0x7f != BLOCK_TYPE_VORBIS_COMMENT {
+        return None;
+    }
+    let old_len = u32::from_le_bytes([block[4], block[5], block[6], block[7]]) as usize;
+    let rest = block.get(8 + old_len..)?;
+    let body_len = 4 + vendor.len() + rest.len();
+    if body_len > MAX_BLOCK_LEN {
+        return Some(Err(FlacError::InvalidMetadata(format!(
+            "VORBIS_COMMENT block of {} bytes is too large",
+            body_len
+        ))));
+    }
+
+    let mut out = Vec::with_capacity(4 + body_len);
+    out.push(block[0]);
+    out.extend_from_slice(&(body_len as u32).to_be_bytes()[1..]);
+    out.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    out.extend_from_slice(vendor.as_bytes());
+    out.extend_from_slice(rest);
+    Some(Ok(out))
+}
+
+/// Metadata to write after STREAMINFO, kept as plain Rust values until the
+/// encoder is initialised.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct MetadataConfig {
+    pub tags: Vec<(String, String)>,
+    pub pictures: Vec<Picture>,
+    pub applications: Vec<(ApplicationId, Vec<u8>)>,
+    pub seek_placeholders: u32,
+    /// Seek point interval and total samples, for points libFLAC fills in.
+    pub seek_spacing: Option<(u32, u64)>,
+    pub padding: Option<u32>,
+    /// Relative order of the blocks; kinds left out follow in
+    /// [`MetadataKind::DEFAULT_ORDER`].
+    pub order: Vec<MetadataKind>,
+}
+
+/// Metadata objects handed to `FLAC__stream_encoder_set_metadata`.
+///
+/// libFLAC keeps the pointers rather than copying the blocks, and reads them
+/// again when it finishes the stream (the seek table is filled in place), so
+/// they must outlive the encoder they were given to. Dropping this frees
+/// them, which the owner must only do after `FLAC__stream_encoder_delete`.
+#[derive(Default)]
+pub(crate) struct Blocks(Vec<*mut ffi::FLAC__StreamMetadata>);
+
+impl Blocks {
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn as_mut_ptr(&mut self) -> *mut *mut ffi::FLAC__StreamMetadata {
+        self.0.as_mut_ptr()
+    }
+}
+
+impl Drop for Blocks {
+    fn drop(&mut self) {
+        for block in self.0.drain(..) {
+            unsafe { ffi::FLAC__metadata_object_delete(block) };
+        }
+    }
+}
+
+impl MetadataConfig {
+    /// Builds the libFLAC objects for `FLAC__stream_encoder_set_metadata`.
+    /// On error the blocks built so far are freed.
+    pub fn build(&self) -> Result<Blocks, FlacError> {
+        let mut blocks = Blocks::default();
+        self.build_into(&mut blocks)?;
+        Ok(blocks)
+    }
+
+    fn build_into(&self, blocks: &mut Blocks) -> Result<(), FlacError> {
+        let remaining = MetadataKind::DEFAULT_ORDER
+            .iter()
+            .filter(|kind| !self.order.contains(kind));
+        for kind in self.order.iter().chain(remaining) {
+            match kind {
+                MetadataKind::VorbisComment => self.build_vorbis_comment(blocks)?,
+                MetadataKind::Picture => self.build_pictures(blocks)?,
+                MetadataKind::Application => self.build_applications(blocks)?,
+                MetadataKind::SeekTable => self.build_seek_table(blocks)?,
+                MetadataKind::Padding => self.build_padding(blocks)?,
+            }
+        }
+        Ok(())
+    }
+
+    fn build_vorbis_comment(&self, blocks: &mut Blocks) -> Result<(), FlacError> {
+        if !self.tags.is_empty() {
+            let block = new_block(ffi::FLAC__METADATA_TYPE_VORBIS_COMMENT, blocks)?;
+            for (name, value) in &self.tags {
+                append_tag(block, name, value)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn build_pictures(&self, blocks: &mut Blocks) -> Result<(), FlacError> {
+        for picture in &self.pictures {
+            let block = new_block(ffi::FLAC__METADATA_TYPE_PICTURE, blocks)?;
+            set_picture(block, picture)?;
+        }
+        Ok(())
+    }
+
+    fn build_applications(&self, blocks: &mut Blocks) -> Result<(), FlacError> {
+        for (id, data) in &self.applications {
+            let block = new_block(ffi::FLAC__METADATA_TYPE_APPLICATION, blocks)?;
+            let length = u32::try_from(data.len())
+                .ok()
+                .filter(|&length| length <= MAX_APPLICATION_DATA)
+                .ok_or_else(|| {
+                    FlacError::InvalidMetadata(format!(
+                        "APPLICATION data of {} bytes is too large",
+                        data.len()
+                    ))
+                })?;
+            unsafe {
+                (*block).data.application.id = id.as_bytes();
+                // copy = true, so libFLAC takes its own copy of the data
+                if ffi::FLAC__metadata_object_application_set_data(
+                    block,
+                    data.as_ptr() as *mut ffi::FLAC__byte,
+                    length,
+                    true as i32,
+                ) == 0
+                {
+                    return Err(FlacError::InvalidMetadata(
+                        "Failed to allocate APPLICATION data".to_string(),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn build_seek_table(&self, blocks: &mut Blocks) -> Result<(), FlacError> {
+        if self.seek_placeholders > 0 || self.seek_spacing.is_some() {
+            let block = new_block(ffi::FLAC__METADATA_TYPE_SEEKTABLE, blocks)?;
+            let ok = unsafe {
+                let spaced = match self.seek_spacing {
+                    Some((interval, total_samples)) => {
+                        ffi::FLAC__metadata_object_seektable_template_append_spaced_points_by_samples(
+                            block,
+                            interval,
+                            total_samples,
+                        ) != 0
+                    }
+                    None => true,
+                };
+                // sorting moves the placeholders after the real points
+                spaced
+                    && ffi::FLAC__metadata_object_seektable_template_append_placeholders(
+                        block,
+                        self.seek_placeholders,
+                    ) != 0
+                    && ffi::FLAC__metadata_object_seektable_template_sort(block, 1) != 0
+            };
+            if !ok {
+                return Err(FlacError::InvalidMetadata(
+                    "Failed to allocate seek table".to_string(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn build_padding(&self, blocks: &mut Blocks) -> Result<(), FlacError> {
+        if let Some(padding) = self.padding {
+            let block = new_block(ffi::FLAC__METADATA_TYPE_PADDING, blocks)?;
+            unsafe { (*block).length = padding };
+        }
+
+        Ok(())
+    }
+}
+
+fn new_block(
+    block_type: ffi::FLAC__MetadataType,
+    blocks: &mut Blocks,
+) -> Result<*mut ffi::FLAC__StreamMetadata, FlacError> {
+    let block = unsafe { ffi::FLAC__metadata_object_new(block_type) };
+    if block.is_null() {
+        return Err(FlacError::InvalidMetadata(
+            "Failed to allocate metadata block".to_string(),
+        ));
+    }
+    blocks.0.push(block);
+    Ok(block)
+}
+
+pub(crate) fn c_string(what: &str, value: &str) -> Result<CString, FlacError> {
+    CString::new(value)
+        .map_err(|_| FlacError::InvalidMetadata(format!("{} contains a NUL byte", what)))
+}
+
+fn append_tag(
+    block: *mut ffi::FLAC__StreamMetadata,
+    name: &str,
+    value: &str,
+) -> Result<(), FlacError> {
+    let entry = tag_entry(name, value)?;
+    unsafe {
+        // ownership of the entry's buffer passes to the block
+        if ffi::FLAC__metadata_object_vorbiscomment_append_comment(block, entry, 0) == 0 {
+            libc::free(entry.entry as *mut libc::c_void);
+            return Err(FlacError::InvalidMetadata(format!(
+                "Failed to add tag {:?}",
+                name
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// A `NAME=value` entry in a buffer libFLAC allocated, for a block to take
+/// ownership of. The name must be a legal field name.
+pub(crate) fn tag_entry(
+    name: &str,
+    value: &str,
+) -> Result<ffi::FLAC__StreamMetadata_VorbisComment_Entry, FlacError> {
+    let c_name = c_string("Tag name", name)?;
+    let c_value = c_string("Tag value", value)?;
+
+    let mut entry = ffi::FLAC__StreamMetadata_VorbisComment_Entry {
+        length: 0,
+        entry: std::ptr::null_mut(),
+    };
+    let ok = unsafe {
+        ffi::FLAC__metadata_object_vorbiscomment_entry_from_name_value_pair(
+            &mut entry,
+            c_name.as_ptr(),
+            c_value.as_ptr(),
+        )
+    };
+    if ok == 0 {
+        return Err(FlacError::InvalidMetadata(format!(
+            "Invalid tag {:?}={:?}",
+            name, value
+        )));
+    }
+    Ok(entry)
+}
+
+fn set_picture(block: *mut ffi::FLAC__StreamMetadata, picture: &Picture) -> Result<(), FlacError> {
+    let mime_type = c_string("Picture MIME type", &picture.mime_type)?;
+    let description = c_string("Picture description", &picture.description)?;
+
+    unsafe {
+        let fields = &mut (*block).data.picture;
+        fields.type_ = picture.picture_type as ffi::FLAC__StreamMetadata_Picture_Type;
+        fields.width = picture.width;
+        fields.height = picture.height;
+        fields.depth = picture.depth;
+        fields.colors = picture.colors;
+
+        // with copy = true libFLAC duplicates every buffer it is handed
+        let ok = ffi::FLAC__metadata_object_picture_set_mime_type(
+            block,
+            mime_type.as_ptr() as *mut libc::c_char,
+            1,
+        ) != 0
+            && ffi::FLAC__metadata_object_picture_set_description(
+                block,
+                description.as_ptr() as *mut ffi::FLAC__byte,
+                1,
+            ) != 0
+            && ffi::FLAC__metadata_object_picture_set_data(
+                block,
+                picture.data.as_ptr() as *mut ffi::FLAC__byte,
+                picture.data.len() as u32,
+                1,
+            ) != 0;
+
+        if !ok {
+            return Err(FlacError::InvalidMetadata(
+                "Failed to set picture data".to_string(),
+            ));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replace_vendor() {
+        // last-block VORBIS_COMMENT: vendor "libFLAC", one comment "A=b"
+        let mut block = vec![0x84, 0, 0, 22];
+        block.extend_from_slice(&7u32.to_le_bytes());
+        block.extend_from_slice(b"libFLAC");
+        block.extend_from_slice(&1u32.to_le_bytes());
+        block.extend_from_slice(&3u32.to_le_bytes());
+        block.extend_from_slice(b"A=b");
+
+        let replaced = replace_vendor(&block, "mine").unwrap().unwrap();
+        assert_eq!(&replaced[..4], &[0x84, 0, 0, 19]);
+        assert_eq!(&replaced[4..12], b"\x04\0\0\0mine");
+        assert_eq!(&replaced[12..], &block[15..]);
+        assert_eq!(replaced.len(), 4 + 19);
+
+        let padding = [0x81, 0, 0, 4, 0, 0, 0, 0];
+        assert!(replace_vendor(&padding, "mine").is_none());
+        assert!(replace_vendor(&block[..10], "mine").is_none());
+    }
+
+    #[test]
+    fn test_validate_order_rejects_duplicates() {
+        assert_eq!(validate_order(&[]), Ok(()));
+        assert_eq!(validate_order(&MetadataKind::DEFAULT_ORDER), Ok(()));
+        assert!(matches!(
+            validate_order(&[
+                MetadataKind::SeekTable,
+                MetadataKind::Padding,
+                MetadataKind::SeekTable
+            ]),
+            Err(FlacError::InvalidMetadata(_))
+        ));
+    }
+}
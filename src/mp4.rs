@@ -0,0 +1,555 @@
+//! Decoding FLAC carried in MP4 (ISO base media file format), as specified
+//! by the FLAC-in-ISOBMFF encapsulation: a `fLaC` sample entry whose `dfLa`
+//! box holds the metadata blocks, and one FLAC frame per MP4 sample.
+
+use crate::header::STREAM_MARKER;
+use crate::{decode_robust, DecodedAudio, FlacError};
+
+/// Box types descended into while looking for the track and its samples.
+const CONTAINERS: [&[u8; 4]; 8] = [
+    b"moov", b"trak", b"mdia", b"minf", b"stbl", b"mvex", b"moof", b"traf",
+];
+
+/// How deep [`walk`] descends; real files nest about five boxes deep, and a
+/// crafted one could otherwise nest deep enough to overflow the stack.
+const MAX_DEPTH: usize = 16;
+
+struct Mp4Box<'a> {
+    kind: [u8; 4],
+    /// Offset of the box header in the file.
+    start: usize,
+    body: &'a [u8],
+    /// Offset of `body` in the file.
+    body_start: usize,
+}
+
+/// The boxes in `data[range]`, which starts `offset` bytes into the file.
+fn boxes(data: &[u8], offset: usize) -> impl Iterator<Item = Result<Mp4Box<'_>, FlacError>> {
+    let mut pos = 0;
+    std::iter::from_fn(move || {
+        if pos >= data.len() {
+            return None;
+        }
+        let parsed = parse_box(&data[pos..], offset + pos);
+        pos = match &parsed {
+            Ok(b) => b.body_start - offset + b.body.len(),
+            Err(_) => data.len(),
+        };
+        Some(parsed)
+    })
+}
+
+fn parse_box(data: &[u8], start: usize) -> Result<Mp4Box<'_>, FlacError> {
+    let truncated = || invalid(format!("Box at offset {} is truncated", start));
+    let header = data.get(..8).ok_or_else(truncated)?;
+    let kind = [header[4], header[5], header[6], header[7]];
+    let (size, header_len) = match u32_at(header, 0) {
+        0 => (data.len() as u64, 8),
+        1 => (u64_at(data, 8).ok_or_else(truncated)?, 16),
+        size => (size as u64, 8),
+    };
+    if size < header_len as u64 || size > data.len() as u64 {
+        return Err(truncated());
+    }
+    Ok(Mp4Box {
+        kind,
+        start,
+        body: &data[header_len..size as usize],
+        body_start: start + header_len,
+    })
+}
+
+/// Every box under `data`, depth first, descending into [`CONTAINERS`] at
+/// most [`MAX_DEPTH`] levels below `depth`.
+fn walk<'a>(
+    data: &'a [u8],
+    offset: usize,
+    depth: usize,
+    visit: &mut impl FnMut(&Mp4Box<'a>) -> Result<(), FlacError>,
+) -> Result<(), FlacError> {
+    if depth > MAX_DEPTH {
+        return Err(invalid(format!(
+            "Boxes at offset {} are nested more than {} deep",
+            offset, MAX_DEPTH
+        )));
+    }
+    for b in boxes(data, offset) {
+        let b = b?;
+        visit(&b)?;
+        if CONTAINERS.contains(&&b.kind) {
+            walk(b.body, b.body_start, depth + 1, visit)?;
+        }
+    }
+    Ok(())
+}
+
+fn invalid(message: String) -> FlacError {
+    FlacError::Decoder(format!("MP4: {}", message))
+}
+
+fn u32_at(data: &[u8], at: usize) -> u32 {
+    data.get(at..at + 4)
+        .map_or(0, |b| u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+}
+
+fn u64_at(data: &[u8], at: usize) -> Option<u64> {
+    let b = data.get(at..at + 8)?;
+    Some(u64::from_be_bytes(b.try_into().unwrap()))
+}
+
+/// A full box's version and flags, then its fields.
+fn full_box(body: &[u8]) -> Result<(u8, u32, &[u8]), FlacError> {
+    if body.len() < 4 {
+        return Err(invalid("Full box is truncated".to_string()));
+    }
+    Ok((body[0], u32_at(body, 0) & 0xff_ffff, &body[4..]))
+}
+
+/// Reads `count` big-endian fields of `width` bytes from `data`.
+fn table(data: &[u8], count: u32, width: usize, what: &str) -> Result<Vec<u64>, FlacError> {
+    let len = (count as usize).checked_mul(width);
+    let data = len
+        .and_then(|len| data.get(..len))
+        .ok_or_else(|| invalid(format!("{} table is truncated", what)))?;
+    Ok(data
+        .chunks_exact(width)
+        .map(|b| {
+            b.iter()
+                .fold(0u64, |value, &byte| (value << 8) | byte as u64)
+        })
+        .collect())
+}
+
+/// The one FLAC track: its ID, metadata blocks and, for a progressive file,
+/// where its samples are.
+#[derive(Default)]
+struct Track<'a> {
+    id: u32,
+    dfla: Option<&'a [u8]>,
+    sizes: Vec<u64>,
+    /// (first chunk, 1-based; samples per chunk) runs.
+    chunk_runs: Vec<(u64, u64)>,
+    chunk_offsets: Vec<u64>,
+    /// From `trex`, for fragments.
+    default_sample_size: u32,
+}
+
+/// Reads one `trak`, returning it if it has a `fLaC` sample entry. The
+/// samples must fit in the `file_len` bytes of the file.
+fn read_track<'a>(trak: &Mp4Box<'a>, file_len: usize) -> Result<Option<Track<'a>>, FlacError> {
+    let mut track = Track::default();
+    walk(trak.body, trak.body_start, 0, &mut |b| {
+        match &b.kind {
+            b"tkhd" => {
+                let (version, _, fields) = full_box(b.body)?;
+                track.id = u32_at(fields, if version == 1 { 16 } else { 8 });
+            }
+            b"stsd" => {
+                let (_, _, fields) = full_box(b.body)?;
+                let entries = fields.get(4..).unwrap_or_default();
+                for entry in boxes(entries, b.body_start + 8) {
+                    let entry = entry?;
+                    if &entry.kind != b"fLaC" {
+                        continue;
+                    }
+                    // the audio sample entry fields come before the child boxes
+                    let children = entry
+                        .body
+                        .get(28..)
+                        .ok_or_else(|| invalid("fLaC sample entry is truncated".to_string()))?;
+                    for child in boxes(children, entry.body_start + 28) {
+                        let child = child?;
+                        if &child.kind == b"dfLa" {
+                            track.dfla = Some(full_box(child.body)?.2);
+                        }
+                    }
+                }
+            }
+            b"stsz" => {
+                let (_, _, fields) = full_box(b.body)?;
+                let (size, count) = (u32_at(fields, 0), u32_at(fields, 4));
+                track.sizes = match size {
+                    0 => table(fields.get(8..).unwrap_or_default(), count, 4, "stsz")?,
+                    // checked before allocating, as the count is untrusted
+                    size if size as u64 * count as u64 > file_len as u64 => {
+                        return Err(invalid(
+                            "stsz describes more data than the file holds".into(),
+                        ))
+                    }
+                    size => vec![size as u64; count as usize],
+                };
+            }
+            b"stz2" => {
+                return Err(invalid(
+                    "Compact sample sizes (stz2) are not supported".into(),
+                ))
+            }
+            b"stsc" => {
+                let (_, _, fields) = full_box(b.body)?;
+                let entries = table(
+                    fields.get(4..).unwrap_or_default(),
+                    u32_at(fields, 0).saturating_mul(3),
+                    4,
+                    "stsc",
+                )?;
+                track.chunk_runs = entries.chunks_exact(3).map(|e| (e[0], e[1])).collect();
+            }
+            b"stco" | b"co64" => {
+                let (_, _, fields) = full_box(b.body)?;
+                let width = if &b.kind == b"co64" { 8 } else { 4 };
+                track.chunk_offsets = table(
+                    fields.get(4..).unwrap_or_default(),
+                    u32_at(fields, 0),
+                    width,
+                    "chunk offset",
+                )?;
+            }
+            _ => {}
+        }
+        Ok(())
+    })?;
+    Ok(track.dfla.is_some().then_some(track))
+}
+
+/// The byte ranges of a progressive track's samples, from its sample tables.
+fn progressive_samples(track: &Track) -> Result<Vec<(u64, u64)>, FlacError> {
+    let mut samples = Vec::with_capacity(track.sizes.len());
+    let mut sizes = track.sizes.iter();
+    for (chunk, &offset) in track.chunk_offsets.iter().enumerate() {
+        let chunk = chunk as u64 + 1;
+        let per_chunk = track
+            .chunk_runs
+            .iter()
+            .take_while(|(first, _)| *first <= chunk)
+            .last()
+            .map_or(0, |&(_, samples)| samples);
+        let mut pos = offset;
+        for _ in 0..per_chunk {
+            let Some(&size) = sizes.next() else { break };
+            samples.push((pos, size));
+            pos = pos.saturating_add(size);
+        }
+    }
+    Ok(samples)
+}
+
+/// The byte ranges of the track's samples in the movie fragments.
+fn fragment_samples(data: &[u8], track: &Track) -> Result<Vec<(u64, u64)>, FlacError> {
+    let mut samples = Vec::new();
+    for moof in boxes(data, 0) {
+        let moof = moof?;
+        if &moof.kind != b"moof" {
+            continue;
+        }
+        for traf in boxes(moof.body, moof.body_start) {
+            let traf = traf?;
+            if &traf.kind != b"traf" {
+                continue;
+            }
+            let mut base = moof.start as u64;
+            let mut default_size = track.default_sample_size;
+            let mut ours = false;
+            // where a trun without a data offset starts
+            let mut next = None;
+            for b in boxes(traf.body, traf.body_start) {
+                let b = b?;
+                let (_, flags, fields) = full_box(b.body)?;
+                match &b.kind {
+                    b"tfhd" => {
+                        ours = u32_at(fields, 0) == track.id;
+                        let mut at = 4;
+                        if flags & 0x01 != 0 {
+                            base = u64_at(fields, at).unwrap_or(base);
+                            at += 8;
+                        }
+                        at += 4 * (flags & 0x02 != 0) as usize + 4 * (flags & 0x08 != 0) as usize;
+                        if flags & 0x10 != 0 {
+                            default_size = u32_at(fields, at);
+                        }
+                    }
+                    b"trun" if ours => {
+                        let count = u32_at(fields, 0);
+                        let mut at = 4;
+                        let mut pos = next.unwrap_or(base);
+                        if flags & 0x01 != 0 {
+                            pos = base.wrapping_add(u32_at(fields, at) as i32 as i64 as u64);
+                            at += 4;
+                        }
+                        at += 4 * (flags & 0x04 != 0) as usize;
+                        let fields_per_sample = [0x100, 0x200, 0x400, 0x800]
+                            .iter()
+                            .filter(|&&f| flags & f != 0)
+                            .count();
+                        let entries = table(
+                            fields.get(at..).unwrap_or_default(),
+                            count.saturating_mul(fields_per_sample as u32),
+                            4,
+                            "trun",
+                        )?;
+                        // the size is the second field when a duration precedes it
+                        let size_field = (flags & 0x100 != 0) as usize;
+                        for i in 0..count as usize {
+                            let size = match flags & 0x200 {
+                                0 => default_size as u64,
+                                _ => entries[i * fields_per_sample + size_field],
+                            };
+                            // the count is untrusted: stop at the end of the file
+                            if size == 0 || pos.saturating_add(size) > data.len() as u64 {
+                                return Err(invalid(format!(
+                                    "trun sample of {} bytes at offset {} is outside the file",
+                                    size, pos
+                                )));
+                            }
+                            samples.push((pos, size));
+                            pos += size;
+                        }
+                        next = Some(pos);
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    Ok(samples)
+}
+
+/// Decodes the first FLAC audio track of an MP4 file held in `data`.
+///
+/// The file must contain the whole `moov` box and the media data. Both
+/// layouts packagers produce are read, whatever the brand in `ftyp`
+/// (`isom`, `mp41`, `iso6`, `dash`, `cmfc` and so on):
+///
+/// - progressive files, with the samples located by the `stsz`, `stsc`
+///   and `stco` or `co64` tables;
+/// - fragmented files (`moof` and `mdat` pairs, as in DASH, HLS fMP4 and
+///   CMAF), with the init segment and media segments joined in order.
+///   Samples are found from `tfhd`, `trun` and `trex`.
+///
+/// The STREAMINFO and other metadata blocks come from the `dfLa` box of the
+/// `fLaC` sample entry; each sample is one FLAC frame. The frames are joined
+/// into a native stream and decoded with [`decode_robust`], so damaged
+/// audio fails as it would there. Compact sample sizes (`stz2`), media data
+/// in other files and edit lists are not supported; an edit list is
+/// ignored and the whole track decoded.
+pub fn decode_mp4_flac(data: &[u8]) -> Result<DecodedAudio, FlacError> {
+    let mut track = None;
+    let mut default_sample_size = 0;
+    walk(data, 0, 0, &mut |b| {
+        match &b.kind {
+            b"trak" if track.is_none() => track = read_track(b, data.len())?,
+            b"trex" => {
+                // one per track; the FLAC track's is picked out below
+                let (_, _, fields) = full_box(b.body)?;
+                if track
+                    .as_ref()
+                    .is_some_and(|t: &Track| t.id == u32_at(fields, 0))
+                {
+                    default_sample_size = u32_at(fields, 12);
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    })?;
+    let mut track = track.ok_or_else(|| invalid("No fLaC track found".to_string()))?;
+    track.default_sample_size = default_sample_size;
+
+    let samples = match track.sizes.is_empty() {
+        false => progressive_samples(&track)?,
+        true => fragment_samples(data, &track)?,
+    };
+
+    let dfla = track.dfla.unwrap_or_default();
+    let mut stream = STREAM_MARKER.to_vec();
+    stream.extend_from_slice(dfla);
+    mark_last_block(&mut stream)?;
+    for (offset, size) in samples {
+        let frame = usize::try_from(offset)
+            .ok()
+            .zip(usize::try_from(size).ok())
+            .and_then(|(offset, size)| data.get(offset..offset.checked_add(size)?))
+            .ok_or_else(|| {
+                invalid(format!(
+                    "Sample of {} bytes at offset {} is outside the file",
+                    size, offset
+                ))
+            })?;
+        stream.extend_from_slice(frame);
+    }
+    decode_robust(&stream)
+}
+
+/// Sets the last-block flag on the final metadata block of `stream`, which
+/// `dfLa` is meant to have already.
+fn mark_last_block(stream: &mut [u8]) -> Result<(), FlacError> {
+    let mut pos = STREAM_MARKER.len();
+    let mut last = None;
+    while pos + 4 <= stream.len() {
+        last = Some(pos);
+        pos +=
+            4 + u32::from_be_bytes([0, stream[pos + 1], stream[pos + 2], stream[pos + 3]]) as usize;
+    }
+    match (last, pos == stream.len()) {
+        (Some(last), true) => {
+            stream[last] |= 0x80;
+            Ok(())
+        }
+        _ => Err(invalid("dfLa metadata blocks are truncated".to_string())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::sine_sweep;
+    use crate::FlacEncoder;
+    use soundkit::audio_packet::Encoder;
+
+    fn mp4_box(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut b = ((body.len() + 8) as u32).to_be_bytes().to_vec();
+        b.extend_from_slice(kind);
+        b.extend_from_slice(body);
+        b
+    }
+
+    fn full(kind: &[u8; 4], flags: u32, fields: &[u8]) -> Vec<u8> {
+        let mut body = flags.to_be_bytes().to_vec();
+        body.extend_from_slice(fields);
+        mp4_box(kind, &body)
+    }
+
+    fn words(values: &[u32]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_be_bytes()).collect()
+    }
+
+    /// A FLAC stream's metadata blocks and its frames.
+    fn encode(samples: &[i32]) -> (Vec<u8>, Vec<Vec<u8>>) {
+        let mut encoder = FlacEncoder::new(44100, 16, 2, 1024, 5);
+        encoder.init().unwrap();
+        let mut output = vec![0u8; 1 << 20];
+        let mut encoded = Vec::new();
+        let len = encoder.encode_i32(samples, &mut output).unwrap();
+        encoded.extend_from_slice(&output[..len]);
+        let len = encoder.finish(&mut output).unwrap();
+        encoded.extend_from_slice(&output[..len]);
+
+        let mut offsets: Vec<usize> = encoder
+            .seek_index()
+            .iter()
+            .map(|p| p.byte_offset as usize)
+            .collect();
+        offsets.push(encoded.len());
+        let frames = offsets
+            .windows(2)
+            .map(|w| encoded[w[0]..w[1]].to_vec())
+            .collect();
+        (encoded[4..offsets[0]].to_vec(), frames)
+    }
+
+    fn trak(dfla: &[u8], tables: &[u8]) -> Vec<u8> {
+        let mut entry = vec![0u8; 28];
+        entry.extend(full(b"dfLa", 0, dfla));
+        let mut stbl = full(
+            b"stsd",
+            0,
+            &[words(&[1]), mp4_box(b"fLaC", &entry)].concat(),
+        );
+        stbl.extend_from_slice(tables);
+        let tkhd = full(b"tkhd", 0, &words(&[0, 0, 1, 0, 0]));
+        let minf = mp4_box(b"minf", &mp4_box(b"stbl", &stbl));
+        mp4_box(b"trak", &[tkhd, mp4_box(b"mdia", &minf)].concat())
+    }
+
+    #[test]
+    fn test_progressive() {
+        let samples = sine_sweep(16, 2, 5000);
+        let (dfla, frames) = encode(&samples);
+        let ftyp = mp4_box(b"ftyp", b"isom\0\0\0\0isom");
+        // two chunks: three frames, then the rest
+        let sizes: Vec<u32> = frames.iter().map(|f| f.len() as u32).collect();
+        let moov_len = |tables: &[u8]| mp4_box(b"moov", &trak(&dfla, tables)).len();
+        let tables = |mdat_start: u32| {
+            let second = mdat_start + sizes[..3].iter().sum::<u32>();
+            [
+                full(
+                    b"stsz",
+                    0,
+                    &[words(&[0, sizes.len() as u32]), words(&sizes)].concat(),
+                ),
+                full(
+                    b"stsc",
+                    0,
+                    &words(&[2, 1, 3, 1, 2, sizes.len() as u32 - 3, 1]),
+                ),
+                full(b"stco", 0, &words(&[2, mdat_start, second])),
+            ]
+            .concat()
+        };
+        let mdat_start = (ftyp.len() + moov_len(&tables(0)) + 8) as u32;
+        let mut file = ftyp;
+        file.extend(mp4_box(b"moov", &trak(&dfla, &tables(mdat_start))));
+        file.extend(mp4_box(b"mdat", &frames.concat()));
+
+        let decoded = decode_mp4_flac(&file).unwrap();
+        assert_eq!((decoded.sample_rate, decoded.channels), (44100, 2));
+        assert_eq!(decoded.samples, samples);
+    }
+
+    #[test]
+    fn test_fragmented() {
+        let samples = sine_sweep(16, 2, 5000);
+        let (dfla, frames) = encode(&samples);
+        let trex = full(b"trex", 0, &words(&[1, 1, 0, 0, 0]));
+        let mut file = mp4_box(b"ftyp", b"iso6\0\0\0\0iso6");
+        let moov = [trak(&dfla, &[]), mp4_box(b"mvex", &trex)].concat();
+        file.extend(mp4_box(b"moov", &moov));
+
+        // one fragment per three frames, sizes in the trun, data offsets
+        // relative to the moof
+        for group in frames.chunks(3) {
+            let sizes: Vec<u32> = group.iter().map(|f| f.len() as u32).collect();
+            let tfhd = full(b"tfhd", 0x02_0000, &words(&[1]));
+            let trun_len = 8 + 4 + 4 + 4 + 4 * sizes.len();
+            let moof_len = 8 + 8 + tfhd.len() + trun_len + 16;
+            let trun = full(
+                b"trun",
+                0x201,
+                &[
+                    words(&[sizes.len() as u32, moof_len as u32 + 8]),
+                    words(&sizes),
+                ]
+                .concat(),
+            );
+            let mfhd = full(b"mfhd", 0, &words(&[1]));
+            let moof = mp4_box(
+                b"moof",
+                &[mfhd, mp4_box(b"traf", &[tfhd, trun].concat())].concat(),
+            );
+            assert_eq!(moof.len(), moof_len);
+            file.extend(moof);
+            file.extend(mp4_box(b"mdat", &group.concat()));
+        }
+
+        assert_eq!(decode_mp4_flac(&file).unwrap().samples, samples);
+    }
+
+    #[test]
+    fn test_rejects_files_without_flac() {
+        let file = mp4_box(b"ftyp", b"isom\0\0\0\0isom");
+        assert!(matches!(decode_mp4_flac(&file), Err(FlacError::Decoder(_))));
+        assert!(decode_mp4_flac(&[0, 0, 0, 99, b'm', b'o', b'o', b'v']).is_err());
+    }
+
+    #[test]
+    fn test_rejects_deeply_nested_boxes() {
+        // a chain of empty moov boxes, each holding the next
+        let depth = 100_000u32;
+        let file: Vec<u8> = (0..depth)
+            .flat_map(|i| [words(&[8 * (depth - i)]), b"moov".to_vec()].concat())
+            .collect();
+        assert!(matches!(
+            decode_mp4_flac(&file),
+            Err(FlacError::Decoder(msg)) if msg.contains("nested")
+        ));
+    }
+}
@@ -0,0 +1,161 @@
+use crate::FlacEncoder;
+use soundkit::audio_packet::Encoder;
+use std::cell::RefCell;
+use std::ops::{Deref, DerefMut};
+use tracing::error;
+
+/// A single-threaded pool of identically configured encoders.
+///
+/// Creating a libFLAC encoder is comparatively expensive, so servers that
+/// encode many short streams can hand encoders out from a pool instead.
+/// Encoders come back ready for a new stream: the guard returned by
+/// [`FlacEncoderPool::acquire`] calls `reset()` when it is dropped.
+pub struct FlacEncoderPool {
+    encoders: RefCell<Vec<FlacEncoder>>,
+    sample_rate: u32,
+    bits_per_sample: u32,
+    channels: u32,
+    frame_length: u32,
+    compression_level: u32,
+}
+
+impl FlacEncoderPool {
+    pub fn new(
+        size: usize,
+        sample_rate: u32,
+        bits_per_sample: u32,
+        channels: u32,
+        frame_length: u32,
+        compression_level: u32,
+    ) -> Result<Self, String> {
+        let pool = FlacEncoderPool {
+            encoders: RefCell::new(Vec::with_capacity(size)),
+            sample_rate,
+            bits_per_sample,
+            channels,
+            frame_length,
+            compression_level,
+        };
+
+        for _ in 0..size {
+            let encoder = pool.create()?;
+            pool.encoders.borrow_mut().push(encoder);
+        }
+
+        Ok(pool)
+    }
+
+    /// Takes an initialised encoder from the pool, creating a new one if the
+    /// pool is exhausted.
+    pub fn acquire(&self) -> Result<PooledEncoder<'_>, String> {
+        let encoder = match self.encoders.borrow_mut().pop() {
+            Some(encoder) => encoder,
+            None => self.create()?,
+        };
+
+        Ok(PooledEncoder {
+            pool: self,
+            encoder: Some(encoder),
+        })
+    }
+
+    /// Number of idle encoders currently held by the pool.
+    pub fn available(&self) -> usize {
+        self.encoders.borrow().len()
+    }
+
+    fn create(&self) -> Result<FlacEncoder, String> {
+        let mut encoder = FlacEncoder::new(
+            self.sample_rate,
+            self.bits_per_sample,
+            self.channels,
+            self.frame_length,
+            self.compression_level,
+        );
+        encoder.init()?;
+        Ok(encoder)
+    }
+}
+
+/// An encoder borrowed from a [`FlacEncoderPool`]. It is reset and returned to
+/// the pool on drop; encoders that fail to reset are discarded.
+pub struct PooledEncoder<'a> {
+    pool: &'a FlacEncoderPool,
+    encoder: Option<FlacEncoder>,
+}
+
+impl Deref for PooledEncoder<'_> {
+    type Target = FlacEncoder;
+
+    fn deref(&self) -> &FlacEncoder {
+        self.encoder.as_ref().unwrap()
+    }
+}
+
+impl DerefMut for PooledEncoder<'_> {
+    fn deref_mut(&mut self) -> &mut FlacEncoder {
+        self.encoder.as_mut().unwrap()
+    }
+}
+
+impl Drop for PooledEncoder<'_> {
+    fn drop(&mut self) {
+        if let Some(mut encoder) = self.encoder.take() {
+            match encoder.reset() {
+                Ok(()) => self.pool.encoders.borrow_mut().push(encoder),
+                Err(e) => error!("Discarding pooled encoder that failed to reset: {}", e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pool_acquire_release_loop() {
+        let pool = FlacEncoderPool::new(4, 44100, 16, 2, 0, 5).unwrap();
+        assert_eq!(pool.available(), 4);
+
+        let input = vec![0i32; 4096 * 2];
+        let mut output = vec![0u8; 1 << 16];
+        for _ in 0..1000 {
+            let mut a = pool.acquire().unwrap();
+            let mut b = pool.acquire().unwrap();
+            assert_eq!(pool.available(), 2);
+
+            a.encode_i32(&input, &mut output).unwrap();
+            b.encode_i32(&input, &mut output).unwrap();
+        }
+        assert_eq!(pool.available(), 4);
+    }
+
+    #[test]
+    fn test_pool_reuses_the_libflac_encoder() {
+        let pool = FlacEncoderPool::new(1, 44100, 16, 2, 0, 5).unwrap();
+        let input = vec![0i32; 4096 * 2];
+        let mut output = vec![0u8; 1 << 16];
+
+        let mut handles = Vec::new();
+        for _ in 0..3 {
+            let mut encoder = pool.acquire().unwrap();
+            encoder.encode_i32(&input, &mut output).unwrap();
+            handles.push((*encoder).encoder);
+        }
+        assert!(!handles[0].is_null());
+        assert!(handles.iter().all(|&handle| handle == handles[0]));
+    }
+
+    #[test]
+    fn test_pool_grows_when_exhausted() {
+        let pool = FlacEncoderPool::new(1, 44100, 16, 2, 0, 5).unwrap();
+        let first = pool.acquire().unwrap();
+        let second = pool.acquire().unwrap();
+        assert_eq!(pool.available(), 0);
+
+        drop(first);
+        drop(second);
+        assert_eq!(pool.available(), 2);
+    }
+}
@@ -0,0 +1,226 @@
+use crate::{FlacEncoder, FlacError, SeekPoint};
+
+/// Feeds an encoder input of any size and hands its output on one frame at
+/// a time.
+///
+/// Network packets and device buffers rarely line up with the FLAC block
+/// size. Samples written here are buffered by libFLAC until a block is full,
+/// and each encoded frame is passed to the callback on its own as soon as it
+/// exists, with the [`SeekPoint`] describing it. The stream header (the
+/// `fLaC` marker and metadata) comes first, as a unit of its own with no
+/// seek point. How the input was split never changes what the callback
+/// sees, so output latency is bounded by one block.
+///
+/// The encoder must return its output from the encode calls: one writing to
+/// a [`seekable sink`](FlacEncoder::set_seekable_sink) or a file is refused.
+pub struct RechunkingEncoder<F: FnMut(&[u8], Option<SeekPoint>)> {
+    encoder: FlacEncoder,
+    on_output: F,
+    /// Frames of the current stream already passed to `on_output`, and the
+    /// stream offset of the first byte not yet passed on.
+    frames_emitted: usize,
+    bytes_emitted: u64,
+}
+
+impl<F: FnMut(&[u8], Option<SeekPoint>)> RechunkingEncoder<F> {
+    /// Wraps `encoder`, which must be initialised before the first `write`.
+    ///
+    /// It may already be part way through a stream: output that was handed
+    /// out before isn't passed on again, and output still pending is. That
+    /// fails if the pending output starts part way through a frame, e.g.
+    /// after `encode_from_iter` stopped on a partial write.
+    pub fn new(mut encoder: FlacEncoder, on_output: F) -> Result<Self, FlacError> {
+        if encoder.is_seekable_output() || encoder.writing_file {
+            return Err(FlacError::InvalidConfig(
+                "RechunkingEncoder needs an encoder without a sink or file output".to_string(),
+            ));
+        }
+        let (bytes_emitted, pending) = {
+            let write_state = encoder.write_state.borrow();
+            let pending = write_state.pending().len() as u64;
+            (write_state.stream_bytes - pending, pending)
+        };
+        let points = encoder.seek_index();
+        let frames_emitted = points
+            .iter()
+            .take_while(|point| point.byte_offset < bytes_emitted)
+            .count();
+        let next_frame = points.get(frames_emitted).map(|point| point.byte_offset);
+        if frames_emitted > 0 && pending > 0 && next_frame != Some(bytes_emitted) {
+            return Err(FlacError::InvalidConfig(
+                "RechunkingEncoder needs an encoder whose pending output starts on a frame"
+                    .to_string(),
+            ));
+        }
+        Ok(RechunkingEncoder {
+            encoder,
+            on_output,
+            frames_emitted,
+            bytes_emitted,
+        })
+    }
+
+    /// Encodes interleaved samples, passing on every frame they complete.
+    pub fn write(&mut self, samples: &[i32]) -> Result<(), FlacError> {
+        self.encoder.encode_buffered(samples)?;
+        self.emit();
+        Ok(())
+    }
+
+    /// Ends the stream, passing on the final, possibly shorter, frame.
+    pub fn finish(&mut self) -> Result<(), FlacError> {
+        self.encoder.finish_stream()?;
+        self.emit();
+        Ok(())
+    }
+
+    /// Starts a new stream on the same encoder, discarding any in progress.
+    pub fn reset(&mut self) -> Result<(), FlacError> {
+        self.encoder.restart()?;
+        self.frames_emitted = 0;
+        self.bytes_emitted = 0;
+        Ok(())
+    }
+
+    pub fn encoder(&self) -> &FlacEncoder {
+        &self.encoder
+    }
+
+    pub fn into_inner(self) -> FlacEncoder {
+        self.encoder
+    }
+
+    /// Splits the newly encoded bytes at the frame boundaries in the seek
+    /// index. The buffer only ever holds whole frames, so everything ahead
+    /// of the first new frame is header.
+    fn emit(&mut self) {
+        let bytes = self.encoder.take_encoded();
+        if bytes.is_empty() {
+            return;
+        }
+        let points = &self.encoder.seek_index()[self.frames_emitted..];
+        let start_of = |point: &SeekPoint| (point.byte_offset - self.bytes_emitted) as usize;
+
+        let header_len = points.first().map_or(bytes.len(), start_of);
+        if header_len > 0 {
+            (self.on_output)(&bytes[..header_len], None);
+        }
+        for (i, point) in points.iter().enumerate() {
+            let end = points.get(i + 1).map_or(bytes.len(), start_of);
+            (self.on_output)(&bytes[start_of(point)..end], Some(*point));
+        }
+
+        self.frames_emitted += points.len();
+        self.bytes_emitted += bytes.len() as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::white_noise;
+    use crate::{decode_robust, EncoderConfig};
+    use soundkit::audio_packet::Encoder;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_output_comes_one_frame_at_a_time() {
+        let frames = 10_000;
+        let samples = white_noise(16, 2, frames);
+        let units = Rc::new(RefCell::new(Vec::new()));
+        let sink = units.clone();
+        let mut encoder =
+            FlacEncoder::from_config(&EncoderConfig::new(44100, 2, 16).block_size(1024));
+        encoder.init().unwrap();
+        let mut rechunker = RechunkingEncoder::new(encoder, move |bytes: &[u8], point| {
+            sink.borrow_mut().push((bytes.to_vec(), point))
+        })
+        .unwrap();
+
+        // packet sizes unrelated to the block size, some of them odd
+        let mut rest = &samples[..];
+        for len in [2, 702, 3000, 10, 4096].iter().cycle() {
+            let (packet, tail) = rest.split_at((*len).min(rest.len()));
+            rechunker.write(packet).unwrap();
+            rest = tail;
+            if rest.is_empty() {
+                break;
+            }
+        }
+        rechunker.finish().unwrap();
+
+        let units = units.borrow();
+        let (header, header_point) = &units[0];
+        assert_eq!(&header[..4], b"fLaC");
+        assert_eq!(*header_point, None);
+        let points: Vec<SeekPoint> = units[1..].iter().map(|(_, p)| p.unwrap()).collect();
+        assert_eq!(points.len(), 10);
+        assert!(points[..9].iter().all(|p| p.frame_samples == 1024));
+        assert_eq!(points[9].frame_samples, 10_000 - 9 * 1024);
+        // each unit starts with a frame sync code
+        assert!(units[1..]
+            .iter()
+            .all(|(b, _)| b[0] == 0xff && b[1] & 0xfe == 0xf8));
+
+        let stream: Vec<u8> = units.iter().flat_map(|(b, _)| b.clone()).collect();
+        assert_eq!(decode_robust(&stream).unwrap().samples, samples);
+    }
+
+    #[test]
+    fn test_wraps_a_part_used_encoder() {
+        let samples = white_noise(16, 2, 10_000);
+        let (before, after) = samples.split_at(2 * 3000);
+        let mut encoder =
+            FlacEncoder::from_config(&EncoderConfig::new(44100, 2, 16).block_size(1024));
+        encoder.init().unwrap();
+        let mut output = vec![0u8; 1 << 20];
+        let len = encoder.encode_interleaved(before, &mut output).unwrap();
+        let mut stream = output[..len].to_vec();
+
+        let units = Rc::new(RefCell::new(Vec::new()));
+        let sink = units.clone();
+        let mut rechunker = RechunkingEncoder::new(encoder, move |bytes: &[u8], point| {
+            sink.borrow_mut().push((bytes.to_vec(), point))
+        })
+        .unwrap();
+        rechunker.write(after).unwrap();
+        rechunker.finish().unwrap();
+
+        // only frames follow, none of them passed on twice
+        let units = units.borrow();
+        assert!(units
+            .iter()
+            .all(|(b, p)| p.is_some() && b[0] == 0xff && b[1] & 0xfe == 0xf8));
+        let first = units[0].1.unwrap();
+        assert_eq!(first.byte_offset, len as u64);
+        stream.extend(units.iter().flat_map(|(b, _)| b.clone()));
+        assert_eq!(decode_robust(&stream).unwrap().samples, samples);
+
+        // pending output that starts inside a frame can't be split
+        let mut encoder = FlacEncoder::new(44100, 16, 2, 1024, 5);
+        encoder.init().unwrap();
+        encoder.process(before).unwrap();
+        let header_len = encoder.seek_index()[0].byte_offset as usize;
+        encoder
+            .write_state
+            .borrow_mut()
+            .consume_front(header_len + 5);
+        assert!(matches!(
+            RechunkingEncoder::new(encoder, |_: &[u8], _| {}),
+            Err(FlacError::InvalidConfig(_))
+        ));
+    }
+
+    #[test]
+    fn test_rejects_an_encoder_with_a_sink() {
+        let mut encoder = FlacEncoder::new(44100, 16, 2, 0, 5);
+        encoder
+            .set_seekable_sink(std::io::Cursor::new(Vec::new()))
+            .unwrap();
+        assert!(matches!(
+            RechunkingEncoder::new(encoder, |_: &[u8], _| {}),
+            Err(FlacError::InvalidConfig(_))
+        ));
+    }
+}
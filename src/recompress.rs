@@ -0,0 +1,165 @@
+use crate::header::{parse_streaminfo, BLOCK_TYPE_STREAMINFO, STREAMINFO_LEN, STREAM_MARKER};
+use crate::verify::first_mismatch;
+use crate::{
+    decode_robust, decode_robust_with_limit, EncoderConfig, FlacDecoder, FlacEncoder, FlacError,
+    Picture, StreamInfo, VorbisComment, MAX_DECODED_SAMPLES,
+};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+/// Re-encodes a native FLAC stream with `new_config`, e.g. a level 0 capture
+/// at level 8 for archiving.
+///
+/// The input is decoded to PCM and encoded again; tags and pictures are
+/// carried over, other metadata blocks are not, and the vendor string is
+/// libFLAC's unless `new_config` sets
+/// [`vendor_string`](EncoderConfig::vendor_string). `new_config` must
+/// describe the same audio as the input:
+/// its sample rate, channel count and bit depth have to match STREAMINFO.
+///
+/// The output header is completed with the real totals and MD5, as if it had
+/// been written to a seekable sink. Two safety checks follow. The MD5 libFLAC
+/// computed over the PCM it was given must equal the one in the input's
+/// STREAMINFO, which catches input that decodes to the wrong audio (skipped
+/// if either stream has no MD5). Then the output is decoded again with
+/// [`decode_robust`] and compared sample by sample, failing with
+/// [`FlacError::VerifyMismatch`] on a difference.
+pub fn recompress(input: &[u8], new_config: EncoderConfig) -> Result<Vec<u8>, FlacError> {
+    let info = parse_streaminfo(input)?;
+    if !new_config.container.is_native() {
+        return Err(FlacError::Unsupported(
+            "recompress only writes native FLAC streams".to_string(),
+        ));
+    }
+    if (
+        new_config.sample_rate,
+        new_config.channels,
+        new_config.bits_per_sample,
+    ) != (
+        info.sample_rate,
+        info.channels as u32,
+        info.bits_per_sample as u32,
+    ) {
+        return Err(FlacError::InvalidConfig(format!(
+            "Config is {} Hz, {} channels, {}-bit but the input is {} Hz, {} channels, {}-bit",
+            new_config.sample_rate,
+            new_config.channels,
+            new_config.bits_per_sample,
+            info.sample_rate,
+            info.channels,
+            info.bits_per_sample
+        )));
+    }
+
+    let tags = Rc::new(RefCell::new(Vec::<VorbisComment>::new()));
+    let pictures = Rc::new(RefCell::new(Vec::<Picture>::new()));
+    let mut decoder = FlacDecoder::new();
+    let sink = tags.clone();
+    decoder.on_vorbis_comment(move |comment| sink.borrow_mut().push(comment.clone()))?;
+    let sink = pictures.clone();
+    decoder.on_picture(move |picture| sink.borrow_mut().push(picture.clone()))?;
+    decoder.init().map_err(FlacError::Decoder)?;
+    decoder.feed(input);
+    let samples = decoder.decode_remaining()?;
+
+    let mut encoder = FlacEncoder::from_config(&new_config);
+    for comment in tags.borrow().iter() {
+        for (name, value) in &comment.comments {
+            encoder.add_tag(name, value)?;
+        }
+    }
+    for picture in pictures.take() {
+        encoder.add_picture(picture)?;
+    }
+    encoder.restart()?;
+    let (encoded, final_info) = encode_complete(&mut encoder, &samples)?;
+
+    let unknown = [0; 16];
+    if info.md5 != unknown && final_info.md5 != unknown && info.md5 != final_info.md5 {
+        return Err(FlacError::Decoder(
+            "Input doesn't decode to the audio its MD5 describes".to_string(),
+        ));
+    }
+    Ok(encoded)
+}
+
+/// Joins native FLAC streams into one, e.g. to bounce a playlist to a
+/// single file: each is decoded with [`decode_robust`], the PCM is
+/// concatenated in order and encoded again with `config`.
+///
+/// Every stream, and `config`, must have the same sample rate, channel count
+/// and bit depth; nothing is resampled or converted. Metadata blocks are not
+/// carried over. The output header is completed and the output verified as
+/// in [`recompress`].
+///
+/// All of the PCM is held in memory, so the streams together may hold at
+/// most [`MAX_DECODED_SAMPLES`] interleaved samples, the cap
+/// [`decode_robust`] applies; the stream that would go over fails to decode
+/// with an error naming the limit.
+///
+/// For a crossfade the tracks have to be mixed before encoding, which is
+/// left to the caller: decode each with [`FlacDecoder::decode_planar`], mix
+/// the overlapping samples, and feed the result to
+/// [`FlacEncoder::encode_channels`].
+pub fn concatenate_flac(streams: &[&[u8]], config: EncoderConfig) -> Result<Vec<u8>, FlacError> {
+    if !config.container.is_native() {
+        return Err(FlacError::Unsupported(
+            "concatenate_flac only writes native FLAC streams".to_string(),
+        ));
+    }
+    let mut samples = Vec::new();
+    for (i, stream) in streams.iter().enumerate() {
+        let remaining = MAX_DECODED_SAMPLES - samples.len() as u64;
+        let decoded = decode_robust_with_limit(stream, remaining)?;
+        if (
+            decoded.sample_rate,
+            decoded.channels,
+            decoded.bits_per_sample,
+        ) != (config.sample_rate, config.channels, config.bits_per_sample)
+        {
+            return Err(FlacError::InvalidConfig(format!(
+                "Config is {} Hz, {} channels, {}-bit but stream {} is {} Hz, {} channels, {}-bit",
+                config.sample_rate,
+                config.channels,
+                config.bits_per_sample,
+                i,
+                decoded.sample_rate,
+                decoded.channels,
+                decoded.bits_per_sample
+            )));
+        }
+        samples.extend_from_slice(&decoded.samples);
+    }
+
+    let mut encoder = FlacEncoder::from_config(&config);
+    encoder.restart()?;
+    let (encoded, _) = encode_complete(&mut encoder, &samples)?;
+    Ok(encoded)
+}
+
+/// Encodes `samples` as the whole of the stream `encoder` has just started,
+/// and fills in STREAMINFO with the real totals and MD5 as back-patching
+/// would have. The output is decoded again and compared with `samples`.
+pub(crate) fn encode_complete(
+    encoder: &mut FlacEncoder,
+    samples: &[i32],
+) -> Result<(Vec<u8>, StreamInfo), FlacError> {
+    let mut encoded = Vec::new();
+    encoder.encode_chunked(samples, |encoder| {
+        encoded.append(&mut encoder.take_encoded())
+    })?;
+
+    // libFLAC always writes STREAMINFO first
+    let final_info = encoder.final_stream_info().ok_or_else(|| {
+        FlacError::Encoder("libFLAC didn't report the final STREAMINFO".to_string())
+    })?;
+    let header = STREAM_MARKER.len() + 4;
+    debug_assert_eq!(encoded[STREAM_MARKER.len()] & 0x7f, BLOCK_TYPE_STREAMINFO);
+    encoded[header..header + STREAMINFO_LEN].copy_from_slice(&final_info.to_bytes());
+
+    let decoded = decode_robust(&encoded)?;
+    if let Some(sample) = first_mismatch(samples, &decoded.samples) {
+        return Err(FlacError::VerifyMismatch { sample });
+    }
+    Ok((encoded, final_info))
+}
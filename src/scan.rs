@@ -0,0 +1,233 @@
+//! Reading the metadata of a FLAC stream without decoding its audio.
+
+use crate::decode::{read_callback, ReadsSlice, SliceInput};
+use crate::header::{id3v2_len, PLACEHOLDER_SAMPLE, STREAM_MARKER};
+use crate::metadata::{picture_from_ffi, stream_info_from_ffi, vorbis_comment_from_ffi};
+use crate::{
+    decoder_state_string, status_string, ApplicationId, FlacError, Picture, SeekPoint, StreamInfo,
+    VorbisComment,
+};
+use libflac_sys as ffi;
+use std::slice;
+
+/// Every metadata block of a stream, as read by [`read_metadata_only`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlacMetadata {
+    pub stream_info: StreamInfo,
+    /// `None` when the stream has no VORBIS_COMMENT block.
+    pub vorbis_comment: Option<VorbisComment>,
+    /// In stream order. `data` is empty unless picture data was asked for.
+    pub pictures: Vec<Picture>,
+    /// SEEKTABLE points with offsets from the start of the stream, as
+    /// [`read_seek_table`](crate::read_seek_table) gives them; placeholders
+    /// are left out.
+    pub seek_table: Vec<SeekPoint>,
+    /// APPLICATION blocks with their data, in stream order.
+    pub applications: Vec<(ApplicationId, Vec<u8>)>,
+    /// Total bytes of PADDING, block headers not included.
+    pub padding: u64,
+    /// Numbers of the CUESHEET tracks flagged as mastered with 50/15 µs
+    /// pre-emphasis, which a player should undo, e.g. from a CD rip.
+    ///
+    /// FLAC frame headers have no pre-emphasis flag; the CUESHEET track
+    /// flag is the only place the format records it. The encoder writes no
+    /// CUESHEET block, so it can't set it, and [`recompress`](crate::recompress)
+    /// doesn't carry it over: check this before re-encoding an archival rip
+    /// and keep the original if it is set.
+    pub pre_emphasis_tracks: Vec<u8>,
+    /// Bytes from the start of `data` to the first frame.
+    pub audio_offset: u64,
+}
+
+struct ScanState<'a> {
+    input: SliceInput<'a>,
+    picture_data: bool,
+    stream_info: Option<StreamInfo>,
+    vorbis_comment: Option<VorbisComment>,
+    pictures: Vec<Picture>,
+    seek_table: Vec<SeekPoint>,
+    applications: Vec<(ApplicationId, Vec<u8>)>,
+    padding: u64,
+    pre_emphasis_tracks: Vec<u8>,
+    audio_offset: u64,
+}
+
+/// Reads the metadata blocks of a native FLAC stream without decoding any
+/// audio, e.g. to index a library of files by their tags.
+///
+/// libFLAC is set to report every block type and stopped at the end of the
+/// metadata, so the cost depends on the header size, not the length of the
+/// audio, and `data` only needs to hold the header. A leading ID3v2 tag is
+/// skipped, as libFLAC does. Picture data can be large and is only copied
+/// with `picture_data` set; without it each [`Picture`] has its other fields
+/// and empty `data`. Of a CUESHEET only the pre-emphasis flags are kept;
+/// unknown blocks are not returned.
+pub fn read_metadata_only(data: &[u8], picture_data: bool) -> Result<FlacMetadata, FlacError> {
+    let mut state = ScanState {
+        input: SliceInput::new(data),
+        picture_data,
+        stream_info: None,
+        vorbis_comment: None,
+        pictures: Vec::new(),
+        seek_table: Vec::new(),
+        applications: Vec::new(),
+        padding: 0,
+        pre_emphasis_tracks: Vec::new(),
+        audio_offset: (id3v2_len(data).unwrap_or(0) + STREAM_MARKER.len()) as u64,
+    };
+
+    let decoder = unsafe { ffi::FLAC__stream_decoder_new() };
+    if decoder.is_null() {
+        return Err(FlacError::Decoder("Failed to allocate decoder".to_string()));
+    }
+    let result = unsafe { run(decoder, &mut state) };
+    unsafe {
+        ffi::FLAC__stream_decoder_finish(decoder);
+        ffi::FLAC__stream_decoder_delete(decoder);
+    }
+    result?;
+
+    let stream_info = state
+        .stream_info
+        .ok_or_else(|| FlacError::InvalidMetadata("No STREAMINFO block".to_string()))?;
+    for point in &mut state.seek_table {
+        point.byte_offset += state.audio_offset;
+    }
+    Ok(FlacMetadata {
+        stream_info,
+        vorbis_comment: state.vorbis_comment,
+        pictures: state.pictures,
+        seek_table: state.seek_table,
+        applications: state.applications,
+        padding: state.padding,
+        pre_emphasis_tracks: state.pre_emphasis_tracks,
+        audio_offset: state.audio_offset,
+    })
+}
+
+unsafe fn run(
+    decoder: *mut ffi::FLAC__StreamDecoder,
+    state: &mut ScanState<'_>,
+) -> Result<(), FlacError> {
+    ffi::FLAC__stream_decoder_set_metadata_respond_all(decoder);
+    let status = ffi::FLAC__stream_decoder_init_stream(
+        decoder,
+        Some(read_callback::<ScanState>),
+        None,
+        None,
+        None,
+        None,
+        Some(write_callback),
+        Some(metadata_callback),
+        Some(error_callback),
+        state as *mut ScanState<'_> as *mut libc::c_void,
+    );
+    if status != ffi::FLAC__STREAM_DECODER_INIT_STATUS_OK {
+        return Err(FlacError::Decoder(format!(
+            "Failed to initialize the decoder. Status: {}",
+            status_string(&ffi::FLAC__StreamDecoderInitStatusString, status)
+        )));
+    }
+    if ffi::FLAC__stream_decoder_process_until_end_of_metadata(decoder) == 0 {
+        return Err(FlacError::InvalidMetadata(format!(
+            "Failed to read the metadata, decoder state: {}",
+            decoder_state_string(decoder)
+        )));
+    }
+    Ok(())
+}
+
+impl<'a> ReadsSlice<'a> for ScanState<'a> {
+    fn input(&mut self) -> &mut SliceInput<'a> {
+        &mut self.input
+    }
+}
+
+/// Never reached: decoding stops before the first frame.
+unsafe extern "C" fn write_callback(
+    _decoder: *const ffi::FLAC__StreamDecoder,
+    _frame: *const ffi::FLAC__Frame,
+    _buffer: *const *const ffi::FLAC__int32,
+    _client_data: *mut libc::c_void,
+) -> ffi::FLAC__StreamDecoderWriteStatus {
+    ffi::FLAC__STREAM_DECODER_WRITE_STATUS_ABORT
+}
+
+unsafe extern "C" fn metadata_callback(
+    _decoder: *const ffi::FLAC__StreamDecoder,
+    block: *const ffi::FLAC__StreamMetadata,
+    client_data: *mut libc::c_void,
+) {
+    let state = &mut *(client_data as *mut ScanState<'_>);
+    let block = &*block;
+    // the 4-byte block header and the body
+    state.audio_offset += 4 + block.length as u64;
+
+    let data = &block.data;
+    match block.type_ {
+        ffi::FLAC__METADATA_TYPE_STREAMINFO => {
+            state.stream_info = Some(stream_info_from_ffi(&data.stream_info))
+        }
+        ffi::FLAC__METADATA_TYPE_PADDING => state.padding += block.length as u64,
+        ffi::FLAC__METADATA_TYPE_APPLICATION => {
+            let application = &data.application;
+            // the length includes the 4-byte ID
+            let len = (block.length as usize).saturating_sub(4);
+            let body = match application.data.is_null() {
+                true => Vec::new(),
+                false => slice::from_raw_parts(application.data, len).to_vec(),
+            };
+            state
+                .applications
+                .push((ApplicationId::from_bytes(application.id), body));
+        }
+        ffi::FLAC__METADATA_TYPE_SEEKTABLE => {
+            let table = &data.seek_table;
+            if !table.points.is_null() {
+                let points = slice::from_raw_parts(table.points, table.num_points as usize);
+                state.seek_table.extend(
+                    points
+                        .iter()
+                        .filter(|point| point.sample_number != PLACEHOLDER_SAMPLE)
+                        .map(|point| SeekPoint {
+                            sample_number: point.sample_number,
+                            byte_offset: point.stream_offset,
+                            frame_samples: point.frame_samples,
+                        }),
+                );
+            }
+        }
+        ffi::FLAC__METADATA_TYPE_CUESHEET => {
+            let cue_sheet = &data.cue_sheet;
+            if !cue_sheet.tracks.is_null() {
+                let tracks = slice::from_raw_parts(cue_sheet.tracks, cue_sheet.num_tracks as usize);
+                state.pre_emphasis_tracks.extend(
+                    tracks
+                        .iter()
+                        .filter(|track| track.pre_emphasis() != 0)
+                        .map(|track| track.number),
+                );
+            }
+        }
+        ffi::FLAC__METADATA_TYPE_VORBIS_COMMENT => {
+            state.vorbis_comment = Some(vorbis_comment_from_ffi(&data.vorbis_comment))
+        }
+        ffi::FLAC__METADATA_TYPE_PICTURE => {
+            let mut picture = data.picture;
+            if !state.picture_data {
+                picture.data = std::ptr::null_mut();
+            }
+            state.pictures.push(picture_from_ffi(&picture));
+        }
+        _ => {}
+    }
+}
+
+/// Decode errors only arise in frames, which are never reached; a bad
+/// header fails `process_until_end_of_metadata` on its own.
+unsafe extern "C" fn error_callback(
+    _decoder: *const ffi::FLAC__StreamDecoder,
+    _status: ffi::FLAC__StreamDecoderErrorStatus,
+    _client_data: *mut libc::c_void,
+) {
+}
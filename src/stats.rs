@@ -0,0 +1,284 @@
+use std::time::Duration;
+
+/// Level statistics for one channel over everything encoded in a stream.
+///
+/// `peak` is the largest absolute sample value and `rms` the square root of
+/// the mean of the squared samples, both in sample units. The dBFS values are
+/// relative to full scale, `2^(bits_per_sample - 1)`, so a full-scale square
+/// wave measures 0 dBFS for both and a full-scale sine about -3 dBFS RMS (no
+/// sine correction is applied). Silence gives negative infinity.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ChannelStat {
+    pub peak: u32,
+    pub peak_dbfs: f64,
+    pub rms: f64,
+    pub rms_dbfs: f64,
+}
+
+/// Running totals for [`ChannelStat`], updated as samples are encoded. The
+/// sum of squares is kept exactly, so the result doesn't depend on how the
+/// input was split across calls.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct ChannelLevels {
+    peak: u32,
+    sum_squares: u128,
+    samples: u64,
+}
+
+impl ChannelLevels {
+    /// Adds interleaved samples; `levels` has one entry per channel, or none
+    /// when statistics are off.
+    pub fn accumulate(levels: &mut [ChannelLevels], input: &[i32]) {
+        if levels.is_empty() {
+            return;
+        }
+        for frame in input.chunks_exact(levels.len()) {
+            for (level, &sample) in levels.iter_mut().zip(frame) {
+                let magnitude = sample.unsigned_abs();
+                level.peak = level.peak.max(magnitude);
+                level.sum_squares += magnitude as u128 * magnitude as u128;
+                level.samples += 1;
+            }
+        }
+    }
+
+    pub fn stat(&self, bits_per_sample: u32) -> ChannelStat {
+        let rms = match self.samples {
+            0 => 0.0,
+            samples => (self.sum_squares as f64 / samples as f64).sqrt(),
+        };
+        let full_scale = (1u64 << (bits_per_sample - 1)) as f64;
+        let dbfs = |level: f64| 20.0 * (level / full_scale).log10();
+        ChannelStat {
+            peak: self.peak,
+            peak_dbfs: dbfs(self.peak as f64),
+            rms,
+            rms_dbfs: dbfs(rms),
+        }
+    }
+}
+
+/// How often libFLAC removed wasted bits from a subframe, over every frame
+/// written in a stream.
+///
+/// Wasted bits are low bits that are zero in every sample of a subframe;
+/// libFLAC detects them per block and channel, shifts them out and records
+/// the count in the subframe header. Audio captured at full resolution
+/// practically never has them, except in digital silence, which libFLAC
+/// codes without any. So `wasted_subframes` close to `subframes` means the
+/// input was padded from a lower bit depth, e.g. 16-bit audio stored as
+/// 24-bit shows `max_wasted_bits` of 8. Stereo streams may code a side or
+/// mid channel, whose wasted bits can differ by one from the channels
+/// themselves.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct WastedBitsStat {
+    pub subframes: u64,
+    pub wasted_subframes: u64,
+    pub max_wasted_bits: u32,
+}
+
+impl WastedBitsStat {
+    /// Adds the wasted bits of one frame's subframes.
+    pub(crate) fn add(&mut self, wasted: &[u32]) {
+        self.subframes += wasted.len() as u64;
+        for &bits in wasted {
+            self.wasted_subframes += (bits > 0) as u64;
+            self.max_wasted_bits = self.max_wasted_bits.max(bits);
+        }
+    }
+}
+
+/// The waveform overview behind [`FlacEncoder::peaks`].
+///
+/// Bin `b` holds the frames (one sample per channel) whose index `i` has
+/// `i * bins_per_second / sample_rate == b`, so bins are as even as whole
+/// frames allow and never drift over a long stream: at 44100 Hz and 100
+/// bins per second each bin is exactly 441 frames. The last bin is kept
+/// even when the stream ends partway through it, covering fewer frames.
+///
+/// [`FlacEncoder::peaks`]: crate::FlacEncoder::peaks
+#[derive(Debug, Clone)]
+pub(crate) struct Peaks {
+    sample_rate: u64,
+    bins_per_second: u64,
+    /// Frames seen so far, and the index of the first frame of the next bin.
+    frames: u64,
+    next_bin: u64,
+    pub bins: Vec<(i32, i32)>,
+}
+
+impl Peaks {
+    pub fn new(sample_rate: u32, bins_per_second: u32) -> Self {
+        Peaks {
+            sample_rate: sample_rate as u64,
+            bins_per_second: bins_per_second as u64,
+            frames: 0,
+            next_bin: 0,
+            bins: Vec::new(),
+        }
+    }
+
+    /// Adds interleaved samples of `channels` channels.
+    pub fn accumulate(&mut self, input: &[i32], channels: usize) {
+        for frame in input.chunks_exact(channels) {
+            let (min, max) = frame.iter().fold((i32::MAX, i32::MIN), |(min, max), &s| {
+                (min.min(s), max.max(s))
+            });
+            if self.frames == self.next_bin {
+                self.bins.push((min, max));
+                let bins = self.bins.len() as u64;
+                self.next_bin = (bins * self.sample_rate).div_ceil(self.bins_per_second);
+            } else if let Some(bin) = self.bins.last_mut() {
+                *bin = (bin.0.min(min), bin.1.max(max));
+            }
+            self.frames += 1;
+        }
+    }
+}
+
+/// Wall-clock time spent in libFLAC's process calls over a stream; see
+/// [`FlacEncoder::encode_timing_stats`].
+///
+/// [`FlacEncoder::encode_timing_stats`]: crate::FlacEncoder::encode_timing_stats
+#[cfg(feature = "timing")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct TimingStats {
+    pub calls: u64,
+    pub total_nanos: u64,
+    /// The slowest single call.
+    pub max_nanos: u64,
+}
+
+#[cfg(feature = "timing")]
+impl TimingStats {
+    pub(crate) fn record(&mut self, elapsed: Duration) {
+        let nanos = elapsed.as_nanos().min(u64::MAX as u128) as u64;
+        self.calls += 1;
+        self.total_nanos = self.total_nanos.saturating_add(nanos);
+        self.max_nanos = self.max_nanos.max(nanos);
+    }
+}
+
+/// How long a [`Throughput`] window runs before it is folded into the
+/// average, so that timer resolution and one-off stalls don't dominate.
+const THROUGHPUT_WINDOW: Duration = Duration::from_millis(100);
+
+/// Weight of the newest window in the moving average.
+const THROUGHPUT_SMOOTHING: f64 = 0.2;
+
+/// Encode speed over a stream, as an exponential moving average of samples
+/// per channel per second, for [`FlacEncoder::estimated_time_remaining`].
+///
+/// Samples and time are gathered into windows of at least
+/// [`THROUGHPUT_WINDOW`] before they count, so there is no rate until the
+/// first window is complete.
+///
+/// [`FlacEncoder::estimated_time_remaining`]: crate::FlacEncoder::estimated_time_remaining
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Throughput {
+    /// Samples per channel handed to libFLAC in the stream.
+    pub samples: u64,
+    window_samples: u64,
+    window_time: Duration,
+    rate: Option<f64>,
+}
+
+impl Throughput {
+    /// Adds `samples` per channel that took `elapsed` to encode.
+    pub fn record(&mut self, samples: u64, elapsed: Duration) {
+        self.samples += samples;
+        self.window_samples += samples;
+        self.window_time += elapsed;
+        if self.window_time < THROUGHPUT_WINDOW {
+            return;
+        }
+        let rate = self.window_samples as f64 / self.window_time.as_secs_f64();
+        self.rate = Some(match self.rate {
+            Some(average) => average + THROUGHPUT_SMOOTHING * (rate - average),
+            None => rate,
+        });
+        self.window_samples = 0;
+        self.window_time = Duration::ZERO;
+    }
+
+    /// The time left to reach `total` samples per channel at the average
+    /// rate, or `None` before there is one.
+    pub fn remaining(&self, total: u64) -> Option<Duration> {
+        let rate = self.rate?;
+        let left = total.saturating_sub(self.samples);
+        Some(Duration::from_secs_f64(left as f64 / rate))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_channel_levels() {
+        let mut levels = vec![ChannelLevels::default(); 2];
+        // left: full-scale 16-bit square wave, right: silence
+        let input: Vec<i32> = (0..1000)
+            .flat_map(|i| [if i % 2 == 0 { 32767 } else { -32768 }, 0])
+            .collect();
+        ChannelLevels::accumulate(&mut levels, &input[..600]);
+        ChannelLevels::accumulate(&mut levels, &input[600..]);
+
+        let left = levels[0].stat(16);
+        assert_eq!(left.peak, 32768);
+        assert_eq!(left.peak_dbfs, 0.0);
+        assert!((left.rms - 32767.5).abs() < 0.01, "{}", left.rms);
+        assert!(left.rms_dbfs.abs() < 0.001);
+
+        let right = levels[1].stat(16);
+        assert_eq!(right.peak, 0);
+        assert_eq!(right.rms, 0.0);
+        assert_eq!(right.peak_dbfs, f64::NEG_INFINITY);
+
+        let mut mono = vec![ChannelLevels::default()];
+        ChannelLevels::accumulate(&mut mono, &[-4, 4, -4, 4]);
+        let stat = mono[0].stat(8);
+        assert_eq!((stat.peak, stat.rms), (4, 4.0));
+        assert!((stat.peak_dbfs - 20.0 * (4.0f64 / 128.0).log10()).abs() < 1e-9);
+
+        assert_eq!(ChannelLevels::default().stat(24).rms, 0.0);
+    }
+
+    #[test]
+    fn test_throughput() {
+        let mut throughput = Throughput::default();
+        // no estimate until a whole window has been timed
+        throughput.record(1000, Duration::from_millis(50));
+        assert_eq!(throughput.remaining(10_000), None);
+        throughput.record(1000, Duration::from_millis(50));
+        assert_eq!(throughput.samples, 2000);
+        // 20,000 samples per second, 8,000 samples to go
+        assert_eq!(
+            throughput.remaining(10_000),
+            Some(Duration::from_millis(400))
+        );
+
+        // a slower window only moves the average part of the way
+        throughput.record(1000, Duration::from_millis(100));
+        let remaining = throughput.remaining(10_000).unwrap();
+        assert!(remaining > Duration::from_millis(7000 / 20));
+        assert!(remaining < Duration::from_millis(7000 / 10));
+
+        assert_eq!(throughput.remaining(1000), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn test_peaks() {
+        // 10 Hz, 3 bins per second: bins of 4, 3 and 3 frames
+        let mut peaks = Peaks::new(10, 3);
+        let left: Vec<i32> = (0..11).collect();
+        let input: Vec<i32> = left.iter().flat_map(|&s| [s, -2 * s]).collect();
+        peaks.accumulate(&input[..6], 2);
+        peaks.accumulate(&input[6..], 2);
+        assert_eq!(
+            peaks.bins,
+            [(-6, 3), (-12, 6), (-18, 9), (-20, 10)],
+            "the partial fourth bin holds the 11th frame"
+        );
+    }
+}
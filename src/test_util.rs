@@ -0,0 +1,65 @@
+//! Test signals and round-trip helpers shared by the unit tests.
+
+use crate::{FlacDecoder, FlacEncoder};
+use libflac_sys as ffi;
+use soundkit::audio_packet::Encoder;
+
+/// A 20 Hz to 20 kHz sweep at 80% of full scale, odd channels inverted.
+pub(crate) fn sine_sweep(bits: u32, channels: usize, frames: usize) -> Vec<i32> {
+    let amplitude = ((1i64 << (bits - 1)) - 1) as f64 * 0.8;
+    let mut phase = 0.0f64;
+    (0..frames)
+        .flat_map(|i| {
+            let freq = 20.0 + 20_000.0 * i as f64 / frames as f64;
+            phase += 2.0 * std::f64::consts::PI * freq / 44100.0;
+            let sample = (phase.sin() * amplitude) as i32;
+            (0..channels).map(move |c| if c % 2 == 0 { sample } else { -sample })
+        })
+        .collect()
+}
+
+/// Full-scale noise, the same on every run.
+pub(crate) fn white_noise(bits: u32, channels: usize, frames: usize) -> Vec<i32> {
+    // xorshift32 with a fixed seed, so every run sees the same noise
+    let mut state = 0x1234_5678u32;
+    (0..frames * channels)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            (state as i32) >> (32 - bits)
+        })
+        .collect()
+}
+
+/// Encodes `samples` as one 44.1 kHz stream at compression level 5.
+pub(crate) fn encode_all(samples: &[i32], bits: u32, channels: u32) -> Vec<u8> {
+    let mut encoder = FlacEncoder::new(44100, bits, channels, 0, 5);
+    encoder.init().expect("Failed to initialize FLAC encoder");
+
+    let mut encoded = Vec::new();
+    let mut output = vec![0u8; encoder.max_output_len(4096)];
+    for chunk in samples.chunks(4096 * channels as usize) {
+        let len = encoder
+            .encode_i32(chunk, &mut output)
+            .expect("Failed to encode chunk");
+        encoded.extend_from_slice(&output[..len]);
+    }
+
+    let len = encoder
+        .finish(&mut output)
+        .expect("Failed to finish stream");
+    encoded.extend_from_slice(&output[..len]);
+    encoded
+}
+
+/// Decodes a whole native stream with [`FlacDecoder`].
+pub(crate) fn decode_all(encoded: &[u8]) -> Vec<i32> {
+    let mut decoder = FlacDecoder::new();
+    decoder.init().expect("Decoder initialization failed");
+    decoder.input_buffer.extend_from_slice(encoded);
+
+    let ok = unsafe { ffi::FLAC__stream_decoder_process_until_end_of_stream(decoder.decoder) };
+    assert!(ok != 0, "Decoding failed");
+    std::mem::take(&mut decoder.output_buffer)
+}
@@ -0,0 +1,44 @@
+use crate::{EncoderConfig, FlacDecoder, FlacEncoder, FlacError};
+
+/// Encodes `samples` to a complete stream, then decodes the result and checks
+/// it matches the input bit for bit.
+///
+/// This is independent of libFLAC's own verify mode, which checks each frame
+/// with a second decoder inside the encoder: here the finished stream goes
+/// through a fresh [`FlacDecoder`], the same way a player would read it. On
+/// a mismatch the index of the first differing interleaved sample is
+/// returned in [`FlacError::VerifyMismatch`]. Only the native container is
+/// supported.
+pub fn encode_and_verify(config: &EncoderConfig, samples: &[i32]) -> Result<Vec<u8>, FlacError> {
+    if !config.container.is_native() {
+        return Err(FlacError::Unsupported(
+            "encode_and_verify can only decode native FLAC streams".to_string(),
+        ));
+    }
+
+    let mut encoder = FlacEncoder::from_config(config);
+    encoder.restart()?;
+
+    let mut encoded = Vec::new();
+    encoder.encode_chunked(samples, |encoder| {
+        encoded.append(&mut encoder.take_encoded())
+    })?;
+
+    let mut decoder = FlacDecoder::new();
+    decoder.init().map_err(FlacError::Decoder)?;
+    decoder.feed(&encoded);
+    let decoded = decoder.decode_remaining()?;
+
+    if let Some(sample) = first_mismatch(samples, &decoded) {
+        return Err(FlacError::VerifyMismatch { sample });
+    }
+    Ok(encoded)
+}
+
+pub(crate) fn first_mismatch(expected: &[i32], actual: &[i32]) -> Option<usize> {
+    match expected.iter().zip(actual).position(|(a, b)| a != b) {
+        Some(i) => Some(i),
+        None if expected.len() != actual.len() => Some(expected.len().min(actual.len())),
+        None => None,
+    }
+}
@@ -0,0 +1,22 @@
+use libflac_sys as ffi;
+use std::ffi::CStr;
+
+/// Version of the linked libFLAC, e.g. `"1.4.3"`.
+pub fn flac_version() -> &'static str {
+    unsafe { CStr::from_ptr(ffi::FLAC__VERSION_STRING) }
+        .to_str()
+        .unwrap_or("unknown")
+}
+
+pub(crate) fn flac_version_at_least(major: u32, minor: u32) -> bool {
+    let mut parts = flac_version()
+        .split('.')
+        .map(|part| part.parse::<u32>().unwrap_or(0));
+    let found = (parts.next().unwrap_or(0), parts.next().unwrap_or(0));
+    found >= (major, minor)
+}
+
+/// Whether the linked libFLAC was built with Ogg support.
+pub(crate) fn ogg_supported() -> bool {
+    unsafe { ffi::FLAC_API_SUPPORTS_OGG_FLAC != 0 }
+}
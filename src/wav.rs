@@ -0,0 +1,346 @@
+//! Transcoding between WAV files and FLAC, optionally keeping the WAV's own
+//! chunks in `riff` APPLICATION blocks as `flac --keep-foreign-metadata`
+//! does.
+
+use crate::recompress::encode_complete;
+use crate::{
+    decode_robust, read_metadata_only, ApplicationId, EncoderConfig, FlacEncoder, FlacError,
+};
+
+/// `WAVE_FORMAT_PCM`.
+const FORMAT_PCM: u16 = 1;
+/// `WAVE_FORMAT_EXTENSIBLE`, whose sub-format GUID starts with the real tag.
+const FORMAT_EXTENSIBLE: u16 = 0xfffe;
+/// The most an APPLICATION block holds after its 4-byte ID.
+const MAX_CHUNK_BLOCK: usize = (1 << 24) - 1 - 4;
+
+/// The parts of a WAV file the transcode needs.
+struct Wav<'a> {
+    sample_rate: u32,
+    channels: u32,
+    bits_per_sample: u32,
+    /// Bytes per sample in the data chunk; `bits_per_sample` are the top bits.
+    container_bytes: usize,
+    data: &'a [u8],
+    /// Everything but the audio, in file order, one entry per `riff` block:
+    /// the 12-byte RIFF header, each chunk before the audio, the data chunk
+    /// header and each chunk after it.
+    foreign: Vec<&'a [u8]>,
+}
+
+fn le16(data: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([data[at], data[at + 1]])
+}
+
+fn le32(data: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+}
+
+fn invalid(message: &str) -> FlacError {
+    FlacError::InvalidInput(format!("WAV: {}", message))
+}
+
+fn parse_wav(wav: &[u8]) -> Result<Wav<'_>, FlacError> {
+    if wav.len() < 12 || &wav[..4] != b"RIFF" || &wav[8..12] != b"WAVE" {
+        return Err(invalid("No RIFF WAVE header"));
+    }
+    let mut foreign = vec![&wav[..12]];
+    let mut format = None;
+    let mut data = None;
+    let mut pos = 12;
+    while pos + 8 <= wav.len() {
+        let (id, size) = (&wav[pos..pos + 4], le32(wav, pos + 4) as usize);
+        let body_end = (pos + 8)
+            .checked_add(size)
+            .filter(|&end| end <= wav.len())
+            .ok_or_else(|| invalid("Chunk runs past the end of the file"))?;
+        if id == b"data" {
+            if data.is_some() {
+                return Err(invalid("More than one data chunk"));
+            }
+            foreign.push(&wav[pos..pos + 8]);
+            data = Some(&wav[pos + 8..body_end]);
+            // the pad byte after odd-sized audio is implied, not stored
+            pos = body_end + (size & 1);
+            continue;
+        }
+        if id == b"fmt " {
+            format = Some(&wav[pos + 8..body_end]);
+        }
+        let end = (body_end + (size & 1)).min(wav.len());
+        foreign.push(&wav[pos..end]);
+        pos = end;
+    }
+    if pos < wav.len() {
+        return Err(invalid("Trailing bytes after the last chunk"));
+    }
+    let format = format.ok_or_else(|| invalid("No fmt chunk"))?;
+    let data = data.ok_or_else(|| invalid("No data chunk"))?;
+    if format.len() < 16 {
+        return Err(invalid("fmt chunk is truncated"));
+    }
+
+    let (tag, channels, sample_rate) = (le16(format, 0), le16(format, 2), le32(format, 4));
+    let (block_align, mut bits) = (le16(format, 12) as usize, le16(format, 14) as u32);
+    let tag = match tag {
+        FORMAT_EXTENSIBLE if format.len() >= 26 => {
+            // wValidBitsPerSample, then the channel mask and the GUID
+            bits = match le16(format, 18) {
+                0 => bits,
+                valid => valid as u32,
+            };
+            le16(format, 24)
+        }
+        tag => tag,
+    };
+    if tag != FORMAT_PCM {
+        return Err(FlacError::Unsupported(format!(
+            "WAV format tag {:#06x}, only integer PCM is supported",
+            tag
+        )));
+    }
+    if channels == 0 || block_align % channels as usize != 0 {
+        return Err(invalid(
+            "fmt chunk block alignment doesn't fit the channels",
+        ));
+    }
+    let container_bytes = block_align / channels as usize;
+    if !(1..=4).contains(&container_bytes) || bits == 0 || bits as usize > container_bytes * 8 {
+        return Err(invalid("fmt chunk sample size is inconsistent"));
+    }
+    if data.len() % block_align != 0 {
+        return Err(invalid("data chunk ends partway through a sample frame"));
+    }
+    Ok(Wav {
+        sample_rate,
+        channels: channels as u32,
+        bits_per_sample: bits,
+        container_bytes,
+        data,
+        foreign,
+    })
+}
+
+/// Encodes a WAV file held in `wav` as a native FLAC stream.
+///
+/// The sample rate, channel count and bit depth are the WAV's and replace
+/// those in `config`; the other settings are used as given. The input must
+/// be integer PCM (`WAVE_FORMAT_PCM`, or `WAVE_FORMAT_EXTENSIBLE` with a PCM
+/// sub-format), whose valid bits become the FLAC bit depth. The output header
+/// is completed and the output verified as in
+/// [`recompress`](crate::recompress).
+///
+/// With `keep_foreign_metadata` every chunk FLAC has no field for is kept
+/// verbatim, in order, in `riff` APPLICATION blocks laid out as the
+/// reference `flac --keep-foreign-metadata` lays them out: the RIFF header,
+/// each chunk before the audio (`fmt ` included), the data chunk header, then
+/// each chunk after the audio. [`flac_to_wav_with_foreign`] puts them back
+/// to give the original file byte for byte. A chunk too large for an
+/// APPLICATION block (16 MiB) fails with [`FlacError::InvalidMetadata`].
+/// AIFF and RF64 sources are not read.
+pub fn wav_to_flac(
+    wav: &[u8],
+    mut config: EncoderConfig,
+    keep_foreign_metadata: bool,
+) -> Result<Vec<u8>, FlacError> {
+    let parsed = parse_wav(wav)?;
+    config.sample_rate = parsed.sample_rate;
+    config.channels = parsed.channels;
+    config.bits_per_sample = parsed.bits_per_sample;
+    if !config.container.is_native() {
+        return Err(FlacError::Unsupported(
+            "wav_to_flac only writes native FLAC streams".to_string(),
+        ));
+    }
+
+    let bytes = parsed.container_bytes;
+    let shift = 32 - 8 * bytes as u32;
+    let unused = (8 * bytes as u32) - parsed.bits_per_sample;
+    let samples: Vec<i32> = parsed
+        .data
+        .chunks_exact(bytes)
+        .map(|sample| match bytes {
+            // 8-bit WAV is unsigned
+            1 => ((sample[0] as i32 - 128) << 24) >> (24 + unused),
+            _ => {
+                let value = sample
+                    .iter()
+                    .rev()
+                    .fold(0u32, |value, &byte| (value << 8) | byte as u32);
+                ((value << shift) as i32) >> (shift + unused)
+            }
+        })
+        .collect();
+
+    let mut encoder = FlacEncoder::from_config(&config);
+    if keep_foreign_metadata {
+        for chunk in &parsed.foreign {
+            if chunk.len() > MAX_CHUNK_BLOCK {
+                return Err(FlacError::InvalidMetadata(format!(
+                    "WAV chunk of {} bytes is too large for an APPLICATION block",
+                    chunk.len()
+                )));
+            }
+            encoder.add_application(ApplicationId::RIFF_CHUNKS, chunk.to_vec())?;
+        }
+    }
+    encoder.restart()?;
+    let (encoded, _) = encode_complete(&mut encoder, &samples)?;
+    Ok(encoded)
+}
+
+/// Decodes a native FLAC stream to a WAV file, restoring the chunks that
+/// [`wav_to_flac`] (or `flac --keep-foreign-metadata`) kept in `riff`
+/// APPLICATION blocks.
+///
+/// With those blocks the output is the original WAV byte for byte; the
+/// stored data chunk header must match the decoded audio's size or the call
+/// fails with [`FlacError::InvalidMetadata`]. Without them a plain
+/// `WAVE_FORMAT_PCM` file is written. The audio is decoded with
+/// [`decode_robust`], so damaged streams fail as they would there.
+pub fn flac_to_wav_with_foreign(flac: &[u8]) -> Result<Vec<u8>, FlacError> {
+    let decoded = decode_robust(flac)?;
+    let metadata = read_metadata_only(flac, false)?;
+    let foreign: Vec<&[u8]> = metadata
+        .applications
+        .iter()
+        .filter(|(id, _)| *id == ApplicationId::RIFF_CHUNKS)
+        .map(|(_, data)| data.as_slice())
+        .collect();
+
+    let channels = decoded.channels as usize;
+    let bits = decoded.bits_per_sample;
+    let mut header;
+    let foreign = match foreign.is_empty() {
+        false => foreign,
+        true => {
+            let block_align = channels * bits.div_ceil(8) as usize;
+            let data_len = (decoded.samples.len() / channels * block_align) as u32;
+            header = b"RIFF".to_vec();
+            header.extend((36 + data_len + (data_len & 1)).to_le_bytes());
+            header.extend(b"WAVEfmt ");
+            header.extend(16u32.to_le_bytes());
+            header.extend(FORMAT_PCM.to_le_bytes());
+            header.extend((channels as u16).to_le_bytes());
+            header.extend(decoded.sample_rate.to_le_bytes());
+            header.extend((decoded.sample_rate * block_align as u32).to_le_bytes());
+            header.extend((block_align as u16).to_le_bytes());
+            header.extend((8 * bits.div_ceil(8) as u16).to_le_bytes());
+            header.extend(b"data");
+            header.extend(data_len.to_le_bytes());
+            vec![&header[..12], &header[12..36], &header[36..]]
+        }
+    };
+
+    // the layout the stored chunks describe
+    let metadata_error =
+        |message: &str| FlacError::InvalidMetadata(format!("riff blocks: {}", message));
+    let data_at = foreign
+        .iter()
+        .position(|chunk| chunk.len() == 8 && chunk.starts_with(b"data"))
+        .ok_or_else(|| metadata_error("no data chunk header"))?;
+    let format = foreign[..data_at]
+        .iter()
+        .find(|chunk| chunk.len() >= 24 && chunk.starts_with(b"fmt "))
+        .ok_or_else(|| metadata_error("no fmt chunk before the audio"))?;
+    let block_align = le16(format, 8 + 12) as usize;
+    let container_bytes = block_align / channels.max(1);
+    if !(1..=4).contains(&container_bytes)
+        || container_bytes * channels != block_align
+        || bits as usize > container_bytes * 8
+    {
+        return Err(metadata_error("fmt chunk doesn't match the FLAC stream"));
+    }
+    let data_len = le32(foreign[data_at], 4) as usize;
+    if data_len != decoded.samples.len() * container_bytes {
+        return Err(metadata_error(
+            "data chunk size doesn't match the decoded audio",
+        ));
+    }
+
+    let mut wav = Vec::with_capacity(data_len + foreign.iter().map(|c| c.len()).sum::<usize>() + 1);
+    for chunk in &foreign[..=data_at] {
+        wav.extend_from_slice(chunk);
+    }
+    let unused = 8 * container_bytes as u32 - bits;
+    for &sample in &decoded.samples {
+        let value = (sample << unused) as u32;
+        match container_bytes {
+            1 => wav.push((value as u8).wrapping_add(128)),
+            bytes => wav.extend_from_slice(&value.to_le_bytes()[..bytes]),
+        }
+    }
+    if data_len & 1 == 1 {
+        wav.push(0);
+    }
+    for chunk in &foreign[data_at + 1..] {
+        wav.extend_from_slice(chunk);
+    }
+    Ok(wav)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(id: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut chunk = id.to_vec();
+        chunk.extend((body.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(body);
+        if body.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    /// A 24-bit stereo WAV with chunks either side of the audio.
+    fn wav_with_chunks() -> Vec<u8> {
+        let mut format = FORMAT_PCM.to_le_bytes().to_vec();
+        format.extend(2u16.to_le_bytes());
+        format.extend(48000u32.to_le_bytes());
+        format.extend((48000u32 * 6).to_le_bytes());
+        format.extend(6u16.to_le_bytes());
+        format.extend(24u16.to_le_bytes());
+        let audio: Vec<u8> = (0..3000u32)
+            .flat_map(|i| (i.wrapping_mul(2654435761) >> 8).to_le_bytes()[..3].to_vec())
+            .collect();
+
+        let mut body = b"WAVE".to_vec();
+        body.extend(chunk(b"fmt ", &format));
+        body.extend(chunk(b"bext", &[7; 602]));
+        body.extend(chunk(b"data", &audio));
+        body.extend(chunk(b"LIST", b"INFOICMT\x05\0\0\0note\0"));
+        chunk(b"RIFF", &body)
+    }
+
+    #[test]
+    fn test_foreign_chunks_round_trip() {
+        let wav = wav_with_chunks();
+        let config = EncoderConfig::new(44100, 1, 16);
+        let flac = wav_to_flac(&wav, config.clone(), true).unwrap();
+        let riff_blocks = read_metadata_only(&flac, false)
+            .unwrap()
+            .applications
+            .iter()
+            .filter(|(id, _)| *id == ApplicationId::RIFF_CHUNKS)
+            .count();
+        // RIFF header, fmt, bext, data header, LIST
+        assert_eq!(riff_blocks, 5);
+        assert_eq!(flac_to_wav_with_foreign(&flac).unwrap(), wav);
+
+        // without the chunks the audio still survives, in a plain header
+        let plain = flac_to_wav_with_foreign(&wav_to_flac(&wav, config, false).unwrap()).unwrap();
+        let (original, restored) = (parse_wav(&wav).unwrap(), parse_wav(&plain).unwrap());
+        assert_eq!(restored.data, original.data);
+        assert_eq!(restored.foreign.len(), 3);
+    }
+
+    #[test]
+    fn test_rejects_float_wav() {
+        let mut wav = wav_with_chunks();
+        // the fmt chunk's format tag
+        wav[20] = 3;
+        let result = wav_to_flac(&wav, EncoderConfig::new(44100, 2, 16), false);
+        assert!(matches!(result, Err(FlacError::Unsupported(_))));
+    }
+}
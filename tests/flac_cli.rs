@@ -0,0 +1,119 @@
+//! Checks encoder output with the reference `flac` tool, which decodes it
+//! independently of the libFLAC build the crate links. Skipped when `flac`
+//! isn't on the `PATH`.
+
+use soundkit::audio_packet::Encoder;
+use soundkit_flac::{encode_and_verify, EncoderConfig, FlacEncoder, Picture};
+use std::path::PathBuf;
+use std::process::Command;
+
+fn flac_available() -> bool {
+    match Command::new("flac").arg("--version").output() {
+        Ok(output) => output.status.success(),
+        Err(_) => {
+            eprintln!("flac not found; skipping reference decoder checks");
+            false
+        }
+    }
+}
+
+/// Runs `flac -t` (decode and compare against the MD5) on `encoded`.
+fn flac_test(name: &str, encoded: &[u8]) {
+    let path: PathBuf = std::env::temp_dir().join(format!(
+        "soundkit-flac-cli-{}-{}.flac",
+        name,
+        std::process::id()
+    ));
+    std::fs::write(&path, encoded).unwrap();
+    let output = Command::new("flac")
+        .args(["-t", "--silent"])
+        .arg(&path)
+        .output()
+        .expect("failed to run flac");
+    std::fs::remove_file(&path).unwrap();
+    assert!(
+        output.status.success(),
+        "flac -t rejected {} ({}): {}",
+        name,
+        output.status,
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+fn test_signal(bits: u32, channels: u32, frames: usize) -> Vec<i32> {
+    let amplitude = ((1i64 << (bits - 1)) - 1) as f64 * 0.8;
+    let mut seed = 0x1234_5678u32;
+    (0..frames * channels as usize)
+        .map(|i| {
+            seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+            let noise = (seed >> 16) as f64 / 65536.0 - 0.5;
+            let t = (i / channels as usize) as f64 / 44100.0;
+            let tone = (t * 440.0 * (1.0 + t) * std::f64::consts::TAU).sin();
+            (amplitude * (0.9 * tone + 0.1 * noise)) as i32
+        })
+        .collect()
+}
+
+#[test]
+fn flac_accepts_encoder_output() {
+    if !flac_available() {
+        return;
+    }
+    let configs = [
+        (
+            "level0",
+            EncoderConfig::new(44100, 2, 16).compression_level(0),
+        ),
+        (
+            "level8",
+            EncoderConfig::new(44100, 2, 16).compression_level(8),
+        ),
+        ("mono24", EncoderConfig::new(96000, 1, 24)),
+        (
+            "surround",
+            EncoderConfig::new(48000, 6, 16).block_size(1152),
+        ),
+        (
+            "deterministic",
+            EncoderConfig::new(44100, 2, 16).deterministic(true),
+        ),
+        (
+            "vendor",
+            EncoderConfig::new(44100, 2, 16).vendor_string("soundkit-flac test"),
+        ),
+    ];
+    for (name, config) in configs {
+        let samples = test_signal(config.bits_per_sample, config.channels, 30_000);
+        let encoded = encode_and_verify(&config, &samples).unwrap();
+        flac_test(name, &encoded);
+    }
+}
+
+#[test]
+fn flac_accepts_a_back_patched_stream_with_metadata() {
+    if !flac_available() {
+        return;
+    }
+    let samples = test_signal(16, 2, 30_000);
+    let path = std::env::temp_dir().join(format!(
+        "soundkit-flac-cli-sink-{}.flac",
+        std::process::id()
+    ));
+    let mut encoder = FlacEncoder::from_config(&EncoderConfig::new(44100, 2, 16));
+    encoder.add_tag("TITLE", "Reference check").unwrap();
+    encoder
+        .add_picture(Picture::front_cover("image/png", vec![1; 128]))
+        .unwrap();
+    encoder.add_seek_points(4096, 30_000).unwrap();
+    encoder
+        .set_seekable_sink(std::fs::File::create(&path).unwrap())
+        .unwrap();
+    encoder.init().unwrap();
+    let mut output = vec![0u8; encoder.max_output_len(30_000)];
+    encoder.encode_interleaved(&samples, &mut output).unwrap();
+    encoder.finish(&mut output).unwrap();
+    drop(encoder);
+    let encoded = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    flac_test("sink", &encoded);
+}